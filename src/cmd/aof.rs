@@ -0,0 +1,403 @@
+use crate::{
+    cmd::*,
+    db::{Entry, Value, DB},
+    protocol::encode::encode,
+    utils::instant_to_unix_millis,
+};
+use anyhow::{anyhow, Result};
+use tokio::time::Instant;
+use tracing::error;
+
+/// Elements of a collection written by a single command when a key is
+/// rewritten, so that no command grows too large.
+const AOF_REWRITE_ITEMS_PER_CMD: usize = 64;
+
+/// What the databases hand over to the AOF writer.
+#[derive(Debug)]
+pub enum AofMsg {
+    /// Writes of a database, encoded. `reply` is held back until they are
+    /// synced if the fsync policy is `always`.
+    Append {
+        db_id: usize,
+        buf: Bytes,
+        reply: Option<(oneshot::Sender<Frame>, Frame)>,
+    },
+    /// A rewrite begins, every database is about to send its `Base`.
+    Rewrite,
+    /// The commands rebuilding every key of a database, which start the
+    /// rewritten log. Writes appended afterwards follow them there. A key
+    /// that can't be rebuilt fails the rewrite.
+    Base { db_id: usize, buf: Result<Bytes> },
+    /// Writes of a transaction that held several databases, what each of
+    /// them logged, appended as one MULTI/EXEC block. `reply` is as for
+    /// `Append`.
+    Transaction {
+        parts: Vec<(usize, Bytes)>,
+        reply: Option<(oneshot::Sender<Frame>, Frame)>,
+    },
+}
+
+/// Where a database logs its writes. What a task logs is sent along with
+/// its reply.
+#[derive(Debug)]
+pub struct AofFeed {
+    tx: mpsc::UnboundedSender<AofMsg>,
+    /// Replies wait for the writes before them to be synced.
+    hold_replies: bool,
+    /// Set while a transaction holds the database, what it logs then is
+    /// taken by the transaction instead.
+    held: bool,
+    pending: Vec<Frame>,
+}
+
+impl AofFeed {
+    pub fn new(tx: mpsc::UnboundedSender<AofMsg>, hold_replies: bool) -> AofFeed {
+        Self {
+            tx,
+            hold_replies,
+            held: false,
+            pending: Vec::new(),
+        }
+    }
+}
+
+/// A single key command along with the frame it was parsed from, which is
+/// logged if the command changes the database.
+#[derive(Debug)]
+pub struct Logged {
+    pub cmd: OneshotCommand,
+    pub frame: Frame,
+}
+
+/// `cmd` as sent to its database, along with its frame if it is a write and
+/// writes are logged.
+pub fn logged(cmd: OneshotCommand, frame: Option<Frame>) -> AtomicCMD {
+    match frame {
+        Some(frame) if cmd.is_write() => Logged { cmd, frame }.into(),
+        _ => cmd.into(),
+    }
+}
+
+/// Ends loading: keys past their deadline expire from now on, and writes
+/// are logged to `feed` if the AOF is enabled.
+#[derive(Debug)]
+pub struct Loaded {
+    pub feed: Option<AofFeed>,
+}
+
+impl Loaded {
+    pub fn exec(self, db: &mut DB) -> Frame {
+        db.loading = false;
+        db.aof = self.feed;
+        Frame::Ok
+    }
+}
+
+/// Sends the commands rebuilding every key of the database, in one go, as
+/// its `Base` of the rewritten log.
+#[derive(Debug)]
+pub struct RewriteAof {}
+
+impl RewriteAof {
+    pub fn exec(self, db: &mut DB) -> Frame {
+        let feed = match &db.aof {
+            Some(feed) => feed,
+            None => return Frame::Ok,
+        };
+        let now = Instant::now();
+        let mut frames = Vec::new();
+        let buf = db
+            .database
+            .iter()
+            .filter(|(_, en)| !en.is_expired(now))
+            .try_for_each(|(key, en)| {
+                rewrite_entry(key, en, &mut frames).map_err(|e| anyhow!("key {:?}: {}", key, e))
+            })
+            .map(|_| encode_commands(&frames));
+        let _ = feed.tx.send(AofMsg::Base { db_id: db.id, buf });
+        Frame::Ok
+    }
+}
+
+/// Hands what the database logged since it was locked over to the
+/// transaction holding it, encoded.
+#[derive(Debug)]
+pub struct TakeLogged {}
+
+impl TakeLogged {
+    pub fn exec(self, db: &mut DB) -> Frame {
+        match &mut db.aof {
+            Some(feed) => Frame::BulkStrings(encode_commands(&std::mem::take(&mut feed.pending))),
+            None => Frame::BulkStrings(Bytes::new()),
+        }
+    }
+}
+
+pub fn command(args: Vec<Bytes>) -> Frame {
+    Frame::Arrays(args.into_iter().map(Frame::BulkStrings).collect())
+}
+
+fn encode_commands(frames: &[Frame]) -> Bytes {
+    let mut buf = BytesMut::new();
+    for frame in frames {
+        for fragment in encode(frame).expect("commands can always be encoded") {
+            buf.extend_from_slice(&fragment);
+        }
+    }
+    buf.freeze()
+}
+
+/// `parts` wrapped in MULTI/EXEC, `None` if there are none.
+pub fn encode_transaction<'a>(parts: impl Iterator<Item = &'a Bytes>) -> Option<Bytes> {
+    let mut parts = parts.peekable();
+    parts.peek()?;
+    let mut buf = BytesMut::from(&b"*1\r\n$5\r\nMULTI\r\n"[..]);
+    for part in parts {
+        buf.extend_from_slice(part);
+    }
+    buf.extend_from_slice(b"*1\r\n$4\r\nEXEC\r\n");
+    Some(buf.freeze())
+}
+
+fn unix_millis(at: Instant) -> Bytes {
+    Bytes::from(instant_to_unix_millis(at).to_string())
+}
+
+/// Appends commands of at most `AOF_REWRITE_ITEMS_PER_CMD` items each,
+/// every item taking one or more arguments.
+fn push_chunked(
+    out: &mut Vec<Frame>,
+    name: &'static [u8],
+    key: &Bytes,
+    items: impl Iterator<Item = Vec<Bytes>>,
+) {
+    let mut items = items.peekable();
+    while items.peek().is_some() {
+        let mut args = vec![Bytes::from_static(name), key.clone()];
+        for item in items.by_ref().take(AOF_REWRITE_ITEMS_PER_CMD) {
+            args.extend(item);
+        }
+        out.push(command(args));
+    }
+}
+
+/// Appends the commands that build `en` under `key` from scratch. Deadlines
+/// are written as unix time, which replays the same whenever it happens.
+/// HyperLogLogs are set as the string Redis keeps them as. Fails on strings
+/// set from a frame that holds no bytes.
+pub fn rewrite_entry(key: &Bytes, en: &Entry, out: &mut Vec<Frame>) -> Result<()> {
+    if en.data.is_str() {
+        let mut args = vec![
+            Bytes::from_static(b"SET"),
+            key.clone(),
+            en.data.str_bytes()?,
+        ];
+        if let Some(at) = en.expiration {
+            args.extend([Bytes::from_static(b"PXAT"), unix_millis(at)]);
+        }
+        out.push(command(args));
+        return Ok(());
+    }
+    match &en.data {
+        Value::Str(_) | Value::MutStr(_) => unreachable!("strings are set above"),
+        Value::List(l) => push_chunked(out, b"RPUSH", key, l.iter().map(|e| vec![e.clone()])),
+        Value::Set(s) => push_chunked(out, b"SADD", key, s.iter().map(|m| vec![m])),
+        Value::ZSet(z) => push_chunked(
+            out,
+            b"ZADD",
+            key,
+            z.iter().map(|(m, s)| vec![score_to_bytes(s), m.clone()]),
+        ),
+        Value::Hash(h) => {
+            push_chunked(
+                out,
+                b"HSET",
+                key,
+                h.iter().map(|(f, v)| vec![f.clone(), v.clone()]),
+            );
+            for (at, field) in h.field_deadlines() {
+                out.push(command(vec![
+                    Bytes::from_static(b"HPEXPIREAT"),
+                    key.clone(),
+                    unix_millis(at),
+                    Bytes::from_static(b"FIELDS"),
+                    Bytes::from_static(b"1"),
+                    field,
+                ]));
+            }
+        }
+        Value::Stream(s) => rewrite_stream(key, s, out),
+        Value::HyperLogLog(h) => out.push(command(vec![
+            Bytes::from_static(b"SET"),
+            key.clone(),
+            Bytes::from(h.dense_string()),
+        ])),
+    }
+    if let Some(at) = en.expiration {
+        out.push(command(vec![
+            Bytes::from_static(b"PEXPIREAT"),
+            key.clone(),
+            unix_millis(at),
+        ]));
+    }
+    Ok(())
+}
+
+/// Streams are added to entry by entry. An ID past the last entry is added
+/// and deleted again to move the last ID there, groups are created along
+/// with the stream in case it is empty.
+fn rewrite_stream(key: &Bytes, s: &StreamValue, out: &mut Vec<Frame>) {
+    let mut last = StreamId::MIN;
+    for (id, fields) in s.range(StreamId::MIN, StreamId::MAX) {
+        let mut args = vec![Bytes::from_static(b"XADD"), key.clone(), id.to_bytes()];
+        for (field, value) in fields {
+            args.extend([field.clone(), value.clone()]);
+        }
+        out.push(command(args));
+        last = *id;
+    }
+    if s.last_id > last {
+        let id = s.last_id.to_bytes();
+        out.push(command(vec![
+            Bytes::from_static(b"XADD"),
+            key.clone(),
+            id.clone(),
+            Bytes::new(),
+            Bytes::new(),
+        ]));
+        out.push(command(vec![Bytes::from_static(b"XDEL"), key.clone(), id]));
+    }
+    for (name, g) in &s.groups {
+        g.rewrite(key, name, s, out);
+    }
+    if s.last_id == StreamId::MIN && s.groups.is_empty() {
+        // an empty stream that never had an entry, made by MKSTREAM.
+        out.push(command(vec![
+            Bytes::from_static(b"XGROUP"),
+            Bytes::from_static(b"CREATE"),
+            key.clone(),
+            Bytes::new(),
+            Bytes::from_static(b"0"),
+            Bytes::from_static(b"MKSTREAM"),
+        ]));
+        out.push(command(vec![
+            Bytes::from_static(b"XGROUP"),
+            Bytes::from_static(b"DESTROY"),
+            key.clone(),
+            Bytes::new(),
+        ]));
+    }
+}
+
+impl DB {
+    /// Whether writes are logged, commands that log something other than
+    /// their frame only build it then.
+    pub fn logging(&self) -> bool {
+        self.aof.is_some()
+    }
+
+    /// Logs `frame`, it is sent to the writer along with the next reply.
+    pub fn propagate(&mut self, frame: Frame) {
+        if let Some(feed) = &mut self.aof {
+            feed.pending.push(frame);
+        }
+    }
+
+    /// Logs the commands that build `key` as it is now, for writes whose
+    /// frame wouldn't replay to the same value.
+    pub fn propagate_key(&mut self, key: &Bytes) {
+        if !self.logging() {
+            return;
+        }
+        let mut frames = Vec::new();
+        match self.database.get(key) {
+            Some(en) => {
+                // SET replaces whatever is there, collections are built up.
+                if !en.data.is_str() {
+                    frames.push(command(vec![Bytes::from_static(b"DEL"), key.clone()]));
+                }
+                if let Err(e) = rewrite_entry(key, en, &mut frames) {
+                    error!("failed to log key {:?} to the AOF: {}", key, e);
+                    return;
+                }
+            }
+            None => frames.push(command(vec![Bytes::from_static(b"DEL"), key.clone()])),
+        }
+        for frame in frames {
+            self.propagate(frame);
+        }
+    }
+
+    /// Logs the deadline `key` has now as unix time.
+    pub fn propagate_ttl(&mut self, key: &Bytes) {
+        if !self.logging() {
+            return;
+        }
+        let args = match self.database.get(key).map(|en| en.expiration) {
+            None => vec![Bytes::from_static(b"DEL"), key.clone()],
+            Some(Some(at)) => vec![
+                Bytes::from_static(b"PEXPIREAT"),
+                key.clone(),
+                unix_millis(at),
+            ],
+            Some(None) => vec![Bytes::from_static(b"PERSIST"), key.clone()],
+        };
+        self.propagate(command(args));
+    }
+
+    /// Wraps what the running task logged in MULTI/EXEC, so that replay
+    /// applies all of it or nothing.
+    pub fn propagate_as_transaction(&mut self) {
+        if let Some(feed) = self.aof.as_mut().filter(|feed| feed.pending.len() > 1) {
+            feed.pending
+                .insert(0, command(vec![Bytes::from_static(b"MULTI")]));
+            feed.pending
+                .push(command(vec![Bytes::from_static(b"EXEC")]));
+        }
+    }
+
+    /// Sends `ret` through `ret_tx`. What the task logged goes to the writer
+    /// first, which sends the reply itself if it has to sync before.
+    pub fn reply(&mut self, ret_tx: oneshot::Sender<Frame>, ret: Frame) {
+        if matches!(&self.aof, Some(feed) if feed.held) {
+            let _ = ret_tx.send(ret);
+        } else if matches!(&self.aof, Some(feed) if feed.hold_replies && !feed.pending.is_empty()) {
+            self.flush_aof(Some((ret_tx, ret)));
+        } else {
+            self.flush_aof(None);
+            let _ = ret_tx.send(ret);
+        }
+    }
+
+    /// While a transaction holds the database, what it logs waits for the
+    /// transaction to take it. Whatever it left is sent once it lets go.
+    pub fn hold_aof(&mut self, held: bool) {
+        if let Some(feed) = &mut self.aof {
+            feed.held = held;
+        }
+        if !held {
+            self.flush_aof(None);
+        }
+    }
+
+    /// Sends what was logged to the writer. Called without a reply for
+    /// what is logged outside of any task, such as the deletions of the
+    /// active expire cycle.
+    pub fn flush_aof(&mut self, reply: Option<(oneshot::Sender<Frame>, Frame)>) {
+        let feed = match &mut self.aof {
+            Some(feed) if !feed.pending.is_empty() => feed,
+            _ => return,
+        };
+        let buf = encode_commands(&std::mem::take(&mut feed.pending));
+        let _ = feed.tx.send(AofMsg::Append {
+            db_id: self.id,
+            buf,
+            reply,
+        });
+    }
+}
+
+impl AtomicCMDMarker for Logged {}
+impl AtomicCMDMarker for Loaded {}
+impl AtomicCMDMarker for RewriteAof {}
+impl AtomicCMDMarker for TakeLogged {}
@@ -0,0 +1,592 @@
+use crate::{cmd::*, db::Entry, impl_traverse_command, utils::normalize_range};
+use anyhow::Result;
+
+const BYTE: usize = rolling_hash_const(b"byte");
+const BIT: usize = rolling_hash_const(b"bit");
+const AND: usize = rolling_hash_const(b"and");
+const OR: usize = rolling_hash_const(b"or");
+const XOR: usize = rolling_hash_const(b"xor");
+const NOT: usize = rolling_hash_const(b"not");
+const GET: usize = rolling_hash_const(b"get");
+const SET: usize = rolling_hash_const(b"set");
+const INCRBY: usize = rolling_hash_const(b"incrby");
+const OVERFLOW: usize = rolling_hash_const(b"overflow");
+const WRAP: usize = rolling_hash_const(b"wrap");
+const SAT: usize = rolling_hash_const(b"sat");
+const FAIL: usize = rolling_hash_const(b"fail");
+
+/// Bitmaps are capped at 512MB like any other string.
+const MAX_BIT_OFFSET: u64 = (1 << 32) - 1;
+
+fn get_bit(buf: &[u8], pos: u64) -> u8 {
+    buf.get((pos >> 3) as usize)
+        .map_or(0, |b| (b >> (7 - (pos & 7))) & 1)
+}
+
+/// Sets bit `pos`, growing `buf` as needed. Returns the former bit.
+fn set_bit(buf: &mut BytesMut, pos: u64, bit: u8) -> u8 {
+    let byte = (pos >> 3) as usize;
+    if buf.len() <= byte {
+        buf.resize(byte + 1, 0);
+    }
+    let mask = 1 << (7 - (pos & 7));
+    let old = (buf[byte] & mask != 0) as u8;
+    if bit == 1 {
+        buf[byte] |= mask;
+    } else {
+        buf[byte] &= !mask;
+    }
+    old
+}
+
+/// Number of set bits from `first` to `last` inclusive.
+fn count_bits(buf: &[u8], first: u64, last: u64) -> u64 {
+    let (mut pos, mut res) = (first, 0);
+    while pos <= last {
+        if pos & 7 == 0 && pos + 7 <= last {
+            res += buf[(pos >> 3) as usize].count_ones() as u64;
+            pos += 8;
+        } else {
+            res += get_bit(buf, pos) as u64;
+            pos += 1;
+        }
+    }
+    res
+}
+
+/// Position of the first `bit` from `first` to `last` inclusive.
+fn find_bit(buf: &[u8], bit: u8, first: u64, last: u64) -> Option<u64> {
+    let skip = if bit == 1 { 0x00 } else { 0xff };
+    let mut pos = first;
+    while pos <= last {
+        if pos & 7 == 0 && pos + 7 <= last && buf[(pos >> 3) as usize] == skip {
+            pos += 8;
+            continue;
+        }
+        if get_bit(buf, pos) == bit {
+            return Some(pos);
+        }
+        pos += 1;
+    }
+    None
+}
+
+/// The bits from `first` to `last` selected by `start` and `end`, which
+/// count bytes unless `in_bits` is set.
+fn bit_range(len: usize, start: i64, end: i64, in_bits: bool) -> Option<(u64, u64)> {
+    if in_bits {
+        normalize_range(start, end, len * 8).map(|(s, e)| (s as u64, e as u64))
+    } else {
+        normalize_range(start, end, len).map(|(s, e)| (s as u64 * 8, e as u64 * 8 + 7))
+    }
+}
+
+fn parse_offset(raw: &Bytes) -> Result<u64> {
+    std::str::from_utf8(raw.as_ref())
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|v| *v <= MAX_BIT_OFFSET)
+        .ok_or_else(invalid_operand)
+}
+
+/// Parses an optional trailing `BYTE|BIT`, returns whether it is `BIT`.
+fn parse_unit(parser: &mut CommandParser) -> Result<bool> {
+    match parser.next_bytes()? {
+        None => Ok(false),
+        Some(v) => match rolling_hash(v.as_ref())? {
+            BYTE => Ok(false),
+            BIT => Ok(true),
+            _ => Err(invalid_operation()),
+        },
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+/// `i<bits>` or `u<bits>`, `u64` is left out as its values don't fit an
+/// integer reply.
+#[derive(Debug, Clone, Copy)]
+struct BitFieldType {
+    signed: bool,
+    bits: u32,
+}
+
+impl BitFieldType {
+    fn parse(raw: &[u8]) -> Result<BitFieldType> {
+        let signed = match raw.first() {
+            Some(b'i') | Some(b'I') => true,
+            Some(b'u') | Some(b'U') => false,
+            _ => return Err(invalid_operand()),
+        };
+        let bits: u32 = std::str::from_utf8(&raw[1..])
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(invalid_operand)?;
+        if bits == 0 || bits > 64 || (!signed && bits == 64) {
+            return Err(invalid_operand());
+        }
+        Ok(Self { signed, bits })
+    }
+
+    fn bounds(&self) -> (i128, i128) {
+        if self.signed {
+            (-(1 << (self.bits - 1)), (1 << (self.bits - 1)) - 1)
+        } else {
+            (0, (1 << self.bits) - 1)
+        }
+    }
+
+    fn decode(&self, raw: u64) -> i128 {
+        if self.signed && (raw >> (self.bits - 1)) & 1 == 1 {
+            raw as i128 - (1 << self.bits)
+        } else {
+            raw as i128
+        }
+    }
+
+    /// Brings `v` within bounds as `overflow` says, `None` if it fails.
+    fn fit(&self, v: i128, overflow: Overflow) -> Option<i128> {
+        let (min, max) = self.bounds();
+        if v >= min && v <= max {
+            return Some(v);
+        }
+        match overflow {
+            Overflow::Wrap => {
+                let wrapped = v.rem_euclid(1 << self.bits);
+                Some(if wrapped > max {
+                    wrapped - (1 << self.bits)
+                } else {
+                    wrapped
+                })
+            }
+            Overflow::Sat => Some(v.clamp(min, max)),
+            Overflow::Fail => None,
+        }
+    }
+
+    fn read(&self, buf: &[u8], offset: u64) -> i128 {
+        let raw =
+            (0..self.bits as u64).fold(0, |res, i| (res << 1) | get_bit(buf, offset + i) as u64);
+        self.decode(raw)
+    }
+
+    fn write(&self, buf: &mut BytesMut, offset: u64, v: i128) {
+        let raw = v as u64;
+        for i in 0..self.bits {
+            let bit = ((raw >> (self.bits - 1 - i)) & 1) as u8;
+            set_bit(buf, offset + i as u64, bit);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BitFieldOp {
+    Get(BitFieldType, u64),
+    Set(BitFieldType, u64, i64, Overflow),
+    IncrBy(BitFieldType, u64, i64, Overflow),
+}
+
+impl BitFieldOp {
+    fn is_get(&self) -> bool {
+        matches!(self, BitFieldOp::Get(..))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BitmapVariant {
+    SetBit,
+    GetBit,
+    BitCount,
+    BitPos,
+    BitField,
+    BitOp,
+}
+
+#[derive(Debug, Clone)]
+enum BitmapOp {
+    SetBit(u64, u8),
+    GetBit(u64),
+    /// The range and whether it counts bits rather than bytes.
+    BitCount(Option<(i64, i64, bool)>),
+    BitPos {
+        bit: u8,
+        start: i64,
+        end: Option<i64>,
+        in_bits: bool,
+    },
+    BitField(Vec<BitFieldOp>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Bitmap {
+    key: Bytes,
+    op: BitmapOp,
+}
+
+/// Parses the type and offset of a BITFIELD operation, `#n` offsets count
+/// in units of the type's width.
+fn parse_field(parser: &mut CommandParser) -> Result<(BitFieldType, u64)> {
+    let ty = BitFieldType::parse(&parser.next_bytes()?.ok_or_else(missing_operand)?)?;
+    let raw = parser.next_bytes()?.ok_or_else(missing_operand)?;
+    let offset = match raw.first() {
+        Some(b'#') => parse_offset(&raw.slice(1..))?
+            .checked_mul(ty.bits as u64)
+            .ok_or_else(invalid_operand)?,
+        _ => parse_offset(&raw)?,
+    };
+    if offset + ty.bits as u64 - 1 > MAX_BIT_OFFSET {
+        return Err(invalid_operand());
+    }
+    Ok((ty, offset))
+}
+
+impl Bitmap {
+    pub fn new(parser: &mut CommandParser, variant: BitmapVariant) -> Result<Bitmap> {
+        use BitmapVariant::*;
+        let key = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let op = match variant {
+            SetBit => {
+                let offset = parse_offset(&parser.next_bytes()?.ok_or_else(missing_operand)?)?;
+                let bit = match parser.next_bytes()?.ok_or_else(missing_operand)?.as_ref() {
+                    b"0" => 0,
+                    b"1" => 1,
+                    _ => return Err(invalid_operand()),
+                };
+                BitmapOp::SetBit(offset, bit)
+            }
+            GetBit => BitmapOp::GetBit(parse_offset(
+                &parser.next_bytes()?.ok_or_else(missing_operand)?,
+            )?),
+            BitCount => match parser.next_integer()? {
+                None => BitmapOp::BitCount(None),
+                Some(start) => {
+                    let end = parser.next_integer()?.ok_or_else(missing_operand)?;
+                    BitmapOp::BitCount(Some((start, end, parse_unit(parser)?)))
+                }
+            },
+            BitPos => {
+                let bit = match parser.next_bytes()?.ok_or_else(missing_operand)?.as_ref() {
+                    b"0" => 0,
+                    b"1" => 1,
+                    _ => return Err(invalid_operand()),
+                };
+                let start = parser.next_integer()?.unwrap_or(0);
+                let end = parser.next_integer()?;
+                let in_bits = end.is_some() && parse_unit(parser)?;
+                BitmapOp::BitPos {
+                    bit,
+                    start,
+                    end,
+                    in_bits,
+                }
+            }
+            BitField => {
+                let (mut ops, mut overflow) = (Vec::new(), Overflow::Wrap);
+                while let Some(token) = parser.next_bytes()? {
+                    match rolling_hash(token.as_ref())? {
+                        GET => {
+                            let (ty, offset) = parse_field(parser)?;
+                            ops.push(BitFieldOp::Get(ty, offset));
+                        }
+                        t @ SET | t @ INCRBY => {
+                            let (ty, offset) = parse_field(parser)?;
+                            let v = parser.next_integer()?.ok_or_else(missing_operand)?;
+                            ops.push(if t == SET {
+                                BitFieldOp::Set(ty, offset, v, overflow)
+                            } else {
+                                BitFieldOp::IncrBy(ty, offset, v, overflow)
+                            });
+                        }
+                        OVERFLOW => {
+                            let raw = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                            overflow = match rolling_hash(raw.as_ref())? {
+                                WRAP => Overflow::Wrap,
+                                SAT => Overflow::Sat,
+                                FAIL => Overflow::Fail,
+                                _ => return Err(invalid_operation()),
+                            };
+                        }
+                        _ => return Err(invalid_operation()),
+                    }
+                }
+                BitmapOp::BitField(ops)
+            }
+            BitOp => unreachable!("BITOP is dispatched to multiple databases"),
+        };
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(Self { key, op })
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        let key = &self.key;
+        match self.op {
+            BitmapOp::SetBit(offset, bit) => {
+                let buf = match db.get_str_mut(key, true) {
+                    Ok(b) => b.unwrap(),
+                    Err(e) => return e,
+                };
+                let old = set_bit(buf, offset, bit);
+                db.after_write(key, false);
+                Frame::Integers(old as i64)
+            }
+            BitmapOp::GetBit(offset) => match db.get_str(key) {
+                Ok(buf) => Frame::Integers(buf.map_or(0, |b| get_bit(&b, offset)) as i64),
+                Err(e) => e,
+            },
+            BitmapOp::BitCount(range) => {
+                let buf = match db.get_str(key) {
+                    Ok(Some(b)) => b,
+                    Ok(None) => return Frame::Integers(0),
+                    Err(e) => return e,
+                };
+                let range = match range {
+                    Some((start, end, in_bits)) => bit_range(buf.len(), start, end, in_bits),
+                    None => bit_range(buf.len(), 0, -1, false),
+                };
+                Frame::Integers(
+                    range.map_or(0, |(first, last)| count_bits(&buf, first, last)) as i64,
+                )
+            }
+            BitmapOp::BitPos {
+                bit,
+                start,
+                end,
+                in_bits,
+            } => {
+                let buf = match db.get_str(key) {
+                    Ok(Some(b)) => b,
+                    Ok(None) => return Frame::Integers(if bit == 0 { 0 } else { -1 }),
+                    Err(e) => return e,
+                };
+                let (first, last) = match bit_range(buf.len(), start, end.unwrap_or(-1), in_bits) {
+                    Some(range) => range,
+                    None => return Frame::Integers(-1),
+                };
+                Frame::Integers(match find_bit(&buf, bit, first, last) {
+                    Some(pos) => pos as i64,
+                    // without an end, the string counts as padded with zeros.
+                    None if bit == 0 && end.is_none() => buf.len() as i64 * 8,
+                    None => -1,
+                })
+            }
+            BitmapOp::BitField(ops) => {
+                if ops.iter().all(|op| op.is_get()) {
+                    let buf = match db.get_str(key) {
+                        Ok(b) => b.unwrap_or_default(),
+                        Err(e) => return e,
+                    };
+                    return Frame::Arrays(
+                        ops.iter()
+                            .map(|op| match op {
+                                BitFieldOp::Get(ty, offset) => {
+                                    Frame::Integers(ty.read(&buf, *offset) as i64)
+                                }
+                                _ => unreachable!(),
+                            })
+                            .collect(),
+                    );
+                }
+                let buf = match db.get_str_mut(key, true) {
+                    Ok(b) => b.unwrap(),
+                    Err(e) => return e,
+                };
+                let res = ops
+                    .iter()
+                    .map(|op| match *op {
+                        BitFieldOp::Get(ty, offset) => Frame::Integers(ty.read(buf, offset) as i64),
+                        BitFieldOp::Set(ty, offset, v, overflow) => {
+                            let old = ty.read(buf, offset);
+                            match ty.fit(v as i128, overflow) {
+                                Some(v) => {
+                                    ty.write(buf, offset, v);
+                                    Frame::Integers(old as i64)
+                                }
+                                None => Frame::NullString,
+                            }
+                        }
+                        BitFieldOp::IncrBy(ty, offset, by, overflow) => {
+                            let old = ty.read(buf, offset);
+                            match ty.fit(old + by as i128, overflow) {
+                                Some(v) => {
+                                    ty.write(buf, offset, v);
+                                    Frame::Integers(v as i64)
+                                }
+                                None => Frame::NullString,
+                            }
+                        }
+                    })
+                    .collect();
+                db.after_write(key, false);
+                Frame::Arrays(res)
+            }
+        }
+    }
+}
+
+impl OneshotExecDB for Bitmap {
+    fn get_key(&self) -> &[u8] {
+        self.key.as_ref()
+    }
+
+    fn is_write(&self) -> bool {
+        match &self.op {
+            BitmapOp::SetBit(..) => true,
+            BitmapOp::BitField(ops) => ops.iter().any(|op| !matches!(op, BitFieldOp::Get(..))),
+            _ => false,
+        }
+    }
+}
+
+/// Overwrites `key` with the result of BITOP, the key is deleted if the
+/// result is empty.
+#[derive(Debug, Clone)]
+pub struct BitStore {
+    key: Bytes,
+    value: Bytes,
+}
+
+impl BitStore {
+    pub fn new(key: Bytes, value: Bytes) -> BitStore {
+        Self { key, value }
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        db.remove(&self.key);
+        let len = self.value.len();
+        if len > 0 {
+            db.counter += 1;
+            let nounce = db.counter;
+            db.insert(
+                self.key.clone(),
+                Entry::new(Frame::BulkStrings(self.value).into(), None, nounce),
+            );
+        }
+        db.propagate_key(&self.key);
+        Frame::Integers(len as i64)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BitOperator {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+fn combine(op: BitOperator, frames: Vec<Frame>) -> Frame {
+    let mut sources = Vec::with_capacity(frames.len());
+    for f in frames {
+        match f {
+            Frame::BulkStrings(b) => sources.push(b),
+            Frame::NullString => sources.push(Bytes::new()),
+            e => return e,
+        }
+    }
+    let len = sources.iter().map(|s| s.len()).max().unwrap_or(0);
+    // shorter strings count as padded with zeros.
+    let byte = |s: &Bytes, i: usize| s.get(i).copied().unwrap_or(0);
+    let res: Vec<u8> = (0..len)
+        .map(|i| match op {
+            BitOperator::Not => !byte(&sources[0], i),
+            BitOperator::And => sources.iter().fold(0xff, |acc, s| acc & byte(s, i)),
+            BitOperator::Or => sources.iter().fold(0, |acc, s| acc | byte(s, i)),
+            BitOperator::Xor => sources.iter().fold(0, |acc, s| acc ^ byte(s, i)),
+        })
+        .collect();
+    Frame::BulkStrings(Bytes::from(res))
+}
+
+/// Gathers the source strings of BITOP from their databases, combines them
+/// once all have answered, and stores the result on the database of `dest`.
+#[derive(Debug, Clone)]
+pub struct BitOpDispatcher {
+    op: BitOperator,
+    dest: Option<Bytes>,
+    dest_db: usize,
+    db_amount: usize,
+    cmds: Vec<MiniCommand>,
+    cmds_tbl: Vec<Vec<MiniCommand>>,
+    order_tbl: Vec<Vec<usize>>,
+    len: usize,
+}
+
+impl BitOpDispatcher {
+    pub fn new(parser: &mut CommandParser) -> Result<BitOpDispatcher> {
+        let raw = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let op = match rolling_hash(raw.as_ref())? {
+            AND => BitOperator::And,
+            OR => BitOperator::Or,
+            XOR => BitOperator::Xor,
+            NOT => BitOperator::Not,
+            _ => return Err(invalid_operation()),
+        };
+        let dest = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let mut cmds: Vec<MiniCommand> = Vec::with_capacity(parser.len());
+        while let Some(key) = parser.next_bytes()? {
+            cmds.push(key.into());
+        }
+        match (op, cmds.len()) {
+            (_, 0) => return Err(missing_operand()),
+            (BitOperator::Not, n) if n > 1 => return Err(invalid_operation()),
+            _ => (),
+        }
+        Ok(Self {
+            op,
+            dest: Some(dest),
+            dest_db: 0,
+            db_amount: 0,
+            len: cmds.len(),
+            cmds,
+            cmds_tbl: Vec::new(),
+            order_tbl: Vec::new(),
+        })
+    }
+}
+
+use crate::default_pop;
+impl DispatchToMultipleDB for BitOpDispatcher {
+    impl_traverse_command!(@Consts, StrGather, default_pop);
+
+    fn get_result_collector(&mut self) -> ResultCollector {
+        let op = self.op;
+        let ret = vec![Frame::NullString; self.len];
+        ResultCollector {
+            result_type: ResultCollectorType::Combine(
+                std::mem::take(&mut self.order_tbl),
+                Box::new(move |frames| combine(op, frames)),
+            ),
+            ret,
+        }
+    }
+
+    fn dispatch(&mut self, db_amount: usize, dispatch_fn: impl Fn(&[u8]) -> usize) {
+        if let Some(dest) = &self.dest {
+            self.dest_db = dispatch_fn(dest);
+        }
+        self.dispatch_sources(db_amount, dispatch_fn);
+    }
+
+    fn complete(&mut self, merged: Frame) -> Completion {
+        match (self.dest.take(), merged) {
+            (Some(dest), Frame::BulkStrings(value)) => {
+                Completion::Forward((self.dest_db, BitStore::new(dest, value).into()))
+            }
+            (_, f) => Completion::Reply(f),
+        }
+    }
+}
+
+impl BitOpDispatcher {
+    impl_traverse_command!(@Dispatch, N:N, dispatch_sources);
+}
+
+impl AtomicCMDMarker for Bitmap {}
+impl AtomicCMDMarker for BitStore {}
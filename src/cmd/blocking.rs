@@ -0,0 +1,598 @@
+use crate::{
+    cmd::stream_group::{parse_xreadgroup, GroupRead, XGatherDispatcher},
+    cmd::zset::score_to_bytes,
+    cmd::*,
+    db::{wrong_type, SharedReply},
+};
+use anyhow::Result;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+const LEFT: usize = rolling_hash_const(b"left");
+const RIGHT: usize = rolling_hash_const(b"right");
+const COUNT: usize = rolling_hash_const(b"count");
+const MIN: usize = rolling_hash_const(b"min");
+const MAX: usize = rolling_hash_const(b"max");
+
+#[derive(Debug, Clone, Copy)]
+pub enum BPopVariant {
+    BLPop,
+    BRPop,
+    BLMPop,
+    LMPop,
+    BLMove,
+    LMove,
+    BZPopMin,
+    BZPopMax,
+    BZMPop,
+    ZMPop,
+    XReadGroup,
+}
+
+/// What a `BPop` takes its elements from.
+#[derive(Debug, Clone, Default)]
+pub enum PopKind {
+    #[default]
+    List,
+    /// `from_left` picks the lowest scores.
+    ZSet,
+    /// XREADGROUP, reading on behalf of a consumer group.
+    Stream(Arc<GroupRead>),
+}
+
+/// Pops from the first non-empty list, or sorted set, of `keys` held by one
+/// database, or parks there until one of them receives elements. Streams
+/// are read rather than popped, every key with new entries is served.
+#[derive(Debug)]
+pub struct BPop {
+    keys: Vec<Bytes>,
+    kind: PopKind,
+    from_left: bool,
+    /// `Some` for the *MPOP commands, which answer with an array of elements.
+    count: Option<usize>,
+    is_move: bool,
+    /// Destination of LMOVE and BLMOVE, if it lives on the same database.
+    dest: Option<(Bytes, bool)>,
+    block: bool,
+    deadline: Option<Instant>,
+    reply: SharedReply,
+    /// Where a move to another database records the deadline of its
+    /// source, for undoing the pop.
+    source_deadline: Arc<Mutex<Option<Instant>>>,
+}
+
+impl BPop {
+    /// Takes the reply channel, unless the client was already served by
+    /// another database or is gone.
+    fn claim(&self) -> Option<oneshot::Sender<Frame>> {
+        self.reply
+            .lock()
+            .unwrap()
+            .take()
+            .filter(|tx| !tx.is_closed())
+    }
+
+    fn is_stale(&self) -> bool {
+        !matches!(&*self.reply.lock().unwrap(), Some(tx) if !tx.is_closed())
+    }
+
+    fn timeout_reply(&self) -> Frame {
+        if self.is_move {
+            Frame::NullString
+        } else {
+            Frame::NullArray
+        }
+    }
+
+    /// Answers through `ret_tx` right away: with the reply if this database
+    /// served the client, with `Frame::NullString` otherwise.
+    pub fn exec(self, db: &mut DB, ret_tx: oneshot::Sender<Frame>) {
+        let ret = match db.first_ready(&self) {
+            Some(ready) => match self.claim() {
+                Some(_) => match ready {
+                    Ok(key) => db.bpop_from(&key, &self),
+                    Err(e) => e,
+                },
+                None => Frame::NullString,
+            },
+            None => {
+                if self.block && !self.is_stale() {
+                    db.park(self);
+                }
+                Frame::NullString
+            }
+        };
+        db.reply(ret_tx, ret);
+    }
+}
+
+impl DB {
+    /// Whether `key` holds a list, or a sorted set, to pop from, or stream
+    /// entries to read. Emptied values are removed, so any list or sorted
+    /// set found has elements.
+    fn holds_elements(&mut self, key: &Bytes, kind: &PopKind) -> std::result::Result<bool, Frame> {
+        match kind {
+            PopKind::List => Ok(self.get_list(key)?.is_some()),
+            PopKind::ZSet => Ok(self.get_zset(key)?.is_some()),
+            PopKind::Stream(read) => self.group_ready(key, read),
+        }
+    }
+
+    /// The first key `cmd` can pop from, or the error it runs into.
+    fn first_ready(&mut self, cmd: &BPop) -> Option<std::result::Result<Bytes, Frame>> {
+        for key in cmd.keys.iter() {
+            match self.holds_elements(key, &cmd.kind) {
+                Ok(true) => {
+                    if let Some((dest, _)) = &cmd.dest {
+                        if let Err(e) = self.get_list(dest) {
+                            return Some(Err(e));
+                        }
+                    }
+                    return Some(Ok(key.clone()));
+                }
+                Ok(false) => (),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+
+    /// Pops for `cmd` from `key`, which `first_ready` picked. What it did is
+    /// logged as the plain pops, and push, that replay to the same.
+    fn bpop_from(&mut self, key: &Bytes, cmd: &BPop) -> Frame {
+        match &cmd.kind {
+            PopKind::List => (),
+            PopKind::ZSet => return self.bzpop_from(key, cmd),
+            PopKind::Stream(read) => {
+                let ret = self.xreadgroup(&cmd.keys, read);
+                self.propagate_read(read, &ret);
+                return ret;
+            }
+        }
+        let list = match self.get_list(key) {
+            Ok(Some(l)) => l,
+            _ => unreachable!("`key` was checked by `first_ready`"),
+        };
+        let n = cmd.count.unwrap_or(1).min(list.len());
+        let mut popped: Vec<Bytes> = (0..n)
+            .map(|_| {
+                if cmd.from_left {
+                    list.pop_front().unwrap()
+                } else {
+                    list.pop_back().unwrap()
+                }
+            })
+            .collect();
+        let emptied = list.is_empty();
+        if cmd.is_move && cmd.dest.is_none() {
+            *cmd.source_deadline.lock().unwrap() =
+                self.database.get(key).and_then(|en| en.expiration);
+        }
+        self.after_write(key, emptied);
+        self.propagate(command(vec![
+            Bytes::from_static(if cmd.from_left { b"LPOP" } else { b"RPOP" }),
+            key.clone(),
+            Bytes::from(n.to_string()),
+        ]));
+
+        if cmd.is_move {
+            let v = popped.pop().unwrap();
+            if let Some((dest, to_left)) = &cmd.dest {
+                self.list_push(dest, *to_left, false, vec![v.clone()]);
+                self.propagate(push_command(dest, *to_left, &v));
+            }
+            return Frame::BulkStrings(v);
+        }
+        let elements = match cmd.count {
+            Some(_) => Frame::Arrays(popped.into_iter().map(|v| v.into()).collect()),
+            None => popped.pop().unwrap().into(),
+        };
+        Frame::Arrays(vec![key.clone().into(), elements])
+    }
+
+    fn bzpop_from(&mut self, key: &Bytes, cmd: &BPop) -> Frame {
+        let mut popped = match self.zpop(key, cmd.from_left, cmd.count.unwrap_or(1)) {
+            Ok(p) => p,
+            Err(e) => return e,
+        };
+        self.propagate(command(vec![
+            Bytes::from_static(if cmd.from_left {
+                b"ZPOPMIN"
+            } else {
+                b"ZPOPMAX"
+            }),
+            key.clone(),
+            Bytes::from(popped.len().to_string()),
+        ]));
+        match cmd.count {
+            Some(_) => Frame::Arrays(vec![
+                key.clone().into(),
+                Frame::Arrays(
+                    popped
+                        .into_iter()
+                        .map(|(m, s)| Frame::Arrays(vec![m.into(), score_to_bytes(s).into()]))
+                        .collect(),
+                ),
+            ]),
+            None => {
+                let (m, s) = popped.pop().unwrap();
+                Frame::Arrays(vec![key.clone().into(), m.into(), score_to_bytes(s).into()])
+            }
+        }
+    }
+
+    /// Marks `key` for `serve_blocked` if somebody is waiting for it.
+    pub fn signal_ready(&mut self, key: &Bytes) {
+        let blocking = &mut self.blocking;
+        if blocking.queues.contains_key(key) && !blocking.ready.contains(key) {
+            blocking.ready.push(key.clone());
+        }
+    }
+
+    fn park(&mut self, cmd: BPop) {
+        let blocking = &mut self.blocking;
+        blocking.waiter_id_gen += 1;
+        let id = blocking.waiter_id_gen;
+        for key in cmd.keys.iter() {
+            blocking
+                .queues
+                .entry(key.clone())
+                .or_default()
+                .push_back(id);
+        }
+        if let Some(deadline) = cmd.deadline {
+            blocking.deadlines.insert((deadline, id));
+        }
+        blocking.waiters.insert(id, cmd);
+    }
+
+    fn unpark(&mut self, id: u64) -> Option<BPop> {
+        let blocking = &mut self.blocking;
+        let cmd = blocking.waiters.remove(&id)?;
+        for key in cmd.keys.iter() {
+            if let Some(queue) = blocking.queues.get_mut(key) {
+                queue.retain(|v| *v != id);
+                if queue.is_empty() {
+                    blocking.queues.remove(key);
+                }
+            }
+        }
+        if let Some(deadline) = cmd.deadline {
+            blocking.deadlines.remove(&(deadline, id));
+        }
+        Some(cmd)
+    }
+
+    /// Hands the elements of keys that became ready to their waiters, in
+    /// the order the waiters were parked.
+    pub fn serve_blocked(&mut self) {
+        while !self.blocking.ready.is_empty() {
+            for key in std::mem::take(&mut self.blocking.ready) {
+                let queue: Vec<u64> = match self.blocking.queues.get(&key) {
+                    Some(q) => q.iter().copied().collect(),
+                    None => continue,
+                };
+                for id in queue {
+                    let kind = self.blocking.waiters[&id].kind.clone();
+                    match self.holds_elements(&key, &kind) {
+                        Ok(true) => (),
+                        // another group may still have entries to read.
+                        Ok(false) if matches!(kind, PopKind::Stream(_)) => continue,
+                        Ok(false) => break,
+                        // waiting for the other kind of value.
+                        Err(_) => continue,
+                    }
+                    let cmd = self.unpark(id).unwrap();
+                    let tx = match cmd.claim() {
+                        Some(tx) => tx,
+                        None => continue,
+                    };
+                    let ret = match &cmd.dest {
+                        Some((dest, _)) if self.get_list(dest).is_err() => wrong_type(),
+                        _ => self.bpop_from(&key, &cmd),
+                    };
+                    self.reply(tx, ret);
+                }
+            }
+        }
+    }
+
+    /// Answers waiters whose deadline passed with a null reply.
+    pub fn time_out_blocked(&mut self, now: Instant) {
+        while let Some(&(deadline, id)) = self.blocking.deadlines.iter().next() {
+            if deadline > now {
+                break;
+            }
+            let cmd = self.unpark(id).unwrap();
+            if let Some(tx) = cmd.claim() {
+                let _ = tx.send(cmd.timeout_reply());
+            }
+        }
+    }
+
+    /// Drops waiters whose client was served by another database or is gone.
+    pub fn sweep_blocked(&mut self) {
+        let stale: Vec<u64> = self
+            .blocking
+            .waiters
+            .iter()
+            .filter(|(_, cmd)| cmd.is_stale())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in stale {
+            self.unpark(id);
+        }
+    }
+}
+
+/// `LPUSH key element`, or RPUSH.
+fn push_command(key: &Bytes, to_left: bool, element: &Bytes) -> Frame {
+    command(vec![
+        Bytes::from_static(if to_left { b"LPUSH" } else { b"RPUSH" }),
+        key.clone(),
+        element.clone(),
+    ])
+}
+
+/// Parses a timeout in seconds, `None` stands for blocking forever.
+pub fn parse_timeout(raw: &Bytes) -> Result<Option<Duration>> {
+    let secs: f64 = std::str::from_utf8(raw.as_ref())
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(invalid_operand)?;
+    if !secs.is_finite() || secs < 0.0 {
+        return Err(invalid_operand());
+    }
+    if secs == 0.0 {
+        Ok(None)
+    } else {
+        Ok(Some(Duration::from_millis((secs * 1000.0).ceil() as u64)))
+    }
+}
+
+fn parse_direction(parser: &mut CommandParser) -> Result<bool> {
+    let raw = parser.next_bytes()?.ok_or_else(missing_operand)?;
+    match rolling_hash(raw.as_ref())? {
+        LEFT => Ok(true),
+        RIGHT => Ok(false),
+        _ => Err(invalid_operation()),
+    }
+}
+
+fn parse_extreme(parser: &mut CommandParser) -> Result<bool> {
+    let raw = parser.next_bytes()?.ok_or_else(missing_operand)?;
+    match rolling_hash(raw.as_ref())? {
+        MIN => Ok(true),
+        MAX => Ok(false),
+        _ => Err(invalid_operation()),
+    }
+}
+
+/// Sends a `BPop` for every run of consecutive keys living on one database,
+/// in the order of the keys, so that a key is only popped from once every
+/// key before it was found empty. All of them share one reply channel, the
+/// first database to take it out serves the client. If none could right
+/// away, the client waits on `reply_rx` for whichever of its parked waiters
+/// fires first.
+#[derive(Debug, Default)]
+pub struct BPopDispatcher {
+    keys: Vec<Bytes>,
+    kind: PopKind,
+    from_left: bool,
+    count: Option<usize>,
+    is_move: bool,
+    dest: Option<(Bytes, bool)>,
+    dest_db: usize,
+    /// The database of the first key, the source of a move.
+    src_db: usize,
+    block: bool,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    // runs of keys and their database, last to be visited first.
+    runs: Vec<(usize, Vec<Bytes>)>,
+    reply: SharedReply,
+    reply_rx: Option<oneshot::Receiver<Frame>>,
+    source_deadline: Arc<Mutex<Option<Instant>>>,
+}
+
+impl BPopDispatcher {
+    pub fn new(parser: &mut CommandParser, variant: BPopVariant) -> Result<BPopDispatcher> {
+        use BPopVariant::*;
+        let mut cmd = Self {
+            block: !matches!(variant, LMPop | LMove | ZMPop),
+            kind: match variant {
+                BZPopMin | BZPopMax | BZMPop | ZMPop => PopKind::ZSet,
+                _ => PopKind::List,
+            },
+            ..Default::default()
+        };
+        match variant {
+            BLPop | BRPop | BZPopMin | BZPopMax => {
+                let mut args = Vec::with_capacity(parser.len());
+                while let Some(v) = parser.next_bytes()? {
+                    args.push(v);
+                }
+                if args.len() < 2 {
+                    return Err(missing_operand());
+                }
+                cmd.timeout = parse_timeout(&args.pop().unwrap())?;
+                cmd.keys = args;
+                cmd.from_left = matches!(variant, BLPop | BZPopMin);
+            }
+            BLMPop | LMPop | BZMPop | ZMPop => {
+                if matches!(variant, BLMPop | BZMPop) {
+                    cmd.timeout =
+                        parse_timeout(&parser.next_bytes()?.ok_or_else(missing_operand)?)?;
+                }
+                let numkeys = parser.next_integer()?.ok_or_else(missing_operand)?;
+                if numkeys <= 0 {
+                    return Err(invalid_operand());
+                }
+                for _ in 0..numkeys {
+                    cmd.keys
+                        .push(parser.next_bytes()?.ok_or_else(missing_operand)?);
+                }
+                cmd.from_left = if matches!(cmd.kind, PopKind::ZSet) {
+                    parse_extreme(parser)?
+                } else {
+                    parse_direction(parser)?
+                };
+                cmd.count = Some(1);
+                if let Some(option) = parser.next_bytes()? {
+                    if rolling_hash(option.as_ref())? != COUNT {
+                        return Err(invalid_operation());
+                    }
+                    match parser.next_integer()?.ok_or_else(missing_operand)? {
+                        v if v > 0 => cmd.count = Some(v as usize),
+                        _ => return Err(invalid_operand()),
+                    }
+                }
+            }
+            BLMove | LMove => {
+                cmd.keys
+                    .push(parser.next_bytes()?.ok_or_else(missing_operand)?);
+                let dest = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                cmd.from_left = parse_direction(parser)?;
+                cmd.dest = Some((dest, parse_direction(parser)?));
+                cmd.is_move = true;
+                if matches!(variant, BLMove) {
+                    cmd.timeout =
+                        parse_timeout(&parser.next_bytes()?.ok_or_else(missing_operand)?)?;
+                }
+            }
+            XReadGroup => {
+                let (read, keys, timeout) = parse_xreadgroup(parser)?;
+                cmd.block = timeout.is_some();
+                cmd.timeout = timeout.flatten();
+                cmd.keys = keys;
+                cmd.kind = PopKind::Stream(Arc::new(read));
+            }
+        }
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(cmd)
+    }
+
+    /// The receiver to wait on after no database could serve the client
+    /// right away, `None` if the command doesn't block.
+    pub fn parked_reply(&mut self) -> Option<oneshot::Receiver<Frame>> {
+        self.reply_rx.take().filter(|_| self.block)
+    }
+
+    pub fn timeout_reply(&self) -> Frame {
+        if self.is_move {
+            Frame::NullString
+        } else {
+            Frame::NullArray
+        }
+    }
+
+    /// XREADGROUP first reads every key on every database at once, its
+    /// waiters are only parked if none of them had anything new.
+    pub fn gather(&self) -> Option<XGatherDispatcher> {
+        match &self.kind {
+            PopKind::Stream(read) => Some(XGatherDispatcher::new(self.runs.clone(), read.clone())),
+            _ => None,
+        }
+    }
+
+    pub fn blocks(&self) -> bool {
+        self.block
+    }
+
+    /// Inside a transaction nothing blocks, the command answers as if it
+    /// timed out right away.
+    pub fn never_block(&mut self) {
+        self.block = false;
+    }
+
+    /// The push that completes a move whose destination lives on another
+    /// database than its source.
+    pub fn remote_push(&self, element: &Bytes) -> Option<IDCommandPair> {
+        match &self.dest {
+            Some((dest, to_left)) if self.dest_db != self.src_db => Some((
+                self.dest_db,
+                Logged {
+                    cmd: List::push(dest.clone(), *to_left, vec![element.clone()]).into(),
+                    frame: push_command(dest, *to_left, element),
+                }
+                .into(),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Puts an element taken by a move back where it came from, used when
+    /// the destination turned out to hold the wrong kind of value.
+    pub fn undo_pop(&self, element: &Bytes) -> IDCommandPair {
+        let deadline = *self.source_deadline.lock().unwrap();
+        (
+            self.src_db,
+            Logged {
+                cmd: List::unpop(
+                    self.keys[0].clone(),
+                    self.from_left,
+                    element.clone(),
+                    deadline,
+                )
+                .into(),
+                frame: push_command(&self.keys[0], self.from_left, element),
+            }
+            .into(),
+        )
+    }
+}
+
+impl DispatchToMultipleDB for BPopDispatcher {
+    fn next_command(&mut self) -> Option<IDCommandPair> {
+        let (db_id, keys) = self.runs.pop()?;
+        let same_db_dest = self.dest.clone().filter(|_| self.dest_db == db_id);
+        Some((
+            db_id,
+            BPop {
+                keys,
+                kind: self.kind.clone(),
+                from_left: self.from_left,
+                count: self.count,
+                is_move: self.is_move,
+                dest: same_db_dest,
+                block: self.block,
+                deadline: self.deadline,
+                reply: self.reply.clone(),
+                source_deadline: self.source_deadline.clone(),
+            }
+            .into(),
+        ))
+    }
+
+    fn get_result_collector(&mut self) -> ResultCollector {
+        assert!(!self.runs.is_empty(), "self.runs wasn't initialized");
+        ResultCollector {
+            result_type: ResultCollectorType::FirstNonNull(self.runs.len()),
+            ret: Vec::with_capacity(1),
+        }
+    }
+
+    fn dispatch(&mut self, _db_amount: usize, dispatch_fn: impl Fn(&[u8]) -> usize) {
+        self.runs = Vec::new();
+        for key in self.keys.iter() {
+            let db_id = dispatch_fn(key);
+            match self.runs.last_mut() {
+                Some((last, keys)) if *last == db_id => keys.push(key.clone()),
+                _ => self.runs.push((db_id, vec![key.clone()])),
+            }
+        }
+        self.runs.reverse();
+        self.src_db = dispatch_fn(&self.keys[0]);
+        if let Some((dest, _)) = &self.dest {
+            self.dest_db = dispatch_fn(dest);
+        }
+        self.deadline = self.timeout.and_then(|v| Instant::now().checked_add(v));
+        let (tx, rx) = oneshot::channel();
+        self.reply = std::sync::Arc::new(std::sync::Mutex::new(Some(tx)));
+        self.reply_rx = Some(rx);
+    }
+}
+
+impl AtomicCMDMarker for BPop {}
@@ -48,6 +48,18 @@ impl CommandParser {
         }
     }
 
+    /// The value of a string, kept as the frame it came in. Frames that hold
+    /// no string can't be stored as one.
+    pub fn next_value(&mut self) -> Result<Option<Frame>> {
+        match self.next() {
+            Some(f @ Frame::BulkStrings(_))
+            | Some(f @ Frame::SimpleString(_))
+            | Some(f @ Frame::Integers(_)) => Ok(Some(f)),
+            Some(_) => Err(Error::new(CommandError::InvalidOperand)),
+            None => Ok(None),
+        }
+    }
+
     pub fn next_kv_pair(&mut self) -> Result<Option<(Bytes, Frame)>> {
         let p1 = match self.next_bytes()? {
             Some(b) => b,
@@ -56,7 +68,7 @@ impl CommandParser {
             }
         };
 
-        let p2 = match self.next() {
+        let p2 = match self.next_value()? {
             Some(b) => b,
             None => {
                 return Err(Error::new(CommandError::MissingOperand));
@@ -1,6 +1,13 @@
 use crate::utils::*;
 
-use super::{get::GetVariant, incr::IncrVariant, set::SetVariant};
+use super::{
+    bitmap::BitmapVariant, blocking::BPopVariant, expire::ExpireVariant, geo::GeoVariant,
+    get::GetVariant, hash::HashVariant, hyperloglog::HyperLogLogVariant, incr::IncrVariant,
+    list::ListVariant, set::SetVariant, set_algebra::SetAlgebraVariant, sets::SetsVariant,
+    snapshot::SnapshotVariant, stream::StreamVariant, stream_group::StreamGroupVariant,
+    strings::StringsVariant, transaction::TransactionVariant, zset::ZSetVariant,
+    zset_algebra::ZSetAlgebraVariant,
+};
 
 #[derive(Clone, Debug, Copy)]
 pub enum CommandTable {
@@ -8,7 +15,28 @@ pub enum CommandTable {
     SET(SetVariant),
     MSET,
     MGET,
+    DEL,
+    EXISTS,
     INCR(IncrVariant),
+    EXPIRE(ExpireVariant),
+    LIST(ListVariant),
+    BLOCKING(BPopVariant),
+    HASH(HashVariant),
+    SETS(SetsVariant),
+    SETALGEBRA(SetAlgebraVariant),
+    ZSET(ZSetVariant),
+    ZSETALGEBRA(ZSetAlgebraVariant),
+    STREAM(StreamVariant),
+    STREAMGROUP(StreamGroupVariant),
+    HYPERLOGLOG(HyperLogLogVariant),
+    BITMAP(BitmapVariant),
+    STRINGS(StringsVariant),
+    GEO(GeoVariant),
+    TRANSACTION(TransactionVariant),
+    SNAPSHOT(SnapshotVariant),
+    KEYS,
+    RANDOMKEY,
+    SCAN,
     DX,
     SHUTDOWN,
     SUBSCRIBE,
@@ -49,10 +77,147 @@ const SETNX: usize = rolling_hash_const(b"setnx");
 const GETSET: usize = rolling_hash_const(b"getset");
 const MSET: usize = rolling_hash_const(b"mset");
 const MGET: usize = rolling_hash_const(b"mget");
+const DEL: usize = rolling_hash_const(b"del");
+const UNLINK: usize = rolling_hash_const(b"unlink");
+const EXISTS: usize = rolling_hash_const(b"exists");
+const TOUCH: usize = rolling_hash_const(b"touch");
 const INCR: usize = rolling_hash_const(b"incr");
 const DECR: usize = rolling_hash_const(b"decr");
 const INCRBY: usize = rolling_hash_const(b"incrby");
+const INCRBYFLOAT: usize = rolling_hash_const(b"incrbyfloat");
 const DECRBY: usize = rolling_hash_const(b"decrby");
+const EXPIRE: usize = rolling_hash_const(b"expire");
+const PEXPIRE: usize = rolling_hash_const(b"pexpire");
+const EXPIREAT: usize = rolling_hash_const(b"expireat");
+const PEXPIREAT: usize = rolling_hash_const(b"pexpireat");
+const PERSIST: usize = rolling_hash_const(b"persist");
+const EXPIRETIME: usize = rolling_hash_const(b"expiretime");
+const PEXPIRETIME: usize = rolling_hash_const(b"pexpiretime");
+const LPUSH: usize = rolling_hash_const(b"lpush");
+const RPUSH: usize = rolling_hash_const(b"rpush");
+const LPUSHX: usize = rolling_hash_const(b"lpushx");
+const RPUSHX: usize = rolling_hash_const(b"rpushx");
+const LPOP: usize = rolling_hash_const(b"lpop");
+const RPOP: usize = rolling_hash_const(b"rpop");
+const LRANGE: usize = rolling_hash_const(b"lrange");
+const LINDEX: usize = rolling_hash_const(b"lindex");
+const LLEN: usize = rolling_hash_const(b"llen");
+const LTRIM: usize = rolling_hash_const(b"ltrim");
+const LINSERT: usize = rolling_hash_const(b"linsert");
+const LREM: usize = rolling_hash_const(b"lrem");
+const LSET: usize = rolling_hash_const(b"lset");
+const LPOS: usize = rolling_hash_const(b"lpos");
+const BLPOP: usize = rolling_hash_const(b"blpop");
+const BRPOP: usize = rolling_hash_const(b"brpop");
+const BLMPOP: usize = rolling_hash_const(b"blmpop");
+const LMPOP: usize = rolling_hash_const(b"lmpop");
+const BLMOVE: usize = rolling_hash_const(b"blmove");
+const LMOVE: usize = rolling_hash_const(b"lmove");
+const HSET: usize = rolling_hash_const(b"hset");
+const HMSET: usize = rolling_hash_const(b"hmset");
+const HSETNX: usize = rolling_hash_const(b"hsetnx");
+const HGET: usize = rolling_hash_const(b"hget");
+const HMGET: usize = rolling_hash_const(b"hmget");
+const HDEL: usize = rolling_hash_const(b"hdel");
+const HLEN: usize = rolling_hash_const(b"hlen");
+const HSTRLEN: usize = rolling_hash_const(b"hstrlen");
+const HEXISTS: usize = rolling_hash_const(b"hexists");
+const HKEYS: usize = rolling_hash_const(b"hkeys");
+const HVALS: usize = rolling_hash_const(b"hvals");
+const HGETALL: usize = rolling_hash_const(b"hgetall");
+const HINCRBY: usize = rolling_hash_const(b"hincrby");
+const HINCRBYFLOAT: usize = rolling_hash_const(b"hincrbyfloat");
+const HSCAN: usize = rolling_hash_const(b"hscan");
+const HRANDFIELD: usize = rolling_hash_const(b"hrandfield");
+const HEXPIRE: usize = rolling_hash_const(b"hexpire");
+const HPEXPIRE: usize = rolling_hash_const(b"hpexpire");
+const HEXPIREAT: usize = rolling_hash_const(b"hexpireat");
+const HPEXPIREAT: usize = rolling_hash_const(b"hpexpireat");
+const HTTL: usize = rolling_hash_const(b"httl");
+const HPTTL: usize = rolling_hash_const(b"hpttl");
+const HPERSIST: usize = rolling_hash_const(b"hpersist");
+const SADD: usize = rolling_hash_const(b"sadd");
+const SREM: usize = rolling_hash_const(b"srem");
+const SISMEMBER: usize = rolling_hash_const(b"sismember");
+const SMISMEMBER: usize = rolling_hash_const(b"smismember");
+const SMEMBERS: usize = rolling_hash_const(b"smembers");
+const SCARD: usize = rolling_hash_const(b"scard");
+const SPOP: usize = rolling_hash_const(b"spop");
+const SRANDMEMBER: usize = rolling_hash_const(b"srandmember");
+const SSCAN: usize = rolling_hash_const(b"sscan");
+const SINTER: usize = rolling_hash_const(b"sinter");
+const SUNION: usize = rolling_hash_const(b"sunion");
+const SDIFF: usize = rolling_hash_const(b"sdiff");
+const SINTERSTORE: usize = rolling_hash_const(b"sinterstore");
+const SUNIONSTORE: usize = rolling_hash_const(b"sunionstore");
+const SDIFFSTORE: usize = rolling_hash_const(b"sdiffstore");
+const SINTERCARD: usize = rolling_hash_const(b"sintercard");
+const ZADD: usize = rolling_hash_const(b"zadd");
+const ZINCRBY: usize = rolling_hash_const(b"zincrby");
+const ZREM: usize = rolling_hash_const(b"zrem");
+const ZCARD: usize = rolling_hash_const(b"zcard");
+const ZSCORE: usize = rolling_hash_const(b"zscore");
+const ZRANK: usize = rolling_hash_const(b"zrank");
+const ZREVRANK: usize = rolling_hash_const(b"zrevrank");
+const ZCOUNT: usize = rolling_hash_const(b"zcount");
+const ZRANGE: usize = rolling_hash_const(b"zrange");
+const ZPOPMIN: usize = rolling_hash_const(b"zpopmin");
+const ZPOPMAX: usize = rolling_hash_const(b"zpopmax");
+const BZPOPMIN: usize = rolling_hash_const(b"bzpopmin");
+const BZPOPMAX: usize = rolling_hash_const(b"bzpopmax");
+const BZMPOP: usize = rolling_hash_const(b"bzmpop");
+const ZMPOP: usize = rolling_hash_const(b"zmpop");
+const ZUNIONSTORE: usize = rolling_hash_const(b"zunionstore");
+const ZINTERSTORE: usize = rolling_hash_const(b"zinterstore");
+const ZDIFFSTORE: usize = rolling_hash_const(b"zdiffstore");
+const XADD: usize = rolling_hash_const(b"xadd");
+const XRANGE: usize = rolling_hash_const(b"xrange");
+const XREVRANGE: usize = rolling_hash_const(b"xrevrange");
+const XLEN: usize = rolling_hash_const(b"xlen");
+const XTRIM: usize = rolling_hash_const(b"xtrim");
+const XDEL: usize = rolling_hash_const(b"xdel");
+const XREADGROUP: usize = rolling_hash_const(b"xreadgroup");
+const XGROUP: usize = rolling_hash_const(b"xgroup");
+const XACK: usize = rolling_hash_const(b"xack");
+const XPENDING: usize = rolling_hash_const(b"xpending");
+const XCLAIM: usize = rolling_hash_const(b"xclaim");
+const XAUTOCLAIM: usize = rolling_hash_const(b"xautoclaim");
+const PFADD: usize = rolling_hash_const(b"pfadd");
+const PFCOUNT: usize = rolling_hash_const(b"pfcount");
+const PFMERGE: usize = rolling_hash_const(b"pfmerge");
+const SETBIT: usize = rolling_hash_const(b"setbit");
+const GETBIT: usize = rolling_hash_const(b"getbit");
+const BITCOUNT: usize = rolling_hash_const(b"bitcount");
+const BITPOS: usize = rolling_hash_const(b"bitpos");
+const BITFIELD: usize = rolling_hash_const(b"bitfield");
+const BITOP: usize = rolling_hash_const(b"bitop");
+const APPEND: usize = rolling_hash_const(b"append");
+const STRLEN: usize = rolling_hash_const(b"strlen");
+const GETRANGE: usize = rolling_hash_const(b"getrange");
+const SUBSTR: usize = rolling_hash_const(b"substr");
+const SETRANGE: usize = rolling_hash_const(b"setrange");
+const GETDEL: usize = rolling_hash_const(b"getdel");
+const GETEX: usize = rolling_hash_const(b"getex");
+const MSETNX: usize = rolling_hash_const(b"msetnx");
+const LCS: usize = rolling_hash_const(b"lcs");
+const GEOADD: usize = rolling_hash_const(b"geoadd");
+const GEOPOS: usize = rolling_hash_const(b"geopos");
+const GEODIST: usize = rolling_hash_const(b"geodist");
+const GEOSEARCH: usize = rolling_hash_const(b"geosearch");
+const GEOSEARCHSTORE: usize = rolling_hash_const(b"geosearchstore");
+const MULTI: usize = rolling_hash_const(b"multi");
+const EXEC: usize = rolling_hash_const(b"exec");
+const DISCARD: usize = rolling_hash_const(b"discard");
+const WATCH: usize = rolling_hash_const(b"watch");
+const UNWATCH: usize = rolling_hash_const(b"unwatch");
+const SAVE: usize = rolling_hash_const(b"save");
+const BGSAVE: usize = rolling_hash_const(b"bgsave");
+const LASTSAVE: usize = rolling_hash_const(b"lastsave");
+const BGREWRITEAOF: usize = rolling_hash_const(b"bgrewriteaof");
+const EXPORTRDB: usize = rolling_hash_const(b"exportrdb");
+const KEYS: usize = rolling_hash_const(b"keys");
+const RANDOMKEY: usize = rolling_hash_const(b"randomkey");
+const SCAN: usize = rolling_hash_const(b"scan");
 const DX: usize = rolling_hash_const(b"dx");
 const SHUTDOWN: usize = rolling_hash_const(b"shutdown");
 const SUBSCRIBE: usize = rolling_hash_const(b"subscribe");
@@ -60,7 +225,7 @@ const PUBLISH: usize = rolling_hash_const(b"publish");
 const PING: usize = rolling_hash_const(b"ping");
 const UNSUBSCRIBE: usize = rolling_hash_const(b"unsubscribe");
 
-pub const COMMAND_NUM: usize = 20;
+pub const COMMAND_NUM: usize = 157;
 
 const UNSORTED_TBL: [(usize, CommandTable); COMMAND_NUM] = [
     (GET, CommandTable::GET(GetVariant::Get)),
@@ -73,10 +238,206 @@ const UNSORTED_TBL: [(usize, CommandTable); COMMAND_NUM] = [
     (GETSET, CommandTable::SET(SetVariant::GetSet)),
     (MSET, CommandTable::MSET),
     (MGET, CommandTable::MGET),
+    (DEL, CommandTable::DEL),
+    // deletion happens inline, there is no lazy-free thread to hand it to.
+    (UNLINK, CommandTable::DEL),
+    (EXISTS, CommandTable::EXISTS),
+    // there is no LRU clock to refresh, so TOUCH only counts live keys.
+    (TOUCH, CommandTable::EXISTS),
     (INCR, CommandTable::INCR(IncrVariant::Incr)),
     (DECR, CommandTable::INCR(IncrVariant::Decr)),
     (INCRBY, CommandTable::INCR(IncrVariant::IncrBy)),
     (DECRBY, CommandTable::INCR(IncrVariant::DecrBy)),
+    (INCRBYFLOAT, CommandTable::INCR(IncrVariant::IncrByFloat)),
+    (EXPIRE, CommandTable::EXPIRE(ExpireVariant::Expire)),
+    (PEXPIRE, CommandTable::EXPIRE(ExpireVariant::PExpire)),
+    (EXPIREAT, CommandTable::EXPIRE(ExpireVariant::ExpireAt)),
+    (PEXPIREAT, CommandTable::EXPIRE(ExpireVariant::PExpireAt)),
+    (PERSIST, CommandTable::EXPIRE(ExpireVariant::Persist)),
+    (EXPIRETIME, CommandTable::EXPIRE(ExpireVariant::ExpireTime)),
+    (
+        PEXPIRETIME,
+        CommandTable::EXPIRE(ExpireVariant::PExpireTime),
+    ),
+    (LPUSH, CommandTable::LIST(ListVariant::LPush)),
+    (RPUSH, CommandTable::LIST(ListVariant::RPush)),
+    (LPUSHX, CommandTable::LIST(ListVariant::LPushX)),
+    (RPUSHX, CommandTable::LIST(ListVariant::RPushX)),
+    (LPOP, CommandTable::LIST(ListVariant::LPop)),
+    (RPOP, CommandTable::LIST(ListVariant::RPop)),
+    (LRANGE, CommandTable::LIST(ListVariant::LRange)),
+    (LINDEX, CommandTable::LIST(ListVariant::LIndex)),
+    (LLEN, CommandTable::LIST(ListVariant::LLen)),
+    (LTRIM, CommandTable::LIST(ListVariant::LTrim)),
+    (LINSERT, CommandTable::LIST(ListVariant::LInsert)),
+    (LREM, CommandTable::LIST(ListVariant::LRem)),
+    (LSET, CommandTable::LIST(ListVariant::LSet)),
+    (LPOS, CommandTable::LIST(ListVariant::LPos)),
+    (BLPOP, CommandTable::BLOCKING(BPopVariant::BLPop)),
+    (BRPOP, CommandTable::BLOCKING(BPopVariant::BRPop)),
+    (BLMPOP, CommandTable::BLOCKING(BPopVariant::BLMPop)),
+    (LMPOP, CommandTable::BLOCKING(BPopVariant::LMPop)),
+    (BLMOVE, CommandTable::BLOCKING(BPopVariant::BLMove)),
+    (LMOVE, CommandTable::BLOCKING(BPopVariant::LMove)),
+    (HSET, CommandTable::HASH(HashVariant::HSet)),
+    (HMSET, CommandTable::HASH(HashVariant::HMSet)),
+    (HSETNX, CommandTable::HASH(HashVariant::HSetNX)),
+    (HGET, CommandTable::HASH(HashVariant::HGet)),
+    (HMGET, CommandTable::HASH(HashVariant::HMGet)),
+    (HDEL, CommandTable::HASH(HashVariant::HDel)),
+    (HLEN, CommandTable::HASH(HashVariant::HLen)),
+    (HSTRLEN, CommandTable::HASH(HashVariant::HStrLen)),
+    (HEXISTS, CommandTable::HASH(HashVariant::HExists)),
+    (HKEYS, CommandTable::HASH(HashVariant::HKeys)),
+    (HVALS, CommandTable::HASH(HashVariant::HVals)),
+    (HGETALL, CommandTable::HASH(HashVariant::HGetAll)),
+    (HINCRBY, CommandTable::HASH(HashVariant::HIncrBy)),
+    (HINCRBYFLOAT, CommandTable::HASH(HashVariant::HIncrByFloat)),
+    (HSCAN, CommandTable::HASH(HashVariant::HScan)),
+    (HRANDFIELD, CommandTable::HASH(HashVariant::HRandField)),
+    (HEXPIRE, CommandTable::HASH(HashVariant::HExpire)),
+    (HPEXPIRE, CommandTable::HASH(HashVariant::HPExpire)),
+    (HEXPIREAT, CommandTable::HASH(HashVariant::HExpireAt)),
+    (HPEXPIREAT, CommandTable::HASH(HashVariant::HPExpireAt)),
+    (HTTL, CommandTable::HASH(HashVariant::HTTL)),
+    (HPTTL, CommandTable::HASH(HashVariant::HPTTL)),
+    (HPERSIST, CommandTable::HASH(HashVariant::HPersist)),
+    (SADD, CommandTable::SETS(SetsVariant::SAdd)),
+    (SREM, CommandTable::SETS(SetsVariant::SRem)),
+    (SISMEMBER, CommandTable::SETS(SetsVariant::SIsMember)),
+    (SMISMEMBER, CommandTable::SETS(SetsVariant::SMIsMember)),
+    (SMEMBERS, CommandTable::SETS(SetsVariant::SMembers)),
+    (SCARD, CommandTable::SETS(SetsVariant::SCard)),
+    (SPOP, CommandTable::SETS(SetsVariant::SPop)),
+    (SRANDMEMBER, CommandTable::SETS(SetsVariant::SRandMember)),
+    (SSCAN, CommandTable::SETS(SetsVariant::SScan)),
+    (SINTER, CommandTable::SETALGEBRA(SetAlgebraVariant::SInter)),
+    (SUNION, CommandTable::SETALGEBRA(SetAlgebraVariant::SUnion)),
+    (SDIFF, CommandTable::SETALGEBRA(SetAlgebraVariant::SDiff)),
+    (
+        SINTERSTORE,
+        CommandTable::SETALGEBRA(SetAlgebraVariant::SInterStore),
+    ),
+    (
+        SUNIONSTORE,
+        CommandTable::SETALGEBRA(SetAlgebraVariant::SUnionStore),
+    ),
+    (
+        SDIFFSTORE,
+        CommandTable::SETALGEBRA(SetAlgebraVariant::SDiffStore),
+    ),
+    (
+        SINTERCARD,
+        CommandTable::SETALGEBRA(SetAlgebraVariant::SInterCard),
+    ),
+    (ZADD, CommandTable::ZSET(ZSetVariant::ZAdd)),
+    (ZINCRBY, CommandTable::ZSET(ZSetVariant::ZIncrBy)),
+    (ZREM, CommandTable::ZSET(ZSetVariant::ZRem)),
+    (ZCARD, CommandTable::ZSET(ZSetVariant::ZCard)),
+    (ZSCORE, CommandTable::ZSET(ZSetVariant::ZScore)),
+    (ZRANK, CommandTable::ZSET(ZSetVariant::ZRank)),
+    (ZREVRANK, CommandTable::ZSET(ZSetVariant::ZRevRank)),
+    (ZCOUNT, CommandTable::ZSET(ZSetVariant::ZCount)),
+    (ZRANGE, CommandTable::ZSET(ZSetVariant::ZRange)),
+    (ZPOPMIN, CommandTable::ZSET(ZSetVariant::ZPopMin)),
+    (ZPOPMAX, CommandTable::ZSET(ZSetVariant::ZPopMax)),
+    (BZPOPMIN, CommandTable::BLOCKING(BPopVariant::BZPopMin)),
+    (BZPOPMAX, CommandTable::BLOCKING(BPopVariant::BZPopMax)),
+    (BZMPOP, CommandTable::BLOCKING(BPopVariant::BZMPop)),
+    (ZMPOP, CommandTable::BLOCKING(BPopVariant::ZMPop)),
+    (
+        ZUNIONSTORE,
+        CommandTable::ZSETALGEBRA(ZSetAlgebraVariant::ZUnionStore),
+    ),
+    (
+        ZINTERSTORE,
+        CommandTable::ZSETALGEBRA(ZSetAlgebraVariant::ZInterStore),
+    ),
+    (
+        ZDIFFSTORE,
+        CommandTable::ZSETALGEBRA(ZSetAlgebraVariant::ZDiffStore),
+    ),
+    (XADD, CommandTable::STREAM(StreamVariant::XAdd)),
+    (XRANGE, CommandTable::STREAM(StreamVariant::XRange)),
+    (XREVRANGE, CommandTable::STREAM(StreamVariant::XRevRange)),
+    (XLEN, CommandTable::STREAM(StreamVariant::XLen)),
+    (XTRIM, CommandTable::STREAM(StreamVariant::XTrim)),
+    (XDEL, CommandTable::STREAM(StreamVariant::XDel)),
+    (XREADGROUP, CommandTable::BLOCKING(BPopVariant::XReadGroup)),
+    (
+        XGROUP,
+        CommandTable::STREAMGROUP(StreamGroupVariant::XGroup),
+    ),
+    (XACK, CommandTable::STREAMGROUP(StreamGroupVariant::XAck)),
+    (
+        XPENDING,
+        CommandTable::STREAMGROUP(StreamGroupVariant::XPending),
+    ),
+    (
+        XCLAIM,
+        CommandTable::STREAMGROUP(StreamGroupVariant::XClaim),
+    ),
+    (
+        XAUTOCLAIM,
+        CommandTable::STREAMGROUP(StreamGroupVariant::XAutoClaim),
+    ),
+    (PFADD, CommandTable::HYPERLOGLOG(HyperLogLogVariant::PfAdd)),
+    (
+        PFCOUNT,
+        CommandTable::HYPERLOGLOG(HyperLogLogVariant::PfCount),
+    ),
+    (
+        PFMERGE,
+        CommandTable::HYPERLOGLOG(HyperLogLogVariant::PfMerge),
+    ),
+    (SETBIT, CommandTable::BITMAP(BitmapVariant::SetBit)),
+    (GETBIT, CommandTable::BITMAP(BitmapVariant::GetBit)),
+    (BITCOUNT, CommandTable::BITMAP(BitmapVariant::BitCount)),
+    (BITPOS, CommandTable::BITMAP(BitmapVariant::BitPos)),
+    (BITFIELD, CommandTable::BITMAP(BitmapVariant::BitField)),
+    (BITOP, CommandTable::BITMAP(BitmapVariant::BitOp)),
+    (APPEND, CommandTable::STRINGS(StringsVariant::Append)),
+    (STRLEN, CommandTable::STRINGS(StringsVariant::StrLen)),
+    (GETRANGE, CommandTable::STRINGS(StringsVariant::GetRange)),
+    (SUBSTR, CommandTable::STRINGS(StringsVariant::GetRange)),
+    (SETRANGE, CommandTable::STRINGS(StringsVariant::SetRange)),
+    (GETDEL, CommandTable::STRINGS(StringsVariant::GetDel)),
+    (GETEX, CommandTable::STRINGS(StringsVariant::GetEx)),
+    (MSETNX, CommandTable::STRINGS(StringsVariant::MSetNx)),
+    (LCS, CommandTable::STRINGS(StringsVariant::Lcs)),
+    (GEOADD, CommandTable::GEO(GeoVariant::GeoAdd)),
+    (GEOPOS, CommandTable::GEO(GeoVariant::GeoPos)),
+    (GEODIST, CommandTable::GEO(GeoVariant::GeoDist)),
+    (GEOSEARCH, CommandTable::GEO(GeoVariant::GeoSearch)),
+    (
+        GEOSEARCHSTORE,
+        CommandTable::GEO(GeoVariant::GeoSearchStore),
+    ),
+    (MULTI, CommandTable::TRANSACTION(TransactionVariant::Multi)),
+    (EXEC, CommandTable::TRANSACTION(TransactionVariant::Exec)),
+    (
+        DISCARD,
+        CommandTable::TRANSACTION(TransactionVariant::Discard),
+    ),
+    (WATCH, CommandTable::TRANSACTION(TransactionVariant::Watch)),
+    (
+        UNWATCH,
+        CommandTable::TRANSACTION(TransactionVariant::Unwatch),
+    ),
+    (SAVE, CommandTable::SNAPSHOT(SnapshotVariant::Save)),
+    (BGSAVE, CommandTable::SNAPSHOT(SnapshotVariant::BgSave)),
+    (LASTSAVE, CommandTable::SNAPSHOT(SnapshotVariant::LastSave)),
+    (
+        BGREWRITEAOF,
+        CommandTable::SNAPSHOT(SnapshotVariant::BgRewriteAof),
+    ),
+    (
+        EXPORTRDB,
+        CommandTable::SNAPSHOT(SnapshotVariant::ExportRdb),
+    ),
+    (KEYS, CommandTable::KEYS),
+    (RANDOMKEY, CommandTable::RANDOMKEY),
+    (SCAN, CommandTable::SCAN),
     (SUBSCRIBE, CommandTable::SUBSCRIBE),
     (PUBLISH, CommandTable::PUBLISH),
     (UNSUBSCRIBE, CommandTable::UNSUBSCRIBE),
@@ -0,0 +1,63 @@
+use crate::{cmd::*, db::DB, impl_traverse_command, new_traverse_command, *};
+use async_redis::*;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct Del {
+    keys: Vec<MiniCommand>,
+}
+
+impl Del {
+    pub fn new(keys: Vec<MiniCommand>) -> Del {
+        Self { keys }
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        let counter = db.counter;
+        let deleted = self
+            .keys
+            .iter()
+            .filter(|cmd| db.del(cmd.ref_single()))
+            .count();
+        if db.counter != counter && db.logging() {
+            let mut args = vec![Bytes::from_static(b"DEL")];
+            args.extend(self.keys.iter().map(|cmd| cmd.ref_single().clone()));
+            db.propagate(command(args));
+        }
+        Frame::Integers(deleted as i64)
+    }
+}
+
+impl OneshotExecDB for Del {
+    fn get_key(&self) -> &[u8] {
+        &self.keys[0].get_key()
+    }
+
+    fn is_write(&self) -> bool {
+        true
+    }
+}
+
+impl DB {
+    /// Returns `true` only if a live key was removed, an expired one is
+    /// reclaimed silently.
+    pub fn del(&mut self, key: &Bytes) -> bool {
+        self.remove(key)
+            .filter(|en| !en.is_expired(Instant::now()))
+            .is_some()
+    }
+}
+
+#[define_traverse_command("N:1")]
+#[derive(Debug, Clone, Default)]
+pub struct DelDispatcher {}
+
+use crate::default_pop;
+impl_traverse_command!(
+    for cmd: Del = DelDispatcher((Key)+).default_pop!() {
+        cmd >> DB
+    },
+    DB >> 1 Frame >> SumFirst
+);
+
+impl AtomicCMDMarker for Del {}
@@ -29,6 +29,10 @@ impl OneshotExecDB for Dx {
     fn get_key(&self) -> &[u8] {
         b""
     }
+
+    fn is_write(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -0,0 +1,52 @@
+use crate::{cmd::*, db::DB, impl_traverse_command, new_traverse_command, *};
+use async_redis::*;
+
+#[derive(Debug, Clone)]
+pub struct Exists {
+    keys: Vec<MiniCommand>,
+}
+
+impl Exists {
+    pub fn new(keys: Vec<MiniCommand>) -> Exists {
+        Self { keys }
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        Frame::Integers(
+            self.keys
+                .iter()
+                .filter(|cmd| db.exists(cmd.ref_single()))
+                .count() as i64,
+        )
+    }
+}
+
+impl OneshotExecDB for Exists {
+    fn get_key(&self) -> &[u8] {
+        &self.keys[0].get_key()
+    }
+
+    fn is_write(&self) -> bool {
+        false
+    }
+}
+
+impl DB {
+    pub fn exists(&mut self, key: &Bytes) -> bool {
+        self.get_live(key).is_some()
+    }
+}
+
+#[define_traverse_command("N:1")]
+#[derive(Debug, Clone, Default)]
+pub struct ExistsDispatcher {}
+
+use crate::default_pop;
+impl_traverse_command!(
+    for cmd: Exists = ExistsDispatcher((Key)+).default_pop!() {
+        cmd >> DB
+    },
+    DB >> 1 Frame >> SumFirst
+);
+
+impl AtomicCMDMarker for Exists {}
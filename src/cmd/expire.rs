@@ -0,0 +1,197 @@
+use crate::{
+    cmd::*,
+    utils::{instant_to_unix_millis, unix_millis_to_instant},
+};
+use anyhow::Result;
+use tokio::time::{Duration, Instant};
+
+const NX: usize = rolling_hash_const(b"nx");
+const XX: usize = rolling_hash_const(b"xx");
+const GT: usize = rolling_hash_const(b"gt");
+const LT: usize = rolling_hash_const(b"lt");
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExpireVariant {
+    Expire,
+    PExpire,
+    ExpireAt,
+    PExpireAt,
+    Persist,
+    ExpireTime,
+    PExpireTime,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExpireCondition {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+}
+
+#[derive(Debug, Clone)]
+enum ExpireAction {
+    Set(Instant, ExpireCondition),
+    Persist,
+    Get { is_millis: bool },
+}
+
+#[derive(Debug, Clone)]
+pub struct Expire {
+    key: Bytes,
+    action: ExpireAction,
+}
+
+impl ExpireCondition {
+    fn new(parser: &mut CommandParser) -> Result<ExpireCondition> {
+        let mut cond = ExpireCondition::default();
+        while let Some(next_byte) = parser.next_bytes()? {
+            if !cond.set(rolling_hash(next_byte.as_ref())?) {
+                return Err(invalid_operation());
+            }
+        }
+        cond.validate()?;
+        Ok(cond)
+    }
+
+    /// Applies an NX/XX/GT/LT token, `false` if `token` is none of them.
+    pub(in crate::cmd) fn set(&mut self, token: usize) -> bool {
+        match token {
+            NX => self.nx = true,
+            XX => self.xx = true,
+            GT => self.gt = true,
+            LT => self.lt = true,
+            _ => return false,
+        }
+        true
+    }
+
+    pub(in crate::cmd) fn validate(&self) -> Result<()> {
+        if (self.nx && (self.xx || self.gt || self.lt)) || (self.gt && self.lt) {
+            return Err(invalid_operation());
+        }
+        Ok(())
+    }
+
+    /// A key without a deadline counts as never expiring for GT/LT.
+    pub(in crate::cmd) fn allows(&self, current: Option<Instant>, new: Instant) -> bool {
+        match current {
+            None => !(self.xx || self.gt),
+            Some(current) => {
+                !(self.nx || (self.gt && new <= current) || (self.lt && new >= current))
+            }
+        }
+    }
+}
+
+impl Expire {
+    pub fn new(parser: &mut CommandParser, variant: ExpireVariant) -> Result<Expire> {
+        let key = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let action = match variant {
+            ExpireVariant::Persist => ExpireAction::Persist,
+            ExpireVariant::ExpireTime => ExpireAction::Get { is_millis: false },
+            ExpireVariant::PExpireTime => ExpireAction::Get { is_millis: true },
+            ExpireVariant::Expire | ExpireVariant::PExpire => {
+                let num = parser.next_integer()?.ok_or_else(missing_operand)?;
+                let millis = match variant {
+                    ExpireVariant::Expire => num.checked_mul(1000).ok_or_else(invalid_operand)?,
+                    _ => num,
+                };
+                let now = Instant::now();
+                let at = if millis > 0 {
+                    now.checked_add(Duration::from_millis(millis as u64))
+                        .ok_or_else(invalid_operand)?
+                } else {
+                    now
+                };
+                ExpireAction::Set(at, ExpireCondition::new(parser)?)
+            }
+            ExpireVariant::ExpireAt | ExpireVariant::PExpireAt => {
+                let num = parser.next_integer()?.ok_or_else(missing_operand)?;
+                let millis = match variant {
+                    ExpireVariant::ExpireAt => num.checked_mul(1000).ok_or_else(invalid_operand)?,
+                    _ => num,
+                };
+                ExpireAction::Set(
+                    unix_millis_to_instant(millis),
+                    ExpireCondition::new(parser)?,
+                )
+            }
+        };
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(Self { key, action })
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        match self.action {
+            ExpireAction::Set(at, cond) => db.expire(&self.key, at, &cond),
+            ExpireAction::Persist => db.persist(&self.key),
+            ExpireAction::Get { is_millis } => db.expire_time(&self.key, is_millis),
+        }
+    }
+}
+
+impl OneshotExecDB for Expire {
+    fn get_key(&self) -> &[u8] {
+        &self.key.as_ref()
+    }
+
+    fn is_write(&self) -> bool {
+        !matches!(self.action, ExpireAction::Get { .. })
+    }
+
+    /// Relative deadlines are logged as unix time.
+    fn propagate(&self, _frame: Frame, _ret: &Frame, db: &mut DB) {
+        if !matches!(self.action, ExpireAction::Get { .. }) {
+            db.propagate_ttl(&self.key);
+        }
+    }
+}
+
+impl DB {
+    fn live_expiration(&mut self, key: &Bytes) -> Option<Option<Instant>> {
+        self.get_live(key).map(|v| v.expiration)
+    }
+
+    fn expire(&mut self, key: &Bytes, at: Instant, cond: &ExpireCondition) -> Frame {
+        let current = match self.live_expiration(key) {
+            Some(v) => v,
+            None => return Frame::Integers(0),
+        };
+        if !cond.allows(current, at) {
+            return Frame::Integers(0);
+        }
+        // while loading the key is reclaimed once replay is over.
+        if at <= Instant::now() && !self.loading {
+            self.remove(key);
+        } else {
+            self.set_expiration(key, Some(at));
+        }
+        Frame::Integers(1)
+    }
+
+    fn persist(&mut self, key: &Bytes) -> Frame {
+        match self.live_expiration(key) {
+            Some(Some(_)) => {
+                self.set_expiration(key, None);
+                Frame::Integers(1)
+            }
+            _ => Frame::Integers(0),
+        }
+    }
+
+    fn expire_time(&mut self, key: &Bytes, is_millis: bool) -> Frame {
+        match self.live_expiration(key) {
+            None => Frame::Integers(-2),
+            Some(None) => Frame::Integers(-1),
+            Some(Some(at)) => {
+                let millis = instant_to_unix_millis(at);
+                Frame::Integers(if is_millis { millis } else { millis / 1000 })
+            }
+        }
+    }
+}
+
+impl AtomicCMDMarker for Expire {}
@@ -0,0 +1,644 @@
+use crate::{
+    cmd::zset::{score_to_bytes, ZSetValue},
+    cmd::zset_algebra::{from_frames, ZStore},
+    cmd::*,
+    utils::get_float,
+};
+use anyhow::Result;
+use std::collections::BTreeSet;
+
+const NX: usize = rolling_hash_const(b"nx");
+const XX: usize = rolling_hash_const(b"xx");
+const CH: usize = rolling_hash_const(b"ch");
+const M: usize = rolling_hash_const(b"m");
+const KM: usize = rolling_hash_const(b"km");
+const FT: usize = rolling_hash_const(b"ft");
+const MI: usize = rolling_hash_const(b"mi");
+const FROMMEMBER: usize = rolling_hash_const(b"frommember");
+const FROMLONLAT: usize = rolling_hash_const(b"fromlonlat");
+const BYRADIUS: usize = rolling_hash_const(b"byradius");
+const BYBOX: usize = rolling_hash_const(b"bybox");
+const ASC: usize = rolling_hash_const(b"asc");
+const DESC: usize = rolling_hash_const(b"desc");
+const COUNT: usize = rolling_hash_const(b"count");
+const ANY: usize = rolling_hash_const(b"any");
+const WITHCOORD: usize = rolling_hash_const(b"withcoord");
+const WITHDIST: usize = rolling_hash_const(b"withdist");
+const WITHHASH: usize = rolling_hash_const(b"withhash");
+const STOREDIST: usize = rolling_hash_const(b"storedist");
+
+/// Bits per coordinate, so that a position fits the mantissa of a score.
+const GEO_STEP_MAX: u32 = 26;
+const LON_MIN: f64 = -180.0;
+const LON_MAX: f64 = 180.0;
+/// The limits of the Web Mercator projection.
+const LAT_MIN: f64 = -85.05112878;
+const LAT_MAX: f64 = 85.05112878;
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+/// Searches use the finest grid that covers the area in at most this many
+/// cells.
+const MAX_SEARCH_CELLS: u64 = 16;
+
+const MEMBER_NOT_FOUND_ERR: &[u8] = b"ERR could not decode requested zset member";
+
+/// Spreads the bits of `v` onto the even positions.
+fn spread(v: u32) -> u64 {
+    let mut x = v as u64;
+    x = (x | (x << 16)) & 0x0000_FFFF_0000_FFFF;
+    x = (x | (x << 8)) & 0x00FF_00FF_00FF_00FF;
+    x = (x | (x << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    (x | (x << 1)) & 0x5555_5555_5555_5555
+}
+
+/// Gathers the bits on the even positions of `x`.
+fn squash(x: u64) -> u32 {
+    let mut x = x & 0x5555_5555_5555_5555;
+    x = (x | (x >> 1)) & 0x3333_3333_3333_3333;
+    x = (x | (x >> 2)) & 0x0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x >> 4)) & 0x00FF_00FF_00FF_00FF;
+    x = (x | (x >> 8)) & 0x0000_FFFF_0000_FFFF;
+    ((x | (x >> 16)) & 0x0000_0000_FFFF_FFFF) as u32
+}
+
+/// Latitude bits go to the even positions, longitude bits to the odd ones.
+fn interleave(lat_idx: u32, lon_idx: u32) -> u64 {
+    spread(lat_idx) | (spread(lon_idx) << 1)
+}
+
+/// Index of the cell holding `v` when `[min, max]` is cut into `2^step`.
+fn cell_of(v: f64, min: f64, max: f64, step: u32) -> u32 {
+    let cells = 1u64 << step;
+    (((v - min) / (max - min) * cells as f64) as u64).min(cells - 1) as u32
+}
+
+/// The 52 bits geohash of a position, used as its score.
+fn encode(lon: f64, lat: f64) -> u64 {
+    interleave(
+        cell_of(lat, LAT_MIN, LAT_MAX, GEO_STEP_MAX),
+        cell_of(lon, LON_MIN, LON_MAX, GEO_STEP_MAX),
+    )
+}
+
+/// The center of the cell a geohash stands for, as `(lon, lat)`.
+fn decode(hash: u64) -> (f64, f64) {
+    let center = |idx: u32, min: f64, max: f64| {
+        let unit = (max - min) / (1u64 << GEO_STEP_MAX) as f64;
+        (min + (idx as f64 + 0.5) * unit).clamp(min, max)
+    };
+    (
+        center(squash(hash >> 1), LON_MIN, LON_MAX),
+        center(squash(hash), LAT_MIN, LAT_MAX),
+    )
+}
+
+/// Great-circle distance in meters.
+fn distance((lon1, lat1): (f64, f64), (lon2, lat2): (f64, f64)) -> f64 {
+    let (lat1r, lat2r) = (lat1.to_radians(), lat2.to_radians());
+    let u = ((lat2r - lat1r) / 2.0).sin();
+    let v = ((lon2 - lon1).to_radians() / 2.0).sin();
+    2.0 * EARTH_RADIUS_IN_METERS * (u * u + lat1r.cos() * lat2r.cos() * v * v).sqrt().asin()
+}
+
+fn format_distance(meters: f64, unit: f64) -> Frame {
+    Frame::BulkStrings(Bytes::from(format!("{:.4}", meters / unit)))
+}
+
+fn coord_frame((lon, lat): (f64, f64)) -> Frame {
+    Frame::Arrays(vec![score_to_bytes(lon).into(), score_to_bytes(lat).into()])
+}
+
+fn parse_coord(parser: &mut CommandParser) -> Result<(f64, f64)> {
+    let mut next = || -> Result<f64> {
+        let raw = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        get_float(&raw).map_err(|_| invalid_operand())
+    };
+    let (lon, lat) = (next()?, next()?);
+    if !(LON_MIN..=LON_MAX).contains(&lon) || !(LAT_MIN..=LAT_MAX).contains(&lat) {
+        return Err(invalid_operand());
+    }
+    Ok((lon, lat))
+}
+
+/// Meters per unit.
+fn parse_unit(parser: &mut CommandParser) -> Result<f64> {
+    match parser.next_bytes()? {
+        None => Ok(1.0),
+        Some(v) => match rolling_hash(v.as_ref())? {
+            M => Ok(1.0),
+            KM => Ok(1000.0),
+            FT => Ok(0.3048),
+            MI => Ok(1609.34),
+            _ => Err(invalid_operation()),
+        },
+    }
+}
+
+fn parse_length(parser: &mut CommandParser) -> Result<f64> {
+    let raw = parser.next_bytes()?.ok_or_else(missing_operand)?;
+    get_float(&raw)
+        .ok()
+        .filter(|v| *v >= 0.0 && v.is_finite())
+        .ok_or_else(invalid_operand)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum GeoVariant {
+    GeoAdd,
+    GeoPos,
+    GeoDist,
+    GeoSearch,
+    GeoSearchStore,
+}
+
+#[derive(Debug, Clone)]
+enum Origin {
+    Member(Bytes),
+    LonLat(f64, f64),
+}
+
+/// Sizes are in meters, a box is given by its full width and height.
+#[derive(Debug, Clone, Copy)]
+enum Shape {
+    Radius(f64),
+    Box(f64, f64),
+}
+
+impl Shape {
+    /// The distance from the center if `pos` lies within the shape.
+    fn contains(&self, center: (f64, f64), pos: (f64, f64)) -> Option<f64> {
+        let dist = distance(center, pos);
+        match *self {
+            Shape::Radius(r) => Some(dist).filter(|d| *d <= r),
+            Shape::Box(w, h) => {
+                let lat_dist =
+                    EARTH_RADIUS_IN_METERS * (pos.1.to_radians() - center.1.to_radians()).abs();
+                let lon_dist = distance((center.0, pos.1), pos);
+                if lat_dist <= h / 2.0 && lon_dist <= w / 2.0 {
+                    Some(dist)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// How far the shape reaches from `lat` in degrees, as `(lon, lat)`.
+    /// The longitude is `None` if the shape wraps all the way round.
+    fn reach(&self, lat: f64) -> (Option<f64>, f64) {
+        let (half_width, half_height) = match *self {
+            Shape::Radius(r) => (r, r),
+            Shape::Box(w, h) => (w / 2.0, h / 2.0),
+        };
+        let lat_reach = (half_height / EARTH_RADIUS_IN_METERS).to_degrees();
+        // parallels get shorter towards the poles, the one farthest from
+        // the equator needs the widest span.
+        let farthest = (lat.abs() + lat_reach).to_radians();
+        let angle = half_width / EARTH_RADIUS_IN_METERS;
+        let ratio = match *self {
+            Shape::Radius(_) if angle < std::f64::consts::FRAC_PI_2 => {
+                angle.sin() / lat.to_radians().cos()
+            }
+            Shape::Radius(_) => f64::INFINITY,
+            Shape::Box(..) => (angle / 2.0).sin() / farthest.cos(),
+        };
+        let lon_reach = match *self {
+            _ if farthest >= std::f64::consts::FRAC_PI_2 || ratio >= 1.0 => None,
+            Shape::Radius(_) => Some(ratio.asin().to_degrees()),
+            Shape::Box(..) => Some(2.0 * ratio.asin().to_degrees()),
+        };
+        (lon_reach, lat_reach)
+    }
+}
+
+/// The score ranges of the grid cells covering the shape around `center`.
+fn covering_ranges(center: (f64, f64), shape: &Shape) -> Vec<(f64, f64)> {
+    let (lon_reach, lat_reach) = shape.reach(center.1);
+    let lat_lo = (center.1 - lat_reach).max(LAT_MIN);
+    let lat_hi = (center.1 + lat_reach).min(LAT_MAX);
+    let mut step = GEO_STEP_MAX;
+    let (lat_cells, lon_cells) = loop {
+        let cells = 1i64 << step;
+        let lat_cells = (cell_of(lat_lo, LAT_MIN, LAT_MAX, step) as i64)
+            ..=(cell_of(lat_hi, LAT_MIN, LAT_MAX, step) as i64);
+        let lon_cells = match lon_reach {
+            Some(reach) if 2.0 * reach < LON_MAX - LON_MIN => {
+                let unit = (LON_MAX - LON_MIN) / cells as f64;
+                let lo = ((center.0 - reach - LON_MIN) / unit).floor() as i64;
+                let hi = ((center.0 + reach - LON_MIN) / unit).floor() as i64;
+                lo..=hi.min(lo + cells - 1)
+            }
+            _ => 0..=cells - 1,
+        };
+        let amount = (lat_cells.end() - lat_cells.start() + 1) as u64
+            * (lon_cells.end() - lon_cells.start() + 1) as u64;
+        if amount <= MAX_SEARCH_CELLS || step == 1 {
+            break (lat_cells, lon_cells);
+        }
+        step -= 1;
+    };
+    let shift = 2 * (GEO_STEP_MAX - step);
+    let mut hashes = BTreeSet::new();
+    for lat_idx in lat_cells {
+        for lon_idx in lon_cells.clone() {
+            // indexes past the antimeridian wrap around.
+            let lon_idx = lon_idx.rem_euclid(1 << step);
+            hashes.insert(interleave(lat_idx as u32, lon_idx as u32));
+        }
+    }
+    hashes
+        .into_iter()
+        .map(|h| ((h << shift) as f64, ((h + 1) << shift) as f64))
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+struct GeoQuery {
+    origin: Origin,
+    shape: Shape,
+    /// Meters per unit of the distances given and returned.
+    unit: f64,
+    /// Ascending if `Some(true)`.
+    sort: Option<bool>,
+    count: Option<usize>,
+    any: bool,
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+    /// Set by GEOSEARCHSTORE, results are then `member score` pairs scored
+    /// by distance if the flag is set, by geohash otherwise.
+    store: Option<bool>,
+}
+
+impl GeoQuery {
+    fn new(parser: &mut CommandParser, store: bool) -> Result<GeoQuery> {
+        let (mut origin, mut shape) = (None, None);
+        let mut unit = 1.0;
+        let mut query = GeoQuery {
+            origin: Origin::LonLat(0.0, 0.0),
+            shape: Shape::Radius(0.0),
+            unit,
+            sort: None,
+            count: None,
+            any: false,
+            with_coord: false,
+            with_dist: false,
+            with_hash: false,
+            store: if store { Some(false) } else { None },
+        };
+        while let Some(option) = parser.next_bytes()? {
+            match rolling_hash(option.as_ref())? {
+                FROMMEMBER if origin.is_none() => {
+                    origin = Some(Origin::Member(
+                        parser.next_bytes()?.ok_or_else(missing_operand)?,
+                    ));
+                }
+                FROMLONLAT if origin.is_none() => {
+                    let (lon, lat) = parse_coord(parser)?;
+                    origin = Some(Origin::LonLat(lon, lat));
+                }
+                BYRADIUS if shape.is_none() => {
+                    let r = parse_length(parser)?;
+                    unit = parse_unit(parser)?;
+                    shape = Some(Shape::Radius(r * unit));
+                }
+                BYBOX if shape.is_none() => {
+                    let (w, h) = (parse_length(parser)?, parse_length(parser)?);
+                    unit = parse_unit(parser)?;
+                    shape = Some(Shape::Box(w * unit, h * unit));
+                }
+                ASC => query.sort = Some(true),
+                DESC => query.sort = Some(false),
+                COUNT => {
+                    query.count = Some(
+                        parser
+                            .next_integer()?
+                            .filter(|v| *v > 0)
+                            .ok_or_else(invalid_operand)? as usize,
+                    );
+                }
+                ANY => query.any = true,
+                WITHCOORD if !store => query.with_coord = true,
+                WITHDIST if !store => query.with_dist = true,
+                WITHHASH if !store => query.with_hash = true,
+                STOREDIST if store => query.store = Some(true),
+                _ => return Err(invalid_operation()),
+            }
+        }
+        if query.any && query.count.is_none() {
+            return Err(invalid_operation());
+        }
+        query.origin = origin.ok_or_else(missing_operand)?;
+        query.shape = shape.ok_or_else(missing_operand)?;
+        query.unit = unit;
+        Ok(query)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum GeoOp {
+    Add {
+        nx: bool,
+        xx: bool,
+        ch: bool,
+        points: Vec<(f64, f64, Bytes)>,
+    },
+    Pos(Vec<Bytes>),
+    Dist(Bytes, Bytes, f64),
+    Search(GeoQuery),
+}
+
+/// Positions are kept as a sorted set scored by their geohash.
+#[derive(Debug, Clone)]
+pub struct Geo {
+    key: Bytes,
+    op: GeoOp,
+}
+
+impl Geo {
+    pub fn new(parser: &mut CommandParser, variant: GeoVariant) -> Result<Geo> {
+        use GeoVariant::*;
+        let key = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let op = match variant {
+            GeoAdd => {
+                let (mut nx, mut xx, mut ch) = (false, false, false);
+                let mut points = Vec::new();
+                while let Some(v) = parser.next_bytes()? {
+                    match rolling_hash(v.as_ref()).ok() {
+                        Some(NX) if points.is_empty() => nx = true,
+                        Some(XX) if points.is_empty() => xx = true,
+                        Some(CH) if points.is_empty() => ch = true,
+                        _ => {
+                            let lon = get_float(&v).map_err(|_| invalid_operand())?;
+                            let raw = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                            let lat = get_float(&raw).map_err(|_| invalid_operand())?;
+                            if !(LON_MIN..=LON_MAX).contains(&lon)
+                                || !(LAT_MIN..=LAT_MAX).contains(&lat)
+                            {
+                                return Err(invalid_operand());
+                            }
+                            let member = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                            points.push((lon, lat, member));
+                        }
+                    }
+                }
+                if points.is_empty() {
+                    return Err(missing_operand());
+                }
+                if nx && xx {
+                    return Err(invalid_operation());
+                }
+                GeoOp::Add { nx, xx, ch, points }
+            }
+            GeoPos => {
+                let mut members = Vec::with_capacity(parser.len());
+                while let Some(m) = parser.next_bytes()? {
+                    members.push(m);
+                }
+                GeoOp::Pos(members)
+            }
+            GeoDist => {
+                let m1 = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let m2 = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                GeoOp::Dist(m1, m2, parse_unit(parser)?)
+            }
+            GeoSearch => GeoOp::Search(GeoQuery::new(parser, false)?),
+            GeoSearchStore => unreachable!("GEOSEARCHSTORE is dispatched to multiple databases"),
+        };
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(Self { key, op })
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        let key = &self.key;
+        if let GeoOp::Add { nx, xx, ch, points } = self.op {
+            return db.geoadd(key, nx, xx, ch, points);
+        }
+        let zset = match db.get_zset(key) {
+            Ok(z) => z,
+            Err(e) => return e,
+        };
+        let pos = |m: &[u8]| {
+            zset.as_ref()
+                .and_then(|z| z.score(m))
+                .map(|s| decode(s as u64))
+        };
+        match self.op {
+            GeoOp::Add { .. } => unreachable!(),
+            GeoOp::Pos(members) => Frame::Arrays(
+                members
+                    .iter()
+                    .map(|m| pos(m).map_or(Frame::NullArray, coord_frame))
+                    .collect(),
+            ),
+            GeoOp::Dist(m1, m2, unit) => match (pos(&m1), pos(&m2)) {
+                (Some(p1), Some(p2)) => format_distance(distance(p1, p2), unit),
+                _ => Frame::NullString,
+            },
+            GeoOp::Search(query) => match zset {
+                Some(zset) => geo_search(zset, &query),
+                None => Frame::Arrays(Vec::new()),
+            },
+        }
+    }
+}
+
+impl OneshotExecDB for Geo {
+    fn get_key(&self) -> &[u8] {
+        self.key.as_ref()
+    }
+
+    fn is_write(&self) -> bool {
+        matches!(self.op, GeoOp::Add { .. })
+    }
+}
+
+struct GeoHit {
+    member: Bytes,
+    dist: f64,
+    hash: u64,
+}
+
+fn geo_search(zset: &ZSetValue, query: &GeoQuery) -> Frame {
+    let center = match &query.origin {
+        Origin::LonLat(lon, lat) => (*lon, *lat),
+        Origin::Member(m) => match zset.score(m) {
+            Some(s) => decode(s as u64),
+            None => return Frame::Errors(Bytes::from_static(MEMBER_NOT_FOUND_ERR)),
+        },
+    };
+    let limit = match query.count {
+        Some(count) if query.any => count,
+        _ => usize::MAX,
+    };
+    let mut hits = Vec::new();
+    'cells: for (min, max) in covering_ranges(center, &query.shape) {
+        for (member, score) in zset.score_range(min, max) {
+            let hash = score as u64;
+            if let Some(dist) = query.shape.contains(center, decode(hash)) {
+                hits.push(GeoHit {
+                    member: member.clone(),
+                    dist,
+                    hash,
+                });
+                if hits.len() >= limit {
+                    break 'cells;
+                }
+            }
+        }
+    }
+    // COUNT without ANY picks the closest ones.
+    let sort = match (query.sort, query.count) {
+        (None, Some(_)) if !query.any => Some(true),
+        (sort, _) => sort,
+    };
+    if let Some(asc) = sort {
+        hits.sort_by(|a, b| {
+            let ord = a.dist.partial_cmp(&b.dist).unwrap();
+            if asc {
+                ord
+            } else {
+                ord.reverse()
+            }
+        });
+    }
+    if let Some(count) = query.count {
+        hits.truncate(count);
+    }
+
+    let unit = query.unit;
+    if let Some(store_dist) = query.store {
+        let mut res = Vec::with_capacity(hits.len() * 2);
+        for hit in hits {
+            let score = if store_dist {
+                hit.dist / unit
+            } else {
+                hit.hash as f64
+            };
+            res.push(hit.member.into());
+            res.push(score_to_bytes(score).into());
+        }
+        return Frame::Arrays(res);
+    }
+    Frame::Arrays(
+        hits.into_iter()
+            .map(|hit| {
+                if !(query.with_dist || query.with_hash || query.with_coord) {
+                    return hit.member.into();
+                }
+                let mut item = vec![hit.member.into()];
+                if query.with_dist {
+                    item.push(format_distance(hit.dist, unit));
+                }
+                if query.with_hash {
+                    item.push(Frame::Integers(hit.hash as i64));
+                }
+                if query.with_coord {
+                    item.push(coord_frame(decode(hit.hash)));
+                }
+                Frame::Arrays(item)
+            })
+            .collect(),
+    )
+}
+
+impl DB {
+    fn geoadd(
+        &mut self,
+        key: &Bytes,
+        nx: bool,
+        xx: bool,
+        ch: bool,
+        points: Vec<(f64, f64, Bytes)>,
+    ) -> Frame {
+        let zset = match self.get_or_create_zset(key) {
+            Ok(z) => z,
+            Err(e) => return e,
+        };
+        let (mut added, mut updated) = (0, 0);
+        for (lon, lat, member) in points {
+            let score = encode(lon, lat) as f64;
+            match zset.score(&member) {
+                Some(_) if nx => (),
+                None if xx => (),
+                Some(old) => {
+                    if old != score {
+                        zset.insert(member, score);
+                        updated += 1;
+                    }
+                }
+                None => {
+                    zset.insert(member, score);
+                    added += 1;
+                }
+            }
+        }
+        let emptied = zset.is_empty();
+        self.after_write(key, emptied);
+        if added > 0 {
+            self.signal_ready(key);
+        }
+        Frame::Integers(if ch { added + updated } else { added })
+    }
+}
+
+/// Runs the search on the database of the source, then stores the result on
+/// the database of `dest`.
+#[derive(Debug, Clone)]
+pub struct GeoSearchStoreDispatcher {
+    dest: Option<Bytes>,
+    dest_db: usize,
+    src_db: usize,
+    search: Option<Geo>,
+}
+
+impl GeoSearchStoreDispatcher {
+    pub fn new(parser: &mut CommandParser) -> Result<GeoSearchStoreDispatcher> {
+        let dest = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let key = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let op = GeoOp::Search(GeoQuery::new(parser, true)?);
+        Ok(Self {
+            dest: Some(dest),
+            dest_db: 0,
+            src_db: 0,
+            search: Some(Geo { key, op }),
+        })
+    }
+}
+
+impl DispatchToMultipleDB for GeoSearchStoreDispatcher {
+    fn next_command(&mut self) -> Option<IDCommandPair> {
+        let search: OneshotCommand = self.search.take()?.into();
+        Some((self.src_db, search.into()))
+    }
+
+    fn get_result_collector(&mut self) -> ResultCollector {
+        let ret = vec![Frame::NullString; 1];
+        ResultCollector {
+            result_type: ResultCollectorType::KeepFirst(1),
+            ret,
+        }
+    }
+
+    fn dispatch(&mut self, _db_amount: usize, dispatch_fn: impl Fn(&[u8]) -> usize) {
+        if let Some(dest) = &self.dest {
+            self.dest_db = dispatch_fn(dest);
+        }
+        if let Some(search) = &self.search {
+            self.src_db = dispatch_fn(&search.key);
+        }
+    }
+
+    fn complete(&mut self, merged: Frame) -> Completion {
+        match (self.dest.take(), merged) {
+            (Some(dest), Frame::Arrays(pairs)) => {
+                Completion::Forward((self.dest_db, ZStore::new(dest, from_frames(pairs)).into()))
+            }
+            (_, f) => Completion::Reply(f),
+        }
+    }
+}
+
+impl AtomicCMDMarker for Geo {}
@@ -1,4 +1,7 @@
-use crate::cmd::*;
+use crate::{
+    cmd::*,
+    db::{wrong_type, Value},
+};
 use anyhow::Result;
 use tokio::time::Instant;
 
@@ -36,35 +39,45 @@ impl OneshotExecDB for Get {
     fn get_key(&self) -> &[u8] {
         &self.key.as_ref()
     }
+
+    fn is_write(&self) -> bool {
+        false
+    }
 }
 
 impl DB {
-    pub fn get(&self, key: &Bytes) -> Frame {
-        self.database
-            .get(key)
-            .filter(|v| v.expiration.is_none() || v.expiration.unwrap() > Instant::now())
-            .map_or_else(|| Frame::NullString, |v| v.data.clone())
+    pub fn get(&mut self, key: &Bytes) -> Frame {
+        match self.database.get(key) {
+            None => Frame::NullString,
+            Some(v) if v.is_expired(Instant::now()) => {
+                self.expire_key(key);
+                Frame::NullString
+            }
+            Some(v) => match &v.data {
+                Value::Str(f) => f.clone(),
+                Value::MutStr(b) => Frame::BulkStrings(Bytes::copy_from_slice(b)),
+                Value::HyperLogLog(h) => Frame::BulkStrings(Bytes::from(h.dense_string())),
+                _ => wrong_type(),
+            },
+        }
     }
 
-    fn ttl(&self, key: &Bytes, is_millis: bool) -> Frame {
-        self.database
-            .get(key)
-            .filter(|v| v.expiration.is_none() || v.expiration.unwrap() > Instant::now())
-            .map_or_else(
-                || Frame::Integers(-2),
-                |v| {
-                    v.expiration.map_or_else(
-                        || Frame::Integers(-1),
-                        |v| {
-                            Frame::Integers(if is_millis {
-                                v.duration_since(Instant::now()).as_millis() as i64
-                            } else {
-                                v.duration_since(Instant::now()).as_secs() as i64
-                            })
-                        },
-                    )
-                },
-            )
+    fn ttl(&mut self, key: &Bytes, is_millis: bool) -> Frame {
+        self.get_live(key).map_or_else(
+            || Frame::Integers(-2),
+            |v| {
+                v.expiration.map_or_else(
+                    || Frame::Integers(-1),
+                    |v| {
+                        Frame::Integers(if is_millis {
+                            v.duration_since(Instant::now()).as_millis() as i64
+                        } else {
+                            v.duration_since(Instant::now()).as_secs() as i64
+                        })
+                    },
+                )
+            },
+        )
     }
 }
 
@@ -0,0 +1,894 @@
+use crate::{
+    cmd::expire::ExpireCondition,
+    cmd::snapshot::{put_bytes, put_deadline, take_bytes, take_deadline, take_len},
+    cmd::*,
+    db::{random_count_out_of_range, wrong_type, Entry, Value, MIN_RANDOM_COUNT},
+    utils::{
+        get_float, get_integer, glob_match, instant_to_unix_millis, unix_millis_now,
+        unix_millis_to_instant, SlotMap,
+    },
+};
+use anyhow::Result;
+use rand::{seq::SliceRandom, thread_rng, Rng};
+use rustc_hash::FxHashMap;
+use std::collections::{BTreeMap, BTreeSet};
+use tokio::time::{Duration, Instant};
+
+const MATCH: usize = rolling_hash_const(b"match");
+const COUNT: usize = rolling_hash_const(b"count");
+const NOVALUES: usize = rolling_hash_const(b"novalues");
+const WITHVALUES: usize = rolling_hash_const(b"withvalues");
+const FIELDS: usize = rolling_hash_const(b"fields");
+
+/// A compact hash is converted into a table once it holds more fields than
+/// this, or a field or value longer than `HASH_MAX_COMPACT_VALUE`.
+const HASH_MAX_COMPACT_ENTRIES: usize = 128;
+const HASH_MAX_COMPACT_VALUE: usize = 64;
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// Upper bound of vacant slots visited per requested field.
+const VACANT_SLOTS_PER_FIELD: usize = 10;
+/// Random probes into a table before falling back to a linear walk.
+const RANDOM_FIELD_TRIES: usize = 32;
+
+const NOT_INTEGER_ERR: &[u8] = b"ERR hash value is not an integer";
+const NOT_FLOAT_ERR: &[u8] = b"ERR hash value is not a float";
+const OVERFLOW_ERR: &[u8] = b"ERR increment or decrement would overflow";
+const NAN_OR_INF_ERR: &[u8] = b"ERR increment would produce NaN or Infinity";
+
+#[derive(Debug)]
+enum HashFields {
+    /// Small hashes are a flat vector of pairs, searched linearly.
+    Compact(Vec<(Bytes, Bytes)>),
+    Table(SlotMap<Bytes>),
+}
+
+#[derive(Debug)]
+pub struct HashValue {
+    fields: HashFields,
+    /// Deadlines of the fields that carry one.
+    ttl: FxHashMap<Bytes, Instant>,
+    /// The same deadlines in expiry order, for reclaiming fields on access.
+    deadlines: BTreeSet<(Instant, Bytes)>,
+}
+
+impl HashValue {
+    pub fn new() -> Self {
+        Self {
+            fields: HashFields::Compact(Vec::new()),
+            ttl: FxHashMap::default(),
+            deadlines: BTreeSet::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.fields {
+            HashFields::Compact(v) => v.len(),
+            HashFields::Table(t) => t.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&Bytes> {
+        match &self.fields {
+            HashFields::Compact(v) => v.iter().find(|p| p.0 == field).map(|p| &p.1),
+            HashFields::Table(t) => t.get(field),
+        }
+    }
+
+    /// Returns whether `field` is new. Overwriting a field drops its
+    /// deadline unless `keep_ttl` is set.
+    pub fn insert(&mut self, field: Bytes, value: Bytes, keep_ttl: bool) -> bool {
+        if !keep_ttl {
+            self.set_ttl(&field, None);
+        }
+        if let HashFields::Compact(v) = &mut self.fields {
+            if value.len() <= HASH_MAX_COMPACT_VALUE {
+                if let Some(p) = v.iter_mut().find(|p| p.0 == field) {
+                    p.1 = value;
+                    return false;
+                }
+                if v.len() < HASH_MAX_COMPACT_ENTRIES && field.len() <= HASH_MAX_COMPACT_VALUE {
+                    v.push((field, value));
+                    return true;
+                }
+            }
+            let mut table = SlotMap::new();
+            for (f, v) in v.drain(..) {
+                table.insert(f, v);
+            }
+            self.fields = HashFields::Table(table);
+        }
+        match &mut self.fields {
+            HashFields::Table(t) => t.insert(field, value).is_none(),
+            HashFields::Compact(_) => unreachable!(),
+        }
+    }
+
+    pub fn remove(&mut self, field: &[u8]) -> bool {
+        if self.ttl.contains_key(field) {
+            self.set_ttl(&Bytes::copy_from_slice(field), None);
+        }
+        match &mut self.fields {
+            HashFields::Compact(v) => match v.iter().position(|p| p.0 == field) {
+                Some(idx) => {
+                    v.remove(idx);
+                    true
+                }
+                None => false,
+            },
+            HashFields::Table(t) => t.remove(field).is_some(),
+        }
+    }
+
+    pub fn ttl(&self, field: &[u8]) -> Option<Instant> {
+        self.ttl.get(field).copied()
+    }
+
+    pub fn set_ttl(&mut self, field: &Bytes, at: Option<Instant>) {
+        let old = match at {
+            Some(at) => {
+                self.deadlines.insert((at, field.clone()));
+                self.ttl.insert(field.clone(), at)
+            }
+            None => self.ttl.remove(field),
+        };
+        if let Some(old) = old.filter(|v| Some(*v) != at) {
+            self.deadlines.remove(&(old, field.clone()));
+        }
+    }
+
+    /// Removes the fields whose deadline is not after `now`, returns them.
+    pub fn expire_fields(&mut self, now: Instant) -> Vec<Bytes> {
+        let mut removed = Vec::new();
+        while let Some((at, field)) = self.deadlines.iter().next().cloned() {
+            if at > now {
+                break;
+            }
+            self.remove(&field);
+            removed.push(field);
+        }
+        removed
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&Bytes, &Bytes)> + '_> {
+        match &self.fields {
+            HashFields::Compact(v) => Box::new(v.iter().map(|p| (&p.0, &p.1))),
+            HashFields::Table(t) => Box::new(t.iter()),
+        }
+    }
+
+    fn random(&self, rng: &mut impl Rng) -> Option<(&Bytes, &Bytes)> {
+        match &self.fields {
+            HashFields::Compact(v) => v.choose(rng).map(|p| (&p.0, &p.1)),
+            HashFields::Table(t) => {
+                if t.len() == 0 {
+                    return None;
+                }
+                let len = t.slots_len();
+                (0..RANDOM_FIELD_TRIES)
+                    .find_map(|_| t.slot(rng.gen_range(0..len)))
+                    .or_else(|| t.iter().nth(rng.gen_range(0..t.len())))
+            }
+        }
+    }
+
+    /// Payload size in bytes.
+    pub fn bytes_len(&self) -> usize {
+        self.iter().fold(0, |res, (f, v)| res + f.len() + v.len())
+    }
+
+    pub fn field_deadlines(&self) -> impl Iterator<Item = (Instant, Bytes)> + '_ {
+        self.deadlines.iter().cloned()
+    }
+
+    pub fn dump(&self, buf: &mut BytesMut) {
+        buf.put_u64_le(self.len() as u64);
+        for (field, value) in self.iter() {
+            put_bytes(buf, field);
+            put_bytes(buf, value);
+            put_deadline(buf, self.ttl(field));
+        }
+    }
+
+    /// Fields that expired in the meantime are left out.
+    pub fn restore(buf: &mut Bytes) -> Result<HashValue> {
+        let now = unix_millis_now() as i64;
+        let mut hash = HashValue::new();
+        for _ in 0..take_len(buf)? {
+            let field = take_bytes(buf)?;
+            let value = take_bytes(buf)?;
+            match take_deadline(buf)? {
+                Some(at) if at <= now => (),
+                Some(at) => {
+                    hash.insert(field.clone(), value, false);
+                    hash.set_ttl(&field, Some(unix_millis_to_instant(at)));
+                }
+                None => {
+                    hash.insert(field, value, false);
+                }
+            }
+        }
+        Ok(hash)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum HashVariant {
+    HSet,
+    HMSet,
+    HSetNX,
+    HGet,
+    HMGet,
+    HDel,
+    HLen,
+    HStrLen,
+    HExists,
+    HKeys,
+    HVals,
+    HGetAll,
+    HIncrBy,
+    HIncrByFloat,
+    HScan,
+    HRandField,
+    HExpire,
+    HPExpire,
+    HExpireAt,
+    HPExpireAt,
+    HTTL,
+    HPTTL,
+    HPersist,
+}
+
+#[derive(Debug, Clone)]
+enum HashOp {
+    Set {
+        pairs: Vec<(Bytes, Bytes)>,
+        only_new: bool,
+        reply_ok: bool,
+    },
+    Get(Bytes),
+    MGet(Vec<Bytes>),
+    Del(Vec<Bytes>),
+    Len,
+    StrLen(Bytes),
+    Exists(Bytes),
+    Keys,
+    Vals,
+    GetAll,
+    IncrBy(Bytes, i64),
+    IncrByFloat(Bytes, f64),
+    Scan {
+        cursor: usize,
+        pattern: Option<Bytes>,
+        count: usize,
+        novalues: bool,
+    },
+    RandField {
+        count: Option<i64>,
+        withvalues: bool,
+    },
+    Expire {
+        at: Instant,
+        cond: ExpireCondition,
+        fields: Vec<Bytes>,
+    },
+    TTL {
+        fields: Vec<Bytes>,
+        is_millis: bool,
+    },
+    Persist(Vec<Bytes>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Hash {
+    key: Bytes,
+    op: HashOp,
+}
+
+fn next_fields(parser: &mut CommandParser) -> Result<Vec<Bytes>> {
+    let mut fields = Vec::with_capacity(parser.len());
+    while let Some(v) = parser.next_bytes()? {
+        fields.push(v);
+    }
+    if fields.is_empty() {
+        return Err(missing_operand());
+    }
+    Ok(fields)
+}
+
+/// Parses `numfields field...`, what follows the `FIELDS` token.
+fn fields_block(parser: &mut CommandParser) -> Result<Vec<Bytes>> {
+    let num = parser
+        .next_integer()?
+        .filter(|v| *v > 0)
+        .ok_or_else(invalid_operand)?;
+    if parser.len() != num as usize {
+        return Err(invalid_operand());
+    }
+    next_fields(parser)
+}
+
+fn expect_fields(parser: &mut CommandParser) -> Result<Vec<Bytes>> {
+    let token = parser.next_bytes()?.ok_or_else(missing_operand)?;
+    if rolling_hash(token.as_ref())? != FIELDS {
+        return Err(invalid_operation());
+    }
+    fields_block(parser)
+}
+
+impl Hash {
+    pub fn new(parser: &mut CommandParser, variant: HashVariant) -> Result<Hash> {
+        use HashVariant::*;
+        let key = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let op = match variant {
+            HSet | HMSet | HSetNX => {
+                if parser.len() == 0 || parser.len() % 2 != 0 {
+                    return Err(missing_operand());
+                }
+                let mut pairs = Vec::with_capacity(parser.len() / 2);
+                while let Some(field) = parser.next_bytes()? {
+                    pairs.push((field, parser.next_bytes()?.ok_or_else(missing_operand)?));
+                }
+                if matches!(variant, HSetNX) && pairs.len() > 1 {
+                    return Err(invalid_operation());
+                }
+                HashOp::Set {
+                    pairs,
+                    only_new: matches!(variant, HSetNX),
+                    reply_ok: matches!(variant, HMSet),
+                }
+            }
+            HGet => HashOp::Get(parser.next_bytes()?.ok_or_else(missing_operand)?),
+            HMGet => HashOp::MGet(next_fields(parser)?),
+            HDel => HashOp::Del(next_fields(parser)?),
+            HLen => HashOp::Len,
+            HStrLen => HashOp::StrLen(parser.next_bytes()?.ok_or_else(missing_operand)?),
+            HExists => HashOp::Exists(parser.next_bytes()?.ok_or_else(missing_operand)?),
+            HKeys => HashOp::Keys,
+            HVals => HashOp::Vals,
+            HGetAll => HashOp::GetAll,
+            HIncrBy => HashOp::IncrBy(
+                parser.next_bytes()?.ok_or_else(missing_operand)?,
+                parser.next_integer()?.ok_or_else(missing_operand)?,
+            ),
+            HIncrByFloat => {
+                let field = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let by = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                HashOp::IncrByFloat(
+                    field,
+                    get_float(&by)
+                        .ok()
+                        .filter(|v| v.is_finite())
+                        .ok_or_else(invalid_operand)?,
+                )
+            }
+            HScan => {
+                let cursor = parser
+                    .next_integer()?
+                    .filter(|v| *v >= 0)
+                    .ok_or_else(invalid_operand)? as usize;
+                let (mut pattern, mut count, mut novalues) = (None, DEFAULT_SCAN_COUNT, false);
+                while let Some(option) = parser.next_bytes()? {
+                    match rolling_hash(option.as_ref())? {
+                        MATCH => pattern = Some(parser.next_bytes()?.ok_or_else(missing_operand)?),
+                        COUNT => {
+                            count = parser
+                                .next_integer()?
+                                .filter(|v| *v > 0)
+                                .ok_or_else(invalid_operand)?
+                                as usize
+                        }
+                        NOVALUES => novalues = true,
+                        _ => return Err(invalid_operation()),
+                    }
+                }
+                HashOp::Scan {
+                    cursor,
+                    pattern,
+                    count,
+                    novalues,
+                }
+            }
+            HRandField => {
+                let count = parser.next_integer()?;
+                let withvalues = match parser.next_bytes()? {
+                    Some(v) if count.is_some() && rolling_hash(v.as_ref())? == WITHVALUES => true,
+                    Some(_) => return Err(invalid_operation()),
+                    None => false,
+                };
+                HashOp::RandField { count, withvalues }
+            }
+            HExpire | HPExpire | HExpireAt | HPExpireAt => {
+                let num = parser.next_integer()?.ok_or_else(missing_operand)?;
+                let millis = match variant {
+                    HExpire | HExpireAt => num.checked_mul(1000).ok_or_else(invalid_operand)?,
+                    _ => num,
+                };
+                let at = match variant {
+                    HExpire | HPExpire => {
+                        let now = Instant::now();
+                        if millis > 0 {
+                            now.checked_add(Duration::from_millis(millis as u64))
+                                .ok_or_else(invalid_operand)?
+                        } else {
+                            now
+                        }
+                    }
+                    _ => unix_millis_to_instant(millis),
+                };
+                let mut cond = ExpireCondition::default();
+                loop {
+                    let token = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                    match rolling_hash(token.as_ref())? {
+                        FIELDS => break,
+                        token if cond.set(token) => {}
+                        _ => return Err(invalid_operation()),
+                    }
+                }
+                cond.validate()?;
+                HashOp::Expire {
+                    at,
+                    cond,
+                    fields: fields_block(parser)?,
+                }
+            }
+            HTTL | HPTTL => HashOp::TTL {
+                fields: expect_fields(parser)?,
+                is_millis: matches!(variant, HPTTL),
+            },
+            HPersist => HashOp::Persist(expect_fields(parser)?),
+        };
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(Self { key, op })
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        let key = &self.key;
+        match self.op {
+            HashOp::Set {
+                pairs,
+                only_new,
+                reply_ok,
+            } => db.hset(key, pairs, only_new, reply_ok),
+            HashOp::Del(fields) => db.hdel(key, &fields),
+            HashOp::IncrBy(field, by) => db.hincr_by(key, field, by),
+            HashOp::IncrByFloat(field, by) => db.hincr_by_float(key, field, by),
+            HashOp::Expire { at, cond, fields } => db.hexpire(key, at, &cond, fields),
+            HashOp::Persist(fields) => db.hpersist(key, &fields),
+            op => {
+                let hash = match db.get_hash(key) {
+                    Ok(h) => h,
+                    Err(e) => return e,
+                };
+                hash_read(hash.map(|h| &*h), op)
+            }
+        }
+    }
+}
+
+impl OneshotExecDB for Hash {
+    fn get_key(&self) -> &[u8] {
+        self.key.as_ref()
+    }
+
+    fn is_write(&self) -> bool {
+        matches!(
+            self.op,
+            HashOp::Set { .. }
+                | HashOp::Del(_)
+                | HashOp::IncrBy(..)
+                | HashOp::IncrByFloat(..)
+                | HashOp::Expire { .. }
+                | HashOp::Persist(_)
+        )
+    }
+
+    /// Deadlines of fields are logged as unix time. Reads change nothing but
+    /// expired fields, whose deletion is logged already.
+    fn propagate(&self, frame: Frame, _ret: &Frame, db: &mut DB) {
+        match &self.op {
+            HashOp::Set { .. } | HashOp::Del(_) | HashOp::IncrBy(..) | HashOp::IncrByFloat(..) => {
+                db.propagate(frame)
+            }
+            HashOp::Expire { fields, .. } | HashOp::Persist(fields) => {
+                db.propagate_field_ttls(&self.key, fields)
+            }
+            _ => (),
+        }
+    }
+}
+
+/// `name key [arg] FIELDS numfields field...`
+fn fields_command(
+    name: &'static [u8],
+    key: &Bytes,
+    arg: Option<Bytes>,
+    fields: Vec<Bytes>,
+) -> Frame {
+    let mut args = vec![Bytes::from_static(name), key.clone()];
+    args.extend(arg);
+    args.push(Bytes::from_static(b"FIELDS"));
+    args.push(Bytes::from(fields.len().to_string()));
+    args.extend(fields);
+    command(args)
+}
+
+fn flatten(pairs: Vec<(&Bytes, &Bytes)>, with_values: bool) -> Frame {
+    let mut res = Vec::with_capacity(pairs.len() * if with_values { 2 } else { 1 });
+    for (f, v) in pairs {
+        res.push(f.clone().into());
+        if with_values {
+            res.push(v.clone().into());
+        }
+    }
+    Frame::Arrays(res)
+}
+
+/// Commands that leave the hash untouched, `hash` is `None` for a missing key.
+fn hash_read(hash: Option<&HashValue>, op: HashOp) -> Frame {
+    let empty = HashValue::new();
+    let is_missing = hash.is_none();
+    let hash = hash.unwrap_or(&empty);
+    match op {
+        HashOp::Get(field) => hash
+            .get(&field)
+            .map_or(Frame::NullString, |v| v.clone().into()),
+        HashOp::MGet(fields) => Frame::Arrays(
+            fields
+                .iter()
+                .map(|f| hash.get(f).map_or(Frame::NullString, |v| v.clone().into()))
+                .collect(),
+        ),
+        HashOp::Len => Frame::Integers(hash.len() as i64),
+        HashOp::StrLen(field) => Frame::Integers(hash.get(&field).map_or(0, |v| v.len()) as i64),
+        HashOp::Exists(field) => Frame::Integers(hash.get(&field).is_some() as i64),
+        HashOp::Keys => Frame::Arrays(hash.iter().map(|(f, _)| f.clone().into()).collect()),
+        HashOp::Vals => Frame::Arrays(hash.iter().map(|(_, v)| v.clone().into()).collect()),
+        HashOp::GetAll => flatten(hash.iter().collect(), true),
+        HashOp::Scan {
+            cursor,
+            pattern,
+            count,
+            novalues,
+        } => {
+            let matches = |f: &Bytes| pattern.as_ref().map_or(true, |p| glob_match(p, f));
+            let (next, found) = match &hash.fields {
+                // a compact hash is small enough to be returned at once.
+                HashFields::Compact(v) => (
+                    0,
+                    v.iter()
+                        .filter(|p| matches(&p.0))
+                        .map(|p| (&p.0, &p.1))
+                        .collect(),
+                ),
+                HashFields::Table(t) => {
+                    let end = t
+                        .slots_len()
+                        .min(cursor.saturating_add(count * VACANT_SLOTS_PER_FIELD));
+                    let (mut idx, mut examined, mut found) = (cursor, 0, Vec::new());
+                    while idx < end && examined < count {
+                        if let Some((f, v)) = t.slot(idx) {
+                            examined += 1;
+                            if matches(f) {
+                                found.push((f, v));
+                            }
+                        }
+                        idx += 1;
+                    }
+                    (if idx >= t.slots_len() { 0 } else { idx }, found)
+                }
+            };
+            Frame::Arrays(vec![
+                Bytes::from(next.to_string()).into(),
+                flatten(found, !novalues),
+            ])
+        }
+        HashOp::RandField { count, withvalues } => {
+            let mut rng = thread_rng();
+            match count {
+                None if is_missing => Frame::NullString,
+                None => hash
+                    .random(&mut rng)
+                    .map_or(Frame::NullString, |(f, _)| f.clone().into()),
+                Some(n) if n >= 0 => {
+                    let mut all: Vec<_> = hash.iter().collect();
+                    let n = (n as usize).min(all.len());
+                    let (picked, _) = all.partial_shuffle(&mut rng, n);
+                    flatten(picked.to_vec(), withvalues)
+                }
+                Some(n) if n < MIN_RANDOM_COUNT => random_count_out_of_range(),
+                // a negative count allows the same field to be picked again.
+                Some(n) => {
+                    let picked = (0..n.unsigned_abs())
+                        .map_while(|_| hash.random(&mut rng))
+                        .collect();
+                    flatten(picked, withvalues)
+                }
+            }
+        }
+        HashOp::TTL { fields, is_millis } => {
+            let now = Instant::now();
+            Frame::Arrays(
+                fields
+                    .iter()
+                    .map(|f| match (hash.get(f), hash.ttl(f)) {
+                        (None, _) => Frame::Integers(-2),
+                        (Some(_), None) => Frame::Integers(-1),
+                        (Some(_), Some(at)) => {
+                            let left = at.duration_since(now);
+                            Frame::Integers(if is_millis {
+                                left.as_millis() as i64
+                            } else {
+                                left.as_secs() as i64
+                            })
+                        }
+                    })
+                    .collect(),
+            )
+        }
+        HashOp::Set { .. }
+        | HashOp::Del(_)
+        | HashOp::IncrBy(..)
+        | HashOp::IncrByFloat(..)
+        | HashOp::Expire { .. }
+        | HashOp::Persist(_) => {
+            unreachable!("writes are handled by `Hash::exec`")
+        }
+    }
+}
+
+impl DB {
+    /// `Ok(None)` if there is no such key, `Err` carries the error reply.
+    /// Fields past their deadline are dropped before the hash is handed out,
+    /// unless loading.
+    pub fn get_hash(&mut self, key: &Bytes) -> std::result::Result<Option<&mut HashValue>, Frame> {
+        let now = Instant::now();
+        let loading = self.loading;
+        let (expired, emptied) = match self.get_live(key).map(|en| &mut en.data) {
+            None => return Ok(None),
+            Some(Value::Hash(_)) if loading => (Vec::new(), false),
+            Some(Value::Hash(h)) => (h.expire_fields(now), h.is_empty()),
+            Some(_) => return Err(wrong_type()),
+        };
+        if !expired.is_empty() {
+            self.propagate_hdel(key, expired);
+            self.after_write(key, emptied);
+        }
+        match self.database.get_mut(key).map(|en| &mut en.data) {
+            Some(Value::Hash(h)) => Ok(Some(h)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Called by the active expire cycle. The field is only removed if it
+    /// still carries `deadline`, the key goes with its last field.
+    pub fn expire_hash_field(&mut self, key: &Bytes, field: &Bytes, deadline: Instant) {
+        self.preserve(key);
+        let emptied = match self.database.get_mut(key).map(|en| &mut en.data) {
+            Some(Value::Hash(h)) if h.ttl(field) == Some(deadline) => {
+                h.remove(field);
+                h.is_empty()
+            }
+            _ => return,
+        };
+        self.propagate_hdel(key, vec![field.clone()]);
+        self.after_write(key, emptied);
+    }
+
+    /// Logs the deadlines `fields` of the hash under `key` have now.
+    fn propagate_field_ttls(&mut self, key: &Bytes, fields: &[Bytes]) {
+        if !self.logging() {
+            return;
+        }
+        let hash = match self.database.get(key).map(|en| &en.data) {
+            Some(Value::Hash(h)) => h,
+            _ => return self.propagate(command(vec![Bytes::from_static(b"DEL"), key.clone()])),
+        };
+        let (mut gone, mut persistent) = (Vec::new(), Vec::new());
+        let mut deadlines: BTreeMap<Instant, Vec<Bytes>> = BTreeMap::new();
+        for field in fields {
+            match (hash.get(field), hash.ttl(field)) {
+                (None, _) => gone.push(field.clone()),
+                (Some(_), None) => persistent.push(field.clone()),
+                (Some(_), Some(at)) => deadlines.entry(at).or_default().push(field.clone()),
+            }
+        }
+        let mut frames = Vec::new();
+        if !gone.is_empty() {
+            let mut args = vec![Bytes::from_static(b"HDEL"), key.clone()];
+            args.extend(gone);
+            frames.push(command(args));
+        }
+        if !persistent.is_empty() {
+            frames.push(fields_command(b"HPERSIST", key, None, persistent));
+        }
+        for (at, fields) in deadlines {
+            let at = Bytes::from(instant_to_unix_millis(at).to_string());
+            frames.push(fields_command(b"HPEXPIREAT", key, Some(at), fields));
+        }
+        for frame in frames {
+            self.propagate(frame);
+        }
+    }
+
+    /// Logs the deletion of fields that expired, replay reclaims nothing.
+    fn propagate_hdel(&mut self, key: &Bytes, fields: Vec<Bytes>) {
+        if self.logging() {
+            let mut args = vec![Bytes::from_static(b"HDEL"), key.clone()];
+            args.extend(fields);
+            self.propagate(command(args));
+        }
+    }
+
+    /// Like `get_hash`, but creates the hash if there is no such key.
+    pub fn get_or_create_hash(
+        &mut self,
+        key: &Bytes,
+    ) -> std::result::Result<&mut HashValue, Frame> {
+        if self.get_hash(key)?.is_none() {
+            self.counter += 1;
+            let nounce = self.counter;
+            self.insert(
+                key.clone(),
+                Entry::new(Value::Hash(HashValue::new()), None, nounce),
+            );
+        }
+        Ok(self.get_hash(key)?.unwrap())
+    }
+
+    fn hset(
+        &mut self,
+        key: &Bytes,
+        pairs: Vec<(Bytes, Bytes)>,
+        only_new: bool,
+        reply_ok: bool,
+    ) -> Frame {
+        let hash = match self.get_or_create_hash(key) {
+            Ok(h) => h,
+            Err(e) => return e,
+        };
+        let mut added = 0;
+        for (field, value) in pairs {
+            if only_new && hash.get(&field).is_some() {
+                continue;
+            }
+            added += hash.insert(field, value, false) as i64;
+        }
+        self.after_write(key, false);
+        if reply_ok {
+            Frame::Ok
+        } else {
+            Frame::Integers(added)
+        }
+    }
+
+    fn hdel(&mut self, key: &Bytes, fields: &[Bytes]) -> Frame {
+        let hash = match self.get_hash(key) {
+            Ok(Some(h)) => h,
+            Ok(None) => return Frame::Integers(0),
+            Err(e) => return e,
+        };
+        let removed = fields.iter().filter(|f| hash.remove(f)).count();
+        let emptied = hash.is_empty();
+        if removed > 0 {
+            self.after_write(key, emptied);
+        }
+        Frame::Integers(removed as i64)
+    }
+
+    fn hincr_by(&mut self, key: &Bytes, field: Bytes, by: i64) -> Frame {
+        let hash = match self.get_or_create_hash(key) {
+            Ok(h) => h,
+            Err(e) => return e,
+        };
+        let current = match hash.get(&field).map(get_integer) {
+            None => 0,
+            Some(Ok(v)) => v,
+            Some(Err(_)) => return Frame::Errors(Bytes::from_static(NOT_INTEGER_ERR)),
+        };
+        let res = match current.checked_add(by) {
+            Some(v) => v,
+            None => return Frame::Errors(Bytes::from_static(OVERFLOW_ERR)),
+        };
+        hash.insert(field, Bytes::from(res.to_string()), true);
+        self.after_write(key, false);
+        Frame::Integers(res)
+    }
+
+    fn hincr_by_float(&mut self, key: &Bytes, field: Bytes, by: f64) -> Frame {
+        let hash = match self.get_or_create_hash(key) {
+            Ok(h) => h,
+            Err(e) => return e,
+        };
+        let current = match hash.get(&field).map(get_float) {
+            None => 0.0,
+            Some(Ok(v)) => v,
+            Some(Err(_)) => return Frame::Errors(Bytes::from_static(NOT_FLOAT_ERR)),
+        };
+        let res = current + by;
+        if !res.is_finite() {
+            return Frame::Errors(Bytes::from_static(NAN_OR_INF_ERR));
+        }
+        let res = Bytes::from(res.to_string());
+        hash.insert(field, res.clone(), true);
+        self.after_write(key, false);
+        Frame::BulkStrings(res)
+    }
+
+    fn hexpire(
+        &mut self,
+        key: &Bytes,
+        at: Instant,
+        cond: &ExpireCondition,
+        fields: Vec<Bytes>,
+    ) -> Frame {
+        let loading = self.loading;
+        let hash = match self.get_hash(key) {
+            Ok(Some(h)) => h,
+            Ok(None) => return Frame::Arrays(fields.iter().map(|_| Frame::Integers(-2)).collect()),
+            Err(e) => return e,
+        };
+        let now = Instant::now();
+        let (mut res, mut armed) = (Vec::with_capacity(fields.len()), Vec::new());
+        let mut changed = false;
+        for field in fields {
+            let code = if hash.get(&field).is_none() {
+                -2
+            } else if !cond.allows(hash.ttl(&field), at) {
+                0
+            } else if at <= now && !loading {
+                hash.remove(&field);
+                changed = true;
+                2
+            } else {
+                hash.set_ttl(&field, Some(at));
+                armed.push(field);
+                changed = true;
+                1
+            };
+            res.push(Frame::Integers(code));
+        }
+        let emptied = hash.is_empty();
+        for field in armed {
+            self.counter += 1;
+            self.expiration.update_field(at, self.counter, key, &field);
+        }
+        if changed {
+            self.after_write(key, emptied);
+        }
+        Frame::Arrays(res)
+    }
+
+    fn hpersist(&mut self, key: &Bytes, fields: &[Bytes]) -> Frame {
+        let hash = match self.get_hash(key) {
+            Ok(Some(h)) => h,
+            Ok(None) => return Frame::Arrays(fields.iter().map(|_| Frame::Integers(-2)).collect()),
+            Err(e) => return e,
+        };
+        let mut persisted = false;
+        let res = fields
+            .iter()
+            .map(|f| match (hash.get(f).is_some(), hash.ttl(f).is_some()) {
+                (false, _) => Frame::Integers(-2),
+                (true, false) => Frame::Integers(-1),
+                (true, true) => {
+                    hash.set_ttl(f, None);
+                    persisted = true;
+                    Frame::Integers(1)
+                }
+            })
+            .collect();
+        if persisted {
+            self.after_write(key, false);
+        }
+        Frame::Arrays(res)
+    }
+}
+
+impl AtomicCMDMarker for Hash {}
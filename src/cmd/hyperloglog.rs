@@ -0,0 +1,620 @@
+use crate::{
+    cmd::snapshot::{corrupt, put_bytes, take_bytes, take_len, take_u8},
+    cmd::*,
+    db::{wrong_type, Entry, Value},
+    impl_traverse_command,
+};
+use anyhow::Result;
+use std::convert::TryInto;
+
+/// Bits of the hash picking the register.
+const HLL_P: u32 = 14;
+/// Bits of the hash left for counting zeros.
+const HLL_Q: u32 = 64 - HLL_P;
+const HLL_REGISTERS: usize = 1 << HLL_P;
+const HLL_BITS: usize = 6;
+const HLL_REGISTER_MAX: u8 = (1 << HLL_BITS) - 1;
+/// The packed registers plus a byte of slack, so that reading the last one
+/// never goes out of bounds.
+const HLL_DENSE_SIZE: usize = (HLL_REGISTERS * HLL_BITS + 7) / 8 + 1;
+/// Past this many non-zero registers the sparse encoding takes more memory
+/// than it saves, and gets slow to update.
+const HLL_SPARSE_MAX_ENTRIES: usize = 1000;
+/// The largest register value the sparse encoding holds.
+const HLL_SPARSE_VAL_MAX: u8 = 32;
+/// Redis keeps HyperLogLogs in strings led by this header, followed by as
+/// many registers as ours, hashed and packed the same way.
+const HLL_MAGIC: &[u8] = b"HYLL";
+const HLL_ENCODING_DENSE: u8 = 0;
+const HLL_ENCODING_SPARSE: u8 = 1;
+const HLL_HEADER_SIZE: usize = 16;
+const HLL_ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+const HLL_HASH_SEED: u64 = 0xadc8_3b19;
+
+/// MurmurHash64A, the 64 bit hash the registers are computed from.
+fn murmurhash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+    let chunks = key.chunks_exact(8);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    if !tail.is_empty() {
+        for (i, b) in tail.iter().enumerate() {
+            h ^= (*b as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// The register `element` falls into, and the length of the run of zeros
+/// in its hash plus one.
+fn hll_pattern(element: &[u8]) -> (usize, u8) {
+    let hash = murmurhash64a(element, HLL_HASH_SEED);
+    let index = (hash & (HLL_REGISTERS as u64 - 1)) as usize;
+    // the sentinel bit bounds the count to `HLL_Q + 1`.
+    let rest = (hash >> HLL_P) | (1 << HLL_Q);
+    (index, rest.trailing_zeros() as u8 + 1)
+}
+
+fn dense_get(regs: &[u8], index: usize) -> u8 {
+    let (byte, fb) = (index * HLL_BITS / 8, (index * HLL_BITS) & 7);
+    let v = (regs[byte] as u16) | ((regs[byte + 1] as u16) << 8);
+    ((v >> fb) as u8) & HLL_REGISTER_MAX
+}
+
+fn dense_set(regs: &mut [u8], index: usize, value: u8) {
+    let (byte, fb) = (index * HLL_BITS / 8, (index * HLL_BITS) & 7);
+    let mut v = (regs[byte] as u16) | ((regs[byte + 1] as u16) << 8);
+    v &= !((HLL_REGISTER_MAX as u16) << fb);
+    v |= (value as u16) << fb;
+    regs[byte] = v as u8;
+    regs[byte + 1] = (v >> 8) as u8;
+}
+
+fn hll_sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let (mut y, mut z) = (1.0, x);
+    loop {
+        x *= x;
+        let z_prev = z;
+        z += x * y;
+        y += y;
+        if z_prev == z {
+            return z;
+        }
+    }
+}
+
+fn hll_tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let (mut y, mut z) = (1.0, 1.0 - x);
+    loop {
+        x = x.sqrt();
+        let z_prev = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z_prev == z {
+            return z / 3.0;
+        }
+    }
+}
+
+/// Estimates the cardinality from a histogram of the register values,
+/// following Ertl's improved estimator.
+fn estimate(histogram: &[usize; HLL_Q as usize + 2]) -> u64 {
+    let m = HLL_REGISTERS as f64;
+    let q = HLL_Q as usize;
+    let mut z = m * hll_tau((m - histogram[q + 1] as f64) / m);
+    for j in (1..=q).rev() {
+        z += histogram[j] as f64;
+        z *= 0.5;
+    }
+    z += m * hll_sigma(histogram[0] as f64 / m);
+    (HLL_ALPHA_INF * m * m / z).round() as u64
+}
+
+/// To clients a HyperLogLog is a string, it is kept apart here to be
+/// updated in place.
+#[derive(Debug, Clone)]
+pub enum HyperLogLog {
+    /// The non-zero registers, sorted by index.
+    Sparse(Vec<(u16, u8)>),
+    /// Every register, packed into `HLL_BITS` bits.
+    Dense(Vec<u8>),
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        HyperLogLog::Sparse(Vec::new())
+    }
+
+    /// Picks the encoding by how many registers are set.
+    pub fn from_registers(registers: &[u8]) -> Self {
+        let mut hll = HyperLogLog::new();
+        for (index, value) in registers.iter().enumerate() {
+            if *value > 0 {
+                hll.set_max(index, *value);
+            }
+        }
+        hll
+    }
+
+    /// One byte per register.
+    pub fn registers(&self) -> Vec<u8> {
+        match self {
+            HyperLogLog::Sparse(regs) => {
+                let mut res = vec![0; HLL_REGISTERS];
+                for (index, value) in regs {
+                    res[*index as usize] = *value;
+                }
+                res
+            }
+            HyperLogLog::Dense(regs) => (0..HLL_REGISTERS).map(|i| dense_get(regs, i)).collect(),
+        }
+    }
+
+    /// Reads a string holding a Redis HyperLogLog, `None` if it holds
+    /// anything else.
+    pub fn from_string(s: &[u8]) -> Option<Self> {
+        if s.len() < HLL_HEADER_SIZE || !s.starts_with(HLL_MAGIC) {
+            return None;
+        }
+        let body = &s[HLL_HEADER_SIZE..];
+        match s[4] {
+            // the string has no slack byte.
+            HLL_ENCODING_DENSE if body.len() == HLL_DENSE_SIZE - 1 => {
+                let mut regs = body.to_vec();
+                regs.push(0);
+                Some(HyperLogLog::Dense(regs))
+            }
+            HLL_ENCODING_SPARSE => {
+                let mut registers = Vec::with_capacity(HLL_REGISTERS);
+                let mut ops = body.iter();
+                while let Some(op) = ops.next() {
+                    let (value, run) = match op >> 6 {
+                        0 => (0, (op & 0x3f) as usize + 1),
+                        1 => (
+                            0,
+                            ((((op & 0x3f) as usize) << 8) | *ops.next()? as usize) + 1,
+                        ),
+                        _ => (((op >> 2) & 0x1f) + 1, (op & 0x03) as usize + 1),
+                    };
+                    if registers.len() + run > HLL_REGISTERS {
+                        return None;
+                    }
+                    registers.resize(registers.len() + run, value);
+                }
+                Some(HyperLogLog::from_registers(&registers))
+                    .filter(|_| registers.len() == HLL_REGISTERS)
+            }
+            _ => None,
+        }
+    }
+
+    /// The string Redis keeps it as, densely encoded, which is what the
+    /// string commands read.
+    pub fn dense_string(&self) -> Vec<u8> {
+        let mut s = vec![0; HLL_HEADER_SIZE + HLL_DENSE_SIZE];
+        s[..4].copy_from_slice(HLL_MAGIC);
+        s[4] = HLL_ENCODING_DENSE;
+        // the cached cardinality is marked stale, Redis counts on first use.
+        s[15] = 1 << 7;
+        match self {
+            HyperLogLog::Sparse(regs) => {
+                for (index, value) in regs {
+                    dense_set(&mut s[HLL_HEADER_SIZE..], *index as usize, *value);
+                }
+            }
+            HyperLogLog::Dense(regs) => s[HLL_HEADER_SIZE..].copy_from_slice(regs),
+        }
+        s.pop();
+        s
+    }
+
+    fn promote(&mut self) {
+        if let HyperLogLog::Sparse(sparse) = self {
+            let mut regs = vec![0; HLL_DENSE_SIZE];
+            for (index, value) in sparse.iter() {
+                dense_set(&mut regs, *index as usize, *value);
+            }
+            *self = HyperLogLog::Dense(regs);
+        }
+    }
+
+    /// Raises register `index` to `value`, returns whether it changed.
+    pub fn set_max(&mut self, index: usize, value: u8) -> bool {
+        match self {
+            HyperLogLog::Sparse(regs) => {
+                match regs.binary_search_by_key(&(index as u16), |e| e.0) {
+                    Ok(pos) if regs[pos].1 >= value => return false,
+                    Ok(pos) if value <= HLL_SPARSE_VAL_MAX => {
+                        regs[pos].1 = value;
+                        return true;
+                    }
+                    Err(pos)
+                        if value <= HLL_SPARSE_VAL_MAX && regs.len() < HLL_SPARSE_MAX_ENTRIES =>
+                    {
+                        regs.insert(pos, (index as u16, value));
+                        return true;
+                    }
+                    _ => (),
+                }
+                self.promote();
+                self.set_max(index, value)
+            }
+            HyperLogLog::Dense(regs) => {
+                if dense_get(regs, index) >= value {
+                    return false;
+                }
+                dense_set(regs, index, value);
+                true
+            }
+        }
+    }
+
+    /// Returns whether any register changed.
+    pub fn add(&mut self, element: &[u8]) -> bool {
+        let (index, count) = hll_pattern(element);
+        self.set_max(index, count)
+    }
+
+    /// Payload size in bytes.
+    pub fn bytes_len(&self) -> usize {
+        match self {
+            HyperLogLog::Sparse(regs) => regs.len() * 3,
+            HyperLogLog::Dense(regs) => regs.len(),
+        }
+    }
+
+    pub fn dump(&self, buf: &mut BytesMut) {
+        match self {
+            HyperLogLog::Sparse(regs) => {
+                buf.put_u8(0);
+                buf.put_u64_le(regs.len() as u64);
+                for (index, value) in regs {
+                    buf.put_u16_le(*index);
+                    buf.put_u8(*value);
+                }
+            }
+            HyperLogLog::Dense(regs) => {
+                buf.put_u8(1);
+                put_bytes(buf, regs);
+            }
+        }
+    }
+
+    pub fn restore(buf: &mut Bytes) -> Result<HyperLogLog> {
+        match take_u8(buf)? {
+            0 => {
+                let len = take_len(buf)?;
+                let mut regs = Vec::with_capacity(len);
+                for _ in 0..len {
+                    if buf.remaining() < 3 {
+                        return Err(corrupt());
+                    }
+                    let index = buf.get_u16_le();
+                    let value = buf.get_u8();
+                    if index as usize >= HLL_REGISTERS
+                        || value > HLL_REGISTER_MAX
+                        || matches!(regs.last(), Some((last, _)) if *last >= index)
+                    {
+                        return Err(corrupt());
+                    }
+                    regs.push((index, value));
+                }
+                Ok(HyperLogLog::Sparse(regs))
+            }
+            1 => {
+                let regs = take_bytes(buf)?;
+                if regs.len() != HLL_DENSE_SIZE {
+                    return Err(corrupt());
+                }
+                Ok(HyperLogLog::Dense(regs.to_vec()))
+            }
+            _ => Err(corrupt()),
+        }
+    }
+}
+
+fn count_registers(registers: &[u8]) -> u64 {
+    let mut histogram = [0; HLL_Q as usize + 2];
+    for value in registers {
+        histogram[*value as usize] += 1;
+    }
+    estimate(&histogram)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum HyperLogLogVariant {
+    PfAdd,
+    PfCount,
+    PfMerge,
+}
+
+#[derive(Debug, Clone)]
+pub struct PfAdd {
+    key: Bytes,
+    elements: Vec<Bytes>,
+}
+
+impl PfAdd {
+    pub fn new(parser: &mut CommandParser) -> Result<PfAdd> {
+        let key = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let mut elements = Vec::with_capacity(parser.len());
+        while let Some(v) = parser.next_bytes()? {
+            elements.push(v);
+        }
+        Ok(Self { key, elements })
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        let created = match db.get_hll(&self.key) {
+            Ok(Some(_)) => false,
+            Ok(None) => {
+                db.counter += 1;
+                let nounce = db.counter;
+                db.insert(
+                    self.key.clone(),
+                    Entry::new(Value::HyperLogLog(HyperLogLog::new()), None, nounce),
+                );
+                true
+            }
+            Err(e) => return e,
+        };
+        let hll = db.get_hll(&self.key).unwrap().unwrap();
+        let mut changed = created;
+        for e in self.elements.iter() {
+            changed |= hll.add(e);
+        }
+        if changed {
+            db.after_write(&self.key, false);
+        }
+        Frame::Integers(changed as i64)
+    }
+}
+
+impl OneshotExecDB for PfAdd {
+    fn get_key(&self) -> &[u8] {
+        self.key.as_ref()
+    }
+
+    fn is_write(&self) -> bool {
+        true
+    }
+}
+
+impl DB {
+    /// `Ok(None)` if there is no such key, `Err` carries the error reply.
+    pub fn get_hll(&mut self, key: &Bytes) -> std::result::Result<Option<&mut HyperLogLog>, Frame> {
+        let data = match self.get_live(key) {
+            None => return Ok(None),
+            Some(en) => &mut en.data,
+        };
+        // a string SET to what GET read of a HyperLogLog is one again.
+        let decoded = match data {
+            Value::Str(Frame::BulkStrings(s)) => HyperLogLog::from_string(s),
+            Value::MutStr(s) => HyperLogLog::from_string(s),
+            _ => None,
+        };
+        if let Some(h) = decoded {
+            *data = Value::HyperLogLog(h);
+        }
+        match data {
+            Value::HyperLogLog(h) => Ok(Some(h)),
+            _ => Err(wrong_type()),
+        }
+    }
+}
+
+/// Reads the registers of every given key living in one database, one byte
+/// per register. A missing key reads as `Frame::NullString`.
+#[derive(Debug, Clone)]
+pub struct PfGather {
+    keys: Vec<MiniCommand>,
+}
+
+impl PfGather {
+    pub fn new(keys: Vec<MiniCommand>) -> PfGather {
+        Self { keys }
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        Frame::Arrays(
+            self.keys
+                .iter()
+                .map(|cmd| match db.get_hll(cmd.ref_single()) {
+                    Ok(Some(hll)) => Frame::BulkStrings(Bytes::from(hll.registers())),
+                    Ok(None) => Frame::NullString,
+                    Err(e) => e,
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Overwrites `key` with the merged registers.
+#[derive(Debug, Clone)]
+pub struct PfStore {
+    key: Bytes,
+    registers: Bytes,
+}
+
+impl PfStore {
+    pub fn new(key: Bytes, registers: Bytes) -> PfStore {
+        Self { key, registers }
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        db.remove(&self.key);
+        db.counter += 1;
+        let nounce = db.counter;
+        db.insert(
+            self.key.clone(),
+            Entry::new(
+                Value::HyperLogLog(HyperLogLog::from_registers(&self.registers)),
+                None,
+                nounce,
+            ),
+        );
+        db.propagate_key(&self.key);
+        Frame::Ok
+    }
+}
+
+/// Takes the largest value of every register, errors are passed on.
+fn merge(frames: Vec<Frame>) -> std::result::Result<Vec<u8>, Frame> {
+    let mut res = vec![0; HLL_REGISTERS];
+    for f in frames {
+        match f {
+            Frame::BulkStrings(regs) => {
+                for (r, v) in res.iter_mut().zip(regs.iter()) {
+                    *r = (*r).max(*v);
+                }
+            }
+            Frame::NullString => (),
+            e => return Err(e),
+        }
+    }
+    Ok(res)
+}
+
+/// Gathers the registers of the given keys from their databases and merges
+/// them. PFCOUNT answers with the estimate of the union, PFMERGE stores it
+/// on the database of `dest`, which is a source as well.
+#[derive(Debug, Clone)]
+pub struct HyperLogLogDispatcher {
+    dest: Option<Bytes>,
+    dest_db: usize,
+    db_amount: usize,
+    cmds: Vec<MiniCommand>,
+    cmds_tbl: Vec<Vec<MiniCommand>>,
+    order_tbl: Vec<Vec<usize>>,
+    len: usize,
+}
+
+impl HyperLogLogDispatcher {
+    pub fn new(
+        parser: &mut CommandParser,
+        variant: HyperLogLogVariant,
+    ) -> Result<HyperLogLogDispatcher> {
+        let dest = match variant {
+            HyperLogLogVariant::PfMerge => Some(parser.next_bytes()?.ok_or_else(missing_operand)?),
+            _ => None,
+        };
+        let mut cmds: Vec<MiniCommand> = Vec::with_capacity(parser.len() + 1);
+        if let Some(dest) = &dest {
+            cmds.push(dest.clone().into());
+        }
+        while let Some(key) = parser.next_bytes()? {
+            cmds.push(key.into());
+        }
+        if cmds.is_empty() {
+            return Err(missing_operand());
+        }
+        Ok(Self {
+            dest,
+            dest_db: 0,
+            db_amount: 0,
+            len: cmds.len(),
+            cmds,
+            cmds_tbl: Vec::new(),
+            order_tbl: Vec::new(),
+        })
+    }
+}
+
+use crate::default_pop;
+impl DispatchToMultipleDB for HyperLogLogDispatcher {
+    impl_traverse_command!(@Consts, PfGather, default_pop);
+
+    fn get_result_collector(&mut self) -> ResultCollector {
+        let store = self.dest.is_some();
+        let ret = vec![Frame::NullString; self.len];
+        ResultCollector {
+            result_type: ResultCollectorType::Combine(
+                std::mem::take(&mut self.order_tbl),
+                Box::new(move |frames| match merge(frames) {
+                    Ok(regs) if store => Frame::BulkStrings(Bytes::from(regs)),
+                    Ok(regs) => Frame::Integers(count_registers(&regs) as i64),
+                    Err(e) => e,
+                }),
+            ),
+            ret,
+        }
+    }
+
+    fn dispatch(&mut self, db_amount: usize, dispatch_fn: impl Fn(&[u8]) -> usize) {
+        if let Some(dest) = &self.dest {
+            self.dest_db = dispatch_fn(dest);
+        }
+        self.dispatch_sources(db_amount, dispatch_fn);
+    }
+
+    fn complete(&mut self, merged: Frame) -> Completion {
+        match (self.dest.take(), merged) {
+            (Some(dest), Frame::BulkStrings(regs)) => {
+                Completion::Forward((self.dest_db, PfStore::new(dest, regs).into()))
+            }
+            (_, f) => Completion::Reply(f),
+        }
+    }
+}
+
+impl HyperLogLogDispatcher {
+    impl_traverse_command!(@Dispatch, N:N, dispatch_sources);
+}
+
+impl AtomicCMDMarker for PfAdd {}
+impl AtomicCMDMarker for PfGather {}
+impl AtomicCMDMarker for PfStore {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dense_strings_read_back() {
+        let mut registers = vec![0; HLL_REGISTERS];
+        registers[0] = 3;
+        registers[1] = HLL_REGISTER_MAX;
+        registers[HLL_REGISTERS - 1] = 17;
+        // sparse, then dense once a register is too large for it.
+        let sparse = HyperLogLog::from_registers(&registers[..HLL_REGISTERS - 1]);
+        let dense = HyperLogLog::from_registers(&registers);
+        assert!(matches!(dense, HyperLogLog::Dense(_)));
+        for h in [sparse, dense] {
+            let s = h.dense_string();
+            assert_eq!(s.len(), HLL_HEADER_SIZE + HLL_DENSE_SIZE - 1);
+            let read = HyperLogLog::from_string(&s).unwrap();
+            assert_eq!(read.registers(), h.registers());
+        }
+    }
+
+    #[test]
+    fn sparse_strings_read_back() {
+        let mut s = b"HYLL".to_vec();
+        s.extend_from_slice(&[HLL_ENCODING_SPARSE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        // XZERO of 16383 registers, then VAL 5 for one.
+        s.extend_from_slice(&[0x40 | 0x3f, 0xfe, 0x80 | (4 << 2)]);
+        let registers = HyperLogLog::from_string(&s).unwrap().registers();
+        assert_eq!(registers[HLL_REGISTERS - 1], 5);
+        assert_eq!(registers.iter().filter(|r| **r > 0).count(), 1);
+        assert!(HyperLogLog::from_string(&s[..s.len() - 1]).is_none());
+    }
+}
@@ -1,11 +1,27 @@
-use crate::{cmd::*, utils::get_integer};
+use crate::{
+    cmd::*,
+    db::{wrong_type, Entry, Value},
+    utils::{get_float, get_integer},
+};
 use anyhow::Result;
-use tokio::time::Instant;
+use std::convert::TryFrom;
+
+const NOT_INTEGER_ERR: &[u8] = b"ERR value is not an integer or out of range";
+const NOT_FLOAT_ERR: &[u8] = b"ERR value is not a valid float";
+const OVERFLOW_ERR: &[u8] = b"ERR increment or decrement would overflow";
+const NAN_OR_INF_ERR: &[u8] = b"ERR increment would produce NaN or Infinity";
+
+#[derive(Debug, Clone)]
+enum Amount {
+    Int(i64),
+    /// The parsed increment along with its text, which adds up exactly.
+    Float(f64, Bytes),
+}
 
 #[derive(Debug, Clone)]
 pub struct Incr {
     key: Bytes,
-    by: i64,
+    by: Amount,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -14,6 +30,7 @@ pub enum IncrVariant {
     IncrBy,
     Decr,
     DecrBy,
+    IncrByFloat,
 }
 
 impl Incr {
@@ -23,7 +40,10 @@ impl Incr {
                 if parser.len() > 1 {
                     return Err(invalid_operation());
                 }
-                (parser.next_bytes()?.ok_or_else(missing_operand)?, 1)
+                (
+                    parser.next_bytes()?.ok_or_else(missing_operand)?,
+                    Amount::Int(1),
+                )
             }
             IncrVariant::IncrBy => {
                 if parser.len() > 2 {
@@ -31,28 +51,50 @@ impl Incr {
                 }
                 let key = parser.next_bytes()?.ok_or_else(missing_operand)?;
                 let by = parser.next_integer()?.ok_or_else(missing_operand)?;
-                (key, by)
+                (key, Amount::Int(by))
             }
             IncrVariant::Decr => {
                 if parser.len() > 1 {
                     return Err(invalid_operation());
                 }
-                (parser.next_bytes()?.ok_or_else(missing_operand)?, -1)
+                (
+                    parser.next_bytes()?.ok_or_else(missing_operand)?,
+                    Amount::Int(-1),
+                )
             }
             IncrVariant::DecrBy => {
                 if parser.len() > 2 {
                     return Err(invalid_operation());
                 }
                 let key = parser.next_bytes()?.ok_or_else(missing_operand)?;
-                let by = -parser.next_integer()?.ok_or_else(missing_operand)?;
-                (key, by)
+                let by = parser
+                    .next_integer()?
+                    .ok_or_else(missing_operand)?
+                    .checked_neg()
+                    .ok_or_else(invalid_operand)?;
+                (key, Amount::Int(by))
+            }
+            IncrVariant::IncrByFloat => {
+                if parser.len() > 2 {
+                    return Err(invalid_operation());
+                }
+                let key = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let raw = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let by = get_float(&raw)
+                    .ok()
+                    .filter(|v| v.is_finite())
+                    .ok_or_else(invalid_operand)?;
+                (key, Amount::Float(by, raw))
             }
         };
         Ok(Self { key, by })
     }
 
     pub fn exec(self, db: &mut DB) -> Frame {
-        db.incr(&self.key, self.by)
+        match self.by {
+            Amount::Int(by) => db.incr(&self.key, by),
+            Amount::Float(by, raw) => db.incr_by_float(&self.key, by, &raw),
+        }
     }
 }
 
@@ -60,35 +102,234 @@ impl OneshotExecDB for Incr {
     fn get_key(&self) -> &[u8] {
         &self.key.as_ref()
     }
+
+    fn is_write(&self) -> bool {
+        true
+    }
 }
 
 impl DB {
+    /// A missing key counts as 0.
     fn incr(&mut self, key: &Bytes, by: i64) -> Frame {
-        self.database
-            .get_mut(key)
-            .filter(|v| v.expiration.is_none() || v.expiration.unwrap() > Instant::now())
-            .map_or_else(
-                || Frame::NullString,
-                |en| match &mut en.data {
-                    Frame::BulkStrings(b) => match get_integer(b) {
-                        Ok(v) => {
-                            en.data = Frame::Integers(v + by);
-                            return Frame::Integers(v + by);
-                        }
-                        Err(_) => {
-                            return Frame::NullString;
-                        }
-                    },
-                    Frame::Integers(i) => {
-                        *i += by;
-                        return Frame::Integers(*i);
+        let current = match self.get_live(key).map(|en| &en.data) {
+            None => Ok(0),
+            Some(Value::Str(Frame::Integers(i))) => Ok(*i),
+            Some(Value::Str(Frame::BulkStrings(b))) => get_integer(b),
+            Some(Value::MutStr(b)) => get_integer(&Bytes::copy_from_slice(b)),
+            Some(_) => return wrong_type(),
+        };
+        let res = match current.map(|v| v.checked_add(by)) {
+            Ok(Some(v)) => v,
+            Ok(None) => return Frame::Errors(Bytes::from_static(OVERFLOW_ERR)),
+            Err(_) => return Frame::Errors(Bytes::from_static(NOT_INTEGER_ERR)),
+        };
+        self.put_counter(key, Frame::Integers(res));
+        Frame::Integers(res)
+    }
+
+    fn incr_by_float(&mut self, key: &Bytes, by: f64, raw: &[u8]) -> Frame {
+        let current = match self.get_live(key).map(|en| &en.data) {
+            None => Bytes::from_static(b"0"),
+            Some(Value::Str(Frame::Integers(i))) => Bytes::from(i.to_string()),
+            Some(Value::Str(Frame::BulkStrings(b))) => b.clone(),
+            Some(Value::MutStr(b)) => Bytes::copy_from_slice(b),
+            Some(_) => return wrong_type(),
+        };
+        let res = match get_float(&current) {
+            Ok(v) => v + by,
+            Err(_) => return Frame::Errors(Bytes::from_static(NOT_FLOAT_ERR)),
+        };
+        if !res.is_finite() {
+            return Frame::Errors(Bytes::from_static(NAN_OR_INF_ERR));
+        }
+        let res = Decimal::parse(&current)
+            .zip(Decimal::parse(raw))
+            .and_then(|(a, b)| a.checked_add(b))
+            .unwrap_or_else(|| Decimal::from_f64(res))
+            .to_bytes();
+        self.put_counter(key, Frame::BulkStrings(res.clone()));
+        Frame::BulkStrings(res)
+    }
+
+    /// Overwrites the value of a live `key` but keeps its deadline, a
+    /// missing key is created without one.
+    fn put_counter(&mut self, key: &Bytes, value: Frame) {
+        match self.get_live(key) {
+            Some(en) => {
+                en.data = value.into();
+                self.renew_nounce(key);
+            }
+            None => {
+                self.counter += 1;
+                let nounce = self.counter;
+                self.insert(key.clone(), Entry::new(value.into(), None, nounce));
+            }
+        }
+    }
+}
+
+impl AtomicCMDMarker for Incr {}
+
+/// `mantissa * 10^exp`, so that INCRBYFLOAT adds the decimal texts exactly
+/// and `0.1` on "0.2" gives "0.3", as the long doubles of Redis do.
+#[derive(Debug, Clone, Copy)]
+struct Decimal {
+    mantissa: i128,
+    exp: i32,
+}
+
+impl Decimal {
+    /// Reads the text of a finite float, `None` if it has more digits than
+    /// fit the mantissa.
+    fn parse(raw: &[u8]) -> Option<Decimal> {
+        let raw = std::str::from_utf8(raw).ok()?;
+        let (digits, mut exp) = match raw.find(['e', 'E']) {
+            Some(i) => (&raw[..i], raw[i + 1..].parse::<i32>().ok()?),
+            None => (raw, 0),
+        };
+        let (negative, digits) = match digits.as_bytes().first() {
+            Some(b'-') => (true, &digits[1..]),
+            Some(b'+') => (false, &digits[1..]),
+            _ => (false, digits),
+        };
+        let (mut mantissa, mut any_digit, mut after_dot) = (0i128, false, false);
+        for c in digits.bytes() {
+            match c {
+                b'0'..=b'9' => {
+                    mantissa = mantissa.checked_mul(10)?.checked_add((c - b'0') as i128)?;
+                    if after_dot {
+                        exp = exp.checked_sub(1)?;
+                    }
+                    any_digit = true;
+                }
+                b'.' if !after_dot => after_dot = true,
+                _ => return None,
+            }
+        }
+        if !any_digit {
+            return None;
+        }
+        let mantissa = if negative { -mantissa } else { mantissa };
+        Some(Decimal { mantissa, exp })
+    }
+
+    /// The 17 significant digits of a finite `f64`.
+    fn from_f64(v: f64) -> Decimal {
+        Decimal::parse(format!("{:.16e}", v).as_bytes()).expect("a finite float")
+    }
+
+    fn checked_add(self, other: Decimal) -> Option<Decimal> {
+        // A term this many digits below the other cannot reach its 17
+        // significant ones.
+        const NEGLIGIBLE: i32 = 40;
+        if self.mantissa == 0 || self.magnitude() + NEGLIGIBLE < other.magnitude() {
+            return Some(other);
+        }
+        if other.mantissa == 0 || other.magnitude() + NEGLIGIBLE < self.magnitude() {
+            return Some(self);
+        }
+        let exp = self.exp.min(other.exp);
+        let scale = |d: Decimal| {
+            let shift = u32::try_from(d.exp - exp).ok()?;
+            d.mantissa.checked_mul(10i128.checked_pow(shift)?)
+        };
+        let mantissa = scale(self)?.checked_add(scale(other)?)?;
+        Some(Decimal { mantissa, exp })
+    }
+
+    /// The power of ten just above the number.
+    fn magnitude(self) -> i32 {
+        self.exp
+            .saturating_add(self.mantissa.unsigned_abs().to_string().len() as i32)
+    }
+
+    /// Formats like `%.17g`, trailing zeros trimmed.
+    fn to_bytes(self) -> Bytes {
+        if self.mantissa == 0 {
+            return Bytes::from_static(b"0");
+        }
+        let mut digits = self.mantissa.unsigned_abs().to_string().into_bytes();
+        // The power of ten of the leading digit.
+        let mut exp = self.exp + digits.len() as i32 - 1;
+        if digits.len() > 17 {
+            let round_up = digits[17] >= b'5';
+            digits.truncate(17);
+            if round_up {
+                match digits.iter().rposition(|d| *d != b'9') {
+                    Some(i) => {
+                        digits[i] += 1;
+                        digits[i + 1..].iter_mut().for_each(|d| *d = b'0');
                     }
-                    _ => {
-                        return Frame::NullString;
+                    None => {
+                        digits = b"1".to_vec();
+                        exp += 1;
                     }
-                },
-            )
+                }
+            }
+        }
+        while digits.len() > 1 && digits.last() == Some(&b'0') {
+            digits.pop();
+        }
+        let mut out = Vec::with_capacity(digits.len() + 24);
+        if self.mantissa < 0 {
+            out.push(b'-');
+        }
+        if !(-4..17).contains(&exp) {
+            out.push(digits[0]);
+            if digits.len() > 1 {
+                out.push(b'.');
+                out.extend_from_slice(&digits[1..]);
+            }
+            let sign = if exp < 0 { '-' } else { '+' };
+            out.extend_from_slice(format!("e{}{:02}", sign, exp.abs()).as_bytes());
+        } else if exp < 0 {
+            out.extend_from_slice(b"0.");
+            out.resize(out.len() + (-exp - 1) as usize, b'0');
+            out.extend_from_slice(&digits);
+        } else {
+            let int_len = exp as usize + 1;
+            if digits.len() <= int_len {
+                out.extend_from_slice(&digits);
+                out.resize(out.len() + int_len - digits.len(), b'0');
+            } else {
+                out.extend_from_slice(&digits[..int_len]);
+                out.push(b'.');
+                out.extend_from_slice(&digits[int_len..]);
+            }
+        }
+        Bytes::from(out)
     }
 }
 
-impl AtomicCMDMarker for Incr {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast;
+
+    fn incr_by_float(current: &str, by: &str) -> String {
+        let (shutdown_tx, _) = broadcast::channel(1);
+        let mut db = DB::new(0, shutdown_tx);
+        let key = Bytes::from_static(b"k");
+        db.put_counter(&key, Frame::BulkStrings(Bytes::from(current.to_string())));
+        match db.incr_by_float(&key, by.parse().unwrap(), by.as_bytes()) {
+            Frame::BulkStrings(b) => String::from_utf8(b.to_vec()).unwrap(),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn float_increments_format_like_redis() {
+        assert_eq!(incr_by_float("0.2", "0.1"), "0.3");
+        assert_eq!(incr_by_float("10.50", "0.1"), "10.6");
+        assert_eq!(incr_by_float("5.0e3", "2.0e2"), "5200");
+        assert_eq!(incr_by_float("3", "-3.0"), "0");
+        assert_eq!(incr_by_float("-1", "0.5"), "-0.5");
+        assert_eq!(incr_by_float("1e20", "1"), "1e+20");
+        assert_eq!(incr_by_float("0", "0.00001"), "1e-05");
+        assert_eq!(
+            incr_by_float("0.99999999999999999", "0.000000000000000009"),
+            "1"
+        );
+        assert_eq!(incr_by_float("1e300", "1e-300"), "1e+300");
+    }
+}
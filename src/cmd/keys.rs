@@ -0,0 +1,189 @@
+use crate::{cmd::*, utils::glob_match};
+use anyhow::Result;
+use rand::{thread_rng, Rng};
+use tokio::time::Instant;
+
+/// Random probes into `DB::slots` before falling back to a linear walk.
+const RANDOM_KEY_TRIES: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct Keys {
+    pattern: Bytes,
+}
+
+impl Keys {
+    pub fn new(pattern: Bytes) -> Keys {
+        Self { pattern }
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        db.keys(&self.pattern)
+    }
+}
+
+impl OneshotExecDB for Keys {
+    fn get_key(&self) -> &[u8] {
+        b""
+    }
+
+    fn is_write(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RandomKey {}
+
+impl RandomKey {
+    pub fn new() -> RandomKey {
+        Self {}
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        db.random_key().into()
+    }
+}
+
+impl OneshotExecDB for RandomKey {
+    fn get_key(&self) -> &[u8] {
+        b""
+    }
+
+    fn is_write(&self) -> bool {
+        false
+    }
+}
+
+impl DB {
+    fn keys(&mut self, pattern: &Bytes) -> Frame {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        let mut res = Vec::new();
+        for (key, en) in self.database.iter() {
+            if en.is_expired(now) {
+                expired.push(key.clone());
+            } else if glob_match(pattern, key) {
+                res.push(key.clone().into());
+            }
+        }
+        for key in expired {
+            self.expire_key(&key);
+        }
+        Frame::Arrays(res)
+    }
+
+    pub fn random_key(&mut self) -> Option<Bytes> {
+        let mut rng = thread_rng();
+        while self.database.len() > 0 {
+            let len = self.slots.len();
+            let key = (0..RANDOM_KEY_TRIES)
+                .find_map(|_| self.slots.get(rng.gen_range(0..len)))
+                .or_else(|| {
+                    let start = rng.gen_range(0..len);
+                    (start..len)
+                        .chain(0..start)
+                        .find_map(|idx| self.slots.get(idx))
+                })
+                .cloned()?;
+            if !self.expire_if_needed(&key) {
+                return Some(key);
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KeysDispatcher {
+    pattern: Bytes,
+    db_amount: usize,
+}
+
+impl DispatchToMultipleDB for KeysDispatcher {
+    fn next_command(&mut self) -> Option<IDCommandPair> {
+        if self.db_amount > 0 {
+            self.db_amount -= 1;
+            Some((self.db_amount, Keys::new(self.pattern.clone()).into()))
+        } else {
+            None
+        }
+    }
+
+    fn get_result_collector(&mut self) -> ResultCollector {
+        assert!(self.db_amount > 0, "self.db_amount wasn't initialized");
+        ResultCollector {
+            result_type: ResultCollectorType::Concat(self.db_amount),
+            ret: Vec::new(),
+        }
+    }
+
+    fn dispatch(&mut self, db_amount: usize, _: impl Fn(&[u8]) -> usize) {
+        self.db_amount = db_amount;
+    }
+}
+
+impl KeysDispatcher {
+    pub fn new(parser: &mut CommandParser) -> Result<KeysDispatcher> {
+        let pattern = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(Self {
+            pattern,
+            db_amount: 0,
+        })
+    }
+}
+
+/// Asks every database for a random key, starting at a random one so that
+/// the first non-empty database found isn't always the same.
+#[derive(Debug, Clone)]
+pub struct RandomKeyDispatcher {
+    db_amount: usize,
+    remain: usize,
+    start: usize,
+}
+
+impl DispatchToMultipleDB for RandomKeyDispatcher {
+    fn next_command(&mut self) -> Option<IDCommandPair> {
+        if self.remain > 0 {
+            self.remain -= 1;
+            Some((
+                (self.start + self.remain) % self.db_amount,
+                RandomKey::new().into(),
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn get_result_collector(&mut self) -> ResultCollector {
+        assert!(self.db_amount > 0, "self.db_amount wasn't initialized");
+        ResultCollector {
+            result_type: ResultCollectorType::FirstNonNull(self.db_amount),
+            ret: Vec::with_capacity(1),
+        }
+    }
+
+    fn dispatch(&mut self, db_amount: usize, _: impl Fn(&[u8]) -> usize) {
+        self.db_amount = db_amount;
+        self.remain = db_amount;
+        self.start = thread_rng().gen_range(0..db_amount);
+    }
+}
+
+impl RandomKeyDispatcher {
+    pub fn new(parser: &mut CommandParser) -> Result<RandomKeyDispatcher> {
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(Self {
+            db_amount: 0,
+            remain: 0,
+            start: 0,
+        })
+    }
+}
+
+impl AtomicCMDMarker for Keys {}
+impl AtomicCMDMarker for RandomKey {}
@@ -0,0 +1,515 @@
+use crate::{
+    cmd::*,
+    db::{wrong_type, Entry, Value},
+    utils::normalize_range,
+};
+use anyhow::Result;
+use std::collections::VecDeque;
+use tokio::time::Instant;
+
+const BEFORE: usize = rolling_hash_const(b"before");
+const AFTER: usize = rolling_hash_const(b"after");
+const RANK: usize = rolling_hash_const(b"rank");
+const COUNT: usize = rolling_hash_const(b"count");
+const MAXLEN: usize = rolling_hash_const(b"maxlen");
+
+const NO_SUCH_KEY_ERR: &[u8] = b"ERR no such key";
+const OUT_OF_RANGE_ERR: &[u8] = b"ERR index out of range";
+
+#[derive(Debug, Clone, Copy)]
+pub enum ListVariant {
+    LPush,
+    RPush,
+    LPushX,
+    RPushX,
+    LPop,
+    RPop,
+    LRange,
+    LIndex,
+    LLen,
+    LTrim,
+    LInsert,
+    LRem,
+    LSet,
+    LPos,
+}
+
+#[derive(Debug, Clone)]
+enum ListOp {
+    Push {
+        left: bool,
+        only_existing: bool,
+        elements: Vec<Bytes>,
+    },
+    Pop {
+        left: bool,
+        count: Option<usize>,
+    },
+    Range(i64, i64),
+    Index(i64),
+    Len,
+    Trim(i64, i64),
+    Insert {
+        before: bool,
+        pivot: Bytes,
+        element: Bytes,
+    },
+    Rem {
+        count: i64,
+        element: Bytes,
+    },
+    Set {
+        index: i64,
+        element: Bytes,
+    },
+    Pos {
+        element: Bytes,
+        rank: i64,
+        count: Option<usize>,
+        maxlen: usize,
+    },
+    /// Puts back an element a move took, a list it re-creates gets back the
+    /// deadline of the one the move emptied.
+    Unpop {
+        left: bool,
+        element: Bytes,
+        deadline: Option<Instant>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct List {
+    key: Bytes,
+    op: ListOp,
+}
+
+impl List {
+    pub fn new(parser: &mut CommandParser, variant: ListVariant) -> Result<List> {
+        use ListVariant::*;
+        let key = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let op = match variant {
+            LPush | RPush | LPushX | RPushX => {
+                let mut elements = Vec::with_capacity(parser.len());
+                while let Some(v) = parser.next_bytes()? {
+                    elements.push(v);
+                }
+                if elements.is_empty() {
+                    return Err(missing_operand());
+                }
+                ListOp::Push {
+                    left: matches!(variant, LPush | LPushX),
+                    only_existing: matches!(variant, LPushX | RPushX),
+                    elements,
+                }
+            }
+            LPop | RPop => ListOp::Pop {
+                left: matches!(variant, LPop),
+                count: match parser.next_integer()? {
+                    Some(v) if v >= 0 => Some(v as usize),
+                    Some(_) => return Err(invalid_operand()),
+                    None => None,
+                },
+            },
+            LRange | LTrim => {
+                let start = parser.next_integer()?.ok_or_else(missing_operand)?;
+                let stop = parser.next_integer()?.ok_or_else(missing_operand)?;
+                if matches!(variant, LRange) {
+                    ListOp::Range(start, stop)
+                } else {
+                    ListOp::Trim(start, stop)
+                }
+            }
+            LIndex => ListOp::Index(parser.next_integer()?.ok_or_else(missing_operand)?),
+            LLen => ListOp::Len,
+            LInsert => {
+                let place = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let before = match rolling_hash(place.as_ref())? {
+                    BEFORE => true,
+                    AFTER => false,
+                    _ => return Err(invalid_operation()),
+                };
+                ListOp::Insert {
+                    before,
+                    pivot: parser.next_bytes()?.ok_or_else(missing_operand)?,
+                    element: parser.next_bytes()?.ok_or_else(missing_operand)?,
+                }
+            }
+            LRem => ListOp::Rem {
+                count: parser.next_integer()?.ok_or_else(missing_operand)?,
+                element: parser.next_bytes()?.ok_or_else(missing_operand)?,
+            },
+            LSet => ListOp::Set {
+                index: parser.next_integer()?.ok_or_else(missing_operand)?,
+                element: parser.next_bytes()?.ok_or_else(missing_operand)?,
+            },
+            LPos => {
+                let element = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let (mut rank, mut count, mut maxlen) = (1, None, 0);
+                while let Some(option) = parser.next_bytes()? {
+                    let v = parser.next_integer()?.ok_or_else(missing_operand)?;
+                    match rolling_hash(option.as_ref())? {
+                        RANK if v != 0 && v != i64::MIN => rank = v,
+                        COUNT if v >= 0 => count = Some(v as usize),
+                        MAXLEN if v >= 0 => maxlen = v as usize,
+                        RANK | COUNT | MAXLEN => return Err(invalid_operand()),
+                        _ => return Err(invalid_operation()),
+                    }
+                }
+                ListOp::Pos {
+                    element,
+                    rank,
+                    count,
+                    maxlen,
+                }
+            }
+        };
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(Self { key, op })
+    }
+
+    /// LPUSH or RPUSH of `elements`, for moves between databases.
+    pub fn push(key: Bytes, left: bool, elements: Vec<Bytes>) -> List {
+        Self {
+            key,
+            op: ListOp::Push {
+                left,
+                only_existing: false,
+                elements,
+            },
+        }
+    }
+
+    /// Undoes a pop of `element` for a move between databases.
+    pub fn unpop(key: Bytes, left: bool, element: Bytes, deadline: Option<Instant>) -> List {
+        Self {
+            key,
+            op: ListOp::Unpop {
+                left,
+                element,
+                deadline,
+            },
+        }
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        let key = &self.key;
+        match self.op {
+            ListOp::Push {
+                left,
+                only_existing,
+                elements,
+            } => db.list_push(key, left, only_existing, elements),
+            ListOp::Pop { left, count } => db.list_pop(key, left, count),
+            ListOp::Range(start, stop) => db.list_range(key, start, stop),
+            ListOp::Index(index) => db.list_index(key, index),
+            ListOp::Len => match db.get_list(key) {
+                Ok(l) => Frame::Integers(l.map_or(0, |l| l.len() as i64)),
+                Err(e) => e,
+            },
+            ListOp::Trim(start, stop) => db.list_trim(key, start, stop),
+            ListOp::Insert {
+                before,
+                pivot,
+                element,
+            } => db.list_insert(key, before, &pivot, element),
+            ListOp::Rem { count, element } => db.list_rem(key, count, &element),
+            ListOp::Set { index, element } => db.list_set(key, index, element),
+            ListOp::Pos {
+                element,
+                rank,
+                count,
+                maxlen,
+            } => db.list_pos(key, &element, rank, count, maxlen),
+            ListOp::Unpop {
+                left,
+                element,
+                deadline,
+            } => {
+                let created = matches!(db.get_list(key), Ok(None));
+                let ret = db.list_push(key, left, false, vec![element]);
+                if created && deadline.is_some() {
+                    db.set_expiration(key, deadline);
+                }
+                ret
+            }
+        }
+    }
+}
+
+impl OneshotExecDB for List {
+    fn get_key(&self) -> &[u8] {
+        self.key.as_ref()
+    }
+
+    fn is_write(&self) -> bool {
+        !matches!(
+            self.op,
+            ListOp::Range(..) | ListOp::Index(_) | ListOp::Len | ListOp::Pos { .. }
+        )
+    }
+
+    /// An undone pop may bring back the deadline of the list, the list is
+    /// logged as it is now.
+    fn propagate(&self, frame: Frame, _ret: &Frame, db: &mut DB) {
+        match self.op {
+            ListOp::Unpop { .. } => db.propagate_key(&self.key),
+            _ => db.propagate(frame),
+        }
+    }
+}
+
+/// Resolves a possibly negative index into a list of `len` elements.
+fn list_offset(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    if index < 0 || index >= len as i64 {
+        None
+    } else {
+        Some(index as usize)
+    }
+}
+
+impl DB {
+    /// `Ok(None)` if there is no such key, `Err` carries the error reply.
+    pub fn get_list(
+        &mut self,
+        key: &Bytes,
+    ) -> std::result::Result<Option<&mut VecDeque<Bytes>>, Frame> {
+        match self.get_live(key).map(|en| &mut en.data) {
+            None => Ok(None),
+            Some(Value::List(l)) => Ok(Some(l)),
+            Some(_) => Err(wrong_type()),
+        }
+    }
+
+    /// Like `get_list`, but creates the list if there is no such key.
+    pub fn get_or_create_list(
+        &mut self,
+        key: &Bytes,
+    ) -> std::result::Result<&mut VecDeque<Bytes>, Frame> {
+        if self.get_list(key)?.is_none() {
+            self.counter += 1;
+            let nounce = self.counter;
+            self.insert(
+                key.clone(),
+                Entry::new(Value::List(VecDeque::new()), None, nounce),
+            );
+        }
+        Ok(self.get_list(key)?.unwrap())
+    }
+
+    pub fn list_push(
+        &mut self,
+        key: &Bytes,
+        left: bool,
+        only_existing: bool,
+        elements: Vec<Bytes>,
+    ) -> Frame {
+        let list = if only_existing {
+            match self.get_list(key) {
+                Ok(Some(l)) => l,
+                Ok(None) => return Frame::Integers(0),
+                Err(e) => return e,
+            }
+        } else {
+            match self.get_or_create_list(key) {
+                Ok(l) => l,
+                Err(e) => return e,
+            }
+        };
+        for v in elements {
+            if left {
+                list.push_front(v);
+            } else {
+                list.push_back(v);
+            }
+        }
+        let len = list.len();
+        self.after_write(key, false);
+        self.signal_ready(key);
+        Frame::Integers(len as i64)
+    }
+
+    fn list_pop(&mut self, key: &Bytes, left: bool, count: Option<usize>) -> Frame {
+        let list = match self.get_list(key) {
+            Ok(Some(l)) => l,
+            Ok(None) => {
+                return match count {
+                    Some(_) => Frame::NullArray,
+                    None => Frame::NullString,
+                }
+            }
+            Err(e) => return e,
+        };
+        let n = count.unwrap_or(1).min(list.len());
+        let mut popped = Vec::with_capacity(n);
+        for _ in 0..n {
+            let v = if left {
+                list.pop_front()
+            } else {
+                list.pop_back()
+            };
+            popped.push(Frame::BulkStrings(v.unwrap()));
+        }
+        let emptied = list.is_empty();
+        if n > 0 {
+            self.after_write(key, emptied);
+        }
+        match count {
+            Some(_) => Frame::Arrays(popped),
+            None => popped.pop().unwrap_or(Frame::NullString),
+        }
+    }
+
+    fn list_range(&mut self, key: &Bytes, start: i64, stop: i64) -> Frame {
+        match self.get_list(key) {
+            Ok(Some(l)) => Frame::Arrays(
+                normalize_range(start, stop, l.len()).map_or_else(Vec::new, |(s, e)| {
+                    l.range(s..=e).map(|v| v.clone().into()).collect()
+                }),
+            ),
+            Ok(None) => Frame::Arrays(Vec::new()),
+            Err(e) => e,
+        }
+    }
+
+    fn list_index(&mut self, key: &Bytes, index: i64) -> Frame {
+        match self.get_list(key) {
+            Ok(Some(l)) => {
+                list_offset(index, l.len()).map_or(Frame::NullString, |idx| l[idx].clone().into())
+            }
+            Ok(None) => Frame::NullString,
+            Err(e) => e,
+        }
+    }
+
+    fn list_trim(&mut self, key: &Bytes, start: i64, stop: i64) -> Frame {
+        let list = match self.get_list(key) {
+            Ok(Some(l)) => l,
+            Ok(None) => return Frame::Ok,
+            Err(e) => return e,
+        };
+        match normalize_range(start, stop, list.len()) {
+            Some((s, e)) => {
+                list.truncate(e + 1);
+                list.drain(..s);
+            }
+            None => list.clear(),
+        }
+        let emptied = list.is_empty();
+        self.after_write(key, emptied);
+        Frame::Ok
+    }
+
+    fn list_insert(&mut self, key: &Bytes, before: bool, pivot: &Bytes, element: Bytes) -> Frame {
+        let list = match self.get_list(key) {
+            Ok(Some(l)) => l,
+            Ok(None) => return Frame::Integers(0),
+            Err(e) => return e,
+        };
+        let idx = match list.iter().position(|v| v == pivot) {
+            Some(idx) => idx,
+            None => return Frame::Integers(-1),
+        };
+        list.insert(if before { idx } else { idx + 1 }, element);
+        let len = list.len();
+        self.after_write(key, false);
+        self.signal_ready(key);
+        Frame::Integers(len as i64)
+    }
+
+    fn list_rem(&mut self, key: &Bytes, count: i64, element: &Bytes) -> Frame {
+        let list = match self.get_list(key) {
+            Ok(Some(l)) => l,
+            Ok(None) => return Frame::Integers(0),
+            Err(e) => return e,
+        };
+        let total = list.iter().filter(|v| *v == element).count();
+        let to_remove = if count == 0 {
+            total
+        } else {
+            total.min(count.unsigned_abs() as usize)
+        };
+        // occurrences are numbered from 1, those in `(skip, skip + to_remove]` go.
+        let skip = if count < 0 { total - to_remove } else { 0 };
+        let mut seen = 0;
+        list.retain(|v| {
+            if v != element {
+                return true;
+            }
+            seen += 1;
+            seen <= skip || seen > skip + to_remove
+        });
+        let emptied = list.is_empty();
+        if to_remove > 0 {
+            self.after_write(key, emptied);
+        }
+        Frame::Integers(to_remove as i64)
+    }
+
+    fn list_set(&mut self, key: &Bytes, index: i64, element: Bytes) -> Frame {
+        let list = match self.get_list(key) {
+            Ok(Some(l)) => l,
+            Ok(None) => return Frame::Errors(Bytes::from_static(NO_SUCH_KEY_ERR)),
+            Err(e) => return e,
+        };
+        match list_offset(index, list.len()) {
+            Some(idx) => {
+                list[idx] = element;
+                self.after_write(key, false);
+                Frame::Ok
+            }
+            None => Frame::Errors(Bytes::from_static(OUT_OF_RANGE_ERR)),
+        }
+    }
+
+    fn list_pos(
+        &mut self,
+        key: &Bytes,
+        element: &Bytes,
+        rank: i64,
+        count: Option<usize>,
+        maxlen: usize,
+    ) -> Frame {
+        let list = match self.get_list(key) {
+            Ok(Some(l)) => l,
+            Ok(None) => {
+                return match count {
+                    Some(_) => Frame::Arrays(Vec::new()),
+                    None => Frame::NullString,
+                }
+            }
+            Err(e) => return e,
+        };
+        let len = list.len();
+        let limit = if maxlen == 0 { len } else { maxlen.min(len) };
+        let wanted = match count {
+            Some(0) => usize::MAX,
+            Some(n) => n,
+            None => 1,
+        };
+        let mut skip = rank.unsigned_abs() as usize - 1;
+        let mut found = Vec::new();
+        for step in 0..limit {
+            let idx = if rank > 0 { step } else { len - 1 - step };
+            if list[idx] != element {
+                continue;
+            }
+            if skip > 0 {
+                skip -= 1;
+                continue;
+            }
+            found.push(Frame::Integers(idx as i64));
+            if found.len() == wanted {
+                break;
+            }
+        }
+        match count {
+            Some(_) => Frame::Arrays(found),
+            None => found.pop().unwrap_or(Frame::NullString),
+        }
+    }
+}
+
+impl AtomicCMDMarker for List {}
@@ -26,6 +26,10 @@ impl OneshotExecDB for MGet {
     fn get_key(&self) -> &[u8] {
         &self.keys[0].get_key()
     }
+
+    fn is_write(&self) -> bool {
+        false
+    }
 }
 
 #[define_traverse_command("N:N")]
@@ -1,35 +1,83 @@
+pub mod aof;
+pub mod bitmap;
+pub mod blocking;
 pub mod command_parser;
 pub mod command_table;
+pub mod del;
 pub mod diagnose;
+pub mod exists;
+pub mod expire;
+pub mod geo;
 pub mod get;
+pub mod hash;
+pub mod hyperloglog;
 pub mod incr;
+pub mod keys;
+pub mod list;
 pub mod mget;
 pub mod mset;
 pub mod publish;
+pub mod rdb;
+pub mod scan;
 pub mod set;
+pub mod set_algebra;
+pub mod sets;
+pub mod snapshot;
+pub mod stream;
+pub mod stream_group;
+pub mod strings;
 pub mod subscribe;
+pub mod transaction;
 pub mod traverse_command;
 pub mod unsubscribe;
+pub mod zset;
+pub mod zset_algebra;
 
+use aof::*;
+use bitmap::*;
+use blocking::*;
 use command_parser::*;
 use command_table::*;
+use del::*;
 use diagnose::*;
+use exists::*;
+use expire::*;
+use geo::*;
 use get::*;
+use hash::*;
+use hyperloglog::*;
 use incr::*;
+use keys::*;
+use list::*;
 use mget::*;
 use mset::*;
 use publish::*;
+use rdb::*;
+use scan::*;
 use set::*;
+use set_algebra::*;
+use sets::*;
+use snapshot::*;
+use stream::*;
+use stream_group::*;
+use strings::*;
 use subscribe::*;
 use tracing::trace;
+use transaction::*;
 use traverse_command::*;
 use unsubscribe::*;
+use zset::*;
+use zset_algebra::*;
 
 use anyhow::{Error, Result};
 use tokio::sync::{mpsc, oneshot};
 use utils::{rolling_hash, rolling_hash_const};
 
-use crate::{db::DB, protocol::Frame, utils};
+use crate::{
+    db::{Lock, DB},
+    protocol::Frame,
+    utils,
+};
 
 use bytes::*;
 use enum_dispatch::*;
@@ -37,11 +85,15 @@ use enum_dispatch::*;
 use std::sync::{atomic::AtomicUsize, Arc};
 
 #[allow(dead_code)]
+#[derive(Debug)]
 pub enum Command {
     Oneshot(OneshotCommand),
     Traverse(TraverseCommand),
     HoldOn(HoldOnCommand),
     Zeroshot(ZeroshotCommand),
+    Blocking(BPopDispatcher),
+    Transaction(TransactionCommand),
+    Snapshot(SnapshotVariant),
 }
 
 #[enum_dispatch]
@@ -51,6 +103,17 @@ pub enum OneshotCommand {
     Set,
     Dx,
     Incr,
+    Expire,
+    List,
+    Hash,
+    Sets,
+    ZSet,
+    Stream,
+    StreamGroup,
+    PfAdd,
+    Bitmap,
+    Strings,
+    Geo,
 }
 
 impl Into<AtomicCMD> for OneshotCommand {
@@ -61,6 +124,17 @@ impl Into<AtomicCMD> for OneshotCommand {
             Set(c) => AtomicCMD::Set(c),
             Incr(c) => AtomicCMD::Incr(c),
             Dx(c) => AtomicCMD::Dx(c),
+            Expire(c) => AtomicCMD::Expire(c),
+            List(c) => AtomicCMD::List(c),
+            Hash(c) => AtomicCMD::Hash(c),
+            Sets(c) => AtomicCMD::Sets(c),
+            ZSet(c) => AtomicCMD::ZSet(c),
+            Stream(c) => AtomicCMD::Stream(c),
+            StreamGroup(c) => AtomicCMD::StreamGroup(c),
+            PfAdd(c) => AtomicCMD::PfAdd(c),
+            Bitmap(c) => AtomicCMD::Bitmap(c),
+            Strings(c) => AtomicCMD::Strings(c),
+            Geo(c) => AtomicCMD::Geo(c),
         }
     }
 }
@@ -72,23 +146,80 @@ pub enum AtomicCMD {
     Set,
     MGet,
     MSet,
+    Del,
+    Exists,
     Dx,
     Incr,
+    Expire,
+    List,
+    Hash,
+    Sets,
+    SGather,
+    SStore,
+    ZSet,
+    ZGather,
+    ZStore,
+    Stream,
+    StreamGroup,
+    XGather,
+    PfAdd,
+    PfGather,
+    PfStore,
+    Bitmap,
+    BitStore,
+    Strings,
+    StrGather,
+    MSetNxCheck,
+    MSetNx,
+    Geo,
+    Logged,
+    Versions,
+    Batch,
+    Lock,
+    Snapshot,
+    Load,
+    Loaded,
+    RewriteAof,
+    TakeLogged,
+    Keys,
+    RandomKey,
+    Scan,
+    BPop,
     Subscribe,
     Publish,
     Unsubscribe,
 }
 
+#[derive(Debug)]
 pub enum ZeroshotCommand {
     Ping(Option<Bytes>),
 }
 
+impl ZeroshotCommand {
+    pub fn exec(self) -> Frame {
+        match self {
+            ZeroshotCommand::Ping(None) => Frame::Pong,
+            ZeroshotCommand::Ping(Some(msg)) => Frame::BulkStrings(msg),
+        }
+    }
+}
+
 #[enum_dispatch(AtomicCMD)]
 pub trait AtomicCMDMarker {}
 
 #[enum_dispatch(OneshotCommand)]
 pub trait OneshotExecDB {
     fn get_key(&self) -> &[u8];
+
+    /// Whether the command can change the database, only writes are logged.
+    fn is_write(&self) -> bool;
+
+    /// Logs the command after it changed the database, replying `ret`.
+    /// Commands whose frame wouldn't replay to the same state log what they
+    /// did instead.
+    fn propagate(&self, frame: Frame, _ret: &Frame, db: &mut DB) {
+        db.propagate(frame);
+    }
 }
 
 #[enum_dispatch(InitSubscription, DispatchToMultipleDB)]
@@ -131,8 +262,14 @@ pub struct ResultCollector {
 
 enum ResultCollectorType {
     Reorder(Vec<Vec<usize>>),
+    // reorders like `Reorder`, then folds every frame into a single one.
+    Combine(Vec<Vec<usize>>, Box<dyn FnOnce(Vec<Frame>) -> Frame + Send>),
     KeepFirst(usize),
     SumFirst((usize, i64)),
+    // flattens the arrays of every database into a single array.
+    Concat(usize),
+    // keeps the first frame that isn't `Frame::NullString`.
+    FirstNonNull(usize),
     AsIs,
 }
 
@@ -142,7 +279,7 @@ impl ResultCollector {
         assert!(
             match &self.result_type {
                 KeepFirst(x) => *x == 0,
-                Reorder(tbl) => {
+                Reorder(tbl) | Combine(tbl, _) => {
                     let mut idx = tbl.len();
                     while idx > 0 && tbl[idx - 1].len() == 0 {
                         idx -= 1;
@@ -150,11 +287,15 @@ impl ResultCollector {
                     idx == 0
                 }
                 SumFirst(_) => true,
+                Concat(x) | FirstNonNull(x) => *x == 0,
                 AsIs => true,
             },
             "result_collector should be exhausted before we can use the result"
         );
-        self.ret
+        match self.result_type {
+            Combine(_, combine) => vec![combine(self.ret)],
+            _ => self.ret,
+        }
     }
 
     pub async fn merge(&mut self, ret_rx: oneshot::Receiver<Frame>) -> Result<()> {
@@ -171,7 +312,7 @@ impl ResultCollector {
                 }
                 Ok(())
             }
-            Reorder(tbl) => {
+            Reorder(tbl) | Combine(tbl, _) => {
                 while tbl.len() > 0 && tbl[tbl.len() - 1].len() == 0 {
                     tbl.pop();
                 }
@@ -207,6 +348,39 @@ impl ResultCollector {
                 }
                 Ok(())
             }
+            Concat(x) => {
+                if *x == 0 {
+                    return Ok(());
+                }
+                *x -= 1;
+                match ret_rx.await.map_err(|e| Error::new(e))? {
+                    Frame::Arrays(arr) => self.ret.extend(arr),
+                    _ => panic!("Concat can only be applied to arrays"),
+                }
+                if *x == 0 {
+                    let all = std::mem::take(&mut self.ret);
+                    self.ret.push(Frame::Arrays(all));
+                }
+                Ok(())
+            }
+            FirstNonNull(x) => {
+                if *x == 0 {
+                    return Ok(());
+                }
+                *x -= 1;
+                match ret_rx.await.map_err(|e| Error::new(e))? {
+                    Frame::NullString => (),
+                    f => {
+                        if self.ret.len() == 0 {
+                            self.ret.push(f);
+                        }
+                    }
+                }
+                if *x == 0 && self.ret.len() == 0 {
+                    self.ret.push(Frame::NullString);
+                }
+                Ok(())
+            }
             AsIs => {
                 let f = ret_rx.await.map_err(|e| Error::new(e))?;
                 let f_arr = match f {
@@ -273,12 +447,42 @@ impl Command {
             SET(v) => Ok(Oneshot(Set::new(&mut parser, v)?.into())),
             MSET => Ok(Traverse(MSetDispatcher::new(&mut parser)?.into())),
             MGET => Ok(Traverse(MGetDispatcher::new(&mut parser)?.into())),
+            DEL => Ok(Traverse(DelDispatcher::new(&mut parser)?.into())),
+            EXISTS => Ok(Traverse(ExistsDispatcher::new(&mut parser)?.into())),
             INCR(v) => Ok(Oneshot(Incr::new(&mut parser, v)?.into())),
+            EXPIRE(v) => Ok(Oneshot(Expire::new(&mut parser, v)?.into())),
+            LIST(v) => Ok(Oneshot(List::new(&mut parser, v)?.into())),
+            HASH(v) => Ok(Oneshot(Hash::new(&mut parser, v)?.into())),
+            SETS(v) => Ok(Oneshot(Sets::new(&mut parser, v)?.into())),
+            ZSET(v) => Ok(Oneshot(ZSet::new(&mut parser, v)?.into())),
+            ZSETALGEBRA(v) => Ok(Traverse(ZSetAlgebraDispatcher::new(&mut parser, v)?.into())),
+            STREAM(v) => Ok(Oneshot(Stream::new(&mut parser, v)?.into())),
+            STREAMGROUP(v) => Ok(Oneshot(StreamGroup::new(&mut parser, v)?.into())),
+            HYPERLOGLOG(HyperLogLogVariant::PfAdd) => Ok(Oneshot(PfAdd::new(&mut parser)?.into())),
+            HYPERLOGLOG(v) => Ok(Traverse(HyperLogLogDispatcher::new(&mut parser, v)?.into())),
+            BITMAP(BitmapVariant::BitOp) => Ok(Traverse(BitOpDispatcher::new(&mut parser)?.into())),
+            BITMAP(v) => Ok(Oneshot(Bitmap::new(&mut parser, v)?.into())),
+            STRINGS(StringsVariant::MSetNx) => {
+                Ok(Traverse(MSetNxDispatcher::new(&mut parser)?.into()))
+            }
+            STRINGS(StringsVariant::Lcs) => Ok(Traverse(LcsDispatcher::new(&mut parser)?.into())),
+            STRINGS(v) => Ok(Oneshot(Strings::new(&mut parser, v)?.into())),
+            GEO(GeoVariant::GeoSearchStore) => {
+                Ok(Traverse(GeoSearchStoreDispatcher::new(&mut parser)?.into()))
+            }
+            GEO(v) => Ok(Oneshot(Geo::new(&mut parser, v)?.into())),
+            SETALGEBRA(v) => Ok(Traverse(SetAlgebraDispatcher::new(&mut parser, v)?.into())),
+            BLOCKING(v) => Ok(Blocking(BPopDispatcher::new(&mut parser, v)?)),
+            KEYS => Ok(Traverse(KeysDispatcher::new(&mut parser)?.into())),
+            RANDOMKEY => Ok(Traverse(RandomKeyDispatcher::new(&mut parser)?.into())),
+            SCAN => Ok(Traverse(ScanDispatcher::new(&mut parser)?.into())),
             DX => Ok(Traverse(DxDispatcher::new(&mut parser)?.into())),
             SHUTDOWN => Ok(Oneshot(Dx::new(DxCommand::Shutdown).into())),
             SUBSCRIBE => Ok(HoldOn(SubscribeDispatcher::new(&mut parser)?.into())),
             PUBLISH => Ok(HoldOn(PublishDispatcher::new(&mut parser)?.into())),
             UNSUBSCRIBE => Ok(HoldOn(UnsubDispatcher::new(&mut parser)?.into())),
+            SNAPSHOT(v) => Ok(Snapshot(SnapshotVariant::new(&mut parser, v)?)),
+            TRANSACTION(v) => Ok(Transaction(TransactionCommand::new(&mut parser, v)?)),
             PING => Ok(Zeroshot(ZeroshotCommand::Ping(if parser.len() == 0 {
                 None
             } else {
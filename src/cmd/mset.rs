@@ -12,6 +12,15 @@ impl MSet {
     }
 
     pub fn exec(self, db: &mut DB) -> Frame {
+        if db.logging() {
+            let mut args = vec![Frame::BulkStrings(Bytes::from_static(b"MSET"))];
+            for cmd in self.cmds.iter() {
+                if let MiniCommand::Pair((k, v)) = cmd {
+                    args.extend([k.clone().into(), v.clone()]);
+                }
+            }
+            db.propagate(Frame::Arrays(args));
+        }
         let nounce0 = db.counter;
         db.counter += self.cmds.len() as u64;
         self.cmds.into_iter().fold(nounce0 + 1, |i, cmd| {
@@ -28,6 +37,10 @@ impl OneshotExecDB for MSet {
     fn get_key(&self) -> &[u8] {
         &self.cmds[0].get_key()
     }
+
+    fn is_write(&self) -> bool {
+        true
+    }
 }
 
 #[define_traverse_command("N:1")]
@@ -0,0 +1,724 @@
+use crate::{
+    cmd::hash::HashValue,
+    cmd::hyperloglog::HyperLogLog,
+    cmd::sets::SetValue,
+    cmd::snapshot::LoadedEntry,
+    cmd::zset::ZSetValue,
+    cmd::*,
+    db::{Entry, Value},
+    utils::{instant_to_unix_millis, unix_millis_now},
+};
+use anyhow::{anyhow, Result};
+use std::{
+    collections::VecDeque,
+    convert::{TryFrom, TryInto},
+};
+use tokio::time::Instant;
+
+/// The version written, the oldest one that every Redis still in use
+/// (5.0 onwards) loads.
+const RDB_VERSION: u32 = 9;
+/// The newest version read, that of Redis 7.4.
+const RDB_VERSION_MAX: u32 = 12;
+/// From this version on the file ends with a CRC64 of everything before.
+const RDB_VERSION_CHECKSUM: u32 = 5;
+
+const OPCODE_SLOT_INFO: u8 = 244;
+const OPCODE_FUNCTION2: u8 = 245;
+const OPCODE_FUNCTION_PRE_GA: u8 = 246;
+const OPCODE_MODULE_AUX: u8 = 247;
+const OPCODE_IDLE: u8 = 248;
+const OPCODE_FREQ: u8 = 249;
+const OPCODE_AUX: u8 = 250;
+const OPCODE_RESIZEDB: u8 = 251;
+const OPCODE_EXPIRETIME_MS: u8 = 252;
+const OPCODE_EXPIRETIME: u8 = 253;
+const OPCODE_SELECTDB: u8 = 254;
+const OPCODE_EOF: u8 = 255;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+/// How a node of a `TYPE_LIST_QUICKLIST_2` list holds its elements.
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+/// The two high bits of the first byte of a length tell its size, or that
+/// a string is encoded rather than prefixed with its length.
+const LEN_6BIT: u8 = 0;
+const LEN_14BIT: u8 = 1;
+const LEN_32BIT: u8 = 0x80;
+const LEN_64BIT: u8 = 0x81;
+const LEN_ENCVAL: u8 = 3;
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+/// A back reference of 3 bytes, the best LZF does, copies 264 bytes. The
+/// length a compressed string claims is checked against this before
+/// anything is allocated for it.
+const LZF_MAX_RATIO: usize = 88;
+
+/// The reflected form of the Jones polynomial Redis checksums files with.
+const CRC64_POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+fn corrupt() -> anyhow::Error {
+    anyhow!("corrupt RDB file")
+}
+
+/// Continues `crc` over `data`, the CRC64 of a whole file is `crc64(0, ..)`.
+pub fn crc64(crc: u64, data: &[u8]) -> u64 {
+    data.iter().fold(crc, |crc, b| {
+        let mut c = (crc ^ *b as u64) & 0xff;
+        for _ in 0..8 {
+            c = if c & 1 == 1 {
+                (c >> 1) ^ CRC64_POLY
+            } else {
+                c >> 1
+            };
+        }
+        c ^ (crc >> 8)
+    })
+}
+
+fn take_u8(buf: &mut Bytes) -> Result<u8> {
+    if buf.remaining() < 1 {
+        return Err(corrupt());
+    }
+    Ok(buf.get_u8())
+}
+
+fn take_slice(buf: &mut Bytes, len: usize) -> Result<Bytes> {
+    if buf.remaining() < len {
+        return Err(corrupt());
+    }
+    Ok(buf.split_to(len))
+}
+
+/// A length, or the encoding of a string if the flag is set.
+fn take_length(buf: &mut Bytes) -> Result<(u64, bool)> {
+    let first = take_u8(buf)?;
+    Ok(match (first >> 6, first) {
+        (LEN_6BIT, _) => ((first & 0x3f) as u64, false),
+        (LEN_14BIT, _) => ((((first & 0x3f) as u64) << 8) | take_u8(buf)? as u64, false),
+        (LEN_ENCVAL, _) => ((first & 0x3f) as u64, true),
+        (_, LEN_32BIT) => (take_slice(buf, 4)?.get_u32() as u64, false),
+        (_, LEN_64BIT) => (take_slice(buf, 8)?.get_u64(), false),
+        _ => return Err(corrupt()),
+    })
+}
+
+/// A count of things that take a byte at least each, checked against what
+/// is left of the file.
+fn take_len(buf: &mut Bytes) -> Result<usize> {
+    match take_length(buf)? {
+        (len, false) if len <= buf.remaining() as u64 => Ok(len as usize),
+        _ => Err(corrupt()),
+    }
+}
+
+fn take_string(buf: &mut Bytes) -> Result<Bytes> {
+    let (len, encoded) = take_length(buf)?;
+    if !encoded {
+        return take_slice(buf, len.try_into().map_err(|_| corrupt())?);
+    }
+    let int = match len as u8 {
+        ENC_INT8 => take_slice(buf, 1)?.get_i8() as i64,
+        ENC_INT16 => take_slice(buf, 2)?.get_i16_le() as i64,
+        ENC_INT32 => take_slice(buf, 4)?.get_i32_le() as i64,
+        ENC_LZF => {
+            let compressed = take_len(buf)?;
+            let (len, _) = take_length(buf)?;
+            let compressed = take_slice(buf, compressed)?;
+            let len = usize::try_from(len)
+                .ok()
+                .filter(|len| *len <= compressed.len() * LZF_MAX_RATIO)
+                .ok_or_else(corrupt)?;
+            return lzf_decompress(&compressed, len);
+        }
+        _ => return Err(corrupt()),
+    };
+    Ok(Bytes::from(int.to_string()))
+}
+
+/// A score of a `TYPE_ZSET` sorted set, written as text.
+fn take_double(buf: &mut Bytes) -> Result<f64> {
+    match take_u8(buf)? {
+        253 => Ok(f64::NAN),
+        254 => Ok(f64::INFINITY),
+        255 => Ok(f64::NEG_INFINITY),
+        len => parse_score(&take_slice(buf, len as usize)?),
+    }
+}
+
+fn parse_score(raw: &[u8]) -> Result<f64> {
+    std::str::from_utf8(raw)
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|v| !v.is_nan())
+        .ok_or_else(corrupt)
+}
+
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Bytes> {
+    let mut out: Vec<u8> = Vec::with_capacity(len);
+    let mut ip = 0;
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+        if ctrl < 32 {
+            // a run of `ctrl + 1` literal bytes.
+            let run = input.get(ip..ip + ctrl + 1).ok_or_else(corrupt)?;
+            out.extend_from_slice(run);
+            ip += ctrl + 1;
+        } else {
+            // a back reference, which may overlap what it copies.
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(ip).ok_or_else(corrupt)? as usize;
+                ip += 1;
+            }
+            let offset = ((ctrl & 0x1f) << 8) + *input.get(ip).ok_or_else(corrupt)? as usize + 1;
+            ip += 1;
+            let from = out.len().checked_sub(offset).ok_or_else(corrupt)?;
+            for i in from..from + run + 2 {
+                out.push(out[i]);
+            }
+        }
+        if out.len() > len {
+            return Err(corrupt());
+        }
+    }
+    if out.len() != len {
+        return Err(corrupt());
+    }
+    Ok(Bytes::from(out))
+}
+
+/// The elements of a ziplist, the encoding of small collections before
+/// Redis 7.0.
+fn ziplist_entries(zl: &Bytes) -> Result<Vec<Bytes>> {
+    let mut buf = zl.clone();
+    // total bytes, offset of the tail, then the number of entries.
+    take_slice(&mut buf, 10)?;
+    let mut entries = Vec::new();
+    loop {
+        let prevlen = take_u8(&mut buf)?;
+        if prevlen == 0xff {
+            return Ok(entries);
+        }
+        if prevlen == 0xfe {
+            take_slice(&mut buf, 4)?;
+        }
+        let enc = take_u8(&mut buf)?;
+        let entry = match enc >> 6 {
+            0 => take_slice(&mut buf, (enc & 0x3f) as usize)?,
+            1 => {
+                let len = (((enc & 0x3f) as usize) << 8) | take_u8(&mut buf)? as usize;
+                take_slice(&mut buf, len)?
+            }
+            2 => {
+                let len = take_slice(&mut buf, 4)?.get_u32() as usize;
+                take_slice(&mut buf, len)?
+            }
+            _ => {
+                let int = match enc {
+                    0xc0 => take_slice(&mut buf, 2)?.get_i16_le() as i64,
+                    0xd0 => take_slice(&mut buf, 4)?.get_i32_le() as i64,
+                    0xe0 => take_slice(&mut buf, 8)?.get_i64_le(),
+                    0xf0 => (take_slice(&mut buf, 3)?.get_int_le(3) << 40) >> 40,
+                    0xfe => take_slice(&mut buf, 1)?.get_i8() as i64,
+                    0xf1..=0xfd => (enc & 0x0f) as i64 - 1,
+                    _ => return Err(corrupt()),
+                };
+                Bytes::from(int.to_string())
+            }
+        };
+        entries.push(entry);
+    }
+}
+
+/// The elements of a listpack, the encoding of small collections since
+/// Redis 7.0.
+fn listpack_entries(lp: &Bytes) -> Result<Vec<Bytes>> {
+    let mut buf = lp.clone();
+    // total bytes, then the number of entries.
+    take_slice(&mut buf, 6)?;
+    let mut entries = Vec::new();
+    loop {
+        let enc = take_u8(&mut buf)?;
+        let (entry, size) = match enc {
+            0xff => return Ok(entries),
+            0x00..=0x7f => (Bytes::from((enc as i64).to_string()), 1),
+            0x80..=0xbf => {
+                let len = (enc & 0x3f) as usize;
+                (take_slice(&mut buf, len)?, 1 + len)
+            }
+            0xc0..=0xdf => {
+                let uint = (((enc & 0x1f) as i64) << 8) | take_u8(&mut buf)? as i64;
+                let int = if uint >= 1 << 12 {
+                    uint - (1 << 13)
+                } else {
+                    uint
+                };
+                (Bytes::from(int.to_string()), 2)
+            }
+            0xe0..=0xef => {
+                let len = (((enc & 0x0f) as usize) << 8) | take_u8(&mut buf)? as usize;
+                (take_slice(&mut buf, len)?, 2 + len)
+            }
+            0xf0 => {
+                let len = take_slice(&mut buf, 4)?.get_u32_le() as usize;
+                (take_slice(&mut buf, len)?, 5 + len)
+            }
+            0xf1..=0xf4 => {
+                let width = match enc {
+                    0xf1 => 2,
+                    0xf2 => 3,
+                    0xf3 => 4,
+                    _ => 8,
+                };
+                let shift = 64 - 8 * width as u32;
+                let int = (take_slice(&mut buf, width)?.get_int_le(width) << shift) >> shift;
+                (Bytes::from(int.to_string()), 1 + width)
+            }
+            _ => return Err(corrupt()),
+        };
+        // every entry ends with its size, 7 bits per byte.
+        let backlen = match size {
+            0..=127 => 1,
+            128..=16383 => 2,
+            16384..=2097151 => 3,
+            2097152..=268435455 => 4,
+            _ => 5,
+        };
+        take_slice(&mut buf, backlen)?;
+        entries.push(entry);
+    }
+}
+
+/// The members of an intset, a set of integers only.
+fn intset_entries(is: &Bytes) -> Result<Vec<Bytes>> {
+    let mut buf = is.clone();
+    let mut header = take_slice(&mut buf, 8)?;
+    let width = header.get_u32_le() as usize;
+    let len = header.get_u32_le() as usize;
+    if !matches!(width, 2 | 4 | 8) || buf.remaining() != width * len {
+        return Err(corrupt());
+    }
+    Ok((0..len)
+        .map(|_| Bytes::from(buf.get_int_le(width).to_string()))
+        .collect())
+}
+
+fn string_value(s: Bytes) -> Value {
+    match HyperLogLog::from_string(&s) {
+        Some(h) => Value::HyperLogLog(h),
+        None => Value::Str(Frame::BulkStrings(s)),
+    }
+}
+
+fn set_value(members: Vec<Bytes>) -> Value {
+    let mut set = SetValue::new();
+    for member in members {
+        set.insert(member);
+    }
+    Value::Set(set)
+}
+
+/// A sorted set out of alternating members and scores.
+fn zset_value(entries: Vec<Bytes>) -> Result<Value> {
+    let mut zset = ZSetValue::new();
+    let mut it = entries.into_iter();
+    loop {
+        match (it.next(), it.next()) {
+            (Some(member), Some(score)) => zset.insert(member, parse_score(&score)?),
+            (None, _) => break,
+            // a member without its score.
+            _ => return Err(corrupt()),
+        };
+    }
+    Ok(Value::ZSet(zset))
+}
+
+/// A hash out of alternating fields and values.
+fn hash_value(entries: Vec<Bytes>) -> Result<Value> {
+    let mut hash = HashValue::new();
+    let mut it = entries.into_iter();
+    loop {
+        match (it.next(), it.next()) {
+            (Some(field), Some(value)) => hash.insert(field, value, false),
+            (None, _) => break,
+            _ => return Err(corrupt()),
+        };
+    }
+    Ok(Value::Hash(hash))
+}
+
+fn unsupported(value_type: u8) -> anyhow::Error {
+    let name = match value_type {
+        6 | 7 => "module values",
+        9 => "zipmap hashes",
+        15 | 19 | 21 => "streams",
+        22..=25 => "hashes with expiring fields",
+        _ => "values of unknown types",
+    };
+    anyhow!("{} (RDB type {}) can't be loaded", name, value_type)
+}
+
+/// Reads the keys of a Redis RDB file one at a time. Keys of every Redis
+/// database end up in the only one there is here.
+pub struct RdbReader {
+    buf: Bytes,
+}
+
+impl RdbReader {
+    pub fn new(mut buf: Bytes) -> Result<RdbReader> {
+        let version = match buf.get(..9) {
+            Some(header) if header.starts_with(b"REDIS") => std::str::from_utf8(&header[5..])
+                .ok()
+                .and_then(|v| v.parse::<u32>().ok()),
+            _ => None,
+        }
+        .ok_or_else(|| anyhow!("not an RDB file"))?;
+        if !(1..=RDB_VERSION_MAX).contains(&version) {
+            return Err(anyhow!("RDB version {} isn't supported", version));
+        }
+        if version >= RDB_VERSION_CHECKSUM {
+            let body = buf.len().checked_sub(8).ok_or_else(corrupt)?;
+            let expected = (&buf[body..]).get_u64_le();
+            // files written with checksums disabled carry a zero.
+            if expected != 0 && crc64(0, &buf[..body]) != expected {
+                return Err(anyhow!("wrong RDB checksum"));
+            }
+        }
+        buf.advance(9);
+        Ok(Self { buf })
+    }
+
+    /// `None` once the end of the file is reached, a file cut short is an
+    /// error.
+    pub fn next_entry(&mut self) -> Result<Option<LoadedEntry>> {
+        let buf = &mut self.buf;
+        let mut expiration = None;
+        loop {
+            match take_u8(buf)? {
+                OPCODE_EOF => return Ok(None),
+                OPCODE_EXPIRETIME_MS => {
+                    expiration = Some(take_slice(buf, 8)?.get_i64_le());
+                }
+                OPCODE_EXPIRETIME => {
+                    expiration = Some(take_slice(buf, 4)?.get_i32_le() as i64 * 1000);
+                }
+                OPCODE_SELECTDB | OPCODE_IDLE => {
+                    take_length(buf)?;
+                }
+                OPCODE_FREQ => {
+                    take_u8(buf)?;
+                }
+                OPCODE_RESIZEDB => {
+                    take_length(buf)?;
+                    take_length(buf)?;
+                }
+                OPCODE_SLOT_INFO => {
+                    for _ in 0..3 {
+                        take_length(buf)?;
+                    }
+                }
+                OPCODE_AUX => {
+                    take_string(buf)?;
+                    take_string(buf)?;
+                }
+                OPCODE_FUNCTION2 => {
+                    take_string(buf)?;
+                }
+                OPCODE_MODULE_AUX | OPCODE_FUNCTION_PRE_GA => {
+                    return Err(anyhow!(
+                        "RDB files holding modules or functions can't be loaded"
+                    ))
+                }
+                value_type => {
+                    let key = take_string(buf)?;
+                    let value = take_value(buf, value_type)?;
+                    return Ok(Some((key, value, expiration)));
+                }
+            }
+        }
+    }
+}
+
+fn take_value(buf: &mut Bytes, value_type: u8) -> Result<Value> {
+    Ok(match value_type {
+        TYPE_STRING => string_value(take_string(buf)?),
+        TYPE_LIST => Value::List(
+            (0..take_len(buf)?)
+                .map(|_| take_string(buf))
+                .collect::<Result<_>>()?,
+        ),
+        TYPE_LIST_ZIPLIST => Value::List(ziplist_entries(&take_string(buf)?)?.into()),
+        TYPE_LIST_QUICKLIST => {
+            let mut list = VecDeque::new();
+            for _ in 0..take_len(buf)? {
+                list.extend(ziplist_entries(&take_string(buf)?)?);
+            }
+            Value::List(list)
+        }
+        TYPE_LIST_QUICKLIST_2 => {
+            let mut list = VecDeque::new();
+            for _ in 0..take_len(buf)? {
+                let (container, _) = take_length(buf)?;
+                let node = take_string(buf)?;
+                match container {
+                    QUICKLIST_NODE_PLAIN => list.push_back(node),
+                    QUICKLIST_NODE_PACKED => list.extend(listpack_entries(&node)?),
+                    _ => return Err(corrupt()),
+                }
+            }
+            Value::List(list)
+        }
+        TYPE_SET => set_value(
+            (0..take_len(buf)?)
+                .map(|_| take_string(buf))
+                .collect::<Result<_>>()?,
+        ),
+        TYPE_SET_INTSET => set_value(intset_entries(&take_string(buf)?)?),
+        TYPE_SET_LISTPACK => set_value(listpack_entries(&take_string(buf)?)?),
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let mut zset = ZSetValue::new();
+            for _ in 0..take_len(buf)? {
+                let member = take_string(buf)?;
+                let score = match value_type {
+                    TYPE_ZSET => take_double(buf)?,
+                    _ => take_slice(buf, 8)?.get_f64_le(),
+                };
+                if score.is_nan() {
+                    return Err(corrupt());
+                }
+                zset.insert(member, score);
+            }
+            Value::ZSet(zset)
+        }
+        TYPE_ZSET_ZIPLIST => zset_value(ziplist_entries(&take_string(buf)?)?)?,
+        TYPE_ZSET_LISTPACK => zset_value(listpack_entries(&take_string(buf)?)?)?,
+        TYPE_HASH => {
+            let mut entries = Vec::new();
+            for _ in 0..take_len(buf)? {
+                entries.push(take_string(buf)?);
+                entries.push(take_string(buf)?);
+            }
+            hash_value(entries)?
+        }
+        TYPE_HASH_ZIPLIST => hash_value(ziplist_entries(&take_string(buf)?)?)?,
+        TYPE_HASH_LISTPACK => hash_value(listpack_entries(&take_string(buf)?)?)?,
+        _ => return Err(unsupported(value_type)),
+    })
+}
+
+fn put_length(buf: &mut BytesMut, len: u64) {
+    if len < 1 << 6 {
+        buf.put_u8(len as u8);
+    } else if len < 1 << 14 {
+        buf.put_u8((LEN_14BIT << 6) | (len >> 8) as u8);
+        buf.put_u8(len as u8);
+    } else if len <= u32::MAX as u64 {
+        buf.put_u8(LEN_32BIT);
+        buf.put_u32(len as u32);
+    } else {
+        buf.put_u8(LEN_64BIT);
+        buf.put_u64(len);
+    }
+}
+
+fn put_string(buf: &mut BytesMut, s: &[u8]) {
+    put_length(buf, s.len() as u64);
+    buf.put_slice(s);
+}
+
+/// Integers that fit in 32 bits are written as such, as Redis does.
+fn put_int(buf: &mut BytesMut, int: i64) {
+    let enc = LEN_ENCVAL << 6;
+    if let Ok(int) = i8::try_from(int) {
+        buf.put_u8(enc | ENC_INT8);
+        buf.put_i8(int);
+    } else if let Ok(int) = i16::try_from(int) {
+        buf.put_u8(enc | ENC_INT16);
+        buf.put_i16_le(int);
+    } else if let Ok(int) = i32::try_from(int) {
+        buf.put_u8(enc | ENC_INT32);
+        buf.put_i32_le(int);
+    } else {
+        put_string(buf, int.to_string().as_bytes());
+    }
+}
+
+/// Starts an RDB file, whose keys all go into database 0.
+pub fn rdb_header() -> Bytes {
+    let mut buf = BytesMut::new();
+    buf.put_slice(format!("REDIS{:04}", RDB_VERSION).as_bytes());
+    for (field, value) in [
+        (&b"redis-bits"[..], 64.to_string()),
+        (&b"ctime"[..], (unix_millis_now() / 1000).to_string()),
+    ] {
+        buf.put_u8(OPCODE_AUX);
+        put_string(&mut buf, field);
+        put_string(&mut buf, value.as_bytes());
+    }
+    buf.put_u8(OPCODE_SELECTDB);
+    put_length(&mut buf, 0);
+    buf.freeze()
+}
+
+/// Ends an RDB file whose bytes so far checksum to `crc`.
+pub fn rdb_end(crc: u64) -> Bytes {
+    let mut buf = BytesMut::new();
+    buf.put_u8(OPCODE_EOF);
+    buf.put_u64_le(crc64(crc, &[OPCODE_EOF]));
+    buf.freeze()
+}
+
+/// Writes `en` under `key` the way Redis would load it, returns whether
+/// there is such a way: streams have none. Hashes are written without the
+/// deadlines of their fields, which RDB version 9 has no room for. Fails on
+/// strings set from a frame that holds no bytes.
+pub fn put_rdb_entry(buf: &mut BytesMut, key: &[u8], en: &Entry, now: Instant) -> Result<bool> {
+    if let Value::Stream(_) = en.data {
+        return Ok(false);
+    }
+    if let Some(at) = en.expiration {
+        buf.put_u8(OPCODE_EXPIRETIME_MS);
+        buf.put_i64_le(instant_to_unix_millis(at));
+    }
+    match &en.data {
+        Value::Str(Frame::Integers(i)) => {
+            buf.put_u8(TYPE_STRING);
+            put_string(buf, key);
+            put_int(buf, *i);
+        }
+        Value::Str(_) | Value::MutStr(_) => {
+            let b = en.data.str_bytes()?;
+            buf.put_u8(TYPE_STRING);
+            put_string(buf, key);
+            put_string(buf, &b);
+        }
+        Value::HyperLogLog(h) => {
+            buf.put_u8(TYPE_STRING);
+            put_string(buf, key);
+            put_string(buf, &h.dense_string());
+        }
+        Value::List(l) => {
+            buf.put_u8(TYPE_LIST);
+            put_string(buf, key);
+            put_length(buf, l.len() as u64);
+            for element in l {
+                put_string(buf, element);
+            }
+        }
+        Value::Set(s) => {
+            buf.put_u8(TYPE_SET);
+            put_string(buf, key);
+            put_length(buf, s.len() as u64);
+            for member in s.iter() {
+                put_string(buf, &member);
+            }
+        }
+        Value::ZSet(z) => {
+            buf.put_u8(TYPE_ZSET_2);
+            put_string(buf, key);
+            put_length(buf, z.len() as u64);
+            for (member, score) in z.iter() {
+                put_string(buf, member);
+                buf.put_f64_le(score);
+            }
+        }
+        Value::Hash(h) => {
+            let fields: Vec<_> = h
+                .iter()
+                .filter(|(f, _)| !matches!(h.ttl(f), Some(at) if at <= now))
+                .collect();
+            buf.put_u8(TYPE_HASH);
+            put_string(buf, key);
+            put_length(buf, fields.len() as u64);
+            for (field, value) in fields {
+                put_string(buf, field);
+                put_string(buf, value);
+            }
+        }
+        Value::Stream(_) => unreachable!("streams are left out"),
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Entry;
+
+    #[test]
+    fn crc64_check_value() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn written_entries_read_back() {
+        let mut file = BytesMut::from(&rdb_header()[..]);
+        let now = Instant::now();
+        let mut list = VecDeque::new();
+        list.extend([Bytes::from_static(b"a"), Bytes::from_static(b"b")]);
+        let entries = [
+            (&b"int"[..], Value::Str(Frame::Integers(-70000))),
+            (
+                &b"str"[..],
+                Value::Str(Frame::BulkStrings(Bytes::from_static(b"v"))),
+            ),
+            (
+                &b"simple"[..],
+                Value::Str(Frame::SimpleString(Bytes::from_static(b"v"))),
+            ),
+            (&b"list"[..], Value::List(list)),
+        ];
+        for (key, value) in entries {
+            let en = Entry::new(value, None, 0);
+            assert!(put_rdb_entry(&mut file, key, &en, now).unwrap());
+        }
+        let crc = crc64(0, &file);
+        file.extend_from_slice(&rdb_end(crc));
+
+        let mut reader = RdbReader::new(file.freeze()).unwrap();
+        let mut keys = Vec::new();
+        while let Some((key, value, expiration)) = reader.next_entry().unwrap() {
+            assert_eq!(expiration, None);
+            match value {
+                Value::Str(Frame::BulkStrings(s)) if key == "int" => assert_eq!(s, "-70000"),
+                Value::Str(Frame::BulkStrings(s)) => assert_eq!(s, "v"),
+                Value::List(l) => assert_eq!(l, ["a", "b"]),
+                v => panic!("unexpected {:?}", v),
+            }
+            keys.push(key);
+        }
+        assert_eq!(keys, ["int", "str", "simple", "list"]);
+    }
+
+    #[test]
+    fn compressed_strings_claim_bounded_lengths() {
+        // one literal byte claimed to decompress to 2^62 bytes.
+        let mut buf = BytesMut::new();
+        buf.put_u8(LEN_ENCVAL << 6 | ENC_LZF);
+        buf.put_u8(2);
+        buf.put_u8(LEN_64BIT);
+        buf.put_u64(1 << 62);
+        buf.extend_from_slice(&[0, b'a']);
+        assert!(take_string(&mut buf.freeze()).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use crate::{cmd::*, utils::glob_match};
+use anyhow::Result;
+use tokio::time::Instant;
+
+const MATCH: usize = rolling_hash_const(b"match");
+const COUNT: usize = rolling_hash_const(b"count");
+const TYPE: usize = rolling_hash_const(b"type");
+
+const DEFAULT_COUNT: usize = 10;
+/// Upper bound of vacant slots visited per requested key.
+const VACANT_SLOTS_PER_KEY: usize = 10;
+
+/// Scans a single database, starting from position `pos` of `DB::slots`.
+///
+/// The cursor handed back to the client encodes both the database and the
+/// position: `cursor = pos * db_amount + db_id`. Once a database is done the
+/// cursor moves on to position 0 of the next one, and to 0 after the last.
+#[derive(Debug, Clone)]
+pub struct Scan {
+    pos: usize,
+    count: usize,
+    pattern: Option<Bytes>,
+    key_type: Option<Bytes>,
+    db_amount: usize,
+}
+
+impl Scan {
+    pub fn exec(self, db: &mut DB) -> Frame {
+        let (next_pos, keys) = db.scan(self.pos, self.count, &self.pattern, &self.key_type);
+        let cursor = if next_pos > 0 {
+            next_pos * self.db_amount + db.id
+        } else if db.id + 1 < self.db_amount {
+            db.id + 1
+        } else {
+            0
+        };
+        Frame::Arrays(vec![
+            Bytes::from(cursor.to_string()).into(),
+            Frame::Arrays(keys),
+        ])
+    }
+}
+
+impl OneshotExecDB for Scan {
+    fn get_key(&self) -> &[u8] {
+        b""
+    }
+
+    fn is_write(&self) -> bool {
+        false
+    }
+}
+
+impl DB {
+    /// Returns the position to resume from (0 once the end is reached) and
+    /// the keys found on the way.
+    fn scan(
+        &mut self,
+        pos: usize,
+        count: usize,
+        pattern: &Option<Bytes>,
+        key_type: &Option<Bytes>,
+    ) -> (usize, Vec<Frame>) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        let mut keys = Vec::new();
+        let (mut idx, mut examined) = (pos, 0);
+        let end = self
+            .slots
+            .len()
+            .min(pos.saturating_add(count * VACANT_SLOTS_PER_KEY));
+        while idx < end && examined < count {
+            if let Some(key) = self.slots.get(idx) {
+                examined += 1;
+                let en = &self.database[key];
+                if en.is_expired(now) {
+                    expired.push(key.clone());
+                } else if key_type
+                    .as_ref()
+                    .map_or(true, |t| t.eq_ignore_ascii_case(en.type_name().as_bytes()))
+                    && pattern.as_ref().map_or(true, |p| glob_match(p, key))
+                {
+                    keys.push(key.clone().into());
+                }
+            }
+            idx += 1;
+        }
+        for key in expired {
+            self.expire_key(&key);
+        }
+        (if idx >= self.slots.len() { 0 } else { idx }, keys)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScanDispatcher {
+    cursor: usize,
+    count: usize,
+    pattern: Option<Bytes>,
+    key_type: Option<Bytes>,
+    db_amount: usize,
+    sent: bool,
+}
+
+impl DispatchToMultipleDB for ScanDispatcher {
+    fn next_command(&mut self) -> Option<IDCommandPair> {
+        if self.sent {
+            return None;
+        }
+        self.sent = true;
+        Some((
+            self.cursor % self.db_amount,
+            Scan {
+                pos: self.cursor / self.db_amount,
+                count: self.count,
+                pattern: self.pattern.clone(),
+                key_type: self.key_type.clone(),
+                db_amount: self.db_amount,
+            }
+            .into(),
+        ))
+    }
+
+    fn get_result_collector(&mut self) -> ResultCollector {
+        assert!(self.db_amount > 0, "self.db_amount wasn't initialized");
+        let ret = vec![Frame::NullString; 1];
+        ResultCollector {
+            result_type: ResultCollectorType::KeepFirst(1),
+            ret,
+        }
+    }
+
+    fn dispatch(&mut self, db_amount: usize, _: impl Fn(&[u8]) -> usize) {
+        self.db_amount = db_amount;
+    }
+}
+
+impl ScanDispatcher {
+    pub fn new(parser: &mut CommandParser) -> Result<ScanDispatcher> {
+        let cursor = parser
+            .next_integer()?
+            .filter(|v| *v >= 0)
+            .ok_or_else(invalid_operand)? as usize;
+        let mut res = Self {
+            cursor,
+            count: DEFAULT_COUNT,
+            pattern: None,
+            key_type: None,
+            db_amount: 0,
+            sent: false,
+        };
+        while let Some(option) = parser.next_bytes()? {
+            match rolling_hash(option.as_ref())? {
+                MATCH => res.pattern = Some(parser.next_bytes()?.ok_or_else(missing_operand)?),
+                COUNT => {
+                    res.count = parser
+                        .next_integer()?
+                        .filter(|v| *v > 0)
+                        .ok_or_else(invalid_operand)? as usize
+                }
+                TYPE => res.key_type = Some(parser.next_bytes()?.ok_or_else(missing_operand)?),
+                _ => {
+                    return Err(invalid_operation());
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl AtomicCMDMarker for Scan {}
@@ -1,6 +1,7 @@
 use crate::{
     cmd::*,
-    db::{Entry, DB},
+    db::{wrong_type, Entry, DB},
+    utils::unix_millis_to_instant,
 };
 use anyhow::Result;
 use tokio::time::{Duration, Instant};
@@ -78,7 +79,7 @@ impl Set {
         match variant {
             SetVariant::Set => {
                 let k = parser.next_bytes()?.ok_or_else(missing_operand)?;
-                let v = parser.next().ok_or_else(missing_operand)?;
+                let v = parser.next_value()?.ok_or_else(missing_operand)?;
                 if parser.len() == 0 {
                     return Ok(Self {
                         key: k,
@@ -138,15 +139,10 @@ impl Set {
                                 .next_integer()?
                                 .filter(|v| *v > 0)
                                 .ok_or_else(missing_operand)?;
-                            let expire_sys = std::time::UNIX_EPOCH
-                                + std::time::Duration::from_secs(next_int as u64);
-                            let dur = expire_sys
-                                .duration_since(std::time::SystemTime::now())
-                                .map_err(|_| invalid_operand())?;
-                            if dur.as_millis() == 0 {
-                                invalid_operand();
-                            }
-                            expiration = Expiration::At(Instant::now() + dur);
+                            // a deadline in the past sets a key that is expired already.
+                            expiration = Expiration::At(unix_millis_to_instant(
+                                next_int.saturating_mul(1000),
+                            ));
                         }
                         PXAT => {
                             check_set!(checklist, I_PXAT, I_PX, I_EX, I_EXAT, I_KEEPTTL);
@@ -154,15 +150,7 @@ impl Set {
                                 .next_integer()?
                                 .filter(|v| *v > 0)
                                 .ok_or_else(missing_operand)?;
-                            let expire_sys = std::time::UNIX_EPOCH
-                                + std::time::Duration::from_millis(next_int as u64);
-                            let dur = expire_sys
-                                .duration_since(std::time::SystemTime::now())
-                                .map_err(|_| invalid_operand())?;
-                            if dur.as_millis() == 0 {
-                                invalid_operand();
-                            }
-                            expiration = Expiration::At(Instant::now() + dur);
+                            expiration = Expiration::At(unix_millis_to_instant(next_int));
                         }
                         KEEPTTL => {
                             check_set!(checklist, I_KEEPTTL, I_PXAT, I_PX, I_EX, I_EXAT);
@@ -183,7 +171,7 @@ impl Set {
             }
             SetVariant::GetSet => {
                 let k = parser.next_bytes()?.ok_or_else(missing_operand)?;
-                let v = parser.next().ok_or_else(missing_operand)?;
+                let v = parser.next_value()?.ok_or_else(missing_operand)?;
                 return Ok(Self {
                     key: k,
                     val: v,
@@ -199,7 +187,7 @@ impl Set {
                     .filter(|v| *v > 0)
                     .ok_or_else(missing_operand)? as u64;
                 let expiration = Expiration::At(Instant::now() + Duration::new(next_int, 0));
-                let v = parser.next().ok_or_else(missing_operand)?;
+                let v = parser.next_value()?.ok_or_else(missing_operand)?;
                 return Ok(Self {
                     key: k,
                     val: v,
@@ -218,7 +206,7 @@ impl Set {
                     Instant::now()
                         + Duration::new(next_int as u64 / 1000, (next_int % 1000) as u32 * 1000000),
                 );
-                let v = parser.next().ok_or_else(missing_operand)?;
+                let v = parser.next_value()?.ok_or_else(missing_operand)?;
                 return Ok(Self {
                     key: k,
                     val: v,
@@ -229,7 +217,7 @@ impl Set {
             }
             SetVariant::SetNX => {
                 let k = parser.next_bytes()?.ok_or_else(missing_operand)?;
-                let v = parser.next().ok_or_else(missing_operand)?;
+                let v = parser.next_value()?.ok_or_else(missing_operand)?;
                 return Ok(Self {
                     key: k,
                     val: v,
@@ -266,18 +254,23 @@ impl OneshotExecDB for Set {
     fn get_key(&self) -> &[u8] {
         &self.key.as_ref()
     }
+
+    fn is_write(&self) -> bool {
+        true
+    }
+
+    /// Logged as the value that was set, with its deadline as unix time. A
+    /// key holding anything but a string was not set.
+    fn propagate(&self, _frame: Frame, _ret: &Frame, db: &mut DB) {
+        if !matches!(db.database.get(&self.key), Some(en) if !en.data.is_str()) {
+            db.propagate_key(&self.key);
+        }
+    }
 }
 
 impl DB {
     pub fn set_lite(&mut self, key: Bytes, data: Frame, nounce: u64, expiration: Option<Instant>) {
-        match self.database.insert(
-            key,
-            Entry {
-                data,
-                expiration,
-                nounce,
-            },
-        ) {
+        match self.insert(key, Entry::new(data.into(), expiration, nounce)) {
             Some(en) => {
                 if en.expiration.is_some() {
                     self.expiration.remove(&(en.expiration.unwrap(), en.nounce));
@@ -297,6 +290,16 @@ impl DB {
         expiration: Option<Instant>,
         get: bool,
     ) -> Frame {
+        self.expire_if_needed(&key);
+        if get
+            && self
+                .database
+                .get(&key)
+                .map_or(false, |en| !en.data.reads_as_str())
+        {
+            return wrong_type();
+        }
+        self.preserve(&key);
         match load_behaviour {
             LoadBehavior::None => {
                 if keep_ttl {
@@ -304,39 +307,27 @@ impl DB {
                         if en.expiration.is_some() && en.expiration.unwrap() < Instant::now() {
                             self.expiration.remove(&(en.expiration.unwrap(), en.nounce));
                             en.expiration = None;
-                            en.data = Frame::NullString;
+                            en.data = Frame::NullString.into();
                             en.nounce = nounce;
                         }
-                        return if get {
-                            std::mem::replace(&mut en.data, data)
+                        let ret = if get {
+                            std::mem::replace(&mut en.data, data.into()).into_str_frame()
                         } else {
-                            en.data = data;
+                            en.data = data.into();
                             Frame::Ok
                         };
+                        // the deadline stays, WATCH still has to see the write.
+                        self.renew_nounce(&key);
+                        return ret;
                     } else {
                         self.expiration.update(expiration, nounce, &key);
-                        self.database.insert(
-                            key,
-                            Entry {
-                                data,
-                                expiration,
-                                nounce,
-                            },
-                        );
+                        self.insert(key, Entry::new(data.into(), expiration, nounce));
                         return if get { Frame::NullString } else { Frame::Ok };
                     }
                 }
                 self.expiration.update(expiration, nounce, &key);
                 return self
-                    .database
-                    .insert(
-                        key,
-                        Entry {
-                            data,
-                            expiration,
-                            nounce,
-                        },
-                    )
+                    .insert(key, Entry::new(data.into(), expiration, nounce))
                     .map_or_else(
                         || if get { Frame::NullString } else { Frame::Ok },
                         |en| {
@@ -344,7 +335,7 @@ impl DB {
                                 self.expiration.remove(&(en.expiration.unwrap(), en.nounce));
                             }
                             if get {
-                                en.data
+                                en.data.into_str_frame()
                             } else {
                                 Frame::Ok
                             }
@@ -354,14 +345,7 @@ impl DB {
             LoadBehavior::NX => match self.database.get_mut(&key) {
                 None => {
                     self.expiration.update(expiration, nounce, &key);
-                    self.database.insert(
-                        key,
-                        Entry {
-                            data,
-                            expiration,
-                            nounce,
-                        },
-                    );
+                    self.insert(key, Entry::new(data.into(), expiration, nounce));
                     return Frame::Ok;
                 }
                 _ => {
@@ -382,12 +366,16 @@ impl DB {
                         en.nounce = nounce;
                         self.expiration.update(expiration, nounce, &key);
                     }
-                    return if get {
-                        std::mem::replace(&mut en.data, data)
+                    let ret = if get {
+                        std::mem::replace(&mut en.data, data.into()).into_str_frame()
                     } else {
-                        en.data = data;
+                        en.data = data.into();
                         Frame::Ok
                     };
+                    if keep_ttl {
+                        self.renew_nounce(&key);
+                    }
+                    return ret;
                 }
                 _ => {
                     return Frame::NullString;
@@ -0,0 +1,264 @@
+use crate::{cmd::sets::SetValue, cmd::*, db::Entry, db::Value, impl_traverse_command};
+use rustc_hash::FxHashSet;
+
+const LIMIT: usize = rolling_hash_const(b"limit");
+
+#[derive(Debug, Clone, Copy)]
+pub enum SetAlgebraVariant {
+    SInter,
+    SUnion,
+    SDiff,
+    SInterStore,
+    SUnionStore,
+    SDiffStore,
+    SInterCard,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SetOperator {
+    Inter,
+    Union,
+    Diff,
+    /// Cardinality of the intersection, counting stops at the limit unless
+    /// it is 0.
+    InterCard(usize),
+}
+
+/// Reads the members of every given key living in one database, a missing
+/// key reads as the empty set.
+#[derive(Debug, Clone)]
+pub struct SGather {
+    keys: Vec<MiniCommand>,
+}
+
+impl SGather {
+    pub fn new(keys: Vec<MiniCommand>) -> SGather {
+        Self { keys }
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        Frame::Arrays(
+            self.keys
+                .iter()
+                .map(|cmd| match db.get_set(cmd.ref_single()) {
+                    Ok(Some(set)) => Frame::Arrays(set.iter().map(|m| m.into()).collect()),
+                    Ok(None) => Frame::Arrays(vec![]),
+                    Err(e) => e,
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Overwrites `key` with the combined set, the key is deleted if the set is
+/// empty.
+#[derive(Debug, Clone)]
+pub struct SStore {
+    key: Bytes,
+    members: Vec<Bytes>,
+}
+
+impl SStore {
+    pub fn new(key: Bytes, members: Vec<Bytes>) -> SStore {
+        Self { key, members }
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        db.remove(&self.key);
+        let mut set = SetValue::new();
+        for m in self.members {
+            set.insert(m);
+        }
+        let len = set.len();
+        if len > 0 {
+            db.counter += 1;
+            let nounce = db.counter;
+            db.insert(self.key.clone(), Entry::new(Value::Set(set), None, nounce));
+        }
+        db.propagate_key(&self.key);
+        Frame::Integers(len as i64)
+    }
+}
+
+fn combine(op: SetOperator, frames: Vec<Frame>) -> Frame {
+    let mut sets = Vec::with_capacity(frames.len());
+    for f in frames {
+        match f {
+            Frame::Arrays(members) => sets.push(
+                members
+                    .into_iter()
+                    .filter_map(|m| match m {
+                        Frame::BulkStrings(b) => Some(b),
+                        _ => None,
+                    })
+                    .collect::<FxHashSet<_>>(),
+            ),
+            e => return e,
+        }
+    }
+    let res: Vec<Bytes> = match op {
+        SetOperator::Inter | SetOperator::InterCard(_) => {
+            sets.sort_by_key(|s| s.len());
+            let (smallest, rest) = sets.split_first().unwrap();
+            let common = smallest
+                .iter()
+                .filter(|m| rest.iter().all(|s| s.contains(*m)));
+            match op {
+                SetOperator::InterCard(limit) if limit > 0 => {
+                    return Frame::Integers(common.take(limit).count() as i64)
+                }
+                SetOperator::InterCard(_) => return Frame::Integers(common.count() as i64),
+                _ => common.cloned().collect(),
+            }
+        }
+        SetOperator::Union => {
+            let mut all = FxHashSet::default();
+            for s in sets {
+                all.extend(s);
+            }
+            all.into_iter().collect()
+        }
+        SetOperator::Diff => {
+            let (first, rest) = sets.split_first().unwrap();
+            first
+                .iter()
+                .filter(|m| !rest.iter().any(|s| s.contains(*m)))
+                .cloned()
+                .collect()
+        }
+    };
+    Frame::Arrays(res.into_iter().map(|m| m.into()).collect())
+}
+
+/// Gathers the source sets from their databases, combines them once all
+/// have answered, and stores the result on the database of `dest` if there
+/// is one.
+#[derive(Debug, Clone)]
+pub struct SetAlgebraDispatcher {
+    op: SetOperator,
+    dest: Option<Bytes>,
+    dest_db: usize,
+    db_amount: usize,
+    cmds: Vec<MiniCommand>,
+    cmds_tbl: Vec<Vec<MiniCommand>>,
+    order_tbl: Vec<Vec<usize>>,
+    len: usize,
+}
+
+impl SetAlgebraDispatcher {
+    pub fn new(
+        parser: &mut CommandParser,
+        variant: SetAlgebraVariant,
+    ) -> Result<SetAlgebraDispatcher> {
+        use SetAlgebraVariant::*;
+        let op = match variant {
+            SInter | SInterStore => SetOperator::Inter,
+            SUnion | SUnionStore => SetOperator::Union,
+            SDiff | SDiffStore => SetOperator::Diff,
+            SInterCard => SetOperator::InterCard(0),
+        };
+        let dest = match variant {
+            SInterStore | SUnionStore | SDiffStore => {
+                Some(parser.next_bytes()?.ok_or_else(missing_operand)?)
+            }
+            _ => None,
+        };
+        let numkeys = match variant {
+            SInterCard => parser
+                .next_integer()?
+                .filter(|v| *v > 0)
+                .ok_or_else(invalid_operand)? as usize,
+            _ => parser.len(),
+        };
+        if numkeys == 0 || parser.len() < numkeys {
+            return Err(missing_operand());
+        }
+        let mut cmds = Vec::with_capacity(numkeys);
+        for _ in 0..numkeys {
+            cmds.push(parser.next_bytes()?.ok_or_else(missing_operand)?.into());
+        }
+        let mut op = op;
+        if let Some(option) = parser.next_bytes()? {
+            match (op, rolling_hash(option.as_ref())?) {
+                (SetOperator::InterCard(_), LIMIT) => {
+                    op = SetOperator::InterCard(
+                        parser
+                            .next_integer()?
+                            .filter(|v| *v >= 0)
+                            .ok_or_else(invalid_operand)? as usize,
+                    )
+                }
+                _ => return Err(invalid_operation()),
+            }
+        }
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(Self {
+            op,
+            dest,
+            dest_db: 0,
+            db_amount: 0,
+            cmds,
+            cmds_tbl: Vec::new(),
+            order_tbl: Vec::new(),
+            len: numkeys,
+        })
+    }
+}
+
+use crate::default_pop;
+impl DispatchToMultipleDB for SetAlgebraDispatcher {
+    impl_traverse_command!(@Consts, SGather, default_pop);
+
+    fn get_result_collector(&mut self) -> ResultCollector {
+        let op = self.op;
+        let ret = vec![Frame::NullString; self.len];
+        ResultCollector {
+            result_type: ResultCollectorType::Combine(
+                std::mem::take(&mut self.order_tbl),
+                Box::new(move |frames| combine(op, frames)),
+            ),
+            ret,
+        }
+    }
+
+    fn dispatch(&mut self, db_amount: usize, dispatch_fn: impl Fn(&[u8]) -> usize) {
+        if let Some(dest) = &self.dest {
+            self.dest_db = dispatch_fn(dest);
+        }
+        self.dispatch_sources(db_amount, dispatch_fn);
+    }
+
+    fn complete(&mut self, merged: Frame) -> Completion {
+        match (self.dest.take(), merged) {
+            (Some(dest), Frame::Arrays(members)) => {
+                let members = members
+                    .into_iter()
+                    .filter_map(|m| match m {
+                        Frame::BulkStrings(b) => Some(b),
+                        _ => None,
+                    })
+                    .collect();
+                Completion::Forward((self.dest_db, SStore::new(dest, members).into()))
+            }
+            (_, f) => Completion::Reply(f),
+        }
+    }
+
+    fn locked_databases(&self) -> Vec<usize> {
+        if self.dest.is_none() {
+            return Vec::new();
+        }
+        (0..self.db_amount)
+            .filter(|&db_id| db_id == self.dest_db || !self.cmds_tbl[db_id].is_empty())
+            .collect()
+    }
+}
+
+impl SetAlgebraDispatcher {
+    impl_traverse_command!(@Dispatch, N:N, dispatch_sources);
+}
+
+impl AtomicCMDMarker for SGather {}
+impl AtomicCMDMarker for SStore {}
@@ -0,0 +1,448 @@
+use crate::{
+    cmd::snapshot::{put_bytes, take_bytes, take_len},
+    cmd::*,
+    db::{random_count_out_of_range, wrong_type, Entry, Value, MIN_RANDOM_COUNT},
+    utils::{glob_match, SlotMap},
+};
+use anyhow::Result;
+use rand::{seq::SliceRandom, thread_rng, Rng};
+
+const MATCH: usize = rolling_hash_const(b"match");
+const COUNT: usize = rolling_hash_const(b"count");
+
+/// An integer set is converted into a table once it holds more members than
+/// this, or a member that isn't the canonical form of an `i64`.
+const SET_MAX_INTSET_ENTRIES: usize = 512;
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// Upper bound of vacant slots visited per requested member.
+const VACANT_SLOTS_PER_MEMBER: usize = 10;
+/// Random probes into a table before falling back to a linear walk.
+const RANDOM_MEMBER_TRIES: usize = 32;
+
+#[derive(Debug)]
+pub enum SetValue {
+    /// Sets of integers only are a sorted vector, searched by bisection.
+    Ints(Vec<i64>),
+    Table(SlotMap<()>),
+}
+
+/// `Some` if `member` reads back exactly as it is written, so `"+1"` or
+/// `"01"` stay strings.
+fn as_int(member: &[u8]) -> Option<i64> {
+    let v = std::str::from_utf8(member).ok()?.parse::<i64>().ok()?;
+    if v.to_string().as_bytes() == member {
+        Some(v)
+    } else {
+        None
+    }
+}
+
+impl SetValue {
+    pub fn new() -> Self {
+        SetValue::Ints(Vec::new())
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            SetValue::Ints(v) => v.len(),
+            SetValue::Table(t) => t.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        match self {
+            SetValue::Ints(v) => as_int(member).map_or(false, |i| v.binary_search(&i).is_ok()),
+            SetValue::Table(t) => t.get(member).is_some(),
+        }
+    }
+
+    /// Returns whether `member` is new.
+    pub fn insert(&mut self, member: Bytes) -> bool {
+        if let SetValue::Ints(v) = self {
+            if let Some(i) = as_int(&member) {
+                match v.binary_search(&i) {
+                    Ok(_) => return false,
+                    Err(idx) if v.len() < SET_MAX_INTSET_ENTRIES => {
+                        v.insert(idx, i);
+                        return true;
+                    }
+                    Err(_) => (),
+                }
+            }
+            let mut table = SlotMap::new();
+            for i in v.drain(..) {
+                table.insert(Bytes::from(i.to_string()), ());
+            }
+            *self = SetValue::Table(table);
+        }
+        match self {
+            SetValue::Table(t) => t.insert(member, ()).is_none(),
+            SetValue::Ints(_) => unreachable!(),
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self {
+            SetValue::Ints(v) => match as_int(member).map(|i| v.binary_search(&i)) {
+                Some(Ok(idx)) => {
+                    v.remove(idx);
+                    true
+                }
+                _ => false,
+            },
+            SetValue::Table(t) => t.remove(member).is_some(),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Bytes> + '_> {
+        match self {
+            SetValue::Ints(v) => Box::new(v.iter().map(|i| Bytes::from(i.to_string()))),
+            SetValue::Table(t) => Box::new(t.iter().map(|(m, _)| m.clone())),
+        }
+    }
+
+    pub fn random(&self, rng: &mut impl Rng) -> Option<Bytes> {
+        match self {
+            SetValue::Ints(v) => v.choose(rng).map(|i| Bytes::from(i.to_string())),
+            SetValue::Table(t) => {
+                if t.len() == 0 {
+                    return None;
+                }
+                let len = t.slots_len();
+                (0..RANDOM_MEMBER_TRIES)
+                    .find_map(|_| t.slot(rng.gen_range(0..len)))
+                    .or_else(|| t.iter().nth(rng.gen_range(0..t.len())))
+                    .map(|(m, _)| m.clone())
+            }
+        }
+    }
+
+    pub fn pop_random(&mut self, rng: &mut impl Rng) -> Option<Bytes> {
+        let member = self.random(rng)?;
+        self.remove(&member);
+        Some(member)
+    }
+
+    /// Payload size in bytes.
+    pub fn bytes_len(&self) -> usize {
+        match self {
+            SetValue::Ints(v) => v.len() * std::mem::size_of::<i64>(),
+            SetValue::Table(t) => t.iter().fold(0, |res, (m, _)| res + m.len()),
+        }
+    }
+
+    pub fn dump(&self, buf: &mut BytesMut) {
+        buf.put_u64_le(self.len() as u64);
+        for member in self.iter() {
+            put_bytes(buf, &member);
+        }
+    }
+
+    pub fn restore(buf: &mut Bytes) -> Result<SetValue> {
+        let mut set = SetValue::new();
+        for _ in 0..take_len(buf)? {
+            set.insert(take_bytes(buf)?);
+        }
+        Ok(set)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SetsVariant {
+    SAdd,
+    SRem,
+    SIsMember,
+    SMIsMember,
+    SMembers,
+    SCard,
+    SPop,
+    SRandMember,
+    SScan,
+}
+
+#[derive(Debug, Clone)]
+enum SetsOp {
+    Add(Vec<Bytes>),
+    Rem(Vec<Bytes>),
+    IsMember(Bytes),
+    MIsMember(Vec<Bytes>),
+    Members,
+    Card,
+    Pop(Option<i64>),
+    RandMember(Option<i64>),
+    Scan {
+        cursor: usize,
+        pattern: Option<Bytes>,
+        count: usize,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Sets {
+    key: Bytes,
+    op: SetsOp,
+}
+
+fn next_members(parser: &mut CommandParser) -> Result<Vec<Bytes>> {
+    let mut members = Vec::with_capacity(parser.len());
+    while let Some(v) = parser.next_bytes()? {
+        members.push(v);
+    }
+    if members.is_empty() {
+        return Err(missing_operand());
+    }
+    Ok(members)
+}
+
+impl Sets {
+    pub fn new(parser: &mut CommandParser, variant: SetsVariant) -> Result<Sets> {
+        use SetsVariant::*;
+        let key = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let op = match variant {
+            SAdd => SetsOp::Add(next_members(parser)?),
+            SRem => SetsOp::Rem(next_members(parser)?),
+            SIsMember => SetsOp::IsMember(parser.next_bytes()?.ok_or_else(missing_operand)?),
+            SMIsMember => SetsOp::MIsMember(next_members(parser)?),
+            SMembers => SetsOp::Members,
+            SCard => SetsOp::Card,
+            SPop => SetsOp::Pop(match parser.next_integer()? {
+                Some(v) if v < 0 => return Err(invalid_operand()),
+                v => v,
+            }),
+            SRandMember => SetsOp::RandMember(parser.next_integer()?),
+            SScan => {
+                let cursor = parser
+                    .next_integer()?
+                    .filter(|v| *v >= 0)
+                    .ok_or_else(invalid_operand)? as usize;
+                let (mut pattern, mut count) = (None, DEFAULT_SCAN_COUNT);
+                while let Some(option) = parser.next_bytes()? {
+                    match rolling_hash(option.as_ref())? {
+                        MATCH => pattern = Some(parser.next_bytes()?.ok_or_else(missing_operand)?),
+                        COUNT => {
+                            count = parser
+                                .next_integer()?
+                                .filter(|v| *v > 0)
+                                .ok_or_else(invalid_operand)?
+                                as usize
+                        }
+                        _ => return Err(invalid_operation()),
+                    }
+                }
+                SetsOp::Scan {
+                    cursor,
+                    pattern,
+                    count,
+                }
+            }
+        };
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(Self { key, op })
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        let key = &self.key;
+        match self.op {
+            SetsOp::Add(members) => db.sadd(key, members),
+            SetsOp::Rem(members) => db.srem(key, &members),
+            SetsOp::Pop(count) => db.spop(key, count),
+            op => {
+                let set = match db.get_set(key) {
+                    Ok(s) => s,
+                    Err(e) => return e,
+                };
+                set_read(set.map(|s| &*s), op)
+            }
+        }
+    }
+}
+
+impl OneshotExecDB for Sets {
+    fn get_key(&self) -> &[u8] {
+        self.key.as_ref()
+    }
+
+    fn is_write(&self) -> bool {
+        matches!(self.op, SetsOp::Add(_) | SetsOp::Rem(_) | SetsOp::Pop(_))
+    }
+
+    /// SPOP picks members at random, it is logged as the removal of those
+    /// it replied with.
+    fn propagate(&self, frame: Frame, ret: &Frame, db: &mut DB) {
+        let popped = match (&self.op, ret) {
+            (SetsOp::Pop(_), Frame::BulkStrings(m)) => vec![m.clone()],
+            (SetsOp::Pop(_), Frame::Arrays(members)) => members
+                .iter()
+                .filter_map(|m| match m {
+                    Frame::BulkStrings(m) => Some(m.clone()),
+                    _ => None,
+                })
+                .collect(),
+            (SetsOp::Pop(_), _) => return,
+            _ => return db.propagate(frame),
+        };
+        if !popped.is_empty() {
+            let mut args = vec![Bytes::from_static(b"SREM"), self.key.clone()];
+            args.extend(popped);
+            db.propagate(command(args));
+        }
+    }
+}
+
+fn to_array(members: Vec<Bytes>) -> Frame {
+    Frame::Arrays(members.into_iter().map(|m| m.into()).collect())
+}
+
+/// Commands that leave the set untouched, `set` is `None` for a missing key.
+fn set_read(set: Option<&SetValue>, op: SetsOp) -> Frame {
+    let empty = SetValue::new();
+    let is_missing = set.is_none();
+    let set = set.unwrap_or(&empty);
+    match op {
+        SetsOp::IsMember(member) => Frame::Integers(set.contains(&member) as i64),
+        SetsOp::MIsMember(members) => Frame::Arrays(
+            members
+                .iter()
+                .map(|m| Frame::Integers(set.contains(m) as i64))
+                .collect(),
+        ),
+        SetsOp::Members => to_array(set.iter().collect()),
+        SetsOp::Card => Frame::Integers(set.len() as i64),
+        SetsOp::RandMember(count) => {
+            let mut rng = thread_rng();
+            match count {
+                None if is_missing => Frame::NullString,
+                None => set.random(&mut rng).map_or(Frame::NullString, |m| m.into()),
+                Some(n) if n >= 0 => {
+                    let mut all: Vec<_> = set.iter().collect();
+                    let n = (n as usize).min(all.len());
+                    let (picked, _) = all.partial_shuffle(&mut rng, n);
+                    to_array(picked.to_vec())
+                }
+                Some(n) if n < MIN_RANDOM_COUNT => random_count_out_of_range(),
+                // a negative count allows the same member to be picked again.
+                Some(n) => to_array(
+                    (0..n.unsigned_abs())
+                        .map_while(|_| set.random(&mut rng))
+                        .collect(),
+                ),
+            }
+        }
+        SetsOp::Scan {
+            cursor,
+            pattern,
+            count,
+        } => {
+            let matches = |m: &[u8]| pattern.as_ref().map_or(true, |p| glob_match(p, m));
+            let (next, found) = match set {
+                // an integer set is small enough to be returned at once.
+                SetValue::Ints(_) => (0, set.iter().filter(|m| matches(m)).collect()),
+                SetValue::Table(t) => {
+                    let end = t
+                        .slots_len()
+                        .min(cursor.saturating_add(count * VACANT_SLOTS_PER_MEMBER));
+                    let (mut idx, mut examined, mut found) = (cursor, 0, Vec::new());
+                    while idx < end && examined < count {
+                        if let Some((m, _)) = t.slot(idx) {
+                            examined += 1;
+                            if matches(m) {
+                                found.push(m.clone());
+                            }
+                        }
+                        idx += 1;
+                    }
+                    (if idx >= t.slots_len() { 0 } else { idx }, found)
+                }
+            };
+            Frame::Arrays(vec![Bytes::from(next.to_string()).into(), to_array(found)])
+        }
+        SetsOp::Add(_) | SetsOp::Rem(_) | SetsOp::Pop(_) => {
+            unreachable!("writes are handled by `Sets::exec`")
+        }
+    }
+}
+
+impl DB {
+    /// `Ok(None)` if there is no such key, `Err` carries the error reply.
+    pub fn get_set(&mut self, key: &Bytes) -> std::result::Result<Option<&mut SetValue>, Frame> {
+        match self.get_live(key).map(|en| &mut en.data) {
+            None => Ok(None),
+            Some(Value::Set(s)) => Ok(Some(s)),
+            Some(_) => Err(wrong_type()),
+        }
+    }
+
+    /// Like `get_set`, but creates the set if there is no such key.
+    pub fn get_or_create_set(&mut self, key: &Bytes) -> std::result::Result<&mut SetValue, Frame> {
+        if self.get_set(key)?.is_none() {
+            self.counter += 1;
+            let nounce = self.counter;
+            self.insert(
+                key.clone(),
+                Entry::new(Value::Set(SetValue::new()), None, nounce),
+            );
+        }
+        Ok(self.get_set(key)?.unwrap())
+    }
+
+    fn sadd(&mut self, key: &Bytes, members: Vec<Bytes>) -> Frame {
+        let set = match self.get_or_create_set(key) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        let added = members
+            .into_iter()
+            .filter(|m| set.insert(m.clone()))
+            .count();
+        self.after_write(key, false);
+        Frame::Integers(added as i64)
+    }
+
+    fn srem(&mut self, key: &Bytes, members: &[Bytes]) -> Frame {
+        let set = match self.get_set(key) {
+            Ok(Some(s)) => s,
+            Ok(None) => return Frame::Integers(0),
+            Err(e) => return e,
+        };
+        let removed = members.iter().filter(|m| set.remove(m)).count();
+        let emptied = set.is_empty();
+        if removed > 0 {
+            self.after_write(key, emptied);
+        }
+        Frame::Integers(removed as i64)
+    }
+
+    fn spop(&mut self, key: &Bytes, count: Option<i64>) -> Frame {
+        let set = match self.get_set(key) {
+            Ok(Some(s)) => s,
+            Ok(None) if count.is_some() => return Frame::Arrays(vec![]),
+            Ok(None) => return Frame::NullString,
+            Err(e) => return e,
+        };
+        let mut rng = thread_rng();
+        let res: Vec<Bytes> = match count {
+            None => set.pop_random(&mut rng).into_iter().collect(),
+            Some(n) => (0..n).map_while(|_| set.pop_random(&mut rng)).collect(),
+        };
+        let emptied = set.is_empty();
+        if !res.is_empty() {
+            self.after_write(key, emptied);
+        }
+        match count {
+            None => res
+                .into_iter()
+                .next()
+                .map_or(Frame::NullString, |m| m.into()),
+            Some(_) => to_array(res),
+        }
+    }
+}
+
+impl AtomicCMDMarker for Sets {}
@@ -0,0 +1,500 @@
+use crate::{
+    cmd::hash::HashValue,
+    cmd::hyperloglog::HyperLogLog,
+    cmd::sets::SetValue,
+    cmd::stream::StreamValue,
+    cmd::zset::ZSetValue,
+    cmd::*,
+    db::{Entry, Value, DB},
+    utils::instant_to_unix_millis,
+};
+use anyhow::{anyhow, Result};
+use std::collections::{HashSet, VecDeque};
+use tokio::time::Instant;
+use tracing::{error, warn};
+
+/// Leads every snapshot file, the last two digits are the format version.
+pub const SNAPSHOT_MAGIC: &[u8] = b"ASYNCREDIS01";
+const RECORD: u8 = 1;
+const END: u8 = 0xff;
+
+const STR: u8 = 0;
+const INT: u8 = 1;
+const LIST: u8 = 2;
+const HASH: u8 = 3;
+const SET: u8 = 4;
+const ZSET: u8 = 5;
+const STREAM: u8 = 6;
+const HYPERLOGLOG: u8 = 7;
+
+/// Keys written out by a database between two tasks while a background
+/// save is running.
+pub const SNAPSHOT_KEYS_PER_STEP: usize = 128;
+
+/// Stands for a missing deadline.
+pub const NO_EXPIRATION: i64 = -1;
+
+#[derive(Debug, Clone, Copy)]
+pub enum SnapshotVariant {
+    Save,
+    BgSave,
+    LastSave,
+    BgRewriteAof,
+    ExportRdb,
+}
+
+impl SnapshotVariant {
+    pub fn new(parser: &mut CommandParser, variant: SnapshotVariant) -> Result<SnapshotVariant> {
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(variant)
+    }
+}
+
+pub fn corrupt() -> anyhow::Error {
+    anyhow!("corrupt snapshot")
+}
+
+pub fn put_bytes(buf: &mut BytesMut, b: &[u8]) {
+    buf.put_u64_le(b.len() as u64);
+    buf.put_slice(b);
+}
+
+pub fn take_u8(buf: &mut Bytes) -> Result<u8> {
+    if buf.remaining() < 1 {
+        return Err(corrupt());
+    }
+    Ok(buf.get_u8())
+}
+
+pub fn take_u64(buf: &mut Bytes) -> Result<u64> {
+    if buf.remaining() < 8 {
+        return Err(corrupt());
+    }
+    Ok(buf.get_u64_le())
+}
+
+pub fn take_i64(buf: &mut Bytes) -> Result<i64> {
+    take_u64(buf).map(|v| v as i64)
+}
+
+pub fn take_f64(buf: &mut Bytes) -> Result<f64> {
+    take_u64(buf).map(f64::from_bits)
+}
+
+/// A length read from the file, checked against what is left of it so that
+/// a corrupt one can't make us reserve absurd amounts of memory.
+pub fn take_len(buf: &mut Bytes) -> Result<usize> {
+    let len = take_u64(buf)?;
+    if len > buf.remaining() as u64 {
+        return Err(corrupt());
+    }
+    Ok(len as usize)
+}
+
+pub fn take_bytes(buf: &mut Bytes) -> Result<Bytes> {
+    let len = take_len(buf)?;
+    Ok(buf.split_to(len))
+}
+
+/// A deadline as unix time in milliseconds, the monotonic clock means
+/// nothing to the next process.
+pub fn put_deadline(buf: &mut BytesMut, at: Option<Instant>) {
+    buf.put_i64_le(at.map_or(NO_EXPIRATION, instant_to_unix_millis));
+}
+
+pub fn take_deadline(buf: &mut Bytes) -> Result<Option<i64>> {
+    let at = take_i64(buf)?;
+    Ok(Some(at).filter(|v| *v != NO_EXPIRATION))
+}
+
+impl Value {
+    /// Fails on strings set from a frame that holds no bytes.
+    pub fn dump(&self, buf: &mut BytesMut) -> Result<()> {
+        match self {
+            Value::Str(Frame::Integers(i)) => {
+                buf.put_u8(INT);
+                buf.put_i64_le(*i);
+            }
+            Value::Str(_) | Value::MutStr(_) => {
+                let b = self.str_bytes()?;
+                buf.put_u8(STR);
+                put_bytes(buf, &b);
+            }
+            Value::List(l) => {
+                buf.put_u8(LIST);
+                buf.put_u64_le(l.len() as u64);
+                for element in l {
+                    put_bytes(buf, element);
+                }
+            }
+            Value::Hash(h) => {
+                buf.put_u8(HASH);
+                h.dump(buf);
+            }
+            Value::Set(s) => {
+                buf.put_u8(SET);
+                s.dump(buf);
+            }
+            Value::ZSet(z) => {
+                buf.put_u8(ZSET);
+                z.dump(buf);
+            }
+            Value::Stream(s) => {
+                buf.put_u8(STREAM);
+                s.dump(buf);
+            }
+            Value::HyperLogLog(h) => {
+                buf.put_u8(HYPERLOGLOG);
+                h.dump(buf);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn restore(buf: &mut Bytes) -> Result<Value> {
+        Ok(match take_u8(buf)? {
+            INT => Value::Str(Frame::Integers(take_i64(buf)?)),
+            STR => Value::Str(Frame::BulkStrings(take_bytes(buf)?)),
+            LIST => {
+                let len = take_len(buf)?;
+                let mut l = VecDeque::with_capacity(len);
+                for _ in 0..len {
+                    l.push_back(take_bytes(buf)?);
+                }
+                Value::List(l)
+            }
+            HASH => Value::Hash(HashValue::restore(buf)?),
+            SET => Value::Set(SetValue::restore(buf)?),
+            ZSET => Value::ZSet(ZSetValue::restore(buf)?),
+            STREAM => Value::Stream(StreamValue::restore(buf)?),
+            HYPERLOGLOG => Value::HyperLogLog(HyperLogLog::restore(buf)?),
+            _ => return Err(corrupt()),
+        })
+    }
+
+    /// Collections are never stored empty, a hash may come out of a
+    /// snapshot that way once its expired fields are left out.
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::List(l) => l.is_empty(),
+            Value::Hash(h) => h.is_empty(),
+            Value::Set(s) => s.is_empty(),
+            Value::ZSet(z) => z.is_empty(),
+            _ => false,
+        }
+    }
+}
+
+/// A key read back from a snapshot, with its deadline as unix time in
+/// milliseconds.
+pub type LoadedEntry = (Bytes, Value, Option<i64>);
+
+/// Reads the records of a snapshot file one at a time.
+pub struct SnapshotReader {
+    buf: Bytes,
+}
+
+impl SnapshotReader {
+    pub fn new(mut buf: Bytes) -> Result<SnapshotReader> {
+        if !buf.starts_with(SNAPSHOT_MAGIC) {
+            return Err(anyhow!("not a snapshot, or one of another version"));
+        }
+        buf.advance(SNAPSHOT_MAGIC.len());
+        Ok(Self { buf })
+    }
+
+    /// `None` once the end marker is reached, a file cut short is an error.
+    pub fn next_entry(&mut self) -> Result<Option<LoadedEntry>> {
+        match take_u8(&mut self.buf)? {
+            RECORD => {
+                let key = take_bytes(&mut self.buf)?;
+                let expiration = take_deadline(&mut self.buf)?;
+                let value = Value::restore(&mut self.buf)?;
+                Ok(Some((key, value, expiration)))
+            }
+            END => Ok(None),
+            _ => Err(corrupt()),
+        }
+    }
+}
+
+pub fn snapshot_end() -> Bytes {
+    Bytes::from_static(&[END])
+}
+
+/// A piece of a snapshot, along with whether it is the last one of its
+/// database. The file is complete once every database sent its last piece.
+pub type SnapshotChunk = (Bytes, bool);
+
+/// The layout keys are written in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnapshotFormat {
+    /// Our own snapshot file, which keeps every value as it is.
+    Native,
+    /// A Redis RDB file, which has no room for some values.
+    Rdb,
+}
+
+/// Writes every key of a database to `out`. `whole` does it in one go,
+/// otherwise a few keys are written between two tasks until all are done.
+/// Replies once the last key is written, with how many keys the format had
+/// no room for.
+#[derive(Debug)]
+pub struct Snapshot {
+    out: mpsc::UnboundedSender<SnapshotChunk>,
+    whole: bool,
+    format: SnapshotFormat,
+}
+
+impl Snapshot {
+    pub fn new(
+        out: mpsc::UnboundedSender<SnapshotChunk>,
+        whole: bool,
+        format: SnapshotFormat,
+    ) -> Snapshot {
+        Self { out, whole, format }
+    }
+}
+
+/// A snapshot being written by a database, `cursor` walks `DB::slots`,
+/// which keep their place while keys come and go. It holds the keys as they
+/// were when it began: a key the cursor hasn't reached is written ahead of
+/// it before anything changes it.
+#[derive(Debug)]
+pub struct SnapshotJob {
+    out: mpsc::UnboundedSender<SnapshotChunk>,
+    cursor: usize,
+    /// Slots past the cursor to pass over, their key is written already or
+    /// was created after the snapshot began.
+    ahead: HashSet<usize>,
+    /// What is sent with the next chunk.
+    buf: BytesMut,
+    failed: bool,
+    ret_tx: oneshot::Sender<Frame>,
+    format: SnapshotFormat,
+    /// Keys the format has no room for.
+    skipped: usize,
+}
+
+impl SnapshotJob {
+    /// Leaves the key created in `slot` out of the snapshot.
+    pub fn skip(&mut self, slot: usize) {
+        if slot >= self.cursor {
+            self.ahead.insert(slot);
+        }
+    }
+
+    /// Writes `key` to the buffer, a key that fails to be written fails the
+    /// whole save.
+    fn put(&mut self, db_id: usize, key: &Bytes, en: &Entry, now: Instant) {
+        if en.is_expired(now) {
+            return;
+        }
+        let res = match self.format {
+            SnapshotFormat::Native => {
+                self.buf.put_u8(RECORD);
+                put_bytes(&mut self.buf, key);
+                put_deadline(&mut self.buf, en.expiration);
+                en.data.dump(&mut self.buf)
+            }
+            SnapshotFormat::Rdb => put_rdb_entry(&mut self.buf, key, en, now)
+                .map(|written| self.skipped += !written as usize),
+        };
+        if let Err(e) = res {
+            error!("failed to write key {:?} of database {}: {}", key, db_id, e);
+            self.failed = true;
+        }
+    }
+}
+
+/// Inserts keys read back from a snapshot.
+#[derive(Debug)]
+pub struct Load {
+    pub entries: Vec<(Bytes, Value, Option<Instant>)>,
+}
+
+impl Load {
+    pub fn exec(self, db: &mut DB) -> Frame {
+        let now = Instant::now();
+        let mut loaded = 0;
+        for (key, value, expiration) in self.entries {
+            if value.is_empty_collection() || matches!(expiration, Some(ex) if ex <= now) {
+                continue;
+            }
+            db.load_entry(key, value, expiration);
+            loaded += 1;
+        }
+        Frame::Integers(loaded)
+    }
+}
+
+impl DB {
+    pub fn begin_snapshot(&mut self, cmd: Snapshot, ret_tx: oneshot::Sender<Frame>) {
+        if self.snapshot.is_some() {
+            let _ = ret_tx.send(Frame::Errors(Bytes::from_static(
+                b"ERR Background save already in progress",
+            )));
+            return;
+        }
+        self.snapshot = Some(SnapshotJob {
+            out: cmd.out,
+            cursor: 0,
+            ahead: HashSet::new(),
+            buf: BytesMut::new(),
+            failed: false,
+            ret_tx,
+            format: cmd.format,
+            skipped: 0,
+        });
+        self.snapshot_step(if cmd.whole {
+            usize::MAX
+        } else {
+            SNAPSHOT_KEYS_PER_STEP
+        });
+    }
+
+    /// Writes up to `budget` keys of the running snapshot.
+    pub fn snapshot_step(&mut self, budget: usize) {
+        let mut job = match self.snapshot.take() {
+            Some(job) => job,
+            None => return,
+        };
+        let now = Instant::now();
+        let mut written = 0;
+        while written < budget && job.cursor < self.slots.len() && !job.failed {
+            let slot = job.cursor;
+            job.cursor += 1;
+            if job.ahead.remove(&slot) {
+                continue;
+            }
+            if let Some((key, en)) = self
+                .slots
+                .get(slot)
+                .and_then(|key| self.database.get_key_value(key))
+            {
+                job.put(self.id, key, en, now);
+                written += 1;
+            }
+        }
+        // the file misses the last chunk of this database, which fails the
+        // save.
+        if job.failed {
+            let _ = job.ret_tx.send(Frame::Errors(Bytes::from_static(
+                b"ERR failed to write the snapshot",
+            )));
+            return;
+        }
+        let last = job.cursor >= self.slots.len();
+        let _ = job.out.send((job.buf.split().freeze(), last));
+        if last {
+            if job.skipped > 0 {
+                warn!(
+                    "{} keys of database {} left out of the RDB file, it has no room for streams",
+                    job.skipped, self.id
+                );
+            }
+            let _ = job.ret_tx.send(Frame::Integers(job.skipped as i64));
+        } else {
+            self.snapshot = Some(job);
+        }
+    }
+
+    /// Called before anything changes `key`: the running snapshot gets it
+    /// as it is now if its cursor hasn't reached it yet.
+    pub fn preserve(&mut self, key: &[u8]) {
+        let job = match &mut self.snapshot {
+            Some(job) => job,
+            None => return,
+        };
+        if let Some((key, en)) = self.database.get_key_value(key) {
+            if en.slot() >= job.cursor && job.ahead.insert(en.slot()) {
+                job.put(self.id, key, en, Instant::now());
+            }
+        }
+    }
+
+    pub fn load_entry(&mut self, key: Bytes, value: Value, expiration: Option<Instant>) {
+        if let Value::Hash(h) = &value {
+            let deadlines: Vec<(Instant, Bytes)> = h.field_deadlines().collect();
+            for (at, field) in deadlines {
+                self.counter += 1;
+                self.expiration.update_field(at, self.counter, &key, &field);
+            }
+        }
+        self.counter += 1;
+        let nounce = self.counter;
+        if let Some(old) = self.insert(key.clone(), Entry::new(value, expiration, nounce)) {
+            if let Some(ex) = old.expiration {
+                self.expiration.remove(&(ex, old.nounce));
+            }
+        }
+        self.expiration.update(expiration, nounce, &key);
+    }
+}
+
+impl AtomicCMDMarker for Snapshot {}
+impl AtomicCMDMarker for Load {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast;
+
+    fn db() -> DB {
+        let (shutdown_tx, _) = broadcast::channel(1);
+        let mut db = DB::new(0, shutdown_tx);
+        db.loading = false;
+        db
+    }
+
+    fn set(db: &mut DB, key: &str, value: &str) {
+        db.counter += 1;
+        let nounce = db.counter;
+        let value = Value::Str(Frame::BulkStrings(Bytes::copy_from_slice(value.as_bytes())));
+        db.insert(
+            Bytes::copy_from_slice(key.as_bytes()),
+            Entry::new(value, None, nounce),
+        );
+    }
+
+    #[test]
+    fn background_snapshot_keeps_keys_as_they_were_when_it_began() {
+        let mut db = db();
+        let total = SNAPSHOT_KEYS_PER_STEP * 3;
+        for i in 0..total {
+            set(&mut db, &format!("k{}", i), "old");
+        }
+        let (out, mut chunks) = mpsc::unbounded_channel();
+        let (ret_tx, _ret_rx) = oneshot::channel();
+        db.begin_snapshot(Snapshot::new(out, false, SnapshotFormat::Native), ret_tx);
+        for i in (0..total).rev() {
+            match i % 3 {
+                0 => set(&mut db, &format!("k{}", i), "new"),
+                1 => drop(db.remove(format!("k{}", i).as_bytes())),
+                // takes one of the slots freed above.
+                _ => set(&mut db, &format!("new{}", i), "new"),
+            }
+        }
+        while db.snapshot.is_some() {
+            db.snapshot_step(SNAPSHOT_KEYS_PER_STEP);
+        }
+
+        let mut buf = BytesMut::from(SNAPSHOT_MAGIC);
+        while let Ok((chunk, _)) = chunks.try_recv() {
+            buf.extend_from_slice(&chunk);
+        }
+        buf.extend_from_slice(&snapshot_end());
+        let mut reader = SnapshotReader::new(buf.freeze()).unwrap();
+        let mut keys = Vec::new();
+        while let Some((key, value, _)) = reader.next_entry().unwrap() {
+            assert_eq!(value.str_bytes().unwrap(), "old");
+            keys.push(key);
+        }
+        keys.sort();
+        let mut expected: Vec<Bytes> = (0..total).map(|i| format!("k{}", i).into()).collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+}
@@ -0,0 +1,708 @@
+use crate::{
+    cmd::snapshot::{corrupt, put_bytes, take_bytes, take_len, take_u64},
+    cmd::stream_group::ConsumerGroup,
+    cmd::*,
+    db::{wrong_type, Entry, Value},
+    utils::unix_millis_now,
+};
+use anyhow::Result;
+use rustc_hash::FxHashMap;
+use std::{collections::BTreeMap, fmt};
+
+const NOMKSTREAM: usize = rolling_hash_const(b"nomkstream");
+const MAXLEN: usize = rolling_hash_const(b"maxlen");
+const MINID: usize = rolling_hash_const(b"minid");
+const LIMIT: usize = rolling_hash_const(b"limit");
+const COUNT: usize = rolling_hash_const(b"count");
+
+/// Entries per block, a new block is started once the last one is full.
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+const ID_TOO_SMALL_ERR: &[u8] =
+    b"ERR The ID specified in XADD is equal or smaller than the target stream top item";
+const ID_ZERO_ERR: &[u8] = b"ERR The ID specified in XADD must be greater than 0-0";
+const ID_EXHAUSTED_ERR: &[u8] =
+    b"ERR The stream has exhausted the last possible ID, unable to add more items";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// Parses `<ms>-<seq>`, or `<ms>` alone with `missing_seq` filled in.
+    pub fn parse(raw: &[u8], missing_seq: u64) -> Result<StreamId> {
+        let raw = std::str::from_utf8(raw).map_err(|_| invalid_operand())?;
+        let (ms, seq) = match raw.split_once('-') {
+            Some((ms, seq)) => (ms, Some(seq)),
+            None => (raw, None),
+        };
+        Ok(StreamId {
+            ms: ms.parse().map_err(|_| invalid_operand())?,
+            seq: match seq {
+                Some(seq) => seq.parse().map_err(|_| invalid_operand())?,
+                None => missing_seq,
+            },
+        })
+    }
+
+    /// The smallest ID after this one.
+    pub fn next(&self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId {
+                ms: self.ms.checked_add(1)?,
+                seq: 0,
+            }),
+        }
+    }
+
+    /// The largest ID before this one.
+    pub fn prev(&self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId {
+                ms: self.ms.checked_sub(1)?,
+                seq: u64::MAX,
+            }),
+        }
+    }
+
+    pub fn to_bytes(self) -> Bytes {
+        Bytes::from(self.to_string())
+    }
+
+    pub fn dump(&self, buf: &mut BytesMut) {
+        buf.put_u64_le(self.ms);
+        buf.put_u64_le(self.seq);
+    }
+
+    pub fn restore(buf: &mut Bytes) -> Result<StreamId> {
+        Ok(StreamId {
+            ms: take_u64(buf)?,
+            seq: take_u64(buf)?,
+        })
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// Parses a range bound: `-`, `+`, an ID, or an ID preceded by `(` to leave
+/// it out of the range. A bare `<ms>` covers every sequence number.
+pub fn parse_range_bound(raw: &[u8], is_start: bool) -> Result<StreamId> {
+    match raw {
+        b"-" => return Ok(StreamId::MIN),
+        b"+" => return Ok(StreamId::MAX),
+        _ => (),
+    }
+    let (exclusive, raw) = match raw.first() {
+        Some(b'(') => (true, &raw[1..]),
+        _ => (false, raw),
+    };
+    let id = StreamId::parse(raw, if is_start { 0 } else { u64::MAX })?;
+    if !exclusive {
+        Ok(id)
+    } else if is_start {
+        id.next().ok_or_else(invalid_operand)
+    } else {
+        id.prev().ok_or_else(invalid_operand)
+    }
+}
+
+pub type StreamFields = Vec<(Bytes, Bytes)>;
+
+/// Entries live in blocks of up to `STREAM_NODE_MAX_ENTRIES`, each block is
+/// keyed by an ID no larger than any of its entries.
+#[derive(Debug)]
+pub struct StreamValue {
+    blocks: BTreeMap<StreamId, Vec<(StreamId, StreamFields)>>,
+    len: usize,
+    pub last_id: StreamId,
+    pub groups: FxHashMap<Bytes, ConsumerGroup>,
+}
+
+impl StreamValue {
+    pub fn new() -> Self {
+        Self {
+            blocks: BTreeMap::new(),
+            len: 0,
+            last_id: StreamId::MIN,
+            groups: FxHashMap::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Appends an entry, `id` has to be larger than `last_id`.
+    pub fn append(&mut self, id: StreamId, fields: StreamFields) {
+        match self.blocks.values_mut().next_back() {
+            Some(block) if block.len() < STREAM_NODE_MAX_ENTRIES => block.push((id, fields)),
+            _ => {
+                self.blocks.insert(id, vec![(id, fields)]);
+            }
+        }
+        self.len += 1;
+        self.last_id = id;
+    }
+
+    /// Entries from `start` to `end` inclusive, ascending.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl DoubleEndedIterator<Item = &(StreamId, StreamFields)> {
+        // the block holding `start` may be keyed below it.
+        let first = self
+            .blocks
+            .range(..=start)
+            .next_back()
+            .map_or(start, |(k, _)| *k);
+        self.blocks
+            .range(first..=end.max(first))
+            .flat_map(|(_, block)| block.iter())
+            .filter(move |(id, _)| *id >= start && *id <= end)
+    }
+
+    pub fn get(&self, id: StreamId) -> Option<&StreamFields> {
+        let (_, block) = self.blocks.range(..=id).next_back()?;
+        block
+            .binary_search_by_key(&id, |e| e.0)
+            .ok()
+            .map(|idx| &block[idx].1)
+    }
+
+    pub fn remove(&mut self, id: StreamId) -> bool {
+        let key = match self.blocks.range(..=id).next_back() {
+            Some((k, _)) => *k,
+            None => return false,
+        };
+        let block = self.blocks.get_mut(&key).unwrap();
+        match block.binary_search_by_key(&id, |e| e.0) {
+            Ok(idx) => {
+                block.remove(idx);
+                if block.is_empty() {
+                    self.blocks.remove(&key);
+                }
+                self.len -= 1;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Evicts the oldest entries while `evict` holds, returns how many were
+    /// removed. `approx` only evicts whole blocks, `limit` bounds the
+    /// number of entries removed.
+    pub fn trim(
+        &mut self,
+        evict: impl Fn(usize, StreamId) -> bool,
+        approx: bool,
+        limit: Option<usize>,
+    ) -> usize {
+        let mut removed = 0;
+        while let Some(mut entry) = self.blocks.first_entry() {
+            let block = entry.get_mut();
+            let block_len = block.len();
+            let last = block[block_len - 1].0;
+            // drops the block as a whole if all of it is due.
+            if evict(self.len - block_len + 1, last)
+                && limit.map_or(true, |l| removed + block_len <= l)
+            {
+                entry.remove();
+                self.len -= block_len;
+                removed += block_len;
+                continue;
+            }
+            if approx {
+                break;
+            }
+            let mut n = 0;
+            while n < block_len
+                && evict(self.len - n, block[n].0)
+                && limit.map_or(true, |l| removed + n < l)
+            {
+                n += 1;
+            }
+            block.drain(..n);
+            self.len -= n;
+            removed += n;
+            break;
+        }
+        removed
+    }
+
+    /// Payload size in bytes.
+    pub fn bytes_len(&self) -> usize {
+        self.blocks
+            .values()
+            .flat_map(|block| block.iter())
+            .fold(0, |res, (_, fields)| {
+                fields
+                    .iter()
+                    .fold(res + 16, |res, (f, v)| res + f.len() + v.len())
+            })
+    }
+
+    pub fn dump(&self, buf: &mut BytesMut) {
+        buf.put_u64_le(self.len as u64);
+        for (id, fields) in self.blocks.values().flat_map(|block| block.iter()) {
+            id.dump(buf);
+            buf.put_u64_le(fields.len() as u64);
+            for (field, value) in fields {
+                put_bytes(buf, field);
+                put_bytes(buf, value);
+            }
+        }
+        self.last_id.dump(buf);
+        buf.put_u64_le(self.groups.len() as u64);
+        for (name, group) in self.groups.iter() {
+            put_bytes(buf, name);
+            group.dump(buf);
+        }
+    }
+
+    pub fn restore(buf: &mut Bytes) -> Result<StreamValue> {
+        let mut stream = StreamValue::new();
+        for _ in 0..take_len(buf)? {
+            let id = StreamId::restore(buf)?;
+            if stream.len > 0 && id <= stream.last_id {
+                return Err(corrupt());
+            }
+            let len = take_len(buf)?;
+            let mut fields = Vec::with_capacity(len);
+            for _ in 0..len {
+                fields.push((take_bytes(buf)?, take_bytes(buf)?));
+            }
+            stream.append(id, fields);
+        }
+        let last_id = StreamId::restore(buf)?;
+        if last_id < stream.last_id {
+            return Err(corrupt());
+        }
+        stream.last_id = last_id;
+        for _ in 0..take_len(buf)? {
+            let name = take_bytes(buf)?;
+            stream.groups.insert(name, ConsumerGroup::restore(buf)?);
+        }
+        Ok(stream)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum StreamVariant {
+    XAdd,
+    XRange,
+    XRevRange,
+    XLen,
+    XTrim,
+    XDel,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TrimBy {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TrimOption {
+    by: TrimBy,
+    approx: bool,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum IdSpec {
+    Auto,
+    /// `<ms>-*`, the sequence number is generated.
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+#[derive(Debug, Clone)]
+enum StreamOp {
+    Add {
+        id: IdSpec,
+        fields: StreamFields,
+        nomkstream: bool,
+        trim: Option<TrimOption>,
+    },
+    Range {
+        start: StreamId,
+        end: StreamId,
+        rev: bool,
+        count: Option<usize>,
+    },
+    Len,
+    Trim(TrimOption),
+    Del(Vec<StreamId>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Stream {
+    key: Bytes,
+    op: StreamOp,
+}
+
+/// Parses `MAXLEN|MINID [=|~] threshold [LIMIT count]`, `token` being the
+/// first word already taken from `parser`.
+fn parse_trim(token: usize, parser: &mut CommandParser) -> Result<TrimOption> {
+    let mut threshold = parser.next_bytes()?.ok_or_else(missing_operand)?;
+    let mut approx = false;
+    if threshold.as_ref() == b"~" || threshold.as_ref() == b"=" {
+        approx = threshold.as_ref() == b"~";
+        threshold = parser.next_bytes()?.ok_or_else(missing_operand)?;
+    }
+    let by = match token {
+        MAXLEN => TrimBy::MaxLen(
+            std::str::from_utf8(threshold.as_ref())
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(invalid_operand)?,
+        ),
+        _ => TrimBy::MinId(StreamId::parse(&threshold, 0)?),
+    };
+    Ok(TrimOption {
+        by,
+        approx,
+        limit: None,
+    })
+}
+
+/// Parses the `LIMIT count` that may follow a trim option.
+fn parse_trim_limit(trim: &mut TrimOption, parser: &mut CommandParser) -> Result<()> {
+    let limit = parser
+        .next_integer()?
+        .filter(|v| *v >= 0)
+        .ok_or_else(invalid_operand)? as usize;
+    if !trim.approx {
+        return Err(invalid_operation());
+    }
+    // 0 stands for no limit.
+    trim.limit = Some(limit).filter(|v| *v > 0);
+    Ok(())
+}
+
+pub fn entry_frame(id: StreamId, fields: &StreamFields) -> Frame {
+    let mut kv = Vec::with_capacity(fields.len() * 2);
+    for (f, v) in fields {
+        kv.push(f.clone().into());
+        kv.push(v.clone().into());
+    }
+    Frame::Arrays(vec![id.to_bytes().into(), Frame::Arrays(kv)])
+}
+
+impl Stream {
+    pub fn new(parser: &mut CommandParser, variant: StreamVariant) -> Result<Stream> {
+        use StreamVariant::*;
+        let key = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let op = match variant {
+            XAdd => {
+                let (mut nomkstream, mut trim) = (false, None);
+                let id = loop {
+                    let token = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                    match rolling_hash(token.as_ref()) {
+                        Ok(NOMKSTREAM) => nomkstream = true,
+                        Ok(t @ MAXLEN) | Ok(t @ MINID) => trim = Some(parse_trim(t, parser)?),
+                        Ok(LIMIT) => match trim.as_mut() {
+                            Some(trim) => parse_trim_limit(trim, parser)?,
+                            None => return Err(invalid_operation()),
+                        },
+                        _ => break token,
+                    }
+                };
+                let id = match id.as_ref() {
+                    b"*" => IdSpec::Auto,
+                    raw if raw.ends_with(b"-*") => IdSpec::AutoSeq(
+                        std::str::from_utf8(&raw[..raw.len() - 2])
+                            .ok()
+                            .and_then(|v| v.parse().ok())
+                            .ok_or_else(invalid_operand)?,
+                    ),
+                    raw => IdSpec::Explicit(StreamId::parse(raw, 0)?),
+                };
+                if parser.len() == 0 || parser.len() % 2 != 0 {
+                    return Err(missing_operand());
+                }
+                let mut fields = Vec::with_capacity(parser.len() / 2);
+                while let Some(f) = parser.next_bytes()? {
+                    fields.push((f, parser.next_bytes()?.ok_or_else(missing_operand)?));
+                }
+                StreamOp::Add {
+                    id,
+                    fields,
+                    nomkstream,
+                    trim,
+                }
+            }
+            XRange | XRevRange => {
+                let rev = matches!(variant, XRevRange);
+                let first = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let second = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let (start, end) = if rev {
+                    (second, first)
+                } else {
+                    (first, second)
+                };
+                let count = match parser.next_bytes()? {
+                    Some(v) if rolling_hash(v.as_ref())? == COUNT => {
+                        Some(parser.next_integer()?.ok_or_else(missing_operand)?.max(0) as usize)
+                    }
+                    Some(_) => return Err(invalid_operation()),
+                    None => None,
+                };
+                StreamOp::Range {
+                    start: parse_range_bound(&start, true)?,
+                    end: parse_range_bound(&end, false)?,
+                    rev,
+                    count,
+                }
+            }
+            XLen => StreamOp::Len,
+            XTrim => {
+                let token = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let mut trim = match rolling_hash(token.as_ref())? {
+                    t @ MAXLEN | t @ MINID => parse_trim(t, parser)?,
+                    _ => return Err(invalid_operation()),
+                };
+                if let Some(v) = parser.next_bytes()? {
+                    if rolling_hash(v.as_ref())? != LIMIT {
+                        return Err(invalid_operation());
+                    }
+                    parse_trim_limit(&mut trim, parser)?;
+                }
+                StreamOp::Trim(trim)
+            }
+            XDel => {
+                let mut ids = Vec::with_capacity(parser.len());
+                while let Some(v) = parser.next_bytes()? {
+                    ids.push(StreamId::parse(&v, 0)?);
+                }
+                if ids.is_empty() {
+                    return Err(missing_operand());
+                }
+                StreamOp::Del(ids)
+            }
+        };
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(Self { key, op })
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        let key = &self.key;
+        match self.op {
+            StreamOp::Add {
+                id,
+                fields,
+                nomkstream,
+                trim,
+            } => db.xadd(key, id, fields, nomkstream, trim),
+            StreamOp::Trim(trim) => db.xtrim(key, trim),
+            StreamOp::Del(ids) => db.xdel(key, &ids),
+            StreamOp::Len => match db.get_stream(key) {
+                Ok(s) => Frame::Integers(s.map_or(0, |s| s.len()) as i64),
+                Err(e) => e,
+            },
+            StreamOp::Range {
+                start,
+                end,
+                rev,
+                count,
+            } => {
+                let stream = match db.get_stream(key) {
+                    Ok(Some(s)) => s,
+                    Ok(None) => return Frame::Arrays(vec![]),
+                    Err(e) => return e,
+                };
+                let count = count.unwrap_or(usize::MAX);
+                let entries = stream.range(start, end);
+                let res = if rev {
+                    entries
+                        .rev()
+                        .take(count)
+                        .map(|(id, f)| entry_frame(*id, f))
+                        .collect()
+                } else {
+                    entries
+                        .take(count)
+                        .map(|(id, f)| entry_frame(*id, f))
+                        .collect()
+                };
+                Frame::Arrays(res)
+            }
+        }
+    }
+}
+
+impl OneshotExecDB for Stream {
+    fn get_key(&self) -> &[u8] {
+        self.key.as_ref()
+    }
+
+    fn is_write(&self) -> bool {
+        matches!(
+            self.op,
+            StreamOp::Add { .. } | StreamOp::Trim(_) | StreamOp::Del(_)
+        )
+    }
+
+    /// XADD logs the ID it generated, for replay to add the same entry.
+    fn propagate(&self, frame: Frame, ret: &Frame, db: &mut DB) {
+        match (&self.op, frame, ret) {
+            (StreamOp::Range { .. } | StreamOp::Len, _, _) => (),
+            (
+                StreamOp::Add {
+                    id: IdSpec::Auto | IdSpec::AutoSeq(_),
+                    fields,
+                    ..
+                },
+                Frame::Arrays(mut args),
+                Frame::BulkStrings(id),
+            ) => {
+                let idx = args.len() - 2 * fields.len() - 1;
+                args[idx] = Frame::BulkStrings(id.clone());
+                db.propagate(Frame::Arrays(args));
+            }
+            (_, frame, _) => db.propagate(frame),
+        }
+    }
+}
+
+fn apply_trim(stream: &mut StreamValue, trim: &TrimOption) -> usize {
+    match trim.by {
+        TrimBy::MaxLen(max) => stream.trim(|len, _| len > max, trim.approx, trim.limit),
+        TrimBy::MinId(min) => stream.trim(|_, id| id < min, trim.approx, trim.limit),
+    }
+}
+
+impl DB {
+    /// `Ok(None)` if there is no such key, `Err` carries the error reply.
+    pub fn get_stream(
+        &mut self,
+        key: &Bytes,
+    ) -> std::result::Result<Option<&mut StreamValue>, Frame> {
+        match self.get_live(key).map(|en| &mut en.data) {
+            None => Ok(None),
+            Some(Value::Stream(s)) => Ok(Some(s)),
+            Some(_) => Err(wrong_type()),
+        }
+    }
+
+    pub fn get_or_create_stream(
+        &mut self,
+        key: &Bytes,
+    ) -> std::result::Result<&mut StreamValue, Frame> {
+        if self.get_stream(key)?.is_none() {
+            self.counter += 1;
+            let nounce = self.counter;
+            self.insert(
+                key.clone(),
+                Entry::new(Value::Stream(StreamValue::new()), None, nounce),
+            );
+        }
+        Ok(self.get_stream(key)?.unwrap())
+    }
+
+    fn xadd(
+        &mut self,
+        key: &Bytes,
+        id: IdSpec,
+        fields: StreamFields,
+        nomkstream: bool,
+        trim: Option<TrimOption>,
+    ) -> Frame {
+        let created = match self.get_stream(key) {
+            Ok(Some(_)) => false,
+            Ok(None) if nomkstream => return Frame::NullString,
+            Ok(None) => true,
+            Err(e) => return e,
+        };
+        let last = match self.get_stream(key) {
+            Ok(Some(s)) => s.last_id,
+            _ => StreamId::MIN,
+        };
+        let id = match id {
+            IdSpec::Auto => {
+                let ms = unix_millis_now();
+                if ms > last.ms {
+                    Some(StreamId { ms, seq: 0 })
+                } else {
+                    last.next()
+                }
+            }
+            IdSpec::AutoSeq(ms) if ms == last.ms => last.next().filter(|v| v.ms == ms),
+            IdSpec::AutoSeq(ms) if ms > last.ms || created => Some(StreamId {
+                ms,
+                seq: (ms == 0) as u64,
+            }),
+            IdSpec::AutoSeq(_) => {
+                return Frame::Errors(Bytes::from_static(ID_TOO_SMALL_ERR));
+            }
+            IdSpec::Explicit(id) if id == StreamId::MIN => {
+                return Frame::Errors(Bytes::from_static(ID_ZERO_ERR));
+            }
+            IdSpec::Explicit(id) if id <= last && !created => {
+                return Frame::Errors(Bytes::from_static(ID_TOO_SMALL_ERR));
+            }
+            IdSpec::Explicit(id) => Some(id),
+        };
+        let id = match id {
+            Some(id) => id,
+            None => return Frame::Errors(Bytes::from_static(ID_EXHAUSTED_ERR)),
+        };
+        let stream = match self.get_or_create_stream(key) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        stream.append(id, fields);
+        if let Some(trim) = &trim {
+            apply_trim(stream, trim);
+        }
+        self.after_write(key, false);
+        self.signal_ready(key);
+        Frame::BulkStrings(id.to_bytes())
+    }
+
+    fn xtrim(&mut self, key: &Bytes, trim: TrimOption) -> Frame {
+        let stream = match self.get_stream(key) {
+            Ok(Some(s)) => s,
+            Ok(None) => return Frame::Integers(0),
+            Err(e) => return e,
+        };
+        let removed = apply_trim(stream, &trim);
+        if removed > 0 {
+            self.after_write(key, false);
+        }
+        Frame::Integers(removed as i64)
+    }
+
+    fn xdel(&mut self, key: &Bytes, ids: &[StreamId]) -> Frame {
+        let stream = match self.get_stream(key) {
+            Ok(Some(s)) => s,
+            Ok(None) => return Frame::Integers(0),
+            Err(e) => return e,
+        };
+        let removed = ids.iter().filter(|id| stream.remove(**id)).count();
+        if removed > 0 {
+            self.after_write(key, false);
+        }
+        Frame::Integers(removed as i64)
+    }
+}
+
+impl AtomicCMDMarker for Stream {}
@@ -0,0 +1,1027 @@
+use crate::{
+    cmd::snapshot::{put_bytes, take_bytes, take_len, take_u64},
+    cmd::stream::{entry_frame, parse_range_bound, StreamId, StreamValue},
+    cmd::*,
+    utils::unix_millis_now,
+};
+use anyhow::Result;
+use rustc_hash::FxHashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+    sync::Arc,
+    time::Duration,
+};
+
+const CREATE: usize = rolling_hash_const(b"create");
+const SETID: usize = rolling_hash_const(b"setid");
+const DESTROY: usize = rolling_hash_const(b"destroy");
+const CREATECONSUMER: usize = rolling_hash_const(b"createconsumer");
+const DELCONSUMER: usize = rolling_hash_const(b"delconsumer");
+const MKSTREAM: usize = rolling_hash_const(b"mkstream");
+const GROUP: usize = rolling_hash_const(b"group");
+const STREAMS: usize = rolling_hash_const(b"streams");
+const BLOCK: usize = rolling_hash_const(b"block");
+const NOACK: usize = rolling_hash_const(b"noack");
+const COUNT: usize = rolling_hash_const(b"count");
+const IDLE: usize = rolling_hash_const(b"idle");
+const TIME: usize = rolling_hash_const(b"time");
+const RETRYCOUNT: usize = rolling_hash_const(b"retrycount");
+const FORCE: usize = rolling_hash_const(b"force");
+const JUSTID: usize = rolling_hash_const(b"justid");
+
+const NO_KEY_ERR: &[u8] = b"ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.";
+const BUSY_GROUP_ERR: &[u8] = b"BUSYGROUP Consumer Group name already exists";
+
+fn no_group(key: &Bytes, group: &Bytes) -> Frame {
+    Frame::Errors(Bytes::from(format!(
+        "NOGROUP No such key '{}' or consumer group '{}'",
+        String::from_utf8_lossy(key),
+        String::from_utf8_lossy(group)
+    )))
+}
+
+/// An entry delivered to a consumer that wasn't acknowledged yet.
+#[derive(Debug)]
+struct PendingEntry {
+    consumer: Bytes,
+    /// Unix time of the last delivery in milliseconds.
+    delivered_at: u64,
+    delivery_count: u64,
+}
+
+#[derive(Debug, Default)]
+struct Consumer {
+    pending: BTreeSet<StreamId>,
+}
+
+/// Delivery state of a consumer group. Every pending entry is listed both
+/// in `pel` and in the `pending` set of the consumer owning it.
+#[derive(Debug)]
+pub struct ConsumerGroup {
+    last_delivered: StreamId,
+    pel: BTreeMap<StreamId, PendingEntry>,
+    consumers: FxHashMap<Bytes, Consumer>,
+}
+
+impl ConsumerGroup {
+    fn new(last_delivered: StreamId) -> Self {
+        Self {
+            last_delivered,
+            pel: BTreeMap::new(),
+            consumers: FxHashMap::default(),
+        }
+    }
+
+    /// Appends the commands that build the group `name` of the stream under
+    /// `key` from scratch. Pending entries that were deleted from the stream
+    /// can't be claimed, they are left out.
+    pub fn rewrite(&self, key: &Bytes, name: &Bytes, stream: &StreamValue, out: &mut Vec<Frame>) {
+        out.push(command(vec![
+            Bytes::from_static(b"XGROUP"),
+            Bytes::from_static(b"CREATE"),
+            key.clone(),
+            name.clone(),
+            self.last_delivered.to_bytes(),
+            Bytes::from_static(b"MKSTREAM"),
+        ]));
+        for consumer in self.consumers.keys() {
+            out.push(command(vec![
+                Bytes::from_static(b"XGROUP"),
+                Bytes::from_static(b"CREATECONSUMER"),
+                key.clone(),
+                name.clone(),
+                consumer.clone(),
+            ]));
+        }
+        for (id, p) in &self.pel {
+            if stream.get(*id).is_some() {
+                out.push(claim_command(key, name, *id, p));
+            }
+        }
+    }
+
+    /// Returns whether the consumer is new.
+    fn add_consumer(&mut self, name: &Bytes) -> bool {
+        if self.consumers.contains_key(name) {
+            return false;
+        }
+        self.consumers.insert(name.clone(), Consumer::default());
+        true
+    }
+
+    /// Makes `id` pending for `consumer`, taking it over from its former
+    /// owner if there was one.
+    fn assign(&mut self, id: StreamId, consumer: &Bytes, delivered_at: u64, delivery_count: u64) {
+        let entry = PendingEntry {
+            consumer: consumer.clone(),
+            delivered_at,
+            delivery_count,
+        };
+        if let Some(old) = self.pel.insert(id, entry) {
+            if let Some(c) = self.consumers.get_mut(&old.consumer) {
+                c.pending.remove(&id);
+            }
+        }
+        self.consumers
+            .entry(consumer.clone())
+            .or_default()
+            .pending
+            .insert(id);
+    }
+
+    fn ack(&mut self, id: StreamId) -> bool {
+        match self.pel.remove(&id) {
+            Some(p) => {
+                if let Some(c) = self.consumers.get_mut(&p.consumer) {
+                    c.pending.remove(&id);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The pending entries of every consumer come from `pel`, so only the
+    /// names of the consumers are written.
+    pub fn dump(&self, buf: &mut BytesMut) {
+        self.last_delivered.dump(buf);
+        buf.put_u64_le(self.consumers.len() as u64);
+        for name in self.consumers.keys() {
+            put_bytes(buf, name);
+        }
+        buf.put_u64_le(self.pel.len() as u64);
+        for (id, p) in self.pel.iter() {
+            id.dump(buf);
+            put_bytes(buf, &p.consumer);
+            buf.put_u64_le(p.delivered_at);
+            buf.put_u64_le(p.delivery_count);
+        }
+    }
+
+    pub fn restore(buf: &mut Bytes) -> Result<ConsumerGroup> {
+        let mut group = ConsumerGroup::new(StreamId::restore(buf)?);
+        for _ in 0..take_len(buf)? {
+            group.add_consumer(&take_bytes(buf)?);
+        }
+        for _ in 0..take_len(buf)? {
+            let id = StreamId::restore(buf)?;
+            let consumer = take_bytes(buf)?;
+            let delivered_at = take_u64(buf)?;
+            let delivery_count = take_u64(buf)?;
+            group.assign(id, &consumer, delivered_at, delivery_count);
+        }
+        Ok(group)
+    }
+}
+
+/// What XREADGROUP reads on behalf of a consumer, shared by the `BPop`s it
+/// is split into.
+#[derive(Debug)]
+pub struct GroupRead {
+    group: Bytes,
+    consumer: Bytes,
+    count: Option<usize>,
+    noack: bool,
+    /// Keys given an ID re-read the pending entries of the consumer after
+    /// it, the others (`>`) read entries never delivered to the group.
+    history: FxHashMap<Bytes, StreamId>,
+}
+
+/// Parses `GROUP group consumer [COUNT count] [BLOCK ms] [NOACK] STREAMS
+/// key... id...`. Along with the keys comes `Some` timeout if the command
+/// blocks, which is `None` for blocking forever.
+pub fn parse_xreadgroup(
+    parser: &mut CommandParser,
+) -> Result<(GroupRead, Vec<Bytes>, Option<Option<Duration>>)> {
+    let token = parser.next_bytes()?.ok_or_else(missing_operand)?;
+    if rolling_hash(token.as_ref())? != GROUP {
+        return Err(invalid_operation());
+    }
+    let mut read = GroupRead {
+        group: parser.next_bytes()?.ok_or_else(missing_operand)?,
+        consumer: parser.next_bytes()?.ok_or_else(missing_operand)?,
+        count: None,
+        noack: false,
+        history: FxHashMap::default(),
+    };
+    let mut timeout = None;
+    loop {
+        let token = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        match rolling_hash(token.as_ref())? {
+            COUNT => {
+                let count = parser.next_integer()?.ok_or_else(missing_operand)?;
+                // COUNT 0 stands for no limit.
+                read.count = Some(count.max(0) as usize).filter(|v| *v > 0);
+            }
+            BLOCK => {
+                let ms = parser
+                    .next_integer()?
+                    .filter(|v| *v >= 0)
+                    .ok_or_else(invalid_operand)?;
+                timeout = Some(Some(Duration::from_millis(ms as u64)).filter(|_| ms > 0));
+            }
+            NOACK => read.noack = true,
+            STREAMS => break,
+            _ => return Err(invalid_operation()),
+        }
+    }
+    if parser.len() == 0 || parser.len() % 2 != 0 {
+        return Err(missing_operand());
+    }
+    let numkeys = parser.len() / 2;
+    let mut keys = Vec::with_capacity(numkeys);
+    for _ in 0..numkeys {
+        keys.push(parser.next_bytes()?.ok_or_else(missing_operand)?);
+    }
+    for key in keys.iter() {
+        let id = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        if id.as_ref() != b">" {
+            read.history.insert(key.clone(), StreamId::parse(&id, 0)?);
+        }
+    }
+    Ok((read, keys, timeout))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum StreamGroupVariant {
+    XGroup,
+    XAck,
+    XPending,
+    XClaim,
+    XAutoClaim,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ClaimOptions {
+    idle: Option<u64>,
+    time: Option<u64>,
+    retry_count: Option<u64>,
+    force: bool,
+    justid: bool,
+}
+
+#[derive(Debug, Clone)]
+enum StreamGroupOp {
+    /// `None` stands for `$`, the last ID of the stream.
+    Create(Option<StreamId>, bool),
+    SetId(Option<StreamId>),
+    Destroy,
+    CreateConsumer(Bytes),
+    DelConsumer(Bytes),
+    Ack(Vec<StreamId>),
+    PendingSummary,
+    Pending {
+        min_idle: u64,
+        start: StreamId,
+        end: StreamId,
+        count: usize,
+        consumer: Option<Bytes>,
+    },
+    Claim {
+        consumer: Bytes,
+        min_idle: u64,
+        ids: Vec<StreamId>,
+        options: ClaimOptions,
+    },
+    AutoClaim {
+        consumer: Bytes,
+        min_idle: u64,
+        start: StreamId,
+        count: usize,
+        justid: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamGroup {
+    key: Bytes,
+    group: Bytes,
+    op: StreamGroupOp,
+}
+
+fn parse_group_id(raw: &Bytes) -> Result<Option<StreamId>> {
+    match raw.as_ref() {
+        b"$" => Ok(None),
+        raw => Ok(Some(StreamId::parse(raw, 0)?)),
+    }
+}
+
+fn next_millis(parser: &mut CommandParser) -> Result<u64> {
+    Ok(parser.next_integer()?.ok_or_else(missing_operand)?.max(0) as u64)
+}
+
+impl StreamGroup {
+    pub fn new(parser: &mut CommandParser, variant: StreamGroupVariant) -> Result<StreamGroup> {
+        use StreamGroupVariant::*;
+        let sub = match variant {
+            XGroup => {
+                let sub = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                Some(rolling_hash(sub.as_ref())?)
+            }
+            _ => None,
+        };
+        let key = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let group = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let op = match (variant, sub) {
+            (XGroup, Some(CREATE)) => {
+                let id = parse_group_id(&parser.next_bytes()?.ok_or_else(missing_operand)?)?;
+                let mkstream = match parser.next_bytes()? {
+                    Some(v) if rolling_hash(v.as_ref())? == MKSTREAM => true,
+                    Some(_) => return Err(invalid_operation()),
+                    None => false,
+                };
+                StreamGroupOp::Create(id, mkstream)
+            }
+            (XGroup, Some(SETID)) => StreamGroupOp::SetId(parse_group_id(
+                &parser.next_bytes()?.ok_or_else(missing_operand)?,
+            )?),
+            (XGroup, Some(DESTROY)) => StreamGroupOp::Destroy,
+            (XGroup, Some(CREATECONSUMER)) => {
+                StreamGroupOp::CreateConsumer(parser.next_bytes()?.ok_or_else(missing_operand)?)
+            }
+            (XGroup, Some(DELCONSUMER)) => {
+                StreamGroupOp::DelConsumer(parser.next_bytes()?.ok_or_else(missing_operand)?)
+            }
+            (XGroup, _) => return Err(invalid_operation()),
+            (XAck, _) => {
+                let mut ids = Vec::with_capacity(parser.len());
+                while let Some(v) = parser.next_bytes()? {
+                    ids.push(StreamId::parse(&v, 0)?);
+                }
+                if ids.is_empty() {
+                    return Err(missing_operand());
+                }
+                StreamGroupOp::Ack(ids)
+            }
+            (XPending, _) if parser.len() == 0 => StreamGroupOp::PendingSummary,
+            (XPending, _) => {
+                let mut start = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let mut min_idle = 0;
+                if rolling_hash(start.as_ref()).ok() == Some(IDLE) {
+                    min_idle = next_millis(parser)?;
+                    start = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                }
+                let end = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                StreamGroupOp::Pending {
+                    min_idle,
+                    start: parse_range_bound(&start, true)?,
+                    end: parse_range_bound(&end, false)?,
+                    count: parser.next_integer()?.ok_or_else(missing_operand)?.max(0) as usize,
+                    consumer: parser.next_bytes()?,
+                }
+            }
+            (XClaim, _) => {
+                let consumer = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let min_idle = next_millis(parser)?;
+                let mut ids = Vec::with_capacity(parser.len());
+                let (mut options, mut in_options) = (ClaimOptions::default(), false);
+                while let Some(v) = parser.next_bytes()? {
+                    // IDs come first, the options follow.
+                    if !in_options {
+                        match StreamId::parse(&v, 0) {
+                            Ok(id) => {
+                                ids.push(id);
+                                continue;
+                            }
+                            Err(_) => in_options = true,
+                        }
+                    }
+                    match rolling_hash(v.as_ref())? {
+                        IDLE => options.idle = Some(next_millis(parser)?),
+                        TIME => options.time = Some(next_millis(parser)?),
+                        RETRYCOUNT => options.retry_count = Some(next_millis(parser)?),
+                        FORCE => options.force = true,
+                        JUSTID => options.justid = true,
+                        _ => return Err(invalid_operation()),
+                    }
+                }
+                if ids.is_empty() {
+                    return Err(missing_operand());
+                }
+                StreamGroupOp::Claim {
+                    consumer,
+                    min_idle,
+                    ids,
+                    options,
+                }
+            }
+            (XAutoClaim, _) => {
+                let consumer = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let min_idle = next_millis(parser)?;
+                let start = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let (mut count, mut justid) = (100, false);
+                while let Some(v) = parser.next_bytes()? {
+                    match rolling_hash(v.as_ref())? {
+                        COUNT => {
+                            count = parser
+                                .next_integer()?
+                                .filter(|v| *v > 0)
+                                .ok_or_else(invalid_operand)?
+                                as usize
+                        }
+                        JUSTID => justid = true,
+                        _ => return Err(invalid_operation()),
+                    }
+                }
+                StreamGroupOp::AutoClaim {
+                    consumer,
+                    min_idle,
+                    start: parse_range_bound(&start, true)?,
+                    count,
+                    justid,
+                }
+            }
+        };
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(Self { key, group, op })
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        let (key, group) = (&self.key, &self.group);
+        let now = unix_millis_now();
+        let ret = match self.op {
+            StreamGroupOp::Create(id, mkstream) => {
+                let stream = match db.get_stream(key) {
+                    Ok(Some(s)) => s,
+                    Ok(None) if mkstream => match db.get_or_create_stream(key) {
+                        Ok(s) => s,
+                        Err(e) => return e,
+                    },
+                    Ok(None) => return Frame::Errors(Bytes::from_static(NO_KEY_ERR)),
+                    Err(e) => return e,
+                };
+                if stream.groups.contains_key(group) {
+                    return Frame::Errors(Bytes::from_static(BUSY_GROUP_ERR));
+                }
+                let id = id.unwrap_or(stream.last_id);
+                stream.groups.insert(group.clone(), ConsumerGroup::new(id));
+                Frame::Ok
+            }
+            StreamGroupOp::Destroy => match db.get_stream(key) {
+                Ok(Some(s)) => Frame::Integers(s.groups.remove(group).is_some() as i64),
+                Ok(None) => return Frame::Errors(Bytes::from_static(NO_KEY_ERR)),
+                Err(e) => return e,
+            },
+            StreamGroupOp::SetId(id) => db.with_group(key, group, |stream, g| {
+                g.last_delivered = id.unwrap_or(stream.last_id);
+                Frame::Ok
+            }),
+            StreamGroupOp::CreateConsumer(consumer) => db.with_group(key, group, |_, g| {
+                Frame::Integers(g.add_consumer(&consumer) as i64)
+            }),
+            StreamGroupOp::DelConsumer(consumer) => db.with_group(key, group, |_, g| {
+                let pending = match g.consumers.remove(&consumer) {
+                    Some(c) => c.pending,
+                    None => return Frame::Integers(0),
+                };
+                for id in pending.iter() {
+                    g.pel.remove(id);
+                }
+                Frame::Integers(pending.len() as i64)
+            }),
+            StreamGroupOp::Ack(ids) => {
+                match db.with_group(key, group, |_, g| {
+                    Frame::Integers(ids.iter().filter(|id| g.ack(**id)).count() as i64)
+                }) {
+                    Frame::Errors(e) if e.starts_with(b"NOGROUP") => return Frame::Integers(0),
+                    f => f,
+                }
+            }
+            StreamGroupOp::PendingSummary => {
+                return db.with_group(key, group, |_, g| pending_summary(g));
+            }
+            StreamGroupOp::Pending {
+                min_idle,
+                start,
+                end,
+                count,
+                consumer,
+            } => {
+                return db.with_group(key, group, |_, g| {
+                    Frame::Arrays(
+                        g.pel
+                            .range(start..=end.max(start))
+                            .filter(|(id, p)| {
+                                **id <= end
+                                    && now.saturating_sub(p.delivered_at) >= min_idle
+                                    && consumer.as_ref().map_or(true, |c| *c == p.consumer)
+                            })
+                            .take(count)
+                            .map(|(id, p)| {
+                                Frame::Arrays(vec![
+                                    id.to_bytes().into(),
+                                    p.consumer.clone().into(),
+                                    Frame::Integers(now.saturating_sub(p.delivered_at) as i64),
+                                    Frame::Integers(p.delivery_count as i64),
+                                ])
+                            })
+                            .collect(),
+                    )
+                });
+            }
+            StreamGroupOp::Claim {
+                consumer,
+                min_idle,
+                ids,
+                options,
+            } => db.with_group(key, group, |stream, g| {
+                g.add_consumer(&consumer);
+                let delivered_at = match (options.time, options.idle) {
+                    (Some(time), _) => time,
+                    (None, Some(idle)) => now.saturating_sub(idle),
+                    (None, None) => now,
+                };
+                let mut res = Vec::with_capacity(ids.len());
+                for id in ids {
+                    let delivery_count = match g.pel.get(&id) {
+                        Some(p) if now.saturating_sub(p.delivered_at) < min_idle => continue,
+                        Some(p) => p.delivery_count,
+                        None if options.force && stream.get(id).is_some() => 0,
+                        None => continue,
+                    };
+                    let fields = match stream.get(id) {
+                        Some(f) => f,
+                        // deleted entries are dropped from the group.
+                        None => {
+                            g.ack(id);
+                            continue;
+                        }
+                    };
+                    let delivery_count = match options.retry_count {
+                        Some(v) => v,
+                        None if options.justid => delivery_count,
+                        None => delivery_count + 1,
+                    };
+                    g.assign(id, &consumer, delivered_at, delivery_count);
+                    res.push(if options.justid {
+                        id.to_bytes().into()
+                    } else {
+                        entry_frame(id, fields)
+                    });
+                }
+                Frame::Arrays(res)
+            }),
+            StreamGroupOp::AutoClaim {
+                consumer,
+                min_idle,
+                start,
+                count,
+                justid,
+            } => db.with_group(key, group, |stream, g| {
+                g.add_consumer(&consumer);
+                let candidates: Vec<(StreamId, u64, u64)> = g
+                    .pel
+                    .range(start..)
+                    .map(|(id, p)| (*id, p.delivered_at, p.delivery_count))
+                    .take(count)
+                    .collect();
+                let (mut claimed, mut deleted) = (Vec::new(), Vec::new());
+                for (id, delivered_at, delivery_count) in candidates.iter().copied() {
+                    if now.saturating_sub(delivered_at) < min_idle {
+                        continue;
+                    }
+                    match stream.get(id) {
+                        Some(fields) => {
+                            let delivery_count = delivery_count + (!justid) as u64;
+                            g.assign(id, &consumer, now, delivery_count);
+                            claimed.push(if justid {
+                                id.to_bytes().into()
+                            } else {
+                                entry_frame(id, fields)
+                            });
+                        }
+                        None => {
+                            g.ack(id);
+                            deleted.push(id.to_bytes().into());
+                        }
+                    }
+                }
+                // the scan goes on right after the last entry looked at.
+                let cursor = candidates
+                    .last()
+                    .and_then(|(id, _, _)| {
+                        g.pel.range((Bound::Excluded(*id), Bound::Unbounded)).next()
+                    })
+                    .map_or(StreamId::MIN, |(id, _)| *id);
+                Frame::Arrays(vec![
+                    cursor.to_bytes().into(),
+                    Frame::Arrays(claimed),
+                    Frame::Arrays(deleted),
+                ])
+            }),
+        };
+        if !matches!(ret, Frame::Errors(_)) {
+            db.after_write(key, false);
+        }
+        ret
+    }
+}
+
+fn pending_summary(g: &ConsumerGroup) -> Frame {
+    let (first, last) = match (g.pel.keys().next(), g.pel.keys().next_back()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => {
+            return Frame::Arrays(vec![
+                Frame::Integers(0),
+                Frame::NullString,
+                Frame::NullString,
+                Frame::NullArray,
+            ])
+        }
+    };
+    let mut consumers: Vec<(&Bytes, usize)> = g
+        .consumers
+        .iter()
+        .filter(|(_, c)| !c.pending.is_empty())
+        .map(|(name, c)| (name, c.pending.len()))
+        .collect();
+    consumers.sort();
+    Frame::Arrays(vec![
+        Frame::Integers(g.pel.len() as i64),
+        first.to_bytes().into(),
+        last.to_bytes().into(),
+        Frame::Arrays(
+            consumers
+                .into_iter()
+                .map(|(name, n)| {
+                    Frame::Arrays(vec![name.clone().into(), Bytes::from(n.to_string()).into()])
+                })
+                .collect(),
+        ),
+    ])
+}
+
+impl OneshotExecDB for StreamGroup {
+    fn get_key(&self) -> &[u8] {
+        self.key.as_ref()
+    }
+
+    fn is_write(&self) -> bool {
+        !matches!(
+            self.op,
+            StreamGroupOp::PendingSummary | StreamGroupOp::Pending { .. }
+        )
+    }
+
+    /// Claims depend on the time they are made at, they are logged as the
+    /// ownership they left behind.
+    fn propagate(&self, frame: Frame, ret: &Frame, db: &mut DB) {
+        let (consumer, ids) = match (&self.op, ret) {
+            (StreamGroupOp::PendingSummary | StreamGroupOp::Pending { .. }, _) => return,
+            (StreamGroupOp::Claim { consumer, ids, .. }, _) => (consumer, ids.clone()),
+            (StreamGroupOp::AutoClaim { consumer, .. }, Frame::Arrays(v)) if v.len() == 3 => {
+                let mut ids = ids_of(&v[1]);
+                ids.extend(ids_of(&v[2]));
+                (consumer, ids)
+            }
+            (StreamGroupOp::AutoClaim { .. }, _) => return,
+            _ => return db.propagate(frame),
+        };
+        db.propagate_claims(&self.key, &self.group, consumer, &ids, false);
+    }
+}
+
+/// The XCLAIM that hands the pending entry `p` to its consumer as it was
+/// delivered, whenever it replays.
+fn claim_command(key: &Bytes, group: &Bytes, id: StreamId, p: &PendingEntry) -> Frame {
+    command(vec![
+        Bytes::from_static(b"XCLAIM"),
+        key.clone(),
+        group.clone(),
+        p.consumer.clone(),
+        Bytes::from_static(b"0"),
+        id.to_bytes(),
+        Bytes::from_static(b"TIME"),
+        Bytes::from(p.delivered_at.to_string()),
+        Bytes::from_static(b"RETRYCOUNT"),
+        Bytes::from(p.delivery_count.to_string()),
+        Bytes::from_static(b"FORCE"),
+        Bytes::from_static(b"JUSTID"),
+    ])
+}
+
+/// The IDs of the entries in `entries`, or of the IDs themselves for JUSTID.
+fn ids_of(entries: &Frame) -> Vec<StreamId> {
+    let entries = match entries {
+        Frame::Arrays(v) => v,
+        _ => return Vec::new(),
+    };
+    entries
+        .iter()
+        .filter_map(|e| match e {
+            Frame::BulkStrings(id) => Some(id),
+            Frame::Arrays(v) => match v.first() {
+                Some(Frame::BulkStrings(id)) => Some(id),
+                _ => None,
+            },
+            _ => None,
+        })
+        .filter_map(|id| StreamId::parse(id, 0).ok())
+        .collect()
+}
+
+impl DB {
+    /// Runs `f` on the stream under `key` and its consumer group `group`,
+    /// replies NOGROUP if either is missing.
+    fn with_group(
+        &mut self,
+        key: &Bytes,
+        group: &Bytes,
+        f: impl FnOnce(&StreamValue, &mut ConsumerGroup) -> Frame,
+    ) -> Frame {
+        let stream = match self.get_stream(key) {
+            Ok(Some(s)) => s,
+            Ok(None) => return no_group(key, group),
+            Err(e) => return e,
+        };
+        // taken out for a while, so that the entries can be read alongside.
+        let mut g = match stream.groups.remove(group) {
+            Some(g) => g,
+            None => return no_group(key, group),
+        };
+        let ret = f(stream, &mut g);
+        stream.groups.insert(group.clone(), g);
+        ret
+    }
+
+    /// Whether XREADGROUP has something to read from `key`, errors if the
+    /// key or the group is missing.
+    pub fn group_ready(
+        &mut self,
+        key: &Bytes,
+        read: &GroupRead,
+    ) -> std::result::Result<bool, Frame> {
+        let stream = self
+            .get_stream(key)?
+            .ok_or_else(|| no_group(key, &read.group))?;
+        let g = stream
+            .groups
+            .get(&read.group)
+            .ok_or_else(|| no_group(key, &read.group))?;
+        if read.history.contains_key(key) {
+            return Ok(true);
+        }
+        Ok(g.last_delivered.next().map_or(false, |from| {
+            stream.range(from, StreamId::MAX).next().is_some()
+        }))
+    }
+
+    /// Reads every key of `keys` for XREADGROUP, keys without anything new
+    /// are left out of the reply.
+    pub fn xreadgroup(&mut self, keys: &[Bytes], read: &GroupRead) -> Frame {
+        let mut res = Vec::with_capacity(keys.len());
+        for key in keys {
+            match self.xreadgroup_key(key, read) {
+                Frame::NullArray => (),
+                e @ Frame::Errors(_) => return e,
+                pair => res.push(pair),
+            }
+        }
+        if res.is_empty() {
+            Frame::NullArray
+        } else {
+            Frame::Arrays(res)
+        }
+    }
+
+    /// Reads `key` for XREADGROUP: the key along with its entries, or
+    /// `Frame::NullArray` if it has nothing new.
+    fn xreadgroup_key(&mut self, key: &Bytes, read: &GroupRead) -> Frame {
+        let now = unix_millis_now();
+        let count = read.count.unwrap_or(usize::MAX);
+        let entries = self.with_group(key, &read.group, |stream, g| {
+            g.add_consumer(&read.consumer);
+            if let Some(after) = read.history.get(key) {
+                let ids: Vec<StreamId> = g.consumers[&read.consumer]
+                    .pending
+                    .range((Bound::Excluded(*after), Bound::Unbounded))
+                    .take(count)
+                    .copied()
+                    .collect();
+                return Frame::Arrays(
+                    ids.into_iter()
+                        .map(|id| {
+                            let p = g.pel.get_mut(&id).unwrap();
+                            p.delivered_at = now;
+                            p.delivery_count += 1;
+                            match stream.get(id) {
+                                Some(fields) => entry_frame(id, fields),
+                                None => Frame::Arrays(vec![id.to_bytes().into(), Frame::NullArray]),
+                            }
+                        })
+                        .collect(),
+                );
+            }
+            let from = match g.last_delivered.next() {
+                Some(from) => from,
+                None => return Frame::NullArray,
+            };
+            let mut entries = Vec::new();
+            for (id, fields) in stream.range(from, StreamId::MAX).take(count) {
+                g.last_delivered = *id;
+                if !read.noack {
+                    g.assign(*id, &read.consumer, now, 1);
+                }
+                entries.push(entry_frame(*id, fields));
+            }
+            if entries.is_empty() {
+                Frame::NullArray
+            } else {
+                Frame::Arrays(entries)
+            }
+        });
+        match entries {
+            Frame::NullArray => Frame::NullArray,
+            e @ Frame::Errors(_) => e,
+            entries => {
+                self.after_write(key, false);
+                Frame::Arrays(vec![key.clone().into(), entries])
+            }
+        }
+    }
+}
+
+impl DB {
+    /// Logs what `consumer` of `group` owns among `ids` after reading or
+    /// claiming them: owned entries as claims carrying their delivery time
+    /// and count, entries nobody owns any more as acknowledged. `set_id`
+    /// logs the last delivered ID of the group as well.
+    pub fn propagate_claims(
+        &mut self,
+        key: &Bytes,
+        group: &Bytes,
+        consumer: &Bytes,
+        ids: &[StreamId],
+        set_id: bool,
+    ) {
+        if !self.logging() {
+            return;
+        }
+        let (stream, g) = match self.get_stream(key) {
+            Ok(Some(stream)) => match stream.groups.get(group) {
+                Some(g) => (&*stream, g),
+                None => return,
+            },
+            _ => return,
+        };
+        let mut frames = vec![command(vec![
+            Bytes::from_static(b"XGROUP"),
+            Bytes::from_static(b"CREATECONSUMER"),
+            key.clone(),
+            group.clone(),
+            consumer.clone(),
+        ])];
+        let mut acked = Vec::new();
+        for id in ids {
+            match g.pel.get(id) {
+                // deleted entries are kept pending, but can't be claimed.
+                Some(p) if p.consumer == *consumer && stream.get(*id).is_some() => {
+                    frames.push(claim_command(key, group, *id, p))
+                }
+                Some(_) => (),
+                None => acked.push(id.to_bytes()),
+            }
+        }
+        if !acked.is_empty() {
+            let mut args = vec![Bytes::from_static(b"XACK"), key.clone(), group.clone()];
+            args.extend(acked);
+            frames.push(command(args));
+        }
+        if set_id {
+            frames.push(command(vec![
+                Bytes::from_static(b"XGROUP"),
+                Bytes::from_static(b"SETID"),
+                key.clone(),
+                group.clone(),
+                g.last_delivered.to_bytes(),
+            ]));
+        }
+        for frame in frames {
+            self.propagate(frame);
+        }
+    }
+
+    /// Logs what XREADGROUP did to the keys it replied `ret` for.
+    pub fn propagate_read(&mut self, read: &GroupRead, ret: &Frame) {
+        let keys = match ret {
+            Frame::Arrays(keys) => keys,
+            _ => return,
+        };
+        for pair in keys {
+            if let Frame::Arrays(pair) = pair {
+                if let [Frame::BulkStrings(key), entries] = pair.as_slice() {
+                    let ids = ids_of(entries);
+                    self.propagate_claims(key, &read.group, &read.consumer, &ids, true);
+                }
+            }
+        }
+    }
+}
+
+/// Reads the keys of XREADGROUP living in one database, answering with a
+/// frame for every key: the key along with its entries, `Frame::NullArray`
+/// if it has nothing new, or the error it ran into. Nothing is read if any
+/// of the keys runs into one.
+#[derive(Debug)]
+pub struct XGather {
+    keys: Vec<Bytes>,
+    read: Arc<GroupRead>,
+}
+
+impl XGather {
+    pub fn exec(self, db: &mut DB) -> Frame {
+        if let Some(e) = self
+            .keys
+            .iter()
+            .find_map(|key| db.group_ready(key, &self.read).err())
+        {
+            return Frame::Arrays(vec![e]);
+        }
+        let ret = Frame::Arrays(
+            self.keys
+                .iter()
+                .map(|key| db.xreadgroup_key(key, &self.read))
+                .collect(),
+        );
+        db.propagate_read(&self.read, &ret);
+        ret
+    }
+}
+
+/// Reads the keys of XREADGROUP on every database, and merges what they
+/// read in the order of the keys. The first error, in that order, is the
+/// reply if there is one.
+#[derive(Debug)]
+pub struct XGatherDispatcher {
+    read: Arc<GroupRead>,
+    // runs of keys and their database, last to be visited first.
+    runs: Vec<(usize, Vec<Bytes>)>,
+    order_tbl: Vec<Vec<usize>>,
+    len: usize,
+}
+
+impl XGatherDispatcher {
+    pub fn new(runs: Vec<(usize, Vec<Bytes>)>, read: Arc<GroupRead>) -> XGatherDispatcher {
+        let mut len = 0;
+        let mut order_tbl: Vec<Vec<usize>> = runs
+            .iter()
+            .rev()
+            .map(|(_, keys)| {
+                len += keys.len();
+                (len - keys.len()..len).collect()
+            })
+            .collect();
+        order_tbl.reverse();
+        Self {
+            read,
+            runs,
+            order_tbl,
+            len,
+        }
+    }
+}
+
+impl DispatchToMultipleDB for XGatherDispatcher {
+    fn next_command(&mut self) -> Option<IDCommandPair> {
+        let (db_id, keys) = self.runs.pop()?;
+        Some((
+            db_id,
+            XGather {
+                keys,
+                read: self.read.clone(),
+            }
+            .into(),
+        ))
+    }
+
+    fn get_result_collector(&mut self) -> ResultCollector {
+        ResultCollector {
+            result_type: ResultCollectorType::Combine(
+                std::mem::take(&mut self.order_tbl),
+                Box::new(|frames| {
+                    if let Some(e) = frames.iter().find(|f| matches!(f, Frame::Errors(_))) {
+                        return e.clone();
+                    }
+                    let read: Vec<Frame> = frames
+                        .into_iter()
+                        .filter(|f| matches!(f, Frame::Arrays(_)))
+                        .collect();
+                    if read.is_empty() {
+                        Frame::NullArray
+                    } else {
+                        Frame::Arrays(read)
+                    }
+                }),
+            ),
+            ret: vec![Frame::NullString; self.len],
+        }
+    }
+
+    fn dispatch(&mut self, _db_amount: usize, _dispatch_fn: impl Fn(&[u8]) -> usize) {}
+}
+
+impl AtomicCMDMarker for StreamGroup {}
+impl AtomicCMDMarker for XGather {}
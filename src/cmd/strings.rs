@@ -0,0 +1,551 @@
+use crate::{
+    cmd::*,
+    impl_traverse_command,
+    utils::{normalize_range, unix_millis_to_instant},
+};
+use anyhow::Result;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::time::{Duration, Instant};
+
+const EX: usize = rolling_hash_const(b"ex");
+const PX: usize = rolling_hash_const(b"px");
+const EXAT: usize = rolling_hash_const(b"exat");
+const PXAT: usize = rolling_hash_const(b"pxat");
+const PERSIST: usize = rolling_hash_const(b"persist");
+const LEN: usize = rolling_hash_const(b"len");
+const IDX: usize = rolling_hash_const(b"idx");
+const MINMATCHLEN: usize = rolling_hash_const(b"minmatchlen");
+const WITHMATCHLEN: usize = rolling_hash_const(b"withmatchlen");
+
+/// Strings are capped at 512MB.
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub enum StringsVariant {
+    Append,
+    StrLen,
+    GetRange,
+    SetRange,
+    GetDel,
+    GetEx,
+    MSetNx,
+    Lcs,
+}
+
+#[derive(Debug, Clone)]
+enum TtlChange {
+    At(Instant),
+    Persist,
+}
+
+#[derive(Debug, Clone)]
+enum StringsOp {
+    Append(Bytes),
+    StrLen,
+    GetRange(i64, i64),
+    SetRange(usize, Bytes),
+    GetDel,
+    GetEx(Option<TtlChange>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Strings {
+    key: Bytes,
+    op: StringsOp,
+}
+
+/// Parses the single optional `EX|PX|EXAT|PXAT|PERSIST` of GETEX.
+fn parse_ttl_change(parser: &mut CommandParser) -> Result<Option<TtlChange>> {
+    let option = match parser.next_bytes()? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let token = rolling_hash(option.as_ref())?;
+    if token == PERSIST {
+        return Ok(Some(TtlChange::Persist));
+    }
+    let num = parser
+        .next_integer()?
+        .filter(|v| *v > 0)
+        .ok_or_else(invalid_operand)?;
+    let at = match token {
+        EX | PX => {
+            let millis = if token == EX {
+                num.checked_mul(1000).ok_or_else(invalid_operand)?
+            } else {
+                num
+            };
+            Instant::now()
+                .checked_add(Duration::from_millis(millis as u64))
+                .ok_or_else(invalid_operand)?
+        }
+        EXAT => unix_millis_to_instant(num.checked_mul(1000).ok_or_else(invalid_operand)?),
+        PXAT => unix_millis_to_instant(num),
+        _ => return Err(invalid_operation()),
+    };
+    Ok(Some(TtlChange::At(at)))
+}
+
+impl Strings {
+    pub fn new(parser: &mut CommandParser, variant: StringsVariant) -> Result<Strings> {
+        use StringsVariant::*;
+        let key = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let op = match variant {
+            Append => StringsOp::Append(parser.next_bytes()?.ok_or_else(missing_operand)?),
+            StrLen => StringsOp::StrLen,
+            GetRange => {
+                let start = parser.next_integer()?.ok_or_else(missing_operand)?;
+                let end = parser.next_integer()?.ok_or_else(missing_operand)?;
+                StringsOp::GetRange(start, end)
+            }
+            SetRange => {
+                let offset = parser
+                    .next_integer()?
+                    .filter(|v| *v >= 0)
+                    .ok_or_else(invalid_operand)? as usize;
+                let value = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                if offset + value.len() > MAX_STRING_LEN {
+                    return Err(invalid_operand());
+                }
+                StringsOp::SetRange(offset, value)
+            }
+            GetDel => StringsOp::GetDel,
+            GetEx => StringsOp::GetEx(parse_ttl_change(parser)?),
+            MSetNx | Lcs => unreachable!("{:?} is dispatched to multiple databases", variant),
+        };
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(Self { key, op })
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        let key = &self.key;
+        match self.op {
+            StringsOp::Append(value) => {
+                let buf = match db.get_str_mut(key, true) {
+                    Ok(b) => b.unwrap(),
+                    Err(e) => return e,
+                };
+                if buf.len() + value.len() > MAX_STRING_LEN {
+                    return Frame::Errors(Bytes::from_static(
+                        b"ERR string exceeds maximum allowed size (512MB)",
+                    ));
+                }
+                buf.extend_from_slice(&value);
+                let len = buf.len();
+                db.after_write(key, false);
+                Frame::Integers(len as i64)
+            }
+            StringsOp::StrLen => match db.get_str(key) {
+                Ok(b) => Frame::Integers(b.map_or(0, |b| b.len()) as i64),
+                Err(e) => e,
+            },
+            StringsOp::GetRange(start, end) => match db.get_str(key) {
+                Ok(b) => {
+                    let b = b.unwrap_or_default();
+                    match normalize_range(start, end, b.len()) {
+                        Some((s, e)) => Frame::BulkStrings(Bytes::copy_from_slice(&b[s..=e])),
+                        None => Frame::BulkStrings(Bytes::new()),
+                    }
+                }
+                Err(e) => e,
+            },
+            StringsOp::SetRange(offset, value) => {
+                // an empty value only reads the length, no key is created.
+                if value.is_empty() {
+                    return match db.get_str(key) {
+                        Ok(b) => Frame::Integers(b.map_or(0, |b| b.len()) as i64),
+                        Err(e) => e,
+                    };
+                }
+                let buf = match db.get_str_mut(key, true) {
+                    Ok(b) => b.unwrap(),
+                    Err(e) => return e,
+                };
+                let end = offset + value.len();
+                if buf.len() < end {
+                    buf.resize(end, 0);
+                }
+                buf[offset..end].copy_from_slice(&value);
+                let len = buf.len();
+                db.after_write(key, false);
+                Frame::Integers(len as i64)
+            }
+            StringsOp::GetDel => match db.get_str(key) {
+                Ok(Some(_)) => db.remove(key).unwrap().data.into_str_frame(),
+                Ok(None) => Frame::NullString,
+                Err(e) => e,
+            },
+            StringsOp::GetEx(change) => {
+                let value = match db.get_str(key) {
+                    Ok(Some(b)) => Frame::BulkStrings(Bytes::copy_from_slice(&b)),
+                    Ok(None) => return Frame::NullString,
+                    Err(e) => return e,
+                };
+                match change {
+                    Some(TtlChange::Persist)
+                        if db.get_live(key).map_or(false, |en| en.expiration.is_some()) =>
+                    {
+                        db.set_expiration(key, None);
+                    }
+                    None | Some(TtlChange::Persist) => (),
+                    Some(TtlChange::At(at)) if at <= Instant::now() && !db.loading => {
+                        db.remove(key);
+                    }
+                    Some(TtlChange::At(at)) => {
+                        db.set_expiration(key, Some(at));
+                    }
+                }
+                value
+            }
+        }
+    }
+}
+
+impl OneshotExecDB for Strings {
+    fn get_key(&self) -> &[u8] {
+        self.key.as_ref()
+    }
+
+    fn is_write(&self) -> bool {
+        !matches!(
+            self.op,
+            StringsOp::StrLen | StringsOp::GetRange(..) | StringsOp::GetEx(None)
+        )
+    }
+
+    /// GETEX logs the deadline it left as unix time.
+    fn propagate(&self, frame: Frame, _ret: &Frame, db: &mut DB) {
+        match self.op {
+            StringsOp::GetEx(Some(_)) => db.propagate_ttl(&self.key),
+            StringsOp::GetEx(None) => (),
+            _ => db.propagate(frame),
+        }
+    }
+}
+
+/// Reads the strings under every given key living in one database, a
+/// missing key reads as `Frame::NullString`.
+#[derive(Debug, Clone)]
+pub struct StrGather {
+    keys: Vec<MiniCommand>,
+}
+
+impl StrGather {
+    pub fn new(keys: Vec<MiniCommand>) -> StrGather {
+        Self { keys }
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        Frame::Arrays(
+            self.keys
+                .iter()
+                .map(|cmd| match db.get_str(cmd.ref_single()) {
+                    Ok(Some(b)) => Frame::BulkStrings(Bytes::copy_from_slice(&b)),
+                    Ok(None) => Frame::NullString,
+                    Err(e) => e,
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Tells whether any key of MSETNX living in one database exists, in which
+/// case none of the pairs is set. Answers with the number of existing keys.
+#[derive(Debug, Clone)]
+pub struct MSetNxCheck {
+    cmds: Vec<MiniCommand>,
+    refused: Arc<AtomicBool>,
+}
+
+impl MSetNxCheck {
+    pub fn exec(self, db: &mut DB) -> Frame {
+        let existing = self
+            .cmds
+            .iter()
+            .filter(|cmd| db.get_live(cmd.get_key()).is_some())
+            .count();
+        if existing > 0 {
+            self.refused.store(true, Ordering::Relaxed);
+        }
+        Frame::Integers(existing as i64)
+    }
+}
+
+/// Sets the pairs of MSETNX living in one database, unless a check refused
+/// them. Answers with `0`, so that only the checks are counted.
+#[derive(Debug, Clone)]
+pub struct MSetNx {
+    cmds: Vec<MiniCommand>,
+    refused: Arc<AtomicBool>,
+}
+
+impl MSetNx {
+    pub fn exec(self, db: &mut DB) -> Frame {
+        if !self.refused.load(Ordering::Relaxed) {
+            MSet::new(self.cmds).exec(db);
+        }
+        Frame::Integers(0)
+    }
+}
+
+/// Checks every database holding some of the keys, then sets the pairs on
+/// all of them if none of the keys exists. It holds the databases
+/// throughout, so nobody sees the keys set on some of them only.
+#[derive(Debug, Clone, Default)]
+pub struct MSetNxDispatcher {
+    db_amount: usize,
+    /// The databases left to check, the pairs are set once it is 0.
+    unchecked: usize,
+    refused: Arc<AtomicBool>,
+    cmds: Vec<MiniCommand>,
+    cmds_tbl: Vec<Vec<MiniCommand>>,
+}
+
+impl MSetNxDispatcher {
+    pub fn new(parser: &mut CommandParser) -> Result<MSetNxDispatcher> {
+        let len = parser.len() / 2;
+        if len == 0 || parser.len() % 2 != 0 {
+            return Err(missing_operand());
+        }
+        let mut cmds = Vec::with_capacity(len);
+        while let Some(p) = parser.next_kv_pair()? {
+            cmds.push(p.into());
+        }
+        Ok(Self {
+            cmds,
+            ..Default::default()
+        })
+    }
+}
+
+use crate::default_pop;
+impl DispatchToMultipleDB for MSetNxDispatcher {
+    fn next_command(&mut self) -> Option<IDCommandPair> {
+        while self.unchecked > 0 {
+            self.unchecked -= 1;
+            let cmds = &self.cmds_tbl[self.unchecked];
+            if !cmds.is_empty() {
+                let check = MSetNxCheck {
+                    cmds: cmds.clone(),
+                    refused: self.refused.clone(),
+                };
+                return Some((self.unchecked, check.into()));
+            }
+        }
+        while self.db_amount > 0 {
+            self.db_amount -= 1;
+            if let Some(cmds) = default_pop!(self) {
+                let set = MSetNx {
+                    cmds,
+                    refused: self.refused.clone(),
+                };
+                return Some((self.db_amount, set.into()));
+            }
+        }
+        None
+    }
+
+    // both the check and the set of every database answer with an integer.
+    fn get_result_collector(&mut self) -> ResultCollector {
+        let involved = self.cmds_tbl.iter().filter(|v| !v.is_empty()).count();
+        self.unchecked = self.db_amount;
+        ResultCollector {
+            result_type: ResultCollectorType::SumFirst((involved * 2, 0)),
+            ret: Vec::with_capacity(1),
+        }
+    }
+
+    impl_traverse_command!(@Dispatch, N:1);
+
+    fn complete(&mut self, merged: Frame) -> Completion {
+        Completion::Reply(Frame::Integers(matches!(merged, Frame::Integers(0)) as i64))
+    }
+
+    fn locked_databases(&self) -> Vec<usize> {
+        (0..self.db_amount)
+            .filter(|&db_id| !self.cmds_tbl[db_id].is_empty())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct LcsOptions {
+    len: bool,
+    idx: bool,
+    min_match_len: usize,
+    with_match_len: bool,
+}
+
+/// A run of common bytes, as inclusive `(start, end)` ranges in both strings.
+type Match = ((usize, usize), (usize, usize));
+
+fn range_frame((start, end): (usize, usize)) -> Frame {
+    Frame::Arrays(vec![
+        Frame::Integers(start as i64),
+        Frame::Integers(end as i64),
+    ])
+}
+
+fn lcs(a: &[u8], b: &[u8], opts: LcsOptions) -> Frame {
+    let width = b.len() + 1;
+    let cells = match (a.len() + 1).checked_mul(width) {
+        Some(v) if v <= MAX_STRING_LEN / 4 => v,
+        _ => {
+            return Frame::Errors(Bytes::from_static(
+                b"ERR Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len",
+            ))
+        }
+    };
+    // `table[i * width + j]` is the length of the LCS of `a[..i]` and `b[..j]`.
+    let mut table = vec![0u32; cells];
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            table[i * width + j] = if a[i - 1] == b[j - 1] {
+                table[(i - 1) * width + j - 1] + 1
+            } else {
+                table[(i - 1) * width + j].max(table[i * width + j - 1])
+            };
+        }
+    }
+    let total = table[a.len() * width + b.len()] as usize;
+    if opts.len {
+        return Frame::Integers(total as i64);
+    }
+
+    // walks back from the end of both strings, matches come out last first.
+    let mut res = vec![0; total];
+    let mut matches: Vec<Match> = Vec::new();
+    let mut current: Option<Match> = None;
+    let (mut i, mut j, mut idx) = (a.len(), b.len(), total);
+    while i > 0 && j > 0 {
+        if a[i - 1] == b[j - 1] {
+            idx -= 1;
+            res[idx] = a[i - 1];
+            current = match current {
+                Some(((a_start, a_end), (b_start, b_end))) if a_start == i && b_start == j => {
+                    Some(((i - 1, a_end), (j - 1, b_end)))
+                }
+                prev => {
+                    matches.extend(prev);
+                    Some(((i - 1, i - 1), (j - 1, j - 1)))
+                }
+            };
+            i -= 1;
+            j -= 1;
+        } else {
+            if table[(i - 1) * width + j] > table[i * width + j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+            matches.extend(current.take());
+        }
+    }
+    matches.extend(current);
+
+    if !opts.idx {
+        return Frame::BulkStrings(Bytes::from(res));
+    }
+    let matches = matches
+        .into_iter()
+        .filter(|((start, end), _)| end - start + 1 >= opts.min_match_len)
+        .map(|((a_start, a_end), (b_start, b_end))| {
+            let mut m = vec![range_frame((a_start, a_end)), range_frame((b_start, b_end))];
+            if opts.with_match_len {
+                m.push(Frame::Integers((a_end - a_start + 1) as i64));
+            }
+            Frame::Arrays(m)
+        })
+        .collect();
+    Frame::Arrays(vec![
+        Bytes::from_static(b"matches").into(),
+        Frame::Arrays(matches),
+        Bytes::from_static(b"len").into(),
+        Frame::Integers(total as i64),
+    ])
+}
+
+fn combine(opts: LcsOptions, frames: Vec<Frame>) -> Frame {
+    let mut sources = Vec::with_capacity(2);
+    for f in frames {
+        match f {
+            Frame::BulkStrings(b) => sources.push(b),
+            Frame::NullString => sources.push(Bytes::new()),
+            e => return e,
+        }
+    }
+    lcs(&sources[0], &sources[1], opts)
+}
+
+/// Gathers both strings of LCS from their databases, and compares them once
+/// both have answered.
+#[derive(Debug, Clone)]
+pub struct LcsDispatcher {
+    opts: LcsOptions,
+    db_amount: usize,
+    cmds: Vec<MiniCommand>,
+    cmds_tbl: Vec<Vec<MiniCommand>>,
+    order_tbl: Vec<Vec<usize>>,
+    len: usize,
+}
+
+impl LcsDispatcher {
+    pub fn new(parser: &mut CommandParser) -> Result<LcsDispatcher> {
+        let mut cmds: Vec<MiniCommand> = Vec::with_capacity(2);
+        for _ in 0..2 {
+            cmds.push(parser.next_bytes()?.ok_or_else(missing_operand)?.into());
+        }
+        let mut opts = LcsOptions::default();
+        while let Some(option) = parser.next_bytes()? {
+            match rolling_hash(option.as_ref())? {
+                LEN => opts.len = true,
+                IDX => opts.idx = true,
+                MINMATCHLEN => {
+                    opts.min_match_len =
+                        parser.next_integer()?.ok_or_else(missing_operand)?.max(0) as usize
+                }
+                WITHMATCHLEN => opts.with_match_len = true,
+                _ => return Err(invalid_operation()),
+            }
+        }
+        if opts.len && opts.idx {
+            return Err(invalid_operation());
+        }
+        Ok(Self {
+            opts,
+            db_amount: 0,
+            cmds,
+            cmds_tbl: Vec::new(),
+            order_tbl: Vec::new(),
+            len: 2,
+        })
+    }
+}
+
+impl DispatchToMultipleDB for LcsDispatcher {
+    impl_traverse_command!(@Consts, StrGather, default_pop);
+
+    fn get_result_collector(&mut self) -> ResultCollector {
+        let opts = self.opts;
+        let ret = vec![Frame::NullString; self.len];
+        ResultCollector {
+            result_type: ResultCollectorType::Combine(
+                std::mem::take(&mut self.order_tbl),
+                Box::new(move |frames| combine(opts, frames)),
+            ),
+            ret,
+        }
+    }
+
+    impl_traverse_command!(@Dispatch, N:N);
+}
+
+impl AtomicCMDMarker for Strings {}
+impl AtomicCMDMarker for StrGather {}
+impl AtomicCMDMarker for MSetNxCheck {}
+impl AtomicCMDMarker for MSetNx {}
@@ -0,0 +1,223 @@
+use crate::{cmd::*, db::DB};
+
+#[derive(Debug, Clone, Copy)]
+pub enum TransactionVariant {
+    Multi,
+    Exec,
+    Discard,
+    Watch,
+    Unwatch,
+}
+
+#[derive(Debug)]
+pub enum TransactionCommand {
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<Bytes>),
+    Unwatch,
+}
+
+impl TransactionCommand {
+    pub fn new(
+        parser: &mut CommandParser,
+        variant: TransactionVariant,
+    ) -> Result<TransactionCommand> {
+        let cmd = match variant {
+            TransactionVariant::Multi => TransactionCommand::Multi,
+            TransactionVariant::Exec => TransactionCommand::Exec,
+            TransactionVariant::Discard => TransactionCommand::Discard,
+            TransactionVariant::Unwatch => TransactionCommand::Unwatch,
+            TransactionVariant::Watch => {
+                let mut keys = vec![parser.next_bytes()?.ok_or_else(missing_operand)?];
+                while let Some(key) = parser.next_bytes()? {
+                    keys.push(key);
+                }
+                return Ok(TransactionCommand::Watch(keys));
+            }
+        };
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(cmd)
+    }
+}
+
+/// A key as WATCH saw it, along with its version.
+pub type WatchedKey = (Bytes, u64);
+
+impl DB {
+    /// The nounce of the entry under `key`, which changes on every write. A
+    /// missing key is at the last removal of the database, so that it reads
+    /// as changed once it was created, even if it is gone again.
+    pub fn version(&mut self, key: &[u8]) -> u64 {
+        match self.get_live(key) {
+            Some(en) => en.nounce,
+            None => self.removed,
+        }
+    }
+
+    /// Whether none of `watched` was written, deleted or expired since.
+    pub fn unchanged(&mut self, watched: &[WatchedKey]) -> bool {
+        watched
+            .iter()
+            .all(|(key, version)| self.version(key) == *version)
+    }
+}
+
+/// Reads the versions of keys living in one database.
+#[derive(Debug)]
+pub struct Versions {
+    pub keys: Vec<Bytes>,
+}
+
+impl Versions {
+    pub fn exec(self, db: &mut DB) -> Frame {
+        Frame::Arrays(
+            self.keys
+                .iter()
+                .map(|key| Frame::Integers(db.version(key) as i64))
+                .collect(),
+        )
+    }
+}
+
+/// The commands of a transaction that all live on one database, shipped in
+/// a single message. Replies with the array of their replies, or with
+/// `Frame::NullArray` if one of the `watched` keys has changed.
+#[derive(Debug)]
+pub struct Batch {
+    pub cmds: Vec<AtomicCMD>,
+    pub watched: Vec<WatchedKey>,
+}
+
+impl AtomicCMDMarker for Versions {}
+impl AtomicCMDMarker for Batch {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::run_task;
+    use tokio::sync::broadcast;
+
+    fn db() -> DB {
+        let (shutdown_tx, _) = broadcast::channel(1);
+        let mut db = DB::new(0, shutdown_tx);
+        db.loading = false;
+        db
+    }
+
+    fn cmd(args: &[&str]) -> AtomicCMD {
+        let frame = Frame::Arrays(
+            args.iter()
+                .map(|arg| Frame::BulkStrings(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect(),
+        );
+        match Command::new(frame).unwrap() {
+            Command::Oneshot(c) => c.into(),
+            c => panic!("{:?} isn't a single key command", c),
+        }
+    }
+
+    async fn run(db: &mut DB, cmd: impl Into<AtomicCMD>) -> Frame {
+        let (ret_tx, ret_rx) = oneshot::channel();
+        run_task(db, cmd.into(), ret_tx).await;
+        ret_rx.await.unwrap()
+    }
+
+    fn watch(db: &mut DB, key: &str) -> Vec<WatchedKey> {
+        let key = Bytes::copy_from_slice(key.as_bytes());
+        let version = db.version(&key);
+        vec![(key, version)]
+    }
+
+    #[tokio::test]
+    async fn batch_runs_commands_in_order() {
+        let mut db = db();
+        let batch = Batch {
+            cmds: vec![
+                cmd(&["SET", "k", "1"]),
+                cmd(&["INCR", "k"]),
+                cmd(&["STRLEN", "k"]),
+            ],
+            watched: Vec::new(),
+        };
+        assert_eq!(
+            format!("{:?}", run(&mut db, batch).await),
+            r#"Arrays([Ok, Integers(2), Integers(1)])"#
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_gives_up_on_changed_watched_key() {
+        let mut db = db();
+        let watched = watch(&mut db, "k");
+        run(&mut db, cmd(&["SET", "k", "v"])).await;
+        let batch = Batch {
+            cmds: vec![cmd(&["SET", "k", "w"])],
+            watched,
+        };
+        assert!(matches!(run(&mut db, batch).await, Frame::NullArray));
+        assert!(matches!(
+            run(&mut db, cmd(&["GET", "k"])).await,
+            Frame::BulkStrings(v) if v == "v"
+        ));
+    }
+
+    #[tokio::test]
+    async fn set_keepttl_changes_watched_key() {
+        let mut db = db();
+        for write in [
+            &["SET", "k", "w", "KEEPTTL"][..],
+            &["SET", "k", "w", "XX", "KEEPTTL"],
+            &["SET", "k", "w", "GET", "KEEPTTL"],
+        ] {
+            run(&mut db, cmd(&["SET", "k", "v", "EX", "100"])).await;
+            let watched = watch(&mut db, "k");
+            run(&mut db, cmd(write)).await;
+            assert!(!db.unchanged(&watched), "{:?}", write);
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_and_deletes_change_watched_key() {
+        let mut db = db();
+        for write in [
+            &["SET", "k", "w"][..],
+            &["APPEND", "k", "w"],
+            &["INCR", "k"],
+            &["EXPIRE", "k", "100"],
+            &["GETDEL", "k"],
+        ] {
+            run(&mut db, cmd(&["SET", "k", "1"])).await;
+            let watched = watch(&mut db, "k");
+            run(&mut db, cmd(write)).await;
+            assert!(!db.unchanged(&watched), "{:?}", write);
+        }
+        // a key missing when watched changes once it is created.
+        let watched = watch(&mut db, "new");
+        run(&mut db, cmd(&["RPUSH", "new", "a"])).await;
+        assert!(!db.unchanged(&watched));
+    }
+
+    #[tokio::test]
+    async fn reads_leave_watched_key_unchanged() {
+        let mut db = db();
+        run(&mut db, cmd(&["SET", "k", "1"])).await;
+        let watched = watch(&mut db, "k");
+        for read in [&["GET", "k"][..], &["STRLEN", "k"], &["TTL", "k"]] {
+            run(&mut db, cmd(read)).await;
+            assert!(db.unchanged(&watched), "{:?}", read);
+        }
+    }
+
+    #[tokio::test]
+    async fn expiry_changes_watched_key() {
+        let mut db = db();
+        run(&mut db, cmd(&["SET", "k", "v", "PX", "10"])).await;
+        let watched = watch(&mut db, "k");
+        assert!(db.unchanged(&watched));
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(!db.unchanged(&watched));
+    }
+}
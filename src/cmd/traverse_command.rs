@@ -5,6 +5,18 @@ use crate::cmd::*;
 pub enum TraverseCommand {
     MSet(MSetDispatcher),
     MGet(MGetDispatcher),
+    Del(DelDispatcher),
+    Exists(ExistsDispatcher),
+    Keys(KeysDispatcher),
+    RandomKey(RandomKeyDispatcher),
+    Scan(ScanDispatcher),
+    SetAlgebra(SetAlgebraDispatcher),
+    ZSetAlgebra(ZSetAlgebraDispatcher),
+    HyperLogLog(HyperLogLogDispatcher),
+    BitOp(BitOpDispatcher),
+    MSetNx(MSetNxDispatcher),
+    Lcs(LcsDispatcher),
+    GeoSearchStore(GeoSearchStoreDispatcher),
     Dx(DxDispatcher),
 }
 
@@ -56,11 +68,26 @@ impl MiniCommand {
 
 pub type IDCommandPair = (usize, AtomicCMD);
 
+/// What becomes of the merged result of a traverse command.
+pub enum Completion {
+    Reply(Frame),
+    /// The command is sent on, and its reply is the one returned instead.
+    Forward(IDCommandPair),
+}
+
 #[enum_dispatch(TraverseCommand)]
 pub trait DispatchToMultipleDB {
     fn next_command(&mut self) -> Option<IDCommandPair>;
     fn get_result_collector(&mut self) -> ResultCollector;
     fn dispatch(&mut self, db_amount: usize, dispatch_fn: impl Fn(&[u8]) -> usize);
+    fn complete(&mut self, merged: Frame) -> Completion {
+        Completion::Reply(merged)
+    }
+    /// The databases, in ascending order, held from everybody else while the
+    /// command runs, for commands that write what they read elsewhere.
+    fn locked_databases(&self) -> Vec<usize> {
+        Vec::new()
+    }
 }
 
 pub(in crate::cmd) unsafe fn new_unsafe_vec(expected_amount_ret: usize) -> Vec<Frame> {
@@ -206,25 +233,54 @@ macro_rules! impl_traverse_command {
 
             crate::new_result_collector!($merge_result);
 
-            fn dispatch(&mut self, db_amount: usize, dispatch_fn: impl Fn(&[u8]) -> usize) {
-                self.db_amount = db_amount;
-                let mut tbl_len = vec![0; db_amount];
-                let mut db_ids: Vec<usize> = self
-                    .cmds.iter()
-                    .map(|v| {
-                        let id = dispatch_fn(v.get_key());
-                        tbl_len[id] += 1 as usize;
-                        id
-                    })
-                    .collect();
-
-                self.cmds_tbl = tbl_len.iter().map(|v| Vec::with_capacity(*v)).collect();
-                self.order_tbl = tbl_len.iter().map(|v| Vec::with_capacity(*v)).collect();
-
-                while let Some(db_id) = db_ids.pop() {
-                    self.cmds_tbl[db_id].push(self.cmds.pop().unwrap());
-                    self.order_tbl[db_id].push(db_ids.len());
-                }
+            impl_traverse_command!(@Dispatch, N:N);
+        }
+    };
+
+    (@Dispatch, N:N) => {
+        impl_traverse_command!(@Dispatch, N:N, dispatch);
+    };
+
+    (@Dispatch, N:N, $fn_name:ident) => {
+        fn $fn_name(&mut self, db_amount: usize, dispatch_fn: impl Fn(&[u8]) -> usize) {
+            self.db_amount = db_amount;
+            let mut tbl_len = vec![0; db_amount];
+            let mut db_ids: Vec<usize> = self
+                .cmds.iter()
+                .map(|v| {
+                    let id = dispatch_fn(v.get_key());
+                    tbl_len[id] += 1 as usize;
+                    id
+                })
+                .collect();
+
+            self.cmds_tbl = tbl_len.iter().map(|v| Vec::with_capacity(*v)).collect();
+            self.order_tbl = tbl_len.iter().map(|v| Vec::with_capacity(*v)).collect();
+
+            while let Some(db_id) = db_ids.pop() {
+                self.cmds_tbl[db_id].push(self.cmds.pop().unwrap());
+                self.order_tbl[db_id].push(db_ids.len());
+            }
+        }
+    };
+
+    (@Dispatch, N:1) => {
+        fn dispatch(&mut self, db_amount: usize, dispatch_fn: impl Fn(&[u8]) -> usize) {
+            self.db_amount = db_amount;
+            let mut tbl_len = vec![0; db_amount];
+            let mut db_ids: Vec<usize> = self
+                .cmds.iter()
+                .map(|v| {
+                    let id = dispatch_fn(v.get_key());
+                    tbl_len[id] += 1 as usize;
+                    id
+                })
+                .collect();
+
+            self.cmds_tbl = tbl_len.iter().map(|v| Vec::with_capacity(*v)).collect();
+
+            while let Some(db_id) = db_ids.pop() {
+                self.cmds_tbl[db_id].push(self.cmds.pop().unwrap());
             }
         }
     };
@@ -247,24 +303,28 @@ macro_rules! impl_traverse_command {
                 }
             }
 
-            fn dispatch(&mut self, db_amount: usize, dispatch_fn: impl Fn(&[u8]) -> usize) {
-                self.db_amount = db_amount;
-                let mut tbl_len = vec![0; db_amount];
-                let mut db_ids: Vec<usize> = self
-                    .cmds.iter()
-                    .map(|v| {
-                        let id = dispatch_fn(v.get_key());
-                        tbl_len[id] += 1 as usize;
-                        id
-                    })
-                    .collect();
-
-                self.cmds_tbl = tbl_len.iter().map(|v| Vec::with_capacity(*v)).collect();
-
-                while let Some(db_id) = db_ids.pop() {
-                    self.cmds_tbl[db_id].push(self.cmds.pop().unwrap());
+            impl_traverse_command!(@Dispatch, N:1);
+        }
+    };
+
+    (for cmd: $atomic_cmd:ident = $dispatcher:ident(($token_stream_schema:ident)$repetition:tt).$pop:ident!() {
+        cmd >> DB
+    }, DB >> 1 Frame >> SumFirst) => {
+        crate::new_traverse_command!($token_stream_schema$repetition, Return1, $dispatcher);
+
+        impl DispatchToMultipleDB for $dispatcher {
+            impl_traverse_command!(@Consts, $atomic_cmd, $pop);
+
+            // every database holding at least one key answers with an integer.
+            fn get_result_collector(&mut self) -> ResultCollector {
+                let involved = self.cmds_tbl.iter().filter(|v| v.len() > 0).count();
+                ResultCollector {
+                    result_type: ResultCollectorType::SumFirst((involved, 0)),
+                    ret: Vec::with_capacity(1),
                 }
             }
+
+            impl_traverse_command!(@Dispatch, N:1);
         }
     };
 }
@@ -0,0 +1,705 @@
+use crate::{
+    cmd::snapshot::{corrupt, put_bytes, take_bytes, take_f64, take_len},
+    cmd::*,
+    db::{wrong_type, Entry, Value},
+    utils::{get_float, get_integer, normalize_range},
+};
+use anyhow::Result;
+use rustc_hash::FxHashMap;
+use std::{cmp::Ordering, collections::BTreeSet};
+
+const NX: usize = rolling_hash_const(b"nx");
+const XX: usize = rolling_hash_const(b"xx");
+const GT: usize = rolling_hash_const(b"gt");
+const LT: usize = rolling_hash_const(b"lt");
+const CH: usize = rolling_hash_const(b"ch");
+const INCR: usize = rolling_hash_const(b"incr");
+const BYSCORE: usize = rolling_hash_const(b"byscore");
+const BYLEX: usize = rolling_hash_const(b"bylex");
+const REV: usize = rolling_hash_const(b"rev");
+const LIMIT: usize = rolling_hash_const(b"limit");
+const WITHSCORES: usize = rolling_hash_const(b"withscores");
+const WITHSCORE: usize = rolling_hash_const(b"withscore");
+
+const NAN_SCORE_ERR: &[u8] = b"ERR resulting score is not a number (NaN)";
+
+/// A score that is never NaN, and thus totally ordered.
+#[derive(Debug, Clone, Copy)]
+pub struct Score(pub f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members ordered by score, then by member, with a dictionary to look up
+/// the score of a member.
+#[derive(Debug)]
+pub struct ZSetValue {
+    dict: FxHashMap<Bytes, f64>,
+    tree: BTreeSet<(Score, Bytes)>,
+}
+
+impl ZSetValue {
+    pub fn new() -> Self {
+        Self {
+            dict: FxHashMap::default(),
+            tree: BTreeSet::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.dict.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dict.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.dict.get(member).copied()
+    }
+
+    /// Returns the previous score of `member`.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> Option<f64> {
+        let old = self.dict.insert(member.clone(), score);
+        if let Some(old) = old {
+            self.tree.remove(&(Score(old), member.clone()));
+        }
+        self.tree.insert((Score(score), member));
+        old
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> Option<f64> {
+        let (member, score) = self.dict.remove_entry(member)?;
+        self.tree.remove(&(Score(score), member));
+        Some(score)
+    }
+
+    /// 0-based position of `member`, counted from the highest score if
+    /// `rev` is set.
+    pub fn rank(&self, member: &[u8], rev: bool) -> Option<usize> {
+        let score = self.score(member)?;
+        let rank = self
+            .tree
+            .range(..(Score(score), Bytes::copy_from_slice(member)))
+            .count();
+        Some(if rev { self.len() - rank - 1 } else { rank })
+    }
+
+    /// Ascending by score.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Bytes, f64)> {
+        self.tree.iter().map(|(s, m)| (m, s.0))
+    }
+
+    /// Removes the member with the lowest score, or the highest one.
+    pub fn pop(&mut self, min: bool) -> Option<(Bytes, f64)> {
+        let (score, member) = if min {
+            self.tree.pop_first()?
+        } else {
+            self.tree.pop_last()?
+        };
+        self.dict.remove(&member);
+        Some((member, score.0))
+    }
+
+    /// Members from `(score, member)` on, ascending, looked up through the
+    /// ordered index.
+    fn seek(&self, score: f64, member: Bytes) -> impl Iterator<Item = (&Bytes, f64)> {
+        self.tree
+            .range((Score(score), member)..)
+            .map(|(s, m)| (m, s.0))
+    }
+
+    /// Members whose score lies in `[min, max)`, ascending.
+    pub fn score_range(&self, min: f64, max: f64) -> impl Iterator<Item = (&Bytes, f64)> {
+        self.seek(min, Bytes::new())
+            .take_while(move |(_, s)| *s < max)
+    }
+
+    /// Members whose score lies within `min` and `max`, ascending.
+    fn range_by_score(
+        &self,
+        min: ScoreBound,
+        max: ScoreBound,
+    ) -> impl Iterator<Item = (&Bytes, f64)> {
+        self.seek(min.value, Bytes::new())
+            .skip_while(move |(_, s)| !min.below(*s))
+            .take_while(move |(_, s)| max.above(*s))
+    }
+
+    /// Members within `min` and `max` in member order, which only means
+    /// something if all of them share one score.
+    fn range_by_lex<'a>(
+        &'a self,
+        min: &'a LexBound,
+        max: &'a LexBound,
+    ) -> impl Iterator<Item = (&'a Bytes, f64)> {
+        let score = self.tree.first().map_or(0.0, |(s, _)| s.0);
+        let from = match min {
+            LexBound::Inclusive(m) | LexBound::Exclusive(m) => m.clone(),
+            LexBound::Min | LexBound::Max => Bytes::new(),
+        };
+        self.seek(score, from)
+            .skip_while(move |(m, _)| !min.below(m))
+            .take_while(move |(m, _)| max.above(m))
+    }
+
+    /// Payload size in bytes.
+    pub fn bytes_len(&self) -> usize {
+        self.dict
+            .keys()
+            .fold(0, |res, m| res + m.len() + std::mem::size_of::<f64>())
+    }
+
+    pub fn dump(&self, buf: &mut BytesMut) {
+        buf.put_u64_le(self.len() as u64);
+        for (member, score) in self.iter() {
+            put_bytes(buf, member);
+            buf.put_f64_le(score);
+        }
+    }
+
+    pub fn restore(buf: &mut Bytes) -> Result<ZSetValue> {
+        let mut zset = ZSetValue::new();
+        for _ in 0..take_len(buf)? {
+            let member = take_bytes(buf)?;
+            let score = take_f64(buf)?;
+            if score.is_nan() {
+                return Err(corrupt());
+            }
+            zset.insert(member, score);
+        }
+        Ok(zset)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ScoreBound {
+    value: f64,
+    exclusive: bool,
+}
+
+impl ScoreBound {
+    /// Parses `1.5`, `(1.5`, `-inf` and `+inf`.
+    fn new(raw: &Bytes) -> Result<Self> {
+        let (exclusive, value) = match raw.first() {
+            Some(b'(') => (true, raw.slice(1..)),
+            _ => (false, raw.clone()),
+        };
+        Ok(Self {
+            value: get_float(&value).map_err(|_| invalid_operand())?,
+            exclusive,
+        })
+    }
+
+    /// Whether `score` is on the upper side of this bound, used as a minimum.
+    fn below(&self, score: f64) -> bool {
+        if self.exclusive {
+            score > self.value
+        } else {
+            score >= self.value
+        }
+    }
+
+    fn above(&self, score: f64) -> bool {
+        if self.exclusive {
+            score < self.value
+        } else {
+            score <= self.value
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum LexBound {
+    Min,
+    Max,
+    Inclusive(Bytes),
+    Exclusive(Bytes),
+}
+
+impl LexBound {
+    /// Parses `-`, `+`, `[member` and `(member`.
+    fn new(raw: &Bytes) -> Result<Self> {
+        match raw.first() {
+            Some(b'-') if raw.len() == 1 => Ok(LexBound::Min),
+            Some(b'+') if raw.len() == 1 => Ok(LexBound::Max),
+            Some(b'[') => Ok(LexBound::Inclusive(raw.slice(1..))),
+            Some(b'(') => Ok(LexBound::Exclusive(raw.slice(1..))),
+            _ => Err(invalid_operand()),
+        }
+    }
+
+    fn below(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(b) => member >= b.as_ref(),
+            LexBound::Exclusive(b) => member > b.as_ref(),
+        }
+    }
+
+    fn above(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(b) => member <= b.as_ref(),
+            LexBound::Exclusive(b) => member < b.as_ref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ZSetVariant {
+    ZAdd,
+    ZIncrBy,
+    ZRem,
+    ZCard,
+    ZScore,
+    ZRank,
+    ZRevRank,
+    ZCount,
+    ZRange,
+    ZPopMin,
+    ZPopMax,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ZAddFlags {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+    ch: bool,
+    incr: bool,
+}
+
+#[derive(Debug, Clone)]
+enum ZRangeBy {
+    Index(i64, i64),
+    Score(ScoreBound, ScoreBound),
+    Lex(LexBound, LexBound),
+}
+
+#[derive(Debug, Clone)]
+enum ZSetOp {
+    Add(ZAddFlags, Vec<(f64, Bytes)>),
+    Rem(Vec<Bytes>),
+    Card,
+    Score(Bytes),
+    Rank {
+        member: Bytes,
+        rev: bool,
+        withscore: bool,
+    },
+    Count(ScoreBound, ScoreBound),
+    Range {
+        by: ZRangeBy,
+        rev: bool,
+        limit: Option<(usize, Option<usize>)>,
+        withscores: bool,
+    },
+    Pop {
+        min: bool,
+        count: Option<usize>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct ZSet {
+    key: Bytes,
+    op: ZSetOp,
+}
+
+fn next_score(parser: &mut CommandParser) -> Result<f64> {
+    let raw = parser.next_bytes()?.ok_or_else(missing_operand)?;
+    get_float(&raw).map_err(|_| invalid_operand())
+}
+
+impl ZSet {
+    pub fn new(parser: &mut CommandParser, variant: ZSetVariant) -> Result<ZSet> {
+        use ZSetVariant::*;
+        let key = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let op = match variant {
+            ZAdd => {
+                let mut flags = ZAddFlags::default();
+                let mut pairs = Vec::new();
+                while let Some(token) = parser.next_bytes()? {
+                    match rolling_hash(token.as_ref()) {
+                        Ok(NX) => flags.nx = true,
+                        Ok(XX) => flags.xx = true,
+                        Ok(GT) => flags.gt = true,
+                        Ok(LT) => flags.lt = true,
+                        Ok(CH) => flags.ch = true,
+                        Ok(INCR) => flags.incr = true,
+                        // the first token that isn't a flag is a score.
+                        _ => {
+                            let score = get_float(&token).map_err(|_| invalid_operand())?;
+                            pairs.push((score, parser.next_bytes()?.ok_or_else(missing_operand)?));
+                            break;
+                        }
+                    }
+                }
+                if parser.len() % 2 != 0 {
+                    return Err(missing_operand());
+                }
+                while parser.len() > 0 {
+                    let score = next_score(parser)?;
+                    pairs.push((score, parser.next_bytes()?.ok_or_else(missing_operand)?));
+                }
+                if pairs.is_empty() {
+                    return Err(missing_operand());
+                }
+                if (flags.nx && (flags.xx || flags.gt || flags.lt))
+                    || (flags.gt && flags.lt)
+                    || (flags.incr && pairs.len() > 1)
+                {
+                    return Err(invalid_operation());
+                }
+                ZSetOp::Add(flags, pairs)
+            }
+            ZIncrBy => {
+                let by = next_score(parser)?;
+                let member = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                ZSetOp::Add(
+                    ZAddFlags {
+                        incr: true,
+                        ..Default::default()
+                    },
+                    vec![(by, member)],
+                )
+            }
+            ZRem => {
+                let mut members = Vec::with_capacity(parser.len());
+                while let Some(m) = parser.next_bytes()? {
+                    members.push(m);
+                }
+                if members.is_empty() {
+                    return Err(missing_operand());
+                }
+                ZSetOp::Rem(members)
+            }
+            ZCard => ZSetOp::Card,
+            ZScore => ZSetOp::Score(parser.next_bytes()?.ok_or_else(missing_operand)?),
+            ZRank | ZRevRank => {
+                let member = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let withscore = match parser.next_bytes()? {
+                    Some(v) if rolling_hash(v.as_ref())? == WITHSCORE => true,
+                    Some(_) => return Err(invalid_operation()),
+                    None => false,
+                };
+                ZSetOp::Rank {
+                    member,
+                    rev: matches!(variant, ZRevRank),
+                    withscore,
+                }
+            }
+            ZCount => {
+                let min = ScoreBound::new(&parser.next_bytes()?.ok_or_else(missing_operand)?)?;
+                let max = ScoreBound::new(&parser.next_bytes()?.ok_or_else(missing_operand)?)?;
+                ZSetOp::Count(min, max)
+            }
+            ZRange => {
+                let start = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let stop = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                let (mut by_score, mut by_lex, mut rev, mut withscores) =
+                    (false, false, false, false);
+                let mut limit = None;
+                while let Some(option) = parser.next_bytes()? {
+                    match rolling_hash(option.as_ref())? {
+                        BYSCORE => by_score = true,
+                        BYLEX => by_lex = true,
+                        REV => rev = true,
+                        WITHSCORES => withscores = true,
+                        LIMIT => {
+                            let offset = parser.next_integer()?.ok_or_else(missing_operand)?;
+                            let count = parser.next_integer()?.ok_or_else(missing_operand)?;
+                            // a negative count returns everything past the offset.
+                            limit = Some((
+                                offset.max(0) as usize,
+                                if count < 0 {
+                                    None
+                                } else {
+                                    Some(count as usize)
+                                },
+                            ));
+                        }
+                        _ => return Err(invalid_operation()),
+                    }
+                }
+                if (by_score && by_lex)
+                    || (withscores && by_lex)
+                    || (limit.is_some() && !by_score && !by_lex)
+                {
+                    return Err(invalid_operation());
+                }
+                // with REV the range is given from the high end.
+                let (low, high) = if rev && (by_score || by_lex) {
+                    (stop, start)
+                } else {
+                    (start, stop)
+                };
+                let by = if by_score {
+                    ZRangeBy::Score(ScoreBound::new(&low)?, ScoreBound::new(&high)?)
+                } else if by_lex {
+                    ZRangeBy::Lex(LexBound::new(&low)?, LexBound::new(&high)?)
+                } else {
+                    let parse = |v: &Bytes| get_integer(v).map_err(|_| invalid_operand());
+                    ZRangeBy::Index(parse(&low)?, parse(&high)?)
+                };
+                ZSetOp::Range {
+                    by,
+                    rev,
+                    limit,
+                    withscores,
+                }
+            }
+            ZPopMin | ZPopMax => ZSetOp::Pop {
+                min: matches!(variant, ZPopMin),
+                count: match parser.next_integer()? {
+                    Some(v) if v < 0 => return Err(invalid_operand()),
+                    v => v.map(|v| v as usize),
+                },
+            },
+        };
+        if parser.len() > 0 {
+            return Err(invalid_operation());
+        }
+        Ok(Self { key, op })
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        let key = &self.key;
+        match self.op {
+            ZSetOp::Add(flags, pairs) => db.zadd(key, &flags, pairs),
+            ZSetOp::Rem(members) => db.zrem(key, &members),
+            ZSetOp::Pop { min, count } => match db.zpop(key, min, count.unwrap_or(1)) {
+                Ok(popped) => flatten(popped.iter().map(|(m, s)| (m, *s)), true),
+                Err(e) => e,
+            },
+            op => {
+                let zset = match db.get_zset(key) {
+                    Ok(z) => z,
+                    Err(e) => return e,
+                };
+                zset_read(zset.map(|z| &*z), op)
+            }
+        }
+    }
+}
+
+impl OneshotExecDB for ZSet {
+    fn get_key(&self) -> &[u8] {
+        self.key.as_ref()
+    }
+
+    fn is_write(&self) -> bool {
+        matches!(
+            self.op,
+            ZSetOp::Add(..) | ZSetOp::Rem(_) | ZSetOp::Pop { .. }
+        )
+    }
+}
+
+pub fn score_to_bytes(score: f64) -> Bytes {
+    Bytes::from(score.to_string())
+}
+
+fn flatten<'a>(pairs: impl Iterator<Item = (&'a Bytes, f64)>, withscores: bool) -> Frame {
+    let mut res = Vec::new();
+    for (m, s) in pairs {
+        res.push(m.clone().into());
+        if withscores {
+            res.push(score_to_bytes(s).into());
+        }
+    }
+    Frame::Arrays(res)
+}
+
+/// Commands that leave the sorted set untouched, `zset` is `None` for a
+/// missing key.
+fn zset_read(zset: Option<&ZSetValue>, op: ZSetOp) -> Frame {
+    let empty = ZSetValue::new();
+    let zset = zset.unwrap_or(&empty);
+    match op {
+        ZSetOp::Card => Frame::Integers(zset.len() as i64),
+        ZSetOp::Score(member) => zset
+            .score(&member)
+            .map_or(Frame::NullString, |s| score_to_bytes(s).into()),
+        ZSetOp::Rank {
+            member,
+            rev,
+            withscore,
+        } => match zset.rank(&member, rev) {
+            None => Frame::NullString,
+            Some(rank) if withscore => Frame::Arrays(vec![
+                Frame::Integers(rank as i64),
+                score_to_bytes(zset.score(&member).unwrap()).into(),
+            ]),
+            Some(rank) => Frame::Integers(rank as i64),
+        },
+        ZSetOp::Count(min, max) => Frame::Integers(zset.range_by_score(min, max).count() as i64),
+        ZSetOp::Range {
+            by,
+            rev,
+            limit,
+            withscores,
+        } => {
+            let mut picked: Vec<_> = match &by {
+                ZRangeBy::Index(start, stop) => match normalize_range(*start, *stop, zset.len()) {
+                    None => Vec::new(),
+                    Some((s, e)) if rev => zset.iter().rev().skip(s).take(e - s + 1).collect(),
+                    Some((s, e)) => zset.iter().skip(s).take(e - s + 1).collect(),
+                },
+                ZRangeBy::Score(min, max) => zset.range_by_score(*min, *max).collect(),
+                ZRangeBy::Lex(min, max) => zset.range_by_lex(min, max).collect(),
+            };
+            // ranges by score or member are looked up ascending.
+            if rev && !matches!(by, ZRangeBy::Index(..)) {
+                picked.reverse();
+            }
+            let (offset, count) = limit.unwrap_or((0, None));
+            flatten(
+                picked
+                    .into_iter()
+                    .skip(offset)
+                    .take(count.unwrap_or(usize::MAX)),
+                withscores,
+            )
+        }
+        ZSetOp::Add(..) | ZSetOp::Rem(_) | ZSetOp::Pop { .. } => {
+            unreachable!("writes are handled by `ZSet::exec`")
+        }
+    }
+}
+
+impl DB {
+    /// `Ok(None)` if there is no such key, `Err` carries the error reply.
+    pub fn get_zset(&mut self, key: &Bytes) -> std::result::Result<Option<&mut ZSetValue>, Frame> {
+        match self.get_live(key).map(|en| &mut en.data) {
+            None => Ok(None),
+            Some(Value::ZSet(z)) => Ok(Some(z)),
+            Some(_) => Err(wrong_type()),
+        }
+    }
+
+    /// Like `get_zset`, but creates the sorted set if there is no such key.
+    pub fn get_or_create_zset(
+        &mut self,
+        key: &Bytes,
+    ) -> std::result::Result<&mut ZSetValue, Frame> {
+        if self.get_zset(key)?.is_none() {
+            self.counter += 1;
+            let nounce = self.counter;
+            self.insert(
+                key.clone(),
+                Entry::new(Value::ZSet(ZSetValue::new()), None, nounce),
+            );
+        }
+        Ok(self.get_zset(key)?.unwrap())
+    }
+
+    fn zadd(&mut self, key: &Bytes, flags: &ZAddFlags, pairs: Vec<(f64, Bytes)>) -> Frame {
+        let zset = match self.get_or_create_zset(key) {
+            Ok(z) => z,
+            Err(e) => return e,
+        };
+        let (mut added, mut updated) = (0, 0);
+        let mut incr_res = None;
+        for (score, member) in pairs {
+            let old = zset.score(&member);
+            if (flags.nx && old.is_some()) || (flags.xx && old.is_none()) {
+                continue;
+            }
+            let score = if flags.incr {
+                old.unwrap_or(0.0) + score
+            } else {
+                score
+            };
+            if score.is_nan() {
+                let emptied = zset.is_empty();
+                self.after_write(key, emptied);
+                return Frame::Errors(Bytes::from_static(NAN_SCORE_ERR));
+            }
+            match old {
+                Some(old) => {
+                    if (flags.gt && score <= old) || (flags.lt && score >= old) {
+                        continue;
+                    }
+                    if score != old {
+                        zset.insert(member, score);
+                        updated += 1;
+                    }
+                }
+                None => {
+                    zset.insert(member, score);
+                    added += 1;
+                }
+            }
+            incr_res = Some(score);
+        }
+        let emptied = zset.is_empty();
+        self.after_write(key, emptied);
+        if added > 0 {
+            self.signal_ready(key);
+        }
+        if flags.incr {
+            incr_res.map_or(Frame::NullString, |s| score_to_bytes(s).into())
+        } else if flags.ch {
+            Frame::Integers(added + updated)
+        } else {
+            Frame::Integers(added)
+        }
+    }
+
+    fn zrem(&mut self, key: &Bytes, members: &[Bytes]) -> Frame {
+        let zset = match self.get_zset(key) {
+            Ok(Some(z)) => z,
+            Ok(None) => return Frame::Integers(0),
+            Err(e) => return e,
+        };
+        let removed = members.iter().filter(|m| zset.remove(m).is_some()).count();
+        let emptied = zset.is_empty();
+        if removed > 0 {
+            self.after_write(key, emptied);
+        }
+        Frame::Integers(removed as i64)
+    }
+
+    /// Pops up to `count` members from the low end, or the high end.
+    pub fn zpop(
+        &mut self,
+        key: &Bytes,
+        min: bool,
+        count: usize,
+    ) -> std::result::Result<Vec<(Bytes, f64)>, Frame> {
+        let zset = match self.get_zset(key)? {
+            Some(z) => z,
+            None => return Ok(Vec::new()),
+        };
+        let popped: Vec<_> = (0..count).map_while(|_| zset.pop(min)).collect();
+        let emptied = zset.is_empty();
+        if !popped.is_empty() {
+            self.after_write(key, emptied);
+        }
+        Ok(popped)
+    }
+}
+
+impl AtomicCMDMarker for ZSet {}
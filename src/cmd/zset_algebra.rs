@@ -0,0 +1,300 @@
+use crate::{
+    cmd::zset::{score_to_bytes, ZSetValue},
+    cmd::*,
+    db::{wrong_type, Entry, Value},
+    impl_traverse_command,
+    utils::get_float,
+};
+use rustc_hash::FxHashMap;
+
+const WEIGHTS: usize = rolling_hash_const(b"weights");
+const AGGREGATE: usize = rolling_hash_const(b"aggregate");
+const SUM: usize = rolling_hash_const(b"sum");
+const MIN: usize = rolling_hash_const(b"min");
+const MAX: usize = rolling_hash_const(b"max");
+
+#[derive(Debug, Clone, Copy)]
+pub enum ZSetAlgebraVariant {
+    ZUnionStore,
+    ZInterStore,
+    ZDiffStore,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(&self, a: f64, b: f64) -> f64 {
+        match self {
+            // `inf + -inf` counts as 0 rather than NaN.
+            Aggregate::Sum => {
+                let res = a + b;
+                if res.is_nan() {
+                    0.0
+                } else {
+                    res
+                }
+            }
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        }
+    }
+}
+
+/// Reads the members and scores of every given key living in one database
+/// as flat `member score` arrays. A plain set reads as if every member was
+/// scored 1, a missing key as the empty set.
+#[derive(Debug, Clone)]
+pub struct ZGather {
+    keys: Vec<MiniCommand>,
+}
+
+impl ZGather {
+    pub fn new(keys: Vec<MiniCommand>) -> ZGather {
+        Self { keys }
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        Frame::Arrays(
+            self.keys
+                .iter()
+                .map(|cmd| db.zgather(cmd.ref_single()))
+                .collect(),
+        )
+    }
+}
+
+/// Overwrites `key` with the combined sorted set, the key is deleted if the
+/// result is empty.
+#[derive(Debug, Clone)]
+pub struct ZStore {
+    key: Bytes,
+    members: Vec<(Bytes, f64)>,
+}
+
+impl ZStore {
+    pub fn new(key: Bytes, members: Vec<(Bytes, f64)>) -> ZStore {
+        Self { key, members }
+    }
+
+    pub fn exec(self, db: &mut DB) -> Frame {
+        db.remove(&self.key);
+        let mut zset = ZSetValue::new();
+        for (m, s) in self.members {
+            zset.insert(m, s);
+        }
+        let len = zset.len();
+        if len > 0 {
+            db.counter += 1;
+            let nounce = db.counter;
+            db.insert(
+                self.key.clone(),
+                Entry::new(Value::ZSet(zset), None, nounce),
+            );
+            db.signal_ready(&self.key);
+        }
+        db.propagate_key(&self.key);
+        Frame::Integers(len as i64)
+    }
+}
+
+impl DB {
+    fn zgather(&mut self, key: &Bytes) -> Frame {
+        let pairs: Vec<(Bytes, f64)> = match self.get_live(key).map(|en| &en.data) {
+            None => Vec::new(),
+            Some(Value::ZSet(z)) => z.iter().map(|(m, s)| (m.clone(), s)).collect(),
+            Some(Value::Set(s)) => s.iter().map(|m| (m, 1.0)).collect(),
+            Some(_) => return wrong_type(),
+        };
+        Frame::Arrays(to_frames(pairs))
+    }
+}
+
+fn to_frames(pairs: Vec<(Bytes, f64)>) -> Vec<Frame> {
+    let mut res = Vec::with_capacity(pairs.len() * 2);
+    for (m, s) in pairs {
+        res.push(m.into());
+        res.push(score_to_bytes(s).into());
+    }
+    res
+}
+
+/// Reads back the flat `member score` arrays gathered from the databases.
+pub fn from_frames(frames: Vec<Frame>) -> Vec<(Bytes, f64)> {
+    let mut res = Vec::with_capacity(frames.len() / 2);
+    let mut frames = frames.into_iter();
+    while let (Some(Frame::BulkStrings(m)), Some(Frame::BulkStrings(s))) =
+        (frames.next(), frames.next())
+    {
+        res.push((m, get_float(&s).unwrap_or(0.0)));
+    }
+    res
+}
+
+fn combine(
+    variant: ZSetAlgebraVariant,
+    weights: &[f64],
+    aggregate: Aggregate,
+    frames: Vec<Frame>,
+) -> Frame {
+    let mut sources = Vec::with_capacity(frames.len());
+    for (f, w) in frames.into_iter().zip(weights) {
+        match f {
+            Frame::Arrays(pairs) => sources.push(
+                from_frames(pairs)
+                    .into_iter()
+                    .map(|(m, s)| {
+                        let s = s * w;
+                        (m, if s.is_nan() { 0.0 } else { s })
+                    })
+                    .collect::<FxHashMap<_, _>>(),
+            ),
+            e => return e,
+        }
+    }
+    let (first, rest) = sources.split_first_mut().unwrap();
+    let res: Vec<(Bytes, f64)> = match variant {
+        ZSetAlgebraVariant::ZUnionStore => {
+            let mut all = std::mem::take(first);
+            for source in rest {
+                for (m, s) in source.drain() {
+                    all.entry(m)
+                        .and_modify(|v| *v = aggregate.apply(*v, s))
+                        .or_insert(s);
+                }
+            }
+            all.into_iter().collect()
+        }
+        ZSetAlgebraVariant::ZInterStore => first
+            .drain()
+            .filter_map(|(m, s)| {
+                rest.iter()
+                    .try_fold(s, |acc, source| {
+                        source.get(&m).map(|v| aggregate.apply(acc, *v))
+                    })
+                    .map(|s| (m, s))
+            })
+            .collect(),
+        // the scores of the first key are kept as they are.
+        ZSetAlgebraVariant::ZDiffStore => first
+            .drain()
+            .filter(|(m, _)| !rest.iter().any(|source| source.contains_key(m)))
+            .collect(),
+    };
+    Frame::Arrays(to_frames(res))
+}
+
+/// Gathers the source sorted sets from their databases, combines them once
+/// all have answered, and stores the result on the database of `dest`.
+#[derive(Debug, Clone)]
+pub struct ZSetAlgebraDispatcher {
+    variant: ZSetAlgebraVariant,
+    dest: Option<Bytes>,
+    weights: Vec<f64>,
+    aggregate: Aggregate,
+    dest_db: usize,
+    db_amount: usize,
+    cmds: Vec<MiniCommand>,
+    cmds_tbl: Vec<Vec<MiniCommand>>,
+    order_tbl: Vec<Vec<usize>>,
+    len: usize,
+}
+
+impl ZSetAlgebraDispatcher {
+    pub fn new(
+        parser: &mut CommandParser,
+        variant: ZSetAlgebraVariant,
+    ) -> Result<ZSetAlgebraDispatcher> {
+        let dest = parser.next_bytes()?.ok_or_else(missing_operand)?;
+        let numkeys = parser
+            .next_integer()?
+            .filter(|v| *v > 0)
+            .ok_or_else(invalid_operand)? as usize;
+        if parser.len() < numkeys {
+            return Err(missing_operand());
+        }
+        let mut cmds = Vec::with_capacity(numkeys);
+        for _ in 0..numkeys {
+            cmds.push(parser.next_bytes()?.ok_or_else(missing_operand)?.into());
+        }
+        let (mut weights, mut aggregate) = (vec![1.0; numkeys], Aggregate::Sum);
+        let has_options = !matches!(variant, ZSetAlgebraVariant::ZDiffStore);
+        while let Some(option) = parser.next_bytes()? {
+            match rolling_hash(option.as_ref())? {
+                WEIGHTS if has_options => {
+                    for w in weights.iter_mut() {
+                        let raw = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                        *w = get_float(&raw).map_err(|_| invalid_operand())?;
+                    }
+                }
+                AGGREGATE if has_options => {
+                    let raw = parser.next_bytes()?.ok_or_else(missing_operand)?;
+                    aggregate = match rolling_hash(raw.as_ref())? {
+                        SUM => Aggregate::Sum,
+                        MIN => Aggregate::Min,
+                        MAX => Aggregate::Max,
+                        _ => return Err(invalid_operation()),
+                    };
+                }
+                _ => return Err(invalid_operation()),
+            }
+        }
+        Ok(Self {
+            variant,
+            dest: Some(dest),
+            weights,
+            aggregate,
+            dest_db: 0,
+            db_amount: 0,
+            cmds,
+            cmds_tbl: Vec::new(),
+            order_tbl: Vec::new(),
+            len: numkeys,
+        })
+    }
+}
+
+use crate::default_pop;
+impl DispatchToMultipleDB for ZSetAlgebraDispatcher {
+    impl_traverse_command!(@Consts, ZGather, default_pop);
+
+    fn get_result_collector(&mut self) -> ResultCollector {
+        let (variant, aggregate) = (self.variant, self.aggregate);
+        let weights = self.weights.clone();
+        let ret = vec![Frame::NullString; self.len];
+        ResultCollector {
+            result_type: ResultCollectorType::Combine(
+                std::mem::take(&mut self.order_tbl),
+                Box::new(move |frames| combine(variant, &weights, aggregate, frames)),
+            ),
+            ret,
+        }
+    }
+
+    fn dispatch(&mut self, db_amount: usize, dispatch_fn: impl Fn(&[u8]) -> usize) {
+        if let Some(dest) = &self.dest {
+            self.dest_db = dispatch_fn(dest);
+        }
+        self.dispatch_sources(db_amount, dispatch_fn);
+    }
+
+    fn complete(&mut self, merged: Frame) -> Completion {
+        match (self.dest.take(), merged) {
+            (Some(dest), Frame::Arrays(pairs)) => {
+                Completion::Forward((self.dest_db, ZStore::new(dest, from_frames(pairs)).into()))
+            }
+            (_, f) => Completion::Reply(f),
+        }
+    }
+}
+
+impl ZSetAlgebraDispatcher {
+    impl_traverse_command!(@Dispatch, N:N, dispatch_sources);
+}
+
+impl AtomicCMDMarker for ZGather {}
+impl AtomicCMDMarker for ZStore {}
@@ -66,6 +66,17 @@ impl Connection {
         }
     }
 
+    /// Resolves once the peer closed the connection. Data that arrives in
+    /// the meantime is left for `read_frame`, and the connection is then
+    /// never reported closed.
+    pub async fn closed(&mut self) {
+        let mut byte = [0u8; 1];
+        match self.stream.peek(&mut byte).await {
+            Ok(0) | Err(_) => (),
+            Ok(_) => future::pending().await,
+        }
+    }
+
     pub async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
         let x = match frame {
             Frame::NullString => Some(NIL_STRING_FRAME),
@@ -1,10 +1,30 @@
-use crate::{cmd::*, protocol::Frame, utils::VecMap};
+use crate::{
+    cmd::aof::{command, AofFeed, Logged},
+    cmd::blocking::BPop,
+    cmd::hash::HashValue,
+    cmd::hyperloglog::HyperLogLog,
+    cmd::sets::SetValue,
+    cmd::snapshot::{SnapshotJob, SNAPSHOT_KEYS_PER_STEP},
+    cmd::stream::StreamValue,
+    cmd::zset::ZSetValue,
+    cmd::*,
+    protocol::Frame,
+    utils::VecMap,
+};
 use bytes::*;
 use diagnose::DxCommand;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use rustc_hash::FxHashMap;
-use std::{cmp::min, collections::BTreeMap};
+use std::{
+    borrow::Cow,
+    cmp::min,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
 use tokio::{
     select,
     sync::{broadcast, mpsc, oneshot},
@@ -14,27 +34,229 @@ use tracing::{debug, info, trace};
 
 pub type TaskParam = (AtomicCMD, oneshot::Sender<Frame>);
 
+/// Hands the database over to whoever sent it. Once it has answered, the
+/// database serves nothing but the commands coming through `rx`, until the
+/// other end is dropped.
+#[derive(Debug)]
+pub struct Lock {
+    pub rx: mpsc::UnboundedReceiver<TaskParam>,
+}
+
+impl AtomicCMDMarker for Lock {}
+
+/// Share of every active expire period a slow cycle may spend reclaiming keys.
+const ACTIVE_EXPIRE_CYCLE_SLOW_TIME_PERC: u32 = 25;
+/// Budget of a catch-up cycle, run while expired keys are piling up.
+const ACTIVE_EXPIRE_CYCLE_FAST_DURATION: Duration = Duration::from_micros(1000);
+/// Catch-up cycles are spaced out so that clients are still being served.
+const ACTIVE_EXPIRE_CYCLE_FAST_INTERVAL: Duration = Duration::from_micros(2000);
+/// How many keys are reclaimed between two looks at the clock.
+const ACTIVE_EXPIRE_CYCLE_CHECK_EVERY: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct DBConfig {
+    /// Frequency (per second) of the active expire cycle of every database.
+    pub hz: u32,
+}
+
+impl Default for DBConfig {
+    fn default() -> Self {
+        Self { hz: 10 }
+    }
+}
+
+pub const WRONG_TYPE_ERR: &'static [u8] =
+    b"WRONGTYPE Operation against a key holding the wrong kind of value";
+
+pub fn wrong_type() -> Frame {
+    Frame::Errors(Bytes::from_static(WRONG_TYPE_ERR))
+}
+
+/// A negative count of random picks allows repeats. Redis refuses one below
+/// this, whose reply could never be sent.
+pub const MIN_RANDOM_COUNT: i64 = -(i64::MAX / 2);
+
+pub fn random_count_out_of_range() -> Frame {
+    Frame::Errors(Bytes::from_static(b"ERR value is out of range"))
+}
+
+#[derive(Debug)]
+pub enum Value {
+    /// Strings and integers, kept as the frame they are served with.
+    Str(Frame),
+    /// Strings modified in place, such as bitmaps.
+    MutStr(BytesMut),
+    List(VecDeque<Bytes>),
+    Hash(HashValue),
+    Set(SetValue),
+    ZSet(ZSetValue),
+    Stream(StreamValue),
+    HyperLogLog(HyperLogLog),
+}
+
+impl From<Frame> for Value {
+    fn from(f: Frame) -> Value {
+        Value::Str(f)
+    }
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) | Value::MutStr(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Stream(_) => "stream",
+            Value::HyperLogLog(_) => "string",
+        }
+    }
+
+    pub fn is_str(&self) -> bool {
+        matches!(self, Value::Str(_) | Value::MutStr(_))
+    }
+
+    /// Whether the string commands read it, a HyperLogLog is read as the
+    /// string Redis keeps it as.
+    pub fn reads_as_str(&self) -> bool {
+        self.is_str() || matches!(self, Value::HyperLogLog(_))
+    }
+
+    /// What GET answers with.
+    pub fn into_str_frame(self) -> Frame {
+        match self {
+            Value::Str(f) => f,
+            Value::MutStr(b) => Frame::BulkStrings(b.freeze()),
+            Value::HyperLogLog(h) => Frame::BulkStrings(Bytes::from(h.dense_string())),
+            _ => wrong_type(),
+        }
+    }
+
+    /// The bytes a string is written out as. A client may set any frame as
+    /// a value, of which only simple strings hold bytes as well.
+    pub fn str_bytes(&self) -> anyhow::Result<Bytes> {
+        match self {
+            Value::Str(Frame::BulkStrings(b)) | Value::Str(Frame::SimpleString(b)) => Ok(b.clone()),
+            Value::Str(Frame::Integers(i)) => Ok(Bytes::from(i.to_string())),
+            Value::MutStr(b) => Ok(Bytes::copy_from_slice(b)),
+            Value::Str(f) => Err(anyhow::anyhow!("{:?} can't be written out as a string", f)),
+            v => Err(anyhow::anyhow!("a {} isn't a string", v.type_name())),
+        }
+    }
+
+    /// Payload size in bytes.
+    pub fn len(&self) -> usize {
+        match self {
+            Value::Str(f) => f.len(),
+            Value::MutStr(b) => b.len(),
+            Value::List(l) => l.iter().fold(0, |res, b| res + b.len()),
+            Value::Hash(h) => h.bytes_len(),
+            Value::Set(s) => s.bytes_len(),
+            Value::ZSet(z) => z.bytes_len(),
+            Value::Stream(s) => s.bytes_len(),
+            Value::HyperLogLog(h) => h.bytes_len(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Entry {
-    pub data: Frame,
+    pub data: Value,
     pub expiration: Option<Instant>,
     pub nounce: u64,
+    /// Position of the key in `DB::slots`, assigned by `DB::insert`.
+    slot: usize,
+}
+
+impl Entry {
+    pub fn new(data: Value, expiration: Option<Instant>, nounce: u64) -> Self {
+        Self {
+            data,
+            expiration,
+            nounce,
+            slot: 0,
+        }
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expiration.map_or(false, |ex| ex <= now)
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.data.type_name()
+    }
+
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+}
+
+/// Every key keeps the same position here for as long as it lives, freed
+/// positions are handed out again to new keys. A cursor into this table
+/// therefore survives inserts, deletes and rehashing of the `FxHashMap`.
+#[derive(Debug, Default)]
+pub struct KeySlots {
+    slots: Vec<Option<Bytes>>,
+    vacant: Vec<usize>,
+}
+
+impl KeySlots {
+    fn occupy(&mut self, key: &Bytes) -> usize {
+        match self.vacant.pop() {
+            Some(idx) => {
+                self.slots[idx] = Some(key.clone());
+                idx
+            }
+            None => {
+                self.slots.push(Some(key.clone()));
+                self.slots.len() - 1
+            }
+        }
+    }
+
+    fn release(&mut self, idx: usize) {
+        self.slots[idx] = None;
+        self.vacant.push(idx);
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&Bytes> {
+        self.slots.get(idx).and_then(|v| v.as_ref())
+    }
 }
 
 #[derive(Debug)]
 pub struct DB {
     pub database: FxHashMap<Bytes, Entry>,
+    pub slots: KeySlots,
     pub expiration: ExpirationSubModule,
     pub subscribe: SubscriptionSubModule,
+    pub blocking: BlockingSubModule,
+    /// The snapshot this database is writing in the background, if any.
+    pub snapshot: Option<SnapshotJob>,
+    /// Where writes are logged, if the AOF is enabled.
+    pub aof: Option<AofFeed>,
+    /// Set until the keys of the previous run are loaded. Replaying the AOF
+    /// expires nothing, keys are only reclaimed after the deletions logged
+    /// for them, or once loading is over.
+    pub loading: bool,
     pub id: usize,
     pub counter: u64,
+    /// The counter as of the last removal, which is the version of every
+    /// missing key.
+    pub removed: u64,
     pub shutdown_tx: broadcast::Sender<()>,
 }
 
 #[derive(Debug)]
 pub struct ExpirationSubModule {
-    expiration: BTreeMap<(Instant, u64), Bytes>,
-    when: Option<Instant>,
+    /// Deadlines of whole keys, and of single hash fields, which carry the
+    /// field next to the key.
+    expiration: BTreeMap<(Instant, u64), (Bytes, Option<Bytes>)>,
 }
 
 impl ExpirationSubModule {
@@ -43,15 +265,23 @@ impl ExpirationSubModule {
             return;
         }
         let expiration = expiration.unwrap();
-        self.expiration.insert((expiration, nounce), key.clone());
-        self.when = match self.when {
-            None => Some(expiration),
-            Some(v) => Some(v.min(expiration)),
-        };
+        self.expiration
+            .insert((expiration, nounce), (key.clone(), None));
+    }
+
+    /// Records the deadline of `field` of the hash under `key`. Records that
+    /// went stale are dropped once they come due.
+    pub fn update_field(&mut self, expiration: Instant, id: u64, key: &Bytes, field: &Bytes) {
+        self.expiration
+            .insert((expiration, id), (key.clone(), Some(field.clone())));
     }
     pub fn remove(&mut self, key: &(Instant, u64)) {
         self.expiration.remove(key);
     }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.expiration.keys().next().map(|v| v.0)
+    }
 }
 
 #[derive(Debug, Default)]
@@ -71,21 +301,245 @@ impl SubscriptionSubModule {
     }
 }
 
+/// Reply channel of a blocked client, shared by the waiters it parked on
+/// every database. Whoever takes the sender out serves the client.
+pub type SharedReply = Arc<Mutex<Option<oneshot::Sender<Frame>>>>;
+
+#[derive(Debug, Default)]
+pub struct BlockingSubModule {
+    pub waiter_id_gen: u64,
+    pub waiters: FxHashMap<u64, BPop>,
+    /// Waiters of every key, in the order they were parked.
+    pub queues: FxHashMap<Bytes, VecDeque<u64>>,
+    pub deadlines: BTreeSet<(Instant, u64)>,
+    /// Keys that received elements while somebody was waiting for them.
+    pub ready: Vec<Bytes>,
+}
+
+impl BlockingSubModule {
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.iter().next().map(|v| v.0)
+    }
+}
+
 impl DB {
-    fn new(id: usize, shutdown_tx: broadcast::Sender<()>) -> Self {
+    pub fn new(id: usize, shutdown_tx: broadcast::Sender<()>) -> Self {
         Self {
             database: FxHashMap::default(),
+            slots: KeySlots::default(),
             expiration: ExpirationSubModule {
                 expiration: BTreeMap::new(),
-                when: None,
             },
             subscribe: SubscriptionSubModule::new(),
+            blocking: BlockingSubModule::default(),
+            snapshot: None,
+            aof: None,
+            loading: true,
             id,
             counter: 0,
+            removed: 0,
             shutdown_tx,
         }
     }
 
+    /// Inserts `entry` under `key`. A replaced entry hands its slot over,
+    /// its expiration record is left for the caller to drop.
+    pub fn insert(&mut self, key: Bytes, mut entry: Entry) -> Option<Entry> {
+        self.preserve(&key);
+        match self.database.get_mut(&key) {
+            Some(en) => {
+                entry.slot = en.slot;
+                Some(std::mem::replace(en, entry))
+            }
+            None => {
+                entry.slot = self.slots.occupy(&key);
+                if let Some(job) = &mut self.snapshot {
+                    job.skip(entry.slot);
+                }
+                self.database.insert(key, entry);
+                None
+            }
+        }
+    }
+
+    /// Removes `key` together with its record in the expiration sub-module.
+    pub fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        self.preserve(key);
+        let en = self.database.remove(key)?;
+        self.counter += 1;
+        self.removed = self.counter;
+        self.slots.release(en.slot);
+        if let Some(ex) = en.expiration {
+            self.expiration.remove(&(ex, en.nounce));
+        }
+        Some(en)
+    }
+
+    /// Lazy expiration: deletes `key` if it is past its deadline, returns
+    /// whether it did so.
+    pub fn expire_if_needed(&mut self, key: &[u8]) -> bool {
+        let expired = !self.loading
+            && self
+                .database
+                .get(key)
+                .map_or(false, |en| en.is_expired(Instant::now()));
+        if expired {
+            self.expire_key(key);
+        }
+        expired
+    }
+
+    /// Reclaims `key`, which is past its deadline. The deletion is logged,
+    /// as replay reclaims nothing until it is over.
+    pub fn expire_key(&mut self, key: &[u8]) {
+        if self.remove(key).is_some() {
+            self.propagate(command(vec![
+                Bytes::from_static(b"DEL"),
+                Bytes::copy_from_slice(key),
+            ]));
+        }
+    }
+
+    /// Looks up a key that is still alive, reclaiming it if it isn't.
+    pub fn get_live(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        self.expire_if_needed(key);
+        self.preserve(key);
+        self.database.get_mut(key)
+    }
+
+    /// The raw bytes of the string under `key`, `Ok(None)` if there is no
+    /// such key.
+    pub fn get_str(&mut self, key: &[u8]) -> std::result::Result<Option<Cow<'_, [u8]>>, Frame> {
+        match self.get_live(key).map(|en| &en.data) {
+            None => Ok(None),
+            Some(Value::Str(Frame::BulkStrings(b))) => Ok(Some(Cow::Borrowed(b.as_ref()))),
+            Some(Value::Str(Frame::Integers(i))) => {
+                Ok(Some(Cow::Owned(i.to_string().into_bytes())))
+            }
+            Some(Value::MutStr(b)) => Ok(Some(Cow::Borrowed(b.as_ref()))),
+            Some(Value::HyperLogLog(h)) => Ok(Some(Cow::Owned(h.dense_string()))),
+            Some(_) => Err(wrong_type()),
+        }
+    }
+
+    /// The string under `key` in its mutable form, which it is converted to
+    /// on first use, a HyperLogLog becoming a plain string. A missing key is
+    /// created empty if `create` is set.
+    pub fn get_str_mut(
+        &mut self,
+        key: &Bytes,
+        create: bool,
+    ) -> std::result::Result<Option<&mut BytesMut>, Frame> {
+        if self.get_live(key).is_none() {
+            if !create {
+                return Ok(None);
+            }
+            self.counter += 1;
+            let nounce = self.counter;
+            self.insert(
+                key.clone(),
+                Entry::new(Value::MutStr(BytesMut::new()), None, nounce),
+            );
+        }
+        let en = self.database.get_mut(key).unwrap();
+        let converted = match &en.data {
+            Value::MutStr(_) => None,
+            Value::Str(Frame::BulkStrings(b)) => Some(BytesMut::from(b.as_ref())),
+            Value::Str(Frame::Integers(i)) => Some(BytesMut::from(i.to_string().as_bytes())),
+            Value::HyperLogLog(h) => Some(BytesMut::from(&h.dense_string()[..])),
+            _ => return Err(wrong_type()),
+        };
+        if let Some(b) = converted {
+            en.data = Value::MutStr(b);
+        }
+        match &mut en.data {
+            Value::MutStr(b) => Ok(Some(b)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Reclaims keys in deadline order until nothing is due or `budget` is
+    /// used up. Returns `true` if due keys are left behind.
+    pub fn active_expire_cycle(&mut self, budget: Duration) -> bool {
+        let start = Instant::now();
+        let mut now = start;
+        let mut reclaimed = 0;
+        while let Some((&(deadline, nounce), _)) = self.expiration.expiration.iter().next() {
+            if deadline > now {
+                return false;
+            }
+            let (key, field) = self
+                .expiration
+                .expiration
+                .remove(&(deadline, nounce))
+                .unwrap();
+            match field {
+                Some(field) => self.expire_hash_field(&key, &field, deadline),
+                // the record is only trusted if the entry still carries it.
+                None => {
+                    if self
+                        .database
+                        .get(&key)
+                        .map_or(false, |en| en.nounce == nounce)
+                    {
+                        self.expire_key(&key);
+                    }
+                }
+            }
+            trace!(
+                "[{}] collecting expired key({:?}): {:?}",
+                self.id,
+                &now,
+                &key
+            );
+            reclaimed += 1;
+            if reclaimed % ACTIVE_EXPIRE_CYCLE_CHECK_EVERY == 0 {
+                now = Instant::now();
+                if now.duration_since(start) >= budget {
+                    return self.expiration.next_deadline().map_or(false, |v| v <= now);
+                }
+            }
+        }
+        false
+    }
+
+    /// Gives `key` a fresh nounce after it was modified in place.
+    pub fn renew_nounce(&mut self, key: &Bytes) {
+        let expiration = match self.database.get(key) {
+            Some(en) => en.expiration,
+            None => return,
+        };
+        self.set_expiration(key, expiration);
+    }
+
+    /// Bookkeeping after a collection under `key` was modified in place: an
+    /// emptied collection is deleted, any other one gets a fresh nounce.
+    pub fn after_write(&mut self, key: &Bytes, emptied: bool) {
+        if emptied {
+            self.remove(key);
+        } else {
+            self.renew_nounce(key);
+        }
+    }
+
+    /// Replaces the deadline of `key`. The entry is given a fresh nounce, so
+    /// its record in the expiration sub-module is re-inserted under a new id.
+    pub fn set_expiration(&mut self, key: &Bytes, expiration: Option<Instant>) -> bool {
+        self.preserve(key);
+        let en = match self.database.get_mut(key) {
+            Some(en) => en,
+            None => return false,
+        };
+        if let Some(ex) = en.expiration {
+            self.expiration.remove(&(ex, en.nounce));
+        }
+        self.counter += 1;
+        en.nounce = self.counter;
+        en.expiration = expiration;
+        self.expiration.update(expiration, en.nounce, key);
+        true
+    }
+
     pub fn diagnose(&self, key: &DxCommand) -> Frame {
         match key {
             DxCommand::KeyNum => {
@@ -141,6 +595,42 @@ macro_rules! exec {
             Set(c) => c.exec($db),
             MGet(c) => c.exec($db),
             MSet(c) => c.exec($db),
+            Del(c) => c.exec($db),
+            Exists(c) => c.exec($db),
+            Expire(c) => c.exec($db),
+            List(c) => c.exec($db),
+            Hash(c) => c.exec($db),
+            Sets(c) => c.exec($db),
+            SGather(c) => c.exec($db),
+            SStore(c) => c.exec($db),
+            ZSet(c) => c.exec($db),
+            ZGather(c) => c.exec($db),
+            ZStore(c) => c.exec($db),
+            Stream(c) => c.exec($db),
+            StreamGroup(c) => c.exec($db),
+            XGather(c) => c.exec($db),
+            PfAdd(c) => c.exec($db),
+            PfGather(c) => c.exec($db),
+            PfStore(c) => c.exec($db),
+            Bitmap(c) => c.exec($db),
+            BitStore(c) => c.exec($db),
+            Strings(c) => c.exec($db),
+            StrGather(c) => c.exec($db),
+            MSetNxCheck(c) => c.exec($db),
+            MSetNx(c) => c.exec($db),
+            Geo(c) => c.exec($db),
+            Keys(c) => c.exec($db),
+            RandomKey(c) => c.exec($db),
+            Scan(c) => c.exec($db),
+            BPop(_) => unreachable!("blocking pops are handed their reply channel"),
+            Versions(c) => c.exec($db),
+            Load(c) => c.exec($db),
+            Snapshot(_) => unreachable!("snapshots are handed their reply channel"),
+            Batch(_) | Lock(_) => unreachable!("transactions are run by the database manager"),
+            Logged(_) => unreachable!("logged commands are run by the database manager"),
+            Loaded(c) => c.exec($db),
+            RewriteAof(c) => c.exec($db),
+            TakeLogged(c) => c.exec($db),
             Dx(c) => c.exec($db),
             Incr(c) => c.exec($db),
             Subscribe(c) => c.exec($db),
@@ -150,19 +640,88 @@ macro_rules! exec {
     }};
 }
 
+/// Runs a command and logs it, if it changed anything.
+async fn exec_logged(db: &mut DB, c: Logged) -> Frame {
+    let counter = db.counter;
+    let cmd: AtomicCMD = c.cmd.clone().into();
+    let ret = exec!(cmd, &mut *db);
+    if db.counter != counter {
+        c.cmd.propagate(c.frame, &ret, db);
+    }
+    ret
+}
+
+async fn exec_one(db: &mut DB, cmd: AtomicCMD) -> Frame {
+    match cmd {
+        AtomicCMD::Logged(c) => exec_logged(db, c).await,
+        cmd => exec!(cmd, &mut *db),
+    }
+}
+
+pub async fn run_task(db: &mut DB, cmd: AtomicCMD, ret_tx: oneshot::Sender<Frame>) {
+    match cmd {
+        AtomicCMD::BPop(c) => c.exec(db, ret_tx),
+        AtomicCMD::Snapshot(c) => db.begin_snapshot(c, ret_tx),
+        AtomicCMD::Batch(c) if !db.unchanged(&c.watched) => {
+            let _ = ret_tx.send(Frame::NullArray);
+        }
+        AtomicCMD::Batch(c) => {
+            let mut ret = Vec::with_capacity(c.cmds.len());
+            for cmd in c.cmds {
+                ret.push(exec_one(db, cmd).await);
+            }
+            db.propagate_as_transaction();
+            db.reply(ret_tx, Frame::Arrays(ret));
+        }
+        cmd => {
+            let ret = exec_one(db, cmd).await;
+            db.reply(ret_tx, ret);
+        }
+    }
+}
+
 pub async fn database_manager(
     mut tasks_rx: mpsc::UnboundedReceiver<TaskParam>,
     shutdown_tx: broadcast::Sender<()>,
     mut shutdown_rx: broadcast::Receiver<()>,
     _shutdown_complete_tx: mpsc::Sender<()>,
     taskid: usize,
+    config: DBConfig,
+    dirty: Arc<AtomicU64>,
 ) {
-    let mut when: Option<Instant> = None;
+    let period = Duration::from_micros(1_000_000 / config.hz as u64);
+    let slow_budget = period * ACTIVE_EXPIRE_CYCLE_SLOW_TIME_PERC / 100;
+    let mut last_cycle = Instant::now();
+    let mut last_sweep = last_cycle;
+    let mut lagging = false;
     let mut db = DB::new(taskid, shutdown_tx);
+    // every write hands out a new nounce, so the counter tells how many
+    // writes the next snapshot has to catch up with.
+    let mut counted = 0;
     info!("[{}] starting backgroud task", taskid);
 
     loop {
         let now = Instant::now();
+        let expire_wake_up = if db.loading {
+            now + Duration::new(3000, 0)
+        } else if lagging {
+            last_cycle + ACTIVE_EXPIRE_CYCLE_FAST_INTERVAL
+        } else {
+            db.expiration
+                .next_deadline()
+                .map(|v| v.max(last_cycle + period))
+                .unwrap_or(now + Duration::new(3000, 0))
+        };
+        // deadlines of parked waiters are honoured exactly, waiters that
+        // were served elsewhere or whose client is gone are swept once per period.
+        let wake_up = if !db.blocking.waiters.is_empty() {
+            db.blocking
+                .next_deadline()
+                .map_or(expire_wake_up, |v| v.min(expire_wake_up))
+                .min(last_sweep + period)
+        } else {
+            expire_wake_up
+        };
 
         select! {
             _ = shutdown_rx.recv() => {
@@ -176,29 +735,47 @@ pub async fn database_manager(
                 let (cmd, ret_tx) = res.unwrap();
                 trace!("[{}] scheduling: {:?}, now: {:?}", taskid, &cmd, &now);
                 trace!("db before: {:?}", db);
-                let _ = ret_tx.send(exec!(cmd, &mut db));
+                match cmd {
+                    // serves its holder alone, until it lets go of the database.
+                    AtomicCMD::Lock(c) => {
+                        let mut rx = c.rx;
+                        let _ = ret_tx.send(Frame::Ok);
+                        db.hold_aof(true);
+                        while let Some((cmd, ret_tx)) = rx.recv().await {
+                            run_task(&mut db, cmd, ret_tx).await;
+                        }
+                        db.hold_aof(false);
+                    }
+                    cmd => run_task(&mut db, cmd, ret_tx).await,
+                }
+                db.serve_blocked();
                 trace!("db after: {:?}", db);
             }
-            _ = tokio::time::sleep_until(
-                when.map(|v| v.max(now + Duration::new(1000, 0)))
-                    .unwrap_or(now + Duration::new(3000, 0)),
-            ) => {
+            _ = tokio::time::sleep_until(wake_up) => {
                 debug!("[{}] task waked up, expirations: {:?}", taskid, db.expiration);
-                when = None;
-                if db.expiration.expiration.len() > 0 {
-                    let now = Instant::now();
-                    while let Some((&(expire_next, id), _)) = db.expiration.expiration.iter().next() {
-                        if expire_next <= now {
-                            let key = db.expiration.expiration.remove(&(expire_next, id)).unwrap();
-                            db.database.remove(&key);
-                            trace!("[{}] collecting expired key({:?}): {:?}", taskid, &now, &key);
-                        } else {
-                            when = Some(expire_next);
-                            break;
-                        }
-                    }
+                let now = Instant::now();
+                db.time_out_blocked(now);
+                if now >= last_sweep + period {
+                    db.sweep_blocked();
+                    last_sweep = now;
+                }
+                if now >= expire_wake_up {
+                    lagging = db.active_expire_cycle(if lagging {
+                        ACTIVE_EXPIRE_CYCLE_FAST_DURATION
+                    } else {
+                        slow_budget
+                    });
+                    last_cycle = Instant::now();
+                    db.flush_aof(None);
                 }
             }
+            _ = async {}, if db.snapshot.is_some() => {
+                db.snapshot_step(SNAPSHOT_KEYS_PER_STEP);
+            }
+        }
+        if db.counter > counted {
+            dirty.fetch_add(db.counter - counted, Ordering::Relaxed);
+            counted = db.counter;
         }
     }
 }
@@ -15,6 +15,7 @@ use tokio::net::TcpListener;
 mod cmd;
 mod connection;
 mod db;
+mod persistence;
 mod protocol;
 mod server;
 mod shutdown;
@@ -37,6 +38,54 @@ async fn main() -> Result<()> {
                 .long("thread")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("hz")
+                .long("hz")
+                .help("frequency of the active expire cycle, 1 to 500")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("snapshot")
+                .long("snapshot")
+                .help("file the snapshot is written to and loaded from at startup")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("save")
+                .long("save")
+                .help("\"<secs> <changes>\", snapshot after that many changes within that many seconds")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("rdbfilename")
+                .long("rdbfilename")
+                .help("Redis RDB file EXPORTRDB writes to and --loadrdb loads")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("loadrdb")
+                .long("loadrdb")
+                .help("load the RDB file at startup, in place of the snapshot or the append only file"),
+        )
+        .arg(
+            Arg::with_name("appendonly")
+                .long("appendonly")
+                .help("log every write to the append only file, replayed at startup instead of the snapshot"),
+        )
+        .arg(
+            Arg::with_name("appendfilename")
+                .long("appendfilename")
+                .help("file the writes are appended to")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("appendfsync")
+                .long("appendfsync")
+                .help("when the append only file is synced: always, everysec or no")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("log-level")
                 .short("l")
@@ -55,6 +104,47 @@ async fn main() -> Result<()> {
         })
         .unwrap_or(num_cpus::get());
 
+    let mut config = db::DBConfig::default();
+    if let Some(hz) = matches.value_of("hz") {
+        config.hz = match hz.parse::<u32>() {
+            Ok(v) if v > 0 => v.min(500),
+            _ => panic!("hz should be a positive number"),
+        };
+    }
+
+    let mut snapshot = persistence::SnapshotConfig::default();
+    if let Some(path) = matches.value_of("snapshot") {
+        snapshot.path = path.into();
+    }
+    if let Some(rules) = matches.values_of("save") {
+        snapshot.rules = rules
+            .map(|v| match persistence::SaveRule::parse(v) {
+                Some(rule) => rule,
+                None => panic!("save should be \"<secs> <changes>\""),
+            })
+            .collect();
+    }
+    if let Some(path) = matches.value_of("rdbfilename") {
+        snapshot.rdb_path = path.into();
+    }
+    snapshot.load_rdb = matches.is_present("loadrdb");
+
+    let aof = if matches.is_present("appendonly") {
+        let mut aof = persistence::AofConfig::default();
+        if let Some(path) = matches.value_of("appendfilename") {
+            aof.path = path.into();
+        }
+        if let Some(fsync) = matches.value_of("appendfsync") {
+            aof.fsync = match persistence::FsyncPolicy::parse(fsync) {
+                Some(fsync) => fsync,
+                None => panic!("appendfsync should be always, everysec or no"),
+            };
+        }
+        Some(aof)
+    } else {
+        None
+    };
+
     let loglevel = matches
         .value_of("log-level")
         .map_or(tracing::Level::INFO, |f| match &f.to_lowercase()[..] {
@@ -73,6 +163,14 @@ async fn main() -> Result<()> {
     let addr = addr.parse::<SocketAddr>()?;
     let listener = TcpListener::bind(&addr).await?;
 
-    server::run(listener, tokio::signal::ctrl_c(), thread_num).await;
+    server::run(
+        listener,
+        tokio::signal::ctrl_c(),
+        thread_num,
+        config,
+        snapshot,
+        aof,
+    )
+    .await;
     Ok(())
 }
@@ -0,0 +1,535 @@
+use crate::{
+    cmd::{
+        aof::{encode_transaction, AofFeed, AofMsg},
+        rdb::{crc64, rdb_end, rdb_header, RdbReader},
+        snapshot::{snapshot_end, SnapshotChunk, SnapshotFormat, SnapshotReader, SNAPSHOT_MAGIC},
+    },
+    protocol::Frame,
+    shutdown::Shutdown,
+    utils::unix_millis_now,
+    Result,
+};
+use anyhow::anyhow;
+use bytes::Bytes;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering::*},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{error, info};
+
+/// `save <secs> <changes>`: snapshot once `changes` writes happened within
+/// `secs` seconds after the last snapshot.
+#[derive(Debug, Clone, Copy)]
+pub struct SaveRule {
+    pub secs: u64,
+    pub changes: u64,
+}
+
+impl SaveRule {
+    /// Parses `<secs> <changes>`.
+    pub fn parse(raw: &str) -> Option<SaveRule> {
+        let mut it = raw.split_whitespace();
+        let rule = SaveRule {
+            secs: it.next()?.parse().ok()?,
+            changes: it.next()?.parse().ok()?,
+        };
+        match it.next() {
+            Some(_) => None,
+            None => Some(rule),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SnapshotConfig {
+    pub path: PathBuf,
+    pub rules: Vec<SaveRule>,
+    /// Where EXPORTRDB writes, and the file `load_rdb` loads at startup.
+    pub rdb_path: PathBuf,
+    pub load_rdb: bool,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("dump.ards"),
+            rules: Vec::new(),
+            rdb_path: PathBuf::from("dump.rdb"),
+            load_rdb: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Persistence {
+    config: SnapshotConfig,
+    saving: AtomicBool,
+    /// Unix time of the last successful snapshot, in seconds.
+    lastsave: AtomicU64,
+    /// Writes since the last snapshot, counted by the databases.
+    pub dirty: Arc<AtomicU64>,
+}
+
+fn unix_secs_now() -> u64 {
+    unix_millis_now() / 1000
+}
+
+impl Persistence {
+    pub fn new(config: SnapshotConfig) -> Self {
+        Self {
+            config,
+            saving: AtomicBool::new(false),
+            lastsave: AtomicU64::new(unix_secs_now()),
+            dirty: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn lastsave(&self) -> u64 {
+        self.lastsave.load(Acquire)
+    }
+
+    /// Whether one of the save rules asks for a snapshot.
+    pub fn is_due(&self) -> bool {
+        let elapsed = unix_secs_now().saturating_sub(self.lastsave());
+        let dirty = self.dirty.load(Relaxed);
+        !self.saving.load(Acquire)
+            && self
+                .config
+                .rules
+                .iter()
+                .any(|rule| elapsed >= rule.secs && dirty >= rule.changes && dirty > 0)
+    }
+
+    /// Starts writing a snapshot made of the chunks of `db_amount`
+    /// databases, `None` if a snapshot is being written already. The
+    /// returned task tells whether the snapshot made it to disk. An RDB
+    /// file is an export, which leaves the save rules be.
+    pub fn begin_save(
+        self: &Arc<Self>,
+        db_amount: usize,
+        format: SnapshotFormat,
+    ) -> Option<(mpsc::UnboundedSender<SnapshotChunk>, JoinHandle<bool>)> {
+        if self.saving.swap(true, AcqRel) {
+            return None;
+        }
+        let dirty = self.dirty.load(Relaxed);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let this = self.clone();
+        let writer = tokio::task::spawn_blocking(move || {
+            let path = match format {
+                SnapshotFormat::Native => &this.config.path,
+                SnapshotFormat::Rdb => &this.config.rdb_path,
+            };
+            let res = write_snapshot(path, rx, db_amount, format);
+            match (&res, format) {
+                (Ok(()), SnapshotFormat::Native) => {
+                    this.dirty.fetch_sub(dirty, Relaxed);
+                    this.lastsave.store(unix_secs_now(), Release);
+                    info!("DB saved on disk");
+                }
+                (Ok(()), SnapshotFormat::Rdb) => info!("RDB file exported"),
+                (Err(e), _) => error!("failed to write the snapshot: {}", e),
+            }
+            this.saving.store(false, Release);
+            res.is_ok()
+        });
+        Some((tx, writer))
+    }
+
+    /// The snapshot left by a previous run, `None` if there is none.
+    pub fn open_snapshot(&self) -> Result<Option<SnapshotReader>> {
+        match fs::read(&self.config.path) {
+            Ok(buf) => Ok(Some(SnapshotReader::new(Bytes::from(buf))?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The RDB file to load in place of the snapshot, it has to be there.
+    pub fn open_rdb(&self) -> Result<RdbReader> {
+        let buf = fs::read(&self.config.rdb_path)
+            .map_err(|e| anyhow!("can't read {}: {}", self.config.rdb_path.display(), e))?;
+        RdbReader::new(Bytes::from(buf))
+    }
+
+    pub fn load_rdb(&self) -> bool {
+        self.config.load_rdb
+    }
+}
+
+/// Writes the chunks coming through `rx` to a temporary file, which only
+/// replaces `path` once every database sent its last chunk and the file is
+/// synced.
+fn write_snapshot(
+    path: &Path,
+    mut rx: mpsc::UnboundedReceiver<SnapshotChunk>,
+    db_amount: usize,
+    format: SnapshotFormat,
+) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".tmp-{}", std::process::id()));
+    let tmp = PathBuf::from(tmp);
+    let res = (|| {
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        // the checksum an RDB file ends with covers everything before it.
+        let mut crc = 0;
+        let mut write = |buf: &[u8]| -> Result<()> {
+            if format == SnapshotFormat::Rdb {
+                crc = crc64(crc, buf);
+            }
+            file.write_all(buf)?;
+            Ok(())
+        };
+        match format {
+            SnapshotFormat::Native => write(SNAPSHOT_MAGIC)?,
+            SnapshotFormat::Rdb => write(&rdb_header())?,
+        }
+        let mut finished = 0;
+        while let Some((chunk, last)) = rx.blocking_recv() {
+            write(&chunk)?;
+            finished += last as usize;
+        }
+        if finished != db_amount {
+            return Err(anyhow!("a database stopped before writing all of its keys"));
+        }
+        match format {
+            SnapshotFormat::Native => file.write_all(&snapshot_end())?,
+            SnapshotFormat::Rdb => file.write_all(&rdb_end(crc))?,
+        }
+        file.into_inner()?.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
+
+/// When the AOF is synced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Before replying to the writes.
+    Always,
+    /// Once a second.
+    EverySec,
+    /// Whenever the OS sees fit.
+    No,
+}
+
+impl FsyncPolicy {
+    pub fn parse(raw: &str) -> Option<FsyncPolicy> {
+        match &raw.to_lowercase()[..] {
+            "always" => Some(FsyncPolicy::Always),
+            "everysec" => Some(FsyncPolicy::EverySec),
+            "no" => Some(FsyncPolicy::No),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AofConfig {
+    pub path: PathBuf,
+    pub fsync: FsyncPolicy,
+}
+
+impl Default for AofConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("appendonly.aof"),
+            fsync: FsyncPolicy::EverySec,
+        }
+    }
+}
+
+/// The append-only file: every write the databases log, encoded the way
+/// clients send it, replayed at startup.
+#[derive(Debug)]
+pub struct Aof {
+    config: AofConfig,
+    rewriting: Arc<AtomicBool>,
+    tx: mpsc::UnboundedSender<AofMsg>,
+    /// Taken by the writer once it starts.
+    rx: Mutex<Option<mpsc::UnboundedReceiver<AofMsg>>>,
+}
+
+impl Aof {
+    pub fn new(config: AofConfig) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self {
+            config,
+            rewriting: Arc::new(AtomicBool::new(false)),
+            tx,
+            rx: Mutex::new(Some(rx)),
+        }
+    }
+
+    /// What a database logs its writes to.
+    pub fn feed(&self) -> AofFeed {
+        AofFeed::new(self.tx.clone(), self.config.fsync == FsyncPolicy::Always)
+    }
+
+    /// Logs the writes of a transaction that held several databases, what
+    /// each of them logged, as a whole. If the fsync policy is `always` the
+    /// receiver resolves once they are synced.
+    pub fn log_transaction(&self, parts: Vec<(usize, Bytes)>) -> Option<oneshot::Receiver<Frame>> {
+        let (reply, ret_rx) = match self.config.fsync {
+            FsyncPolicy::Always => {
+                let (ret_tx, ret_rx) = oneshot::channel();
+                (Some((ret_tx, Frame::Ok)), Some(ret_rx))
+            }
+            _ => (None, None),
+        };
+        let _ = self.tx.send(AofMsg::Transaction { parts, reply });
+        ret_rx
+    }
+
+    /// The log left by a previous run, `None` if there is none.
+    pub fn read(&self) -> Result<Option<Bytes>> {
+        match fs::read(&self.config.path) {
+            Ok(buf) => Ok(Some(Bytes::from(buf))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Cuts the log down to its first `len` bytes, dropping a command that
+    /// was only partly written.
+    pub fn truncate(&self, len: u64) -> Result<()> {
+        let file = OpenOptions::new().write(true).open(&self.config.path)?;
+        file.set_len(len)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Opens the log for appending and spawns the writer for `db_amount`
+    /// databases, which runs until `shutdown` and syncs whatever it got by
+    /// then.
+    pub fn start(
+        &self,
+        db_amount: usize,
+        shutdown: Shutdown,
+        shutdown_complete_tx: mpsc::Sender<()>,
+    ) -> Result<()> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.config.path)?;
+        let rx = self
+            .rx
+            .lock()
+            .unwrap()
+            .take()
+            .expect("the AOF writer is started once");
+        let writer = AofWriter {
+            path: self.config.path.clone(),
+            fsync: self.config.fsync,
+            file: tokio::fs::File::from_std(file),
+            dirty: false,
+            rewrite: None,
+            rewriting: self.rewriting.clone(),
+            db_amount,
+        };
+        tokio::spawn(writer.run(rx, shutdown, shutdown_complete_tx));
+        Ok(())
+    }
+
+    /// Tells the writer a rewrite begins, `false` if one is running already.
+    /// The databases are then expected to send their `Base`.
+    pub fn begin_rewrite(&self) -> bool {
+        if self.rewriting.swap(true, AcqRel) {
+            return false;
+        }
+        let _ = self.tx.send(AofMsg::Rewrite);
+        true
+    }
+}
+
+/// A rewritten log in the making, it replaces the current one once every
+/// database sent its `Base`.
+struct Rewrite {
+    tmp: PathBuf,
+    file: tokio::fs::File,
+    /// Databases whose `Base` is written, their appends go to both logs.
+    based: Vec<bool>,
+}
+
+struct AofWriter {
+    path: PathBuf,
+    fsync: FsyncPolicy,
+    file: tokio::fs::File,
+    /// Written to since the last sync.
+    dirty: bool,
+    rewrite: Option<Rewrite>,
+    rewriting: Arc<AtomicBool>,
+    db_amount: usize,
+}
+
+impl AofWriter {
+    async fn run(
+        mut self,
+        mut rx: mpsc::UnboundedReceiver<AofMsg>,
+        mut shutdown: Shutdown,
+        _shutdown_complete_tx: mpsc::Sender<()>,
+    ) {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+        let mut replies = Vec::new();
+        loop {
+            tokio::select! {
+                msg = rx.recv() => {
+                    let mut msg = match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    };
+                    loop {
+                        self.handle(msg, &mut replies).await;
+                        msg = match rx.try_recv() {
+                            Ok(msg) => msg,
+                            Err(_) => break,
+                        };
+                    }
+                    self.flush(self.fsync == FsyncPolicy::Always).await;
+                    for (ret_tx, ret) in replies.drain(..) {
+                        let _ = ret_tx.send(ret);
+                    }
+                }
+                _ = interval.tick() => {
+                    if self.fsync == FsyncPolicy::EverySec && self.dirty {
+                        self.flush(true).await;
+                    }
+                }
+                _ = shutdown.recv() => {
+                    while let Ok(msg) = rx.try_recv() {
+                        self.handle(msg, &mut replies).await;
+                    }
+                    self.flush(self.fsync != FsyncPolicy::No).await;
+                    break;
+                }
+            }
+        }
+        if let Some(rewrite) = self.rewrite.take() {
+            let _ = fs::remove_file(&rewrite.tmp);
+        }
+    }
+
+    async fn handle(&mut self, msg: AofMsg, replies: &mut Vec<(oneshot::Sender<Frame>, Frame)>) {
+        match msg {
+            AofMsg::Append { db_id, buf, reply } => {
+                if let Err(e) = self.file.write_all(&buf).await {
+                    error!("failed to append to the AOF: {}", e);
+                }
+                self.dirty = true;
+                if let Some(rewrite) = self.rewrite.as_mut().filter(|r| r.based[db_id]) {
+                    if let Err(e) = rewrite.file.write_all(&buf).await {
+                        self.abort_rewrite(e.into());
+                    }
+                }
+                replies.extend(reply);
+            }
+            AofMsg::Transaction { parts, reply } => {
+                let buf = encode_transaction(parts.iter().map(|(_, part)| part));
+                if let Some(buf) = buf {
+                    if let Err(e) = self.file.write_all(&buf).await {
+                        error!("failed to append to the AOF: {}", e);
+                    }
+                    self.dirty = true;
+                }
+                // the databases that haven't sent their `Base` yet write it
+                // with the transaction applied.
+                if let Some(rewrite) = self.rewrite.as_mut() {
+                    let based = parts.iter().filter(|(db_id, _)| rewrite.based[*db_id]);
+                    if let Some(buf) = encode_transaction(based.map(|(_, part)| part)) {
+                        if let Err(e) = rewrite.file.write_all(&buf).await {
+                            self.abort_rewrite(e.into());
+                        }
+                    }
+                }
+                replies.extend(reply);
+            }
+            AofMsg::Rewrite => {
+                let mut tmp = self.path.as_os_str().to_owned();
+                tmp.push(format!(".tmp-{}", std::process::id()));
+                let tmp = PathBuf::from(tmp);
+                match tokio::fs::File::create(&tmp).await {
+                    Ok(file) => {
+                        self.rewrite = Some(Rewrite {
+                            tmp,
+                            file,
+                            based: vec![false; self.db_amount],
+                        })
+                    }
+                    Err(e) => {
+                        error!("failed to rewrite the AOF: {}", e);
+                        self.rewriting.store(false, Release);
+                    }
+                }
+            }
+            AofMsg::Base { db_id, buf } => {
+                let buf = match buf {
+                    Ok(buf) => buf,
+                    Err(e) => return self.abort_rewrite(e),
+                };
+                let rewrite = match self.rewrite.as_mut() {
+                    Some(rewrite) => rewrite,
+                    None => return,
+                };
+                if let Err(e) = rewrite.file.write_all(&buf).await {
+                    return self.abort_rewrite(e.into());
+                }
+                rewrite.based[db_id] = true;
+                if let Err(e) = self.finish_rewrite().await {
+                    self.abort_rewrite(e);
+                }
+            }
+        }
+    }
+
+    /// Swaps the rewritten log in once every database sent its `Base`.
+    async fn finish_rewrite(&mut self) -> Result<()> {
+        let rewrite = self.rewrite.as_mut().unwrap();
+        if rewrite.based.contains(&false) {
+            return Ok(());
+        }
+        rewrite.file.flush().await?;
+        rewrite.file.sync_all().await?;
+        tokio::fs::rename(&rewrite.tmp, &self.path).await?;
+        let rewrite = self.rewrite.take().unwrap();
+        self.file = rewrite.file;
+        self.dirty = false;
+        self.rewriting.store(false, Release);
+        info!("Background AOF rewrite finished successfully");
+        Ok(())
+    }
+
+    fn abort_rewrite(&mut self, e: anyhow::Error) {
+        error!("failed to rewrite the AOF: {}", e);
+        if let Some(rewrite) = self.rewrite.take() {
+            let _ = fs::remove_file(&rewrite.tmp);
+        }
+        self.rewriting.store(false, Release);
+    }
+
+    /// Hands what was written over to the OS, and has it reach the disk if
+    /// `sync`.
+    async fn flush(&mut self, sync: bool) {
+        let mut res = self.file.flush().await;
+        if sync && res.is_ok() {
+            res = self.file.sync_data().await;
+            self.dirty = false;
+        }
+        if let Err(e) = res {
+            error!("failed to sync the AOF: {}", e);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    cell::Cell,
     collections::hash_map::DefaultHasher,
     future::Future,
     hash::{Hash, Hasher},
@@ -6,18 +7,34 @@ use std::{
     sync::Arc,
 };
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 
 use bytes::Bytes;
 use tokio::{net::TcpListener, spawn, sync::*};
 use tracing::*;
 
 use crate::{
-    cmd::traverse_command::*, cmd::unsubscribe::UnsubDispatcher, cmd::*, connection::*, db::*,
-    protocol::Frame, shutdown::Shutdown, Result,
+    cmd::aof::*,
+    cmd::blocking::BPopDispatcher,
+    cmd::snapshot::*,
+    cmd::transaction::*,
+    cmd::traverse_command::*,
+    cmd::unsubscribe::UnsubDispatcher,
+    cmd::*,
+    connection::*,
+    db::*,
+    persistence::*,
+    protocol::{decode::IntermediateParser, reusable_buf::ReusableBuf, Frame, FrameError},
+    shutdown::Shutdown,
+    utils::unix_millis_to_instant,
+    Result,
 };
 
 const BUFSIZE: usize = 50;
+/// Keys of a snapshot sent to a database in one go while loading it.
+const LOAD_BATCH: usize = 1024;
+
+const SAVE_IN_PROGRESS_ERR: &[u8] = b"ERR Background save already in progress";
 
 #[allow(dead_code)]
 fn calculate_hash<T: Hash>(t: &T) -> usize {
@@ -31,6 +48,8 @@ pub struct Dispatcher {
     num_threads: usize,
     counter: AtomicU64,
     tasks_tx: Vec<mpsc::UnboundedSender<TaskParam>>,
+    persistence: Arc<Persistence>,
+    aof: Option<Aof>,
 }
 
 impl Dispatcher {
@@ -38,7 +57,11 @@ impl Dispatcher {
         notify_tx: &broadcast::Sender<()>,
         shutdown_complete_tx: &mpsc::Sender<()>,
         num_threads: usize,
+        config: &DBConfig,
+        snapshot: SnapshotConfig,
+        aof: Option<AofConfig>,
     ) -> Self {
+        let persistence = Arc::new(Persistence::new(snapshot));
         let mut tasks_tx = Vec::with_capacity(num_threads);
         let mut tasks_rx = Vec::with_capacity(num_threads);
         for _ in 0..num_threads {
@@ -51,6 +74,8 @@ impl Dispatcher {
             let notify_rx = notify_tx.subscribe();
             let notify_tx_clone = notify_tx.clone();
             let shutdown_complete_tx_copy = shutdown_complete_tx.clone();
+            let config_copy = config.clone();
+            let dirty = persistence.dirty.clone();
             spawn(async move {
                 database_manager(
                     rx,
@@ -58,6 +83,8 @@ impl Dispatcher {
                     notify_rx,
                     shutdown_complete_tx_copy,
                     id,
+                    config_copy,
+                    dirty,
                 )
                 .await;
             });
@@ -66,6 +93,220 @@ impl Dispatcher {
             num_threads,
             counter: AtomicU64::new(0),
             tasks_tx,
+            persistence,
+            aof: aof.map(Aof::new),
+        }
+    }
+
+    /// Has every database write its keys to a new snapshot, a few at a time
+    /// between the tasks it serves. All of them are held while it begins, so
+    /// that the snapshot is of one instant.
+    pub async fn bgsave(&self) -> Result<Frame> {
+        let (out, _) = match self
+            .persistence
+            .begin_save(self.num_threads, SnapshotFormat::Native)
+        {
+            Some(v) => v,
+            None => return Ok(Frame::Errors(Bytes::from_static(SAVE_IN_PROGRESS_ERR))),
+        };
+        let mut held = Vec::with_capacity(self.num_threads);
+        for db_id in 0..self.num_threads {
+            held.push(self.lock(db_id).await?);
+        }
+        for tx in held {
+            let (ret_tx, _) = oneshot::channel();
+            tx.send((
+                Snapshot::new(out.clone(), false, SnapshotFormat::Native).into(),
+                ret_tx,
+            ))?;
+        }
+        Ok(Frame::SimpleString(Bytes::from_static(
+            b"Background saving started",
+        )))
+    }
+
+    /// Takes the database `db_id` over from everybody else, it serves nothing
+    /// but what comes through the returned queue until it is dropped.
+    async fn lock(&self, db_id: usize) -> Result<mpsc::UnboundedSender<TaskParam>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (ret_tx, ret_rx) = oneshot::channel();
+        self.tasks_tx[db_id].send((Lock { rx }.into(), ret_tx))?;
+        ret_rx.await.map_err(Error::new)?;
+        Ok(tx)
+    }
+
+    /// Hands the keys of the snapshot left by a previous run over to the
+    /// databases they belong to now, returns how many there were.
+    pub async fn load_snapshot(&self) -> Result<usize> {
+        let mut reader = match self.persistence.open_snapshot()? {
+            Some(reader) => reader,
+            None => return Ok(0),
+        };
+        let loaded = self.load_entries(|| reader.next_entry()).await?;
+        // loading is no change that needs saving.
+        self.persistence.dirty.store(0, Relaxed);
+        Ok(loaded)
+    }
+
+    /// Hands the keys of the Redis RDB file over to the databases they
+    /// belong to, returns how many there were. They count as changes, the
+    /// snapshot doesn't hold them yet.
+    pub async fn load_rdb(&self) -> Result<usize> {
+        let mut reader = self.persistence.open_rdb()?;
+        self.load_entries(|| reader.next_entry()).await
+    }
+
+    /// Loads the keys `next_entry` reads until it runs out, in batches.
+    async fn load_entries(
+        &self,
+        mut next_entry: impl FnMut() -> Result<Option<LoadedEntry>>,
+    ) -> Result<usize> {
+        let mut batches: Vec<Vec<_>> = (0..self.num_threads).map(|_| Vec::new()).collect();
+        let mut loaded = 0;
+        while let Some((key, value, expiration)) = next_entry()? {
+            let db_id = self.determine_database(&key);
+            batches[db_id].push((key, value, expiration.map(unix_millis_to_instant)));
+            if batches[db_id].len() >= LOAD_BATCH {
+                loaded += self
+                    .load(db_id, std::mem::take(&mut batches[db_id]))
+                    .await?;
+            }
+        }
+        for (db_id, entries) in batches.into_iter().enumerate() {
+            loaded += self.load(db_id, entries).await?;
+        }
+        Ok(loaded)
+    }
+
+    /// Replays the log left by a previous run, returns how many commands it
+    /// held, `None` if there is none or the AOF is disabled. A command cut
+    /// short at the end of the log, or a transaction missing its EXEC, is
+    /// dropped from the file.
+    pub async fn load_aof(&self) -> Result<Option<usize>> {
+        let aof = match &self.aof {
+            Some(aof) => aof,
+            None => return Ok(None),
+        };
+        let log = match aof.read()? {
+            Some(log) => log,
+            None => return Ok(None),
+        };
+        let mut buf = ReusableBuf::new();
+        buf.extend_from_slice(&log);
+        let mut parser = IntermediateParser::new();
+        let mut replayed = 0;
+        // where the last command replayed, or transaction, ends.
+        let (mut offset, mut committed) = (0, 0);
+        let mut queued: Option<Vec<Command>> = None;
+        loop {
+            let frame = match parser.parse(&mut buf) {
+                Ok(frame) => frame,
+                Err(FrameError::Incomplete) => break,
+                Err(e) => return Err(anyhow!("bad AOF format at byte {}: {}", offset, e)),
+            };
+            let cmd = Command::new(frame)
+                .map_err(|e| anyhow!("bad command in the AOF at byte {}: {}", offset, e))?;
+            match (cmd, queued.as_mut()) {
+                (Command::Transaction(TransactionCommand::Multi), None) => {
+                    queued = Some(Vec::new());
+                }
+                (Command::Transaction(TransactionCommand::Exec), Some(_)) => {
+                    for cmd in queued.take().unwrap() {
+                        self.replay(cmd).await?;
+                        replayed += 1;
+                    }
+                }
+                (cmd, Some(queued)) => queued.push(cmd),
+                (cmd, None) => {
+                    self.replay(cmd).await?;
+                    replayed += 1;
+                }
+            }
+            offset = log.len() - buf.len();
+            if queued.is_none() {
+                committed = offset;
+            }
+        }
+        if committed < log.len() {
+            warn!(
+                "the AOF ends with an incomplete command or transaction, truncating it to {} bytes",
+                committed
+            );
+            aof.truncate(committed as u64)?;
+        }
+        Ok(Some(replayed))
+    }
+
+    /// Sends a command read from the log to the databases it belongs to.
+    /// Single key commands are not waited for, a database runs what it gets
+    /// in order anyway.
+    async fn replay(&self, cmd: Command) -> Result<()> {
+        match cmd {
+            Command::Oneshot(cmd) => {
+                let (ret_tx, _) = oneshot::channel();
+                let db_id = self.determine_database(cmd.get_key());
+                self.tasks_tx[db_id].send((cmd.into(), ret_tx))?;
+            }
+            Command::Traverse(mut cmd) => {
+                cmd.dispatch(self.num_threads, |key: &[u8]| self.determine_database(key));
+                run_traverse(&mut cmd, |db_id| &self.tasks_tx[db_id]).await?;
+            }
+            Command::Zeroshot(_) => (),
+            cmd => return Err(anyhow!("{:?} can't be replayed from the AOF", cmd)),
+        }
+        Ok(())
+    }
+
+    /// Ends loading on every database: keys past their deadline expire from
+    /// now on, and writes are logged if the AOF is enabled.
+    pub async fn finish_loading(
+        &self,
+        shutdown: Shutdown,
+        shutdown_complete_tx: mpsc::Sender<()>,
+    ) -> Result<()> {
+        if let Some(aof) = &self.aof {
+            aof.start(self.num_threads, shutdown, shutdown_complete_tx)?;
+        }
+        for tx in self.tasks_tx.iter() {
+            let (ret_tx, ret_rx) = oneshot::channel();
+            let feed = self.aof.as_ref().map(Aof::feed);
+            tx.send((Loaded { feed }.into(), ret_tx))?;
+            ret_rx.await?;
+        }
+        Ok(())
+    }
+
+    /// Has every database send the commands rebuilding its keys to a new
+    /// log, which replaces the current one once they all did.
+    pub fn bgrewriteaof(&self) -> Frame {
+        let aof = match &self.aof {
+            Some(aof) => aof,
+            None => return Frame::Errors(Bytes::from_static(b"ERR Append only file is disabled")),
+        };
+        if !aof.begin_rewrite() {
+            return Frame::Errors(Bytes::from_static(
+                b"ERR Background append only file rewriting already in progress",
+            ));
+        }
+        for tx in self.tasks_tx.iter() {
+            let (ret_tx, _) = oneshot::channel();
+            let _ = tx.send((RewriteAof {}.into(), ret_tx));
+        }
+        Frame::SimpleString(Bytes::from_static(
+            b"Background append only file rewriting started",
+        ))
+    }
+
+    async fn load(
+        &self,
+        db_id: usize,
+        entries: Vec<(Bytes, crate::db::Value, Option<tokio::time::Instant>)>,
+    ) -> Result<usize> {
+        let (ret_tx, ret_rx) = oneshot::channel();
+        self.tasks_tx[db_id].send((Load { entries }.into(), ret_tx))?;
+        match ret_rx.await? {
+            Frame::Integers(n) => Ok(n as usize),
+            _ => unreachable!("loading replies with the number of keys loaded"),
         }
     }
 
@@ -99,6 +340,8 @@ async fn recycle_handler(mut handler: Handler, sender: mpsc::Sender<Handler>) ->
     debug!("[{}]: entered", handler.id);
     debug!("[{}]: send to recycle channel", handler.id);
     let id = handler.id;
+    handler.transaction = None;
+    handler.watched.clear();
     handler.connection.close_connection().await;
     let x = sender.try_send(handler).is_ok();
     debug!("[{}]: {}", id, if x { "recycled" } else { "discarded" });
@@ -141,6 +384,9 @@ impl Listener {
                     shutdown_complete_tx: self.shutdown_complete_tx.clone(),
                     id: conn_id,
                     thread_num: self.dispatcher.num_threads,
+                    transaction: None,
+                    held: Vec::new(),
+                    watched: Vec::new(),
                 }
             };
 
@@ -174,6 +420,15 @@ impl Listener {
     }
 }
 
+/// Commands queued after MULTI, waiting for EXEC, along with the frames
+/// they are logged as.
+#[derive(Debug, Default)]
+struct Transaction {
+    queued: Vec<(Command, Option<Frame>)>,
+    /// A command was rejected while queuing, EXEC discards the transaction.
+    aborted: bool,
+}
+
 #[derive(Debug)]
 struct Handler {
     connection: Connection,
@@ -182,9 +437,22 @@ struct Handler {
     shutdown_complete_tx: mpsc::Sender<()>,
     id: u64,
     thread_num: usize,
+    transaction: Option<Transaction>,
+    /// Private queues of the databases held by the running command.
+    held: Vec<Option<mpsc::UnboundedSender<TaskParam>>>,
+    /// Keys under WATCH, EXEC gives up if any of them changed.
+    watched: Vec<WatchedKey>,
 }
 
 impl Handler {
+    /// The queue of database `db_id`, the private one while it is held.
+    fn tasks_tx(&self, db_id: usize) -> &mpsc::UnboundedSender<TaskParam> {
+        match self.held.get(db_id) {
+            Some(Some(tx)) => tx,
+            _ => &self.dispatcher.tasks_tx[db_id],
+        }
+    }
+
     async fn traverse_exec<T>(&self, cmd: &mut T) -> Result<Frame>
     where
         T: DispatchToMultipleDB + std::fmt::Debug,
@@ -196,33 +464,7 @@ impl Handler {
             cmd,
         );
 
-        let mut result_collector = cmd.get_result_collector();
-
-        while let Some((db_id, atomic_cmd)) = cmd.next_command() {
-            let (ret_tx, ret_rx) = oneshot::channel();
-            trace!(
-                "[{}]<{}>send to db: {}: {:?}",
-                self.id,
-                self.connection.id,
-                db_id,
-                atomic_cmd
-            );
-            self.dispatcher.tasks_tx[db_id].send((atomic_cmd, ret_tx))?;
-
-            result_collector.merge(ret_rx).await?;
-            trace!(
-                "[{}]<{}>merge db {} result",
-                self.id,
-                self.connection.id,
-                db_id
-            );
-        }
-        let mut ret = result_collector.get_ret();
-        if ret.len() == 1 {
-            Ok(ret.pop().unwrap())
-        } else {
-            Ok(Frame::Arrays(ret))
-        }
+        run_traverse(cmd, |db_id| self.tasks_tx(db_id)).await
     }
 
     // #[instrument(skip(self))]
@@ -258,22 +500,33 @@ impl Handler {
                 }
             };
 
+            // single key writes are logged as they were sent.
+            let logged_frame = self.dispatcher.aof.as_ref().map(|_| frame.clone());
             let command = Command::new(frame);
             let ret_frame = match command {
-                Ok(Command::Zeroshot(cmd)) => match cmd {
-                    ZeroshotCommand::Ping(pong) => {
-                        if pong.is_none() {
-                            Frame::Pong
-                        } else {
-                            Frame::BulkStrings(pong.unwrap())
-                        }
-                    }
-                },
+                Ok(Command::Transaction(cmd)) => self.transaction_exec(cmd).await?,
+                Ok(cmd) if self.transaction.is_some() => self.queue(cmd, logged_frame),
+                Ok(Command::Snapshot(cmd)) => self.snapshot_exec(cmd).await?,
+                Ok(Command::Zeroshot(cmd)) => cmd.exec(),
                 Ok(Command::Traverse(mut cmd)) => {
                     cmd.dispatch(self.thread_num, |key: &[u8]| {
                         self.dispatcher.determine_database(key)
                     });
-                    self.traverse_exec(&mut cmd).await?
+                    match cmd.locked_databases() {
+                        dbs if dbs.is_empty() => self.traverse_exec(&mut cmd).await?,
+                        dbs => self.locked_traverse_exec(&mut cmd, dbs).await?,
+                    }
+                }
+                Ok(Command::Blocking(mut cmd)) => {
+                    cmd.dispatch(self.thread_num, |key: &[u8]| {
+                        self.dispatcher.determine_database(key)
+                    });
+                    match self.blocking_exec(&mut cmd).await? {
+                        Some(f) => f,
+                        None => {
+                            return Ok(());
+                        }
+                    }
                 }
                 Ok(Command::Oneshot(cmd)) => {
                     trace!(
@@ -285,12 +538,18 @@ impl Handler {
                     let (ret_tx, ret_rx) = oneshot::channel();
                     let db_id = self.dispatcher.determine_database(cmd.get_key());
 
-                    self.dispatcher.tasks_tx[db_id].send((cmd.into(), ret_tx))?;
+                    self.tasks_tx(db_id)
+                        .send((logged(cmd, logged_frame), ret_tx))?;
 
                     ret_rx.await.map_err(|e| Error::new(e))?
                 }
                 Err(e) => match e.downcast_ref::<CommandError>() {
-                    Some(e) => Frame::Errors(format!("{}", e).into()),
+                    Some(e) => {
+                        if let Some(transaction) = &mut self.transaction {
+                            transaction.aborted = true;
+                        }
+                        Frame::Errors(format!("{}", e).into())
+                    }
                     None => {
                         return Err(e);
                     }
@@ -329,6 +588,417 @@ impl Handler {
         Ok(())
     }
 
+    async fn transaction_exec(&mut self, cmd: TransactionCommand) -> Result<Frame> {
+        use TransactionCommand::*;
+        Ok(match (cmd, self.transaction.take()) {
+            (Multi, None) => {
+                self.transaction = Some(Transaction::default());
+                Frame::Ok
+            }
+            (Multi, transaction) => {
+                self.transaction = transaction;
+                Frame::Errors(Bytes::from_static(b"ERR MULTI calls can not be nested"))
+            }
+            (Exec, None) => Frame::Errors(Bytes::from_static(b"ERR EXEC without MULTI")),
+            (Discard, None) => Frame::Errors(Bytes::from_static(b"ERR DISCARD without MULTI")),
+            (Watch(_), transaction @ Some(_)) => {
+                self.transaction = transaction;
+                Frame::Errors(Bytes::from_static(b"ERR WATCH inside MULTI is not allowed"))
+            }
+            (Watch(keys), None) => {
+                self.watch(keys).await?;
+                Frame::Ok
+            }
+            // EXEC forgets the watched keys anyway, UNWATCH only has to answer.
+            (Unwatch, Some(mut transaction)) => {
+                transaction
+                    .queued
+                    .push((Command::Transaction(Unwatch), None));
+                self.transaction = Some(transaction);
+                Frame::SimpleString(Bytes::from_static(b"QUEUED"))
+            }
+            (Unwatch, None) | (Discard, Some(_)) => {
+                self.watched.clear();
+                Frame::Ok
+            }
+            (Exec, Some(transaction)) if transaction.aborted => {
+                self.watched.clear();
+                Frame::Errors(Bytes::from_static(
+                    b"EXECABORT Transaction discarded because of previous errors.",
+                ))
+            }
+            (Exec, Some(transaction)) => {
+                let watched = std::mem::take(&mut self.watched);
+                self.exec_queued(transaction.queued, watched).await?
+            }
+        })
+    }
+
+    /// Records the version every key is at, for EXEC to compare against.
+    async fn watch(&mut self, keys: Vec<Bytes>) -> Result<()> {
+        let mut keys_tbl = vec![Vec::new(); self.thread_num];
+        for key in keys {
+            keys_tbl[self.dispatcher.determine_database(&key)].push(key);
+        }
+        for (db_id, keys) in keys_tbl.into_iter().enumerate() {
+            if !keys.is_empty() {
+                let versions = self.versions(db_id, keys.clone()).await?;
+                self.watched.extend(keys.into_iter().zip(versions));
+            }
+        }
+        Ok(())
+    }
+
+    async fn versions(&self, db_id: usize, keys: Vec<Bytes>) -> Result<Vec<u64>> {
+        let (ret_tx, ret_rx) = oneshot::channel();
+        self.tasks_tx(db_id)
+            .send((Versions { keys }.into(), ret_tx))?;
+        match ret_rx.await.map_err(Error::new)? {
+            Frame::Arrays(v) => Ok(v
+                .into_iter()
+                .map(|f| match f {
+                    Frame::Integers(version) => version as u64,
+                    _ => unreachable!("versions are integers"),
+                })
+                .collect()),
+            _ => unreachable!("versions are replied as an array"),
+        }
+    }
+
+    /// Whether none of `watched` changed, asked through the held queues.
+    async fn unchanged(&self, watched: Vec<WatchedKey>) -> Result<bool> {
+        let mut watched_tbl = vec![Vec::new(); self.thread_num];
+        for (key, version) in watched {
+            watched_tbl[self.dispatcher.determine_database(&key)].push((key, version));
+        }
+        for (db_id, watched) in watched_tbl.into_iter().enumerate() {
+            if !watched.is_empty() {
+                let (keys, versions): (Vec<_>, Vec<_>) = watched.into_iter().unzip();
+                if self.versions(db_id, keys).await? != versions {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn queue(&mut self, cmd: Command, frame: Option<Frame>) -> Frame {
+        let transaction = self.transaction.as_mut().unwrap();
+        let allowed = match &cmd {
+            Command::HoldOn(c) => !c.need_subscribe(),
+            Command::Snapshot(_) => false,
+            _ => true,
+        };
+        if !allowed {
+            transaction.aborted = true;
+            return Frame::Errors(Bytes::from_static(
+                b"ERR Command not allowed inside a transaction",
+            ));
+        }
+        transaction.queued.push((cmd, frame));
+        Frame::SimpleString(Bytes::from_static(b"QUEUED"))
+    }
+
+    /// Runs the commands queued by MULTI, unless one of the `watched` keys
+    /// changed. If they all live on one database they are shipped in a single
+    /// message, otherwise every database they touch is locked, in ascending
+    /// order, until the last one has run.
+    async fn exec_queued(
+        &mut self,
+        mut queued: Vec<(Command, Option<Frame>)>,
+        watched: Vec<WatchedKey>,
+    ) -> Result<Frame> {
+        let involved = vec![Cell::new(false); self.thread_num];
+        for (key, _) in watched.iter() {
+            involved[self.dispatcher.determine_database(key)].set(true);
+        }
+        let mut single_key = true;
+        for (cmd, _) in queued.iter_mut() {
+            let called = Cell::new(false);
+            let dispatcher = &self.dispatcher;
+            let record = |key: &[u8]| {
+                let db_id = dispatcher.determine_database(key);
+                involved[db_id].set(true);
+                called.set(true);
+                db_id
+            };
+            match cmd {
+                Command::Oneshot(c) => {
+                    record(c.get_key());
+                }
+                Command::Traverse(c) => {
+                    single_key = false;
+                    c.dispatch(self.thread_num, record);
+                }
+                Command::HoldOn(c) => {
+                    single_key = false;
+                    c.dispatch(self.thread_num, record);
+                }
+                Command::Blocking(c) => {
+                    single_key = false;
+                    c.never_block();
+                    c.dispatch(self.thread_num, record);
+                }
+                Command::Zeroshot(_) | Command::Transaction(_) | Command::Snapshot(_) => {
+                    called.set(true)
+                }
+            }
+            // commands that pick no key visit every database.
+            if !called.get() {
+                involved.iter().for_each(|v| v.set(true));
+            }
+        }
+        let dbs: Vec<usize> = (0..self.thread_num)
+            .filter(|&db_id| involved[db_id].get())
+            .collect();
+        if single_key && dbs.len() <= 1 {
+            return self.batch_exec(queued, dbs.first().copied(), watched).await;
+        }
+
+        self.held = vec![None; self.thread_num];
+        let ret = self.locked_exec(queued, dbs, watched).await;
+        // dropping the private queues releases the databases.
+        self.held.clear();
+        ret
+    }
+
+    async fn batch_exec(
+        &self,
+        queued: Vec<(Command, Option<Frame>)>,
+        db_id: Option<usize>,
+        watched: Vec<WatchedKey>,
+    ) -> Result<Frame> {
+        let mut pongs = Vec::new();
+        let mut cmds = Vec::with_capacity(queued.len());
+        for (idx, (cmd, frame)) in queued.into_iter().enumerate() {
+            match cmd {
+                Command::Oneshot(c) => cmds.push(logged(c, frame)),
+                Command::Zeroshot(c) => pongs.push((idx, c.exec())),
+                Command::Transaction(_) => pongs.push((idx, Frame::Ok)),
+                _ => unreachable!("only single key commands are batched"),
+            }
+        }
+        let mut ret = match db_id {
+            Some(db_id) => {
+                let (ret_tx, ret_rx) = oneshot::channel();
+                self.tasks_tx(db_id)
+                    .send((Batch { cmds, watched }.into(), ret_tx))?;
+                match ret_rx.await.map_err(Error::new)? {
+                    Frame::Arrays(v) => v,
+                    Frame::NullArray => return Ok(Frame::NullArray),
+                    _ => unreachable!("a batch replies with an array"),
+                }
+            }
+            None => Vec::new(),
+        };
+        for (idx, pong) in pongs {
+            ret.insert(idx, pong);
+        }
+        Ok(Frame::Arrays(ret))
+    }
+
+    async fn locked_exec(
+        &mut self,
+        queued: Vec<(Command, Option<Frame>)>,
+        dbs: Vec<usize>,
+        watched: Vec<WatchedKey>,
+    ) -> Result<Frame> {
+        self.lock(dbs).await?;
+        if !self.unchanged(watched).await? {
+            return Ok(Frame::NullArray);
+        }
+
+        let mut ret = Vec::with_capacity(queued.len());
+        for (cmd, frame) in queued {
+            ret.push(match cmd {
+                Command::Oneshot(c) => {
+                    let (ret_tx, ret_rx) = oneshot::channel();
+                    let db_id = self.dispatcher.determine_database(c.get_key());
+                    self.tasks_tx(db_id).send((logged(c, frame), ret_tx))?;
+                    ret_rx.await.map_err(Error::new)?
+                }
+                Command::Traverse(mut c) => self.traverse_exec(&mut c).await?,
+                Command::HoldOn(mut c) => self.traverse_exec(&mut c).await?,
+                Command::Blocking(mut c) => match self.blocking_exec(&mut c).await? {
+                    Some(f) => f,
+                    None => c.timeout_reply(),
+                },
+                Command::Zeroshot(c) => c.exec(),
+                Command::Transaction(_) => Frame::Ok,
+                Command::Snapshot(_) => unreachable!("snapshots are never queued"),
+            });
+        }
+        self.log_transaction().await?;
+        Ok(Frame::Arrays(ret))
+    }
+
+    /// Logs what the held databases logged as one transaction, before any
+    /// of them is let go, so that nothing they run afterwards is logged
+    /// ahead of it.
+    async fn log_transaction(&self) -> Result<()> {
+        let aof = match &self.dispatcher.aof {
+            Some(aof) => aof,
+            None => return Ok(()),
+        };
+        let mut parts = Vec::new();
+        for (db_id, tx) in self.held.iter().enumerate() {
+            let tx = match tx {
+                Some(tx) => tx,
+                None => continue,
+            };
+            let (ret_tx, ret_rx) = oneshot::channel();
+            tx.send((TakeLogged {}.into(), ret_tx))?;
+            match ret_rx.await.map_err(Error::new)? {
+                Frame::BulkStrings(part) if !part.is_empty() => parts.push((db_id, part)),
+                _ => (),
+            }
+        }
+        if parts.is_empty() {
+            return Ok(());
+        }
+        if let Some(synced) = aof.log_transaction(parts) {
+            synced.await.map_err(Error::new)?;
+        }
+        Ok(())
+    }
+
+    /// Runs a traverse command holding the databases `dbs`, so that nobody
+    /// sees or changes them halfway through.
+    async fn locked_traverse_exec(
+        &mut self,
+        cmd: &mut TraverseCommand,
+        dbs: Vec<usize>,
+    ) -> Result<Frame> {
+        self.held = vec![None; self.thread_num];
+        let ret = self.held_traverse_exec(cmd, dbs).await;
+        self.held.clear();
+        ret
+    }
+
+    async fn held_traverse_exec(
+        &mut self,
+        cmd: &mut TraverseCommand,
+        dbs: Vec<usize>,
+    ) -> Result<Frame> {
+        self.lock(dbs).await?;
+        let ret = self.traverse_exec(cmd).await?;
+        self.log_transaction().await?;
+        Ok(ret)
+    }
+
+    /// Takes the databases `dbs`, in ascending order, over from everybody
+    /// else until `held` is cleared.
+    async fn lock(&mut self, dbs: impl IntoIterator<Item = usize>) -> Result<()> {
+        for db_id in dbs {
+            self.held[db_id] = Some(self.dispatcher.lock(db_id).await?);
+        }
+        Ok(())
+    }
+
+    async fn snapshot_exec(&mut self, cmd: SnapshotVariant) -> Result<Frame> {
+        match cmd {
+            SnapshotVariant::Save => self.save(SnapshotFormat::Native).await,
+            SnapshotVariant::BgSave => self.dispatcher.bgsave().await,
+            SnapshotVariant::LastSave => Ok(Frame::Integers(
+                self.dispatcher.persistence.lastsave() as i64,
+            )),
+            SnapshotVariant::BgRewriteAof => Ok(self.dispatcher.bgrewriteaof()),
+            SnapshotVariant::ExportRdb => self.save(SnapshotFormat::Rdb).await,
+        }
+    }
+
+    /// Writes a snapshot of all databases at once, none of them serves
+    /// anybody else until it has written all of its keys.
+    async fn save(&mut self, format: SnapshotFormat) -> Result<Frame> {
+        let (out, writer) = match self
+            .dispatcher
+            .persistence
+            .begin_save(self.thread_num, format)
+        {
+            Some(v) => v,
+            None => return Ok(Frame::Errors(Bytes::from_static(SAVE_IN_PROGRESS_ERR))),
+        };
+        self.held = vec![None; self.thread_num];
+        let ret = self.locked_snapshot(out, format).await;
+        self.held.clear();
+        let skipped = ret?;
+        Ok(match writer.await? {
+            false => Frame::Errors(Bytes::from_static(b"ERR failed to write the snapshot")),
+            // the file is written still, so that the rest of the keys can be
+            // migrated.
+            true if skipped > 0 => Frame::Errors(
+                format!(
+                    "ERR {} keys left out of the RDB file, it has no room for streams",
+                    skipped
+                )
+                .into(),
+            ),
+            true => Frame::Ok,
+        })
+    }
+
+    async fn locked_snapshot(
+        &mut self,
+        out: mpsc::UnboundedSender<SnapshotChunk>,
+        format: SnapshotFormat,
+    ) -> Result<usize> {
+        self.lock(0..self.thread_num).await?;
+        let mut skipped = 0;
+        for db_id in 0..self.thread_num {
+            let (ret_tx, ret_rx) = oneshot::channel();
+            self.tasks_tx(db_id)
+                .send((Snapshot::new(out.clone(), true, format).into(), ret_tx))?;
+            // a database that failed to write replies with an error, the
+            // writer tells the save failed.
+            if let Frame::Integers(n) = ret_rx.await.map_err(Error::new)? {
+                skipped += n as usize;
+            }
+        }
+        Ok(skipped)
+    }
+
+    /// Runs a blocking pop: tries every involved database in key order, then
+    /// waits for one of the waiters it left behind to be served or to time out.
+    /// `None` if the client went away or the server is shutting down meanwhile.
+    async fn blocking_exec(&mut self, cmd: &mut BPopDispatcher) -> Result<Option<Frame>> {
+        if let Some(mut gather) = cmd.gather() {
+            let ret = self.traverse_exec(&mut gather).await?;
+            if !matches!(ret, Frame::NullArray) || !cmd.blocks() {
+                return Ok(Some(ret));
+            }
+        }
+        let mut ret = self.traverse_exec(cmd).await?;
+        if let Frame::NullString = ret {
+            ret = match cmd.parked_reply() {
+                // dropping `reply_rx` tells the waiters nobody is listening.
+                Some(reply_rx) => tokio::select! {
+                    _ = self.shutdown_begin.recv() => {
+                        return Ok(None);
+                    }
+                    _ = self.connection.closed() => {
+                        return Ok(None);
+                    }
+                    res = reply_rx => res.map_err(Error::new)?,
+                },
+                None => cmd.timeout_reply(),
+            };
+        }
+        if let Frame::BulkStrings(element) = &ret {
+            if let Some((db_id, push)) = cmd.remote_push(element) {
+                let (ret_tx, ret_rx) = oneshot::channel();
+                self.tasks_tx(db_id).send((push, ret_tx))?;
+                if let err @ Frame::Errors(_) = ret_rx.await.map_err(Error::new)? {
+                    let (db_id, undo) = cmd.undo_pop(element);
+                    let (ret_tx, ret_rx) = oneshot::channel();
+                    self.tasks_tx(db_id).send((undo, ret_tx))?;
+                    ret_rx.await.map_err(Error::new)?;
+                    ret = err;
+                }
+            }
+        }
+        Ok(Some(ret))
+    }
+
     async fn unsubscribe_all(&self, sub_state: Vec<bool>) {
         let mut unsub_all = UnsubDispatcher::unsubscribe_all(self.id, sub_state, self.thread_num);
         let _ = self.traverse_exec(&mut unsub_all).await;
@@ -420,7 +1090,14 @@ impl Handler {
 }
 
 // #[instrument(skip(listener, shutdown_signal))]
-pub async fn run(listener: TcpListener, shutdown_signal: impl Future, num_threads: usize) {
+pub async fn run(
+    listener: TcpListener,
+    shutdown_signal: impl Future,
+    num_threads: usize,
+    config: DBConfig,
+    snapshot: SnapshotConfig,
+    aof: Option<AofConfig>,
+) {
     info!("Service Starting");
     let (shutdown_begin_tx, mut shutdown_begin_rx) = broadcast::channel(1);
 
@@ -432,12 +1109,34 @@ pub async fn run(listener: TcpListener, shutdown_signal: impl Future, num_thread
             &shutdown_begin_tx,
             &shutdown_complete_tx,
             num_threads,
+            &config,
+            snapshot,
+            aof,
         )),
         shutdown_begin_tx,
         shutdown_complete_rx,
         shutdown_complete_tx,
     };
 
+    let restored = restore(
+        &server.dispatcher,
+        Shutdown::new(server.shutdown_begin_tx.subscribe()),
+        server.shutdown_complete_tx.clone(),
+    )
+    .await;
+    match restored {
+        Ok(()) => (),
+        Err(e) => {
+            error!("failed to restore the databases: {}", e);
+            let _ = server.shutdown_begin_tx.send(());
+            return;
+        }
+    }
+    spawn(save_on_rules(
+        server.dispatcher.clone(),
+        Shutdown::new(server.shutdown_begin_tx.subscribe()),
+    ));
+
     tokio::select! {
         res = server.run() => {
             match res {
@@ -470,3 +1169,444 @@ pub async fn run(listener: TcpListener, shutdown_signal: impl Future, num_thread
     let _ = shutdown_complete_rx.recv().await;
     info!("Shutdown Complete");
 }
+
+/// Restores what a previous run left, from the AOF if there is one and from
+/// the snapshot otherwise, then has the databases serve clients. An RDB file
+/// to load takes the place of both.
+async fn restore(
+    dispatcher: &Dispatcher,
+    shutdown: Shutdown,
+    shutdown_complete_tx: mpsc::Sender<()>,
+) -> Result<()> {
+    let rewrite = if dispatcher.persistence.load_rdb() {
+        let loaded = dispatcher.load_rdb().await?;
+        info!("{} keys loaded from the RDB file", loaded);
+        // the log left by a previous run no longer tells what we hold.
+        dispatcher.aof.is_some()
+    } else {
+        match dispatcher.load_aof().await? {
+            Some(replayed) => {
+                info!("{} commands replayed from the AOF", replayed);
+                false
+            }
+            None => {
+                let loaded = dispatcher.load_snapshot().await?;
+                info!("{} keys loaded from the snapshot", loaded);
+                // a new log starts from what the snapshot held.
+                loaded > 0 && dispatcher.aof.is_some()
+            }
+        }
+    };
+    dispatcher
+        .finish_loading(shutdown, shutdown_complete_tx)
+        .await?;
+    if rewrite {
+        dispatcher.bgrewriteaof();
+    }
+    Ok(())
+}
+
+/// Runs `cmd` on the databases it is dispatched to, reached through
+/// `tasks_tx`.
+async fn run_traverse<'a, T>(
+    cmd: &mut T,
+    tasks_tx: impl Fn(usize) -> &'a mpsc::UnboundedSender<TaskParam>,
+) -> Result<Frame>
+where
+    T: DispatchToMultipleDB + std::fmt::Debug,
+{
+    let mut result_collector = cmd.get_result_collector();
+
+    while let Some((db_id, atomic_cmd)) = cmd.next_command() {
+        let (ret_tx, ret_rx) = oneshot::channel();
+        trace!("send to db: {}: {:?}", db_id, atomic_cmd);
+        tasks_tx(db_id).send((atomic_cmd, ret_tx))?;
+
+        result_collector.merge(ret_rx).await?;
+        trace!("merge db {} result", db_id);
+    }
+    let mut ret = result_collector.get_ret();
+    let merged = if ret.len() == 1 {
+        ret.pop().unwrap()
+    } else {
+        Frame::Arrays(ret)
+    };
+    match cmd.complete(merged) {
+        Completion::Reply(f) => Ok(f),
+        Completion::Forward((db_id, atomic_cmd)) => {
+            let (ret_tx, ret_rx) = oneshot::channel();
+            tasks_tx(db_id).send((atomic_cmd, ret_tx))?;
+            ret_rx.await.map_err(|e| Error::new(e))
+        }
+    }
+}
+
+/// Starts a background save whenever one of the save rules asks for it.
+async fn save_on_rules(dispatcher: Arc<Dispatcher>, mut shutdown: Shutdown) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            _ = interval.tick() => {
+                if dispatcher.persistence.is_due() {
+                    let _ = dispatcher.bgsave().await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::SocketAddr, path::PathBuf};
+    use tokio::net::TcpStream;
+
+    /// A client of a fresh server with 4 databases, which serves until the
+    /// test ends.
+    async fn client() -> Connection {
+        connect(server().await).await
+    }
+
+    async fn server() -> SocketAddr {
+        serve(snapshot_at(temp_file("dump.ards")), None).await
+    }
+
+    /// A path in the temp dir no other test uses.
+    fn temp_file(name: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let id = NEXT.fetch_add(1, SeqCst);
+        let path = std::env::temp_dir().join(format!(
+            "async-redis-test-{}-{}-{}",
+            std::process::id(),
+            id,
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn snapshot_at(path: PathBuf) -> SnapshotConfig {
+        SnapshotConfig {
+            path,
+            ..SnapshotConfig::default()
+        }
+    }
+
+    fn aof_at(path: &PathBuf) -> Option<AofConfig> {
+        Some(AofConfig {
+            path: path.clone(),
+            fsync: FsyncPolicy::Always,
+        })
+    }
+
+    /// A server with 4 databases which restores what `snapshot` and `aof`
+    /// hold, and serves until the test ends.
+    async fn serve(snapshot: SnapshotConfig, aof: Option<AofConfig>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = std::future::pending::<()>();
+        spawn(run(
+            listener,
+            shutdown,
+            4,
+            DBConfig::default(),
+            snapshot,
+            aof,
+        ));
+        addr
+    }
+
+    async fn connect(addr: SocketAddr) -> Connection {
+        Connection::new(TcpStream::connect(addr).await.unwrap(), 0)
+    }
+
+    async fn call(conn: &mut Connection, args: &[&str]) -> String {
+        let frame = Frame::Arrays(
+            args.iter()
+                .map(|arg| Frame::BulkStrings(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect(),
+        );
+        conn.write_frame(&frame).await.unwrap();
+        format!("{:?}", conn.read_frame().await.unwrap().unwrap())
+    }
+
+    #[tokio::test]
+    async fn exec_runs_queued_commands() {
+        let mut conn = client().await;
+        assert_eq!(call(&mut conn, &["MULTI"]).await, r#"SimpleString(b"OK")"#);
+        for cmd in [&["SET", "a", "1"][..], &["INCR", "a"], &["MGET", "a", "b"]] {
+            assert_eq!(call(&mut conn, cmd).await, r#"SimpleString(b"QUEUED")"#);
+        }
+        assert_eq!(
+            call(&mut conn, &["EXEC"]).await,
+            r#"Arrays([SimpleString(b"OK"), Integers(2), Arrays([Integers(2), NullString])])"#
+        );
+        assert_eq!(
+            call(&mut conn, &["EXEC"]).await,
+            r#"Errors(b"ERR EXEC without MULTI")"#
+        );
+    }
+
+    #[tokio::test]
+    async fn discard_drops_queued_commands() {
+        let mut conn = client().await;
+        call(&mut conn, &["MULTI"]).await;
+        call(&mut conn, &["SET", "a", "1"]).await;
+        assert_eq!(
+            call(&mut conn, &["DISCARD"]).await,
+            r#"SimpleString(b"OK")"#
+        );
+        assert_eq!(call(&mut conn, &["GET", "a"]).await, "NullString");
+        assert_eq!(
+            call(&mut conn, &["DISCARD"]).await,
+            r#"Errors(b"ERR DISCARD without MULTI")"#
+        );
+    }
+
+    #[tokio::test]
+    async fn queuing_error_aborts_exec() {
+        let mut conn = client().await;
+        for rejected in [&["NOSUCHCMD"][..], &["GET"], &["SUBSCRIBE", "ch"]] {
+            call(&mut conn, &["MULTI"]).await;
+            call(&mut conn, &["SET", "a", "1"]).await;
+            assert!(call(&mut conn, rejected).await.starts_with("Errors"));
+            assert_eq!(
+                call(&mut conn, &["EXEC"]).await,
+                r#"Errors(b"EXECABORT Transaction discarded because of previous errors.")"#,
+                "{:?}",
+                rejected
+            );
+            assert_eq!(call(&mut conn, &["GET", "a"]).await, "NullString");
+        }
+    }
+
+    #[tokio::test]
+    async fn write_to_watched_key_aborts_exec() {
+        let addr = server().await;
+        let (mut conn, mut other) = (connect(addr).await, connect(addr).await);
+        call(&mut conn, &["SET", "a", "1"]).await;
+        call(&mut conn, &["WATCH", "a", "b"]).await;
+        call(&mut other, &["SET", "a", "2"]).await;
+        call(&mut conn, &["MULTI"]).await;
+        call(&mut conn, &["SET", "b", "1"]).await;
+        assert_eq!(call(&mut conn, &["EXEC"]).await, "NullArray");
+        assert_eq!(call(&mut conn, &["GET", "b"]).await, "NullString");
+        // EXEC forgets the watched keys.
+        call(&mut other, &["SET", "a", "3"]).await;
+        call(&mut conn, &["MULTI"]).await;
+        call(&mut conn, &["SET", "b", "1"]).await;
+        assert_eq!(
+            call(&mut conn, &["EXEC"]).await,
+            r#"Arrays([SimpleString(b"OK")])"#
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_watched_key_created_and_deleted_aborts_exec() {
+        let addr = server().await;
+        let (mut conn, mut other) = (connect(addr).await, connect(addr).await);
+        call(&mut conn, &["WATCH", "a"]).await;
+        call(&mut other, &["SET", "a", "1"]).await;
+        call(&mut other, &["DEL", "a"]).await;
+        call(&mut conn, &["MULTI"]).await;
+        call(&mut conn, &["SET", "b", "1"]).await;
+        assert_eq!(call(&mut conn, &["EXEC"]).await, "NullArray");
+        assert_eq!(call(&mut conn, &["GET", "b"]).await, "NullString");
+    }
+
+    #[tokio::test]
+    async fn xreadgroup_reads_every_database_in_key_order() {
+        let mut conn = client().await;
+        let keys = ["s4", "s1", "s3", "s2"];
+        for key in keys {
+            call(&mut conn, &["XADD", key, "1-1", "f", key]).await;
+            call(&mut conn, &["XGROUP", "CREATE", key, "g", "0"]).await;
+        }
+        let mut args = vec!["XREADGROUP", "GROUP", "g", "c", "STREAMS"];
+        args.extend(keys);
+        args.extend([">"; 4]);
+        let ret = call(&mut conn, &args).await;
+        let at: Vec<usize> = keys
+            .iter()
+            .map(|key| ret.find(&format!(r#"BulkStrings(b"{}")"#, key)).unwrap())
+            .collect();
+        assert!(at.windows(2).all(|w| w[0] < w[1]), "{}", ret);
+        assert_eq!(call(&mut conn, &args).await, "NullArray");
+    }
+
+    /// Leaves a key of every kind, and every encoding, on the server.
+    async fn populate(conn: &mut Connection) {
+        let cmds: &[&[&str]] = &[
+            &["SET", "str", "hello"],
+            &["SET", "int", "41"],
+            &["INCR", "int"],
+            &["SETBIT", "bits", "7", "1"],
+            &["SET", "ttl", "v", "EX", "1000"],
+            &["RPUSH", "list", "a", "b", "c"],
+            &["HSET", "hash", "f1", "v1", "f2", "v2"],
+            &["HEXPIRE", "hash", "1000", "FIELDS", "1", "f2"],
+            &["SADD", "ints", "3", "1", "2"],
+            &["SADD", "set", "a", "b"],
+            &["ZADD", "zset", "1", "a", "2.5", "b"],
+            &["XADD", "stream", "1-1", "f", "1"],
+            &["XADD", "stream", "2-1", "f", "2"],
+            &["XADD", "stream", "3-1", "f", "3"],
+            &["XDEL", "stream", "3-1"],
+            &["XGROUP", "CREATE", "stream", "g", "0"],
+            &[
+                "XREADGROUP",
+                "GROUP",
+                "g",
+                "c",
+                "COUNT",
+                "1",
+                "STREAMS",
+                "stream",
+                ">",
+            ],
+            &["PFADD", "hll", "a", "b", "c"],
+        ];
+        for cmd in cmds {
+            assert!(!call(conn, cmd).await.starts_with("Errors"), "{:?}", cmd);
+        }
+    }
+
+    /// What the keys left by `populate` read as.
+    async fn describe(conn: &mut Connection) -> Vec<String> {
+        let cmds: &[&[&str]] = &[
+            &["GET", "str"],
+            &["GETRANGE", "int", "0", "-1"],
+            &["GET", "bits"],
+            &["EXPIRETIME", "ttl"],
+            &["LRANGE", "list", "0", "-1"],
+            &["HMGET", "hash", "f1", "f2"],
+            &["SMEMBERS", "ints"],
+            &["SMISMEMBER", "set", "a", "b", "c"],
+            &["ZRANGE", "zset", "0", "-1", "WITHSCORES"],
+            &["XRANGE", "stream", "-", "+"],
+            &["XPENDING", "stream", "g"],
+            &["XADD", "stream", "3-1", "f", "3"],
+            &["PFCOUNT", "hll"],
+        ];
+        let mut ret = Vec::new();
+        for cmd in cmds {
+            ret.push(call(conn, cmd).await);
+        }
+        let ttl = call(conn, &["HTTL", "hash", "FIELDS", "2", "f1", "f2"]).await;
+        assert!(
+            ttl.starts_with("Arrays([Integers(-1), Integers("),
+            "{}",
+            ttl
+        );
+        assert!(!ttl.ends_with("Integers(-2)])"), "{}", ttl);
+        ret
+    }
+
+    #[tokio::test]
+    async fn snapshot_restores_every_type() {
+        let path = temp_file("dump.ards");
+        let mut conn = connect(serve(snapshot_at(path.clone()), None).await).await;
+        populate(&mut conn).await;
+        let before = describe(&mut conn).await;
+        assert_eq!(call(&mut conn, &["SAVE"]).await, r#"SimpleString(b"OK")"#);
+
+        let mut conn = connect(serve(snapshot_at(path.clone()), None).await).await;
+        assert_eq!(describe(&mut conn).await, before);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn save_rules_snapshot_after_enough_writes() {
+        let path = temp_file("dump.ards");
+        let snapshot = SnapshotConfig {
+            rules: vec![SaveRule {
+                secs: 0,
+                changes: 2,
+            }],
+            ..snapshot_at(path.clone())
+        };
+        let mut conn = connect(serve(snapshot, None).await).await;
+        call(&mut conn, &["SET", "a", "1"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        assert!(!path.exists());
+        call(&mut conn, &["SET", "b", "2"]).await;
+        for _ in 0..50 {
+            if path.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        let mut conn = connect(serve(snapshot_at(path.clone()), None).await).await;
+        assert_eq!(
+            call(&mut conn, &["MGET", "a", "b"]).await,
+            r#"Arrays([BulkStrings(b"1"), BulkStrings(b"2")])"#
+        );
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn aof_logs_relative_deadlines_as_unix_time() {
+        let (snapshot, path) = (temp_file("dump.ards"), temp_file("appendonly.aof"));
+        let addr = serve(snapshot_at(snapshot.clone()), aof_at(&path)).await;
+        let mut conn = connect(addr).await;
+        call(&mut conn, &["SET", "k", "v", "EX", "10"]).await;
+        let log = String::from_utf8(std::fs::read(&path).unwrap()).unwrap();
+        let at = log.split("\r\n").skip_while(|arg| *arg != "PXAT").nth(2);
+        let at: u64 = at.unwrap().parse().unwrap();
+        let expected = crate::utils::unix_millis_now() + 10_000;
+        assert!(at <= expected && at + 1000 > expected, "{}", log);
+
+        let addr = serve(snapshot_at(snapshot.clone()), aof_at(&path)).await;
+        let mut conn = connect(addr).await;
+        let ttl = call(&mut conn, &["PTTL", "k"]).await;
+        assert!(ttl.starts_with("Integers(9"), "{}", ttl);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn aof_drops_a_transaction_missing_its_exec() {
+        let (snapshot, path) = (temp_file("dump.ards"), temp_file("appendonly.aof"));
+        let committed = "*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n";
+        let cut = "*1\r\n$5\r\nMULTI\r\n*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n";
+        std::fs::write(&path, format!("{}{}", committed, cut)).unwrap();
+
+        let addr = serve(snapshot_at(snapshot), aof_at(&path)).await;
+        let mut conn = connect(addr).await;
+        assert_eq!(
+            call(&mut conn, &["MGET", "a", "b"]).await,
+            r#"Arrays([BulkStrings(b"1"), NullString])"#
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), committed.as_bytes());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn aof_rewrite_restores_every_type() {
+        let (snapshot, path) = (temp_file("dump.ards"), temp_file("appendonly.aof"));
+        let mut conn = connect(serve(snapshot_at(snapshot.clone()), aof_at(&path)).await).await;
+        populate(&mut conn).await;
+        let before = describe(&mut conn).await;
+
+        // replays the log as it was written.
+        let mut other = connect(serve(snapshot_at(snapshot.clone()), aof_at(&path)).await).await;
+        assert_eq!(describe(&mut other).await, before);
+
+        assert!(call(&mut conn, &["BGREWRITEAOF"])
+            .await
+            .starts_with("SimpleString"));
+        // the rewritten log sets the counter rather than incrementing it.
+        for _ in 0..50 {
+            let log = std::fs::read(&path).unwrap();
+            if !log.windows(4).any(|w| w == b"INCR") {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        let log = std::fs::read(&path).unwrap();
+        assert!(!log.windows(4).any(|w| w == b"INCR"));
+        let mut other = connect(serve(snapshot_at(snapshot), aof_at(&path)).await).await;
+        assert_eq!(describe(&mut other).await, before);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -3,7 +3,12 @@ use anyhow::{anyhow, Error};
 use bytes::Bytes;
 use num_traits::{FromPrimitive, PrimInt, ToPrimitive, Zero};
 use rustc_hash::FxHashMap;
-use std::{hash::Hash, slice::Iter};
+use std::{
+    hash::Hash,
+    slice::Iter,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Instant;
 
 #[macro_export]
 macro_rules! BytesToString {
@@ -64,16 +69,170 @@ pub fn get_integer(line: &Bytes) -> Result<i64> {
     } else {
         (false, &line[..])
     };
-    let mut res = 0;
+    if line.is_empty() {
+        return Err(anyhow!("Not Digit: {:?}", line));
+    }
+    let mut res: i64 = 0;
     for v in line {
         if *v >= b'0' && *v <= b'9' {
-            res = res * 10 + ((*v - b'0') as i64);
+            let digit = (*v - b'0') as i64;
+            // negatives are accumulated as such, so that `i64::MIN` fits.
+            res = res
+                .checked_mul(10)
+                .and_then(|r| {
+                    if neg {
+                        r.checked_sub(digit)
+                    } else {
+                        r.checked_add(digit)
+                    }
+                })
+                .ok_or_else(|| anyhow!("Out Of Range: {:?}", line))?;
         } else {
             return Err(anyhow!("Not Digit: {:?}", line));
         }
     }
 
-    Ok(if neg { -res } else { res })
+    Ok(res)
+}
+
+pub fn get_float(line: &Bytes) -> Result<f64> {
+    std::str::from_utf8(line.as_ref())
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|v| !v.is_nan())
+        .ok_or_else(|| anyhow!("Not Float: {:?}", line))
+}
+
+/// Maps a unix timestamp onto the monotonic clock, timestamps in the past
+/// become `now` (or earlier) so that they read as already expired.
+pub fn unix_millis_to_instant(millis: i64) -> Instant {
+    let target = UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64);
+    let now = Instant::now();
+    match target.duration_since(SystemTime::now()) {
+        Ok(dur) => now + dur,
+        Err(e) => now.checked_sub(e.duration()).unwrap_or(now),
+    }
+}
+
+pub fn instant_to_unix_millis(instant: Instant) -> i64 {
+    let now = Instant::now();
+    let sys_now = SystemTime::now();
+    let sys = if instant >= now {
+        sys_now + instant.duration_since(now)
+    } else {
+        sys_now - now.duration_since(instant)
+    };
+    sys.duration_since(UNIX_EPOCH)
+        .map_or(0, |v| v.as_millis() as i64)
+}
+
+pub fn unix_millis_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |v| v.as_millis() as u64)
+}
+
+/// Clamps a Redis style inclusive range, where negative indexes count from
+/// the end, to a collection of `len` elements. `None` if nothing is left.
+pub fn normalize_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+/// Matches `c` against the `[...]` class `pattern` starts with. Returns
+/// whether it matched and how many bytes the class spans.
+fn glob_match_class(pattern: &[u8], c: u8) -> (bool, usize) {
+    let mut i = 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            i += 1;
+            matched |= pattern[i] == c;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' {
+            let (lo, hi) = if pattern[i] <= pattern[i + 2] {
+                (pattern[i], pattern[i + 2])
+            } else {
+                (pattern[i + 2], pattern[i])
+            };
+            matched |= lo <= c && c <= hi;
+            i += 2;
+        } else {
+            matched |= pattern[i] == c;
+        }
+        i += 1;
+    }
+    // an unterminated class ends with the pattern.
+    (matched != negate, (i + 1).min(pattern.len()))
+}
+
+/// Redis flavoured glob matching: `*`, `?`, `[...]` (with `^` and ranges)
+/// and `\` to escape the next byte.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // where to resume after the last `*` if the current attempt fails.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while s < string.len() {
+        if p < pattern.len() {
+            let step = match pattern[p] {
+                b'*' => {
+                    backtrack = Some((p, s));
+                    p += 1;
+                    continue;
+                }
+                b'?' => Some(1),
+                b'[' => match glob_match_class(&pattern[p..], string[s]) {
+                    (true, len) => Some(len),
+                    _ => None,
+                },
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == string[s] {
+                        Some(2)
+                    } else {
+                        None
+                    }
+                }
+                c => {
+                    if c == string[s] {
+                        Some(1)
+                    } else {
+                        None
+                    }
+                }
+            };
+            if let Some(len) = step {
+                p += len;
+                s += 1;
+                continue;
+            }
+        }
+        match backtrack {
+            Some((star_p, star_s)) => {
+                backtrack = Some((star_p, star_s + 1));
+                p = star_p + 1;
+                s = star_s + 1;
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
 }
 
 const ASSERT: [(); 1] = [()];
@@ -202,3 +361,100 @@ where
         }
     }
 }
+
+/// Map from byte keys whose entries keep the position they were inserted at
+/// for as long as they live, freed positions are handed out again. Positions
+/// can thus be used as cursors, which survive inserts and removals.
+#[derive(Debug)]
+pub struct SlotMap<V> {
+    index: FxHashMap<Bytes, usize>,
+    slots: Vec<Option<(Bytes, V)>>,
+    vacant: Vec<usize>,
+}
+
+impl<V> SlotMap<V> {
+    pub fn new() -> Self {
+        Self {
+            index: FxHashMap::default(),
+            slots: Vec::new(),
+            vacant: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn get(&self, k: &[u8]) -> Option<&V> {
+        self.index
+            .get(k)
+            .and_then(|idx| self.slots[*idx].as_ref())
+            .map(|v| &v.1)
+    }
+
+    pub fn get_mut(&mut self, k: &[u8]) -> Option<&mut V> {
+        let idx = *self.index.get(k)?;
+        self.slots[idx].as_mut().map(|v| &mut v.1)
+    }
+
+    pub fn insert(&mut self, k: Bytes, v: V) -> Option<V> {
+        if let Some(old) = self.get_mut(&k) {
+            return Some(std::mem::replace(old, v));
+        }
+        let idx = match self.vacant.pop() {
+            Some(idx) => idx,
+            None => {
+                self.slots.push(None);
+                self.slots.len() - 1
+            }
+        };
+        self.index.insert(k.clone(), idx);
+        self.slots[idx] = Some((k, v));
+        None
+    }
+
+    pub fn remove(&mut self, k: &[u8]) -> Option<V> {
+        let idx = self.index.remove(k)?;
+        self.vacant.push(idx);
+        self.slots[idx].take().map(|v| v.1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &V)> {
+        self.slots
+            .iter()
+            .filter_map(|v| v.as_ref().map(|v| (&v.0, &v.1)))
+    }
+
+    /// Number of positions, taken or vacant.
+    pub fn slots_len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn slot(&self, idx: usize) -> Option<(&Bytes, &V)> {
+        self.slots
+            .get(idx)
+            .and_then(|v| v.as_ref())
+            .map(|v| (&v.0, &v.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h*llo", b"heeeello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(glob_match(b"user:*:name", b"user:42:name"));
+        assert!(!glob_match(b"user:*:name", b"user:42:age"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(glob_match(b"*a*b*", b"xxaxxbxx"));
+        assert!(!glob_match(b"?", b""));
+    }
+}